@@ -0,0 +1,677 @@
+use std::{borrow::Cow, collections::BTreeMap, fmt, str};
+
+use serde::{
+    de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor},
+    ser::{Serialize, SerializeMap, SerializeSeq, Serializer},
+};
+
+// 防止恶意输入耗尽栈或内存
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+pub const DEFAULT_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+// serde 通过这个名字识别 RawValue，和 serde_json 的做法一样
+pub(crate) const RAW_VALUE_TOKEN: &str = "$bencode::RawValue";
+
+pub type Dict<'a> = BTreeMap<Cow<'a, [u8]>, Value<'a>>;
+
+// Value is a decoded bencode value, byte strings and keys borrow from the input when decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<'a> {
+    Int(i64),
+    Bytes(Cow<'a, [u8]>),
+    List(Vec<Value<'a>>),
+    Dict(Dict<'a>),
+}
+
+impl<'a> Value<'a> {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Value<'a>]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict<'a>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    // get looks up a key when the value is a dictionary
+    pub fn get(&self, key: &str) -> Option<&Value<'a>> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Value::Int(i) => Value::Int(i),
+            Value::Bytes(b) => Value::Bytes(Cow::Owned(b.into_owned())),
+            Value::List(list) => Value::List(list.into_iter().map(Value::into_owned).collect()),
+            Value::Dict(dict) => Value::Dict(dict.into_iter().map(|(k, v)| (Cow::Owned(k.into_owned()), v.into_owned())).collect()),
+        }
+    }
+
+    // encode returns the canonical encoding, dictionary keys are written in sorted order
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_into(&mut out);
+        out
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(i) => {
+                out.push(b'i');
+                out.extend_from_slice(i.to_string().as_bytes());
+                out.push(b'e');
+            },
+            Value::Bytes(b) => encode_bytes(b, out),
+            Value::List(list) => {
+                out.push(b'l');
+                for v in list {
+                    v.encode_into(out);
+                }
+                out.push(b'e');
+            },
+            Value::Dict(dict) => {
+                out.push(b'd');
+                for (k, v) in dict {
+                    encode_bytes(k, out);
+                    v.encode_into(out);
+                }
+                out.push(b'e');
+            },
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+impl From<i64> for Value<'_> {
+    fn from(i: i64) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(s: &'a str) -> Self {
+        Value::Bytes(Cow::Borrowed(s.as_bytes()))
+    }
+}
+
+impl From<String> for Value<'_> {
+    fn from(s: String) -> Self {
+        Value::Bytes(Cow::Owned(s.into_bytes()))
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value<'a> {
+    fn from(b: &'a [u8]) -> Self {
+        Value::Bytes(Cow::Borrowed(b))
+    }
+}
+
+impl From<Vec<u8>> for Value<'_> {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Bytes(Cow::Owned(b))
+    }
+}
+
+impl<'a> From<Vec<Value<'a>>> for Value<'a> {
+    fn from(list: Vec<Value<'a>>) -> Self {
+        Value::List(list)
+    }
+}
+
+impl<'a> From<Dict<'a>> for Value<'a> {
+    fn from(dict: Dict<'a>) -> Self {
+        Value::Dict(dict)
+    }
+}
+
+// BencodeError is returned for malformed input or values bencode can't represent
+#[derive(Debug, Clone, PartialEq)]
+pub struct BencodeError {
+    // byte offset in the input, None for encoding and serde errors
+    pub pos: Option<usize>,
+    pub reason: String,
+}
+
+impl BencodeError {
+    pub fn new(reason: impl fmt::Display) -> Self {
+        Self {
+            pos: None,
+            reason: reason.to_string(),
+        }
+    }
+
+    pub(crate) fn at(pos: usize, reason: impl fmt::Display) -> Self {
+        Self {
+            pos: Some(pos),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pos {
+            Some(pos) => write!(f, "bencode: {} at byte {}", self.reason, pos),
+            None => write!(f, "bencode: {}", self.reason),
+        }
+    }
+}
+
+impl std::error::Error for BencodeError {}
+
+impl de::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::new(msg)
+    }
+}
+
+impl serde::ser::Error for BencodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::new(msg)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, BencodeError>;
+
+// Options controls how forgiving the decoder is. Strict mode only accepts the canonical
+// encoding: no leading zeros, no negative zero, sorted unique keys and nothing after the value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub strict: bool,
+    // deepest nesting of lists and dictionaries
+    pub max_depth: usize,
+    // largest input accepted at all
+    pub max_size: usize,
+}
+
+impl Options {
+    pub fn strict() -> Self {
+        Self {
+            strict: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn lenient() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+// Parser walks the input without copying, it is shared by the Value decoder and the serde deserializer
+pub(crate) struct Parser<'a> {
+    input: &'a [u8],
+    pub(crate) pos: usize,
+    options: Options,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(input: &'a [u8], options: Options) -> Result<Self> {
+        if input.len() > options.max_size {
+            return Err(BencodeError::new(format!("input of {} bytes exceeds the limit of {}", input.len(), options.max_size)));
+        }
+        Ok(Self { input, pos: 0, options, depth: 0 })
+    }
+
+    pub(crate) fn peek(&self) -> Result<u8> {
+        self.input.get(self.pos).copied().ok_or_else(|| BencodeError::at(self.pos, "unexpected end of input"))
+    }
+
+    pub(crate) fn expect(&mut self, byte: u8) -> Result<()> {
+        if self.peek()? != byte {
+            return Err(BencodeError::at(self.pos, format!("expected '{}'", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // enter consumes the 'l' or 'd' of a container and checks the depth limit
+    pub(crate) fn enter(&mut self) -> Result<()> {
+        if self.depth >= self.options.max_depth {
+            return Err(BencodeError::at(self.pos, "nesting too deep"));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    // leave consumes the 'e' closing a container
+    pub(crate) fn leave(&mut self) -> Result<()> {
+        self.expect(b'e')?;
+        self.depth -= 1;
+        Ok(())
+    }
+
+    pub(crate) fn at_end_of_container(&self) -> Result<bool> {
+        Ok(self.peek()? == b'e')
+    }
+
+    // digits reads an unsigned decimal up to terminator
+    fn digits(&mut self, terminator: u8) -> Result<u64> {
+        let start = self.pos;
+        let mut n = 0u64;
+        while self.peek()? != terminator {
+            let c = self.peek()?;
+            if !c.is_ascii_digit() {
+                return Err(BencodeError::at(self.pos, format!("unexpected byte 0x{:02x} in number", c)));
+            }
+            n = n
+                .checked_mul(10)
+                .and_then(|n| n.checked_add((c - b'0') as u64))
+                .ok_or_else(|| BencodeError::at(start, "number out of range"))?;
+            self.pos += 1;
+        }
+        let len = self.pos - start;
+        if len == 0 {
+            return Err(BencodeError::at(start, "empty number"));
+        }
+        if self.options.strict && len > 1 && self.input[start] == b'0' {
+            return Err(BencodeError::at(start, "leading zero"));
+        }
+        self.pos += 1;
+        Ok(n)
+    }
+
+    pub(crate) fn int(&mut self) -> Result<i64> {
+        let start = self.pos;
+        self.expect(b'i')?;
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let n = self.digits(b'e')?;
+        if negative {
+            if n == 0 && self.options.strict {
+                return Err(BencodeError::at(start, "negative zero"));
+            }
+            if n > i64::MAX as u64 + 1 {
+                return Err(BencodeError::at(start, "integer out of range"));
+            }
+            return Ok((n as i64).wrapping_neg());
+        }
+        i64::try_from(n).map_err(|_| BencodeError::at(start, "integer out of range"))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8]> {
+        let start = self.pos;
+        let len = self.digits(b':')? as usize;
+        if len > self.input.len() - self.pos {
+            return Err(BencodeError::at(start, format!("byte string of {} bytes runs past the end", len)));
+        }
+        let bytes = &self.input[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    // dict_key reads the next key and checks the ordering against the previous one in strict mode
+    pub(crate) fn dict_key(&mut self, prev: Option<&[u8]>) -> Result<&'a [u8]> {
+        let start = self.pos;
+        if !self.peek()?.is_ascii_digit() {
+            return Err(BencodeError::at(start, "dictionary key is not a byte string"));
+        }
+        let key = self.bytes()?;
+        if self.options.strict && prev.is_some_and(|prev| prev >= key) {
+            return Err(BencodeError::at(start, "dictionary keys are not sorted or not unique"));
+        }
+        Ok(key)
+    }
+
+    pub(crate) fn value(&mut self) -> Result<Value<'a>> {
+        match self.peek()? {
+            b'i' => Ok(Value::Int(self.int()?)),
+            b'0'..=b'9' => Ok(Value::Bytes(Cow::Borrowed(self.bytes()?))),
+            b'l' => {
+                self.enter()?;
+                let mut list = vec![];
+                while !self.at_end_of_container()? {
+                    list.push(self.value()?);
+                }
+                self.leave()?;
+                Ok(Value::List(list))
+            },
+            b'd' => {
+                self.enter()?;
+                let mut dict = Dict::new();
+                let mut prev = None;
+                while !self.at_end_of_container()? {
+                    let key = self.dict_key(prev)?;
+                    let value = self.value()?;
+                    dict.insert(Cow::Borrowed(key), value);
+                    prev = Some(key);
+                }
+                self.leave()?;
+                Ok(Value::Dict(dict))
+            },
+            c => Err(BencodeError::at(self.pos, format!("unexpected byte 0x{:02x}", c))),
+        }
+    }
+
+    // skip_value steps over one value and returns its exact bytes
+    pub(crate) fn skip_value(&mut self) -> Result<&'a [u8]> {
+        let start = self.pos;
+        match self.peek()? {
+            b'i' => {
+                self.int()?;
+            },
+            b'0'..=b'9' => {
+                self.bytes()?;
+            },
+            b'l' => {
+                self.enter()?;
+                while !self.at_end_of_container()? {
+                    self.skip_value()?;
+                }
+                self.leave()?;
+            },
+            b'd' => {
+                self.enter()?;
+                let mut prev = None;
+                while !self.at_end_of_container()? {
+                    prev = Some(self.dict_key(prev)?);
+                    self.skip_value()?;
+                }
+                self.leave()?;
+            },
+            c => return Err(BencodeError::at(self.pos, format!("unexpected byte 0x{:02x}", c))),
+        }
+        Ok(&self.input[start..self.pos])
+    }
+
+    // finish rejects trailing data in strict mode, lenient mode ignores it
+    pub(crate) fn finish(&self) -> Result<()> {
+        if self.options.strict && self.pos != self.input.len() {
+            return Err(BencodeError::at(self.pos, "trailing data after value"));
+        }
+        Ok(())
+    }
+}
+
+// decode parses a single value in lenient mode
+pub fn decode(input: &[u8]) -> Result<Value<'_>> {
+    decode_with(input, Options::default())
+}
+
+pub fn decode_with(input: &[u8], options: Options) -> Result<Value<'_>> {
+    let mut parser = Parser::new(input, options)?;
+    let value = parser.value()?;
+    parser.finish()?;
+    Ok(value)
+}
+
+// decode_prefix parses the value at the start of input and returns it with the number of bytes
+// it took, for messages that carry raw data after a bencoded header
+pub fn decode_prefix(input: &[u8]) -> Result<(Value<'_>, usize)> {
+    let mut parser = Parser::new(input, Options::lenient())?;
+    let value = parser.value()?;
+    Ok((value, parser.pos))
+}
+
+// RawValue captures the exact bytes of a value while deserializing, an `info: RawValue` field
+// keeps the info dictionary as it was in the file so its hash is always right
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawValue<'a>(&'a [u8]);
+
+impl<'a> RawValue<'a> {
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub fn decode(&self, options: Options) -> Result<Value<'a>> {
+        decode_with(self.0, options)
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for RawValue<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct RawVisitor;
+
+        impl<'de> Visitor<'de> for RawVisitor {
+            type Value = RawValue<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a raw bencode value")
+            }
+
+            fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> std::result::Result<Self::Value, E> {
+                Ok(RawValue(v))
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(RAW_VALUE_TOKEN, RawVisitor)
+    }
+}
+
+impl Serialize for Value<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::Bytes(b) => serializer.serialize_bytes(b),
+            Value::List(list) => {
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for v in list {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            },
+            Value::Dict(dict) => {
+                let mut map = serializer.serialize_map(Some(dict.len()))?;
+                for (k, v) in dict {
+                    map.serialize_entry(&BytesKey(k), v)?;
+                }
+                map.end()
+            },
+        }
+    }
+}
+
+struct BytesKey<'a>(&'a [u8]);
+
+impl Serialize for BytesKey<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Value<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct ValueVisitor;
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a bencode value")
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> std::result::Result<Self::Value, E> {
+                Ok(Value::Int(v as i64))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<Self::Value, E> {
+                Ok(Value::Int(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<Self::Value, E> {
+                i64::try_from(v).map(Value::Int).map_err(|_| E::custom("integer out of range"))
+            }
+
+            fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> std::result::Result<Self::Value, E> {
+                Ok(Value::from(v))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+                Ok(Value::from(v.to_string()))
+            }
+
+            fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> std::result::Result<Self::Value, E> {
+                Ok(Value::from(v))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<Self::Value, E> {
+                Ok(Value::from(v.to_vec()))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error> {
+                let mut list = vec![];
+                while let Some(v) = seq.next_element()? {
+                    list.push(v);
+                }
+                Ok(Value::List(list))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Self::Value, A::Error> {
+                let mut dict = Dict::new();
+                while let Some((k, v)) = map.next_entry::<Value<'de>, Value<'de>>()? {
+                    match k {
+                        Value::Bytes(k) => {
+                            dict.insert(k, v);
+                        },
+                        _ => return Err(de::Error::custom("dictionary key is not a byte string")),
+                    }
+                }
+                Ok(Value::Dict(dict))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(values: Vec<Value<'static>>) -> Value<'static> {
+        Value::List(values)
+    }
+
+    #[test]
+    fn decodes_the_spec_examples() {
+        assert_eq!(decode(b"4:spam").unwrap(), Value::from("spam"));
+        assert_eq!(decode(b"0:").unwrap(), Value::from(""));
+        assert_eq!(decode(b"i3e").unwrap(), Value::Int(3));
+        assert_eq!(decode(b"i-3e").unwrap(), Value::Int(-3));
+        assert_eq!(decode(b"i0e").unwrap(), Value::Int(0));
+        assert_eq!(decode(b"l4:spam4:eggse").unwrap(), list(vec![Value::from("spam"), Value::from("eggs")]));
+        assert_eq!(decode(b"le").unwrap(), list(vec![]));
+        let dict = decode(b"d3:cow3:moo4:spam4:eggse").unwrap();
+        assert_eq!(dict.get("cow").and_then(Value::as_str), Some("moo"));
+        assert_eq!(dict.get("spam").and_then(Value::as_str), Some("eggs"));
+        let dict = decode(b"d4:spaml1:a1:bee").unwrap();
+        assert_eq!(dict.get("spam"), Some(&list(vec![Value::from("a"), Value::from("b")])));
+        assert_eq!(decode(b"de").unwrap(), Value::Dict(Dict::new()));
+    }
+
+    #[test]
+    fn integer_limits() {
+        assert_eq!(decode(b"i9223372036854775807e").unwrap(), Value::Int(i64::MAX));
+        assert_eq!(decode(b"i-9223372036854775808e").unwrap(), Value::Int(i64::MIN));
+        assert!(decode(b"i9223372036854775808e").is_err());
+        assert!(decode(b"i-9223372036854775809e").is_err());
+        assert!(decode(b"i99999999999999999999999e").is_err());
+        assert!(decode(b"ie").is_err());
+        assert!(decode(b"i-e").is_err());
+        assert!(decode(b"i1.5e").is_err());
+        assert!(decode(b"i12").is_err());
+    }
+
+    #[test]
+    fn strict_mode_only_accepts_the_canonical_encoding() {
+        let strict = Options::strict();
+        for input in [&b"i03e"[..], b"i-0e", b"03:abc", b"d1:b0:1:a0:e", b"d1:a0:1:a0:e", b"i1ei2e"] {
+            assert!(decode_with(input, strict).is_err(), "{:?}", String::from_utf8_lossy(input));
+            assert!(decode(input).is_ok(), "{:?}", String::from_utf8_lossy(input));
+        }
+        assert_eq!(decode(b"i03e").unwrap(), Value::Int(3));
+        assert_eq!(decode(b"3:abcjunk").unwrap(), Value::from("abc"));
+    }
+
+    #[test]
+    fn rejects_malformed_input_with_its_position() {
+        assert_eq!(decode(b"5:abc").unwrap_err().pos, Some(0));
+        assert_eq!(decode(b"l4:spam").unwrap_err().pos, Some(7));
+        assert_eq!(decode(b"di1e3:abce").unwrap_err().pos, Some(1));
+        assert_eq!(decode(b"x").unwrap_err().pos, Some(0));
+        assert!(decode(b"").is_err());
+        assert!(decode(b"d3:abce").is_err());
+        assert!(decode(b"18446744073709551616:a").is_err());
+    }
+
+    #[test]
+    fn limits_depth_and_size() {
+        let deep = |n: usize| [vec![b'l'; n], vec![b'e'; n]].concat();
+        assert!(decode(&deep(DEFAULT_MAX_DEPTH)).is_ok());
+        assert!(decode(&deep(DEFAULT_MAX_DEPTH + 1)).is_err());
+        let options = Options { max_size: 4, ..Options::default() };
+        assert!(decode_with(b"i10e", options).is_ok());
+        assert!(decode_with(b"i100e", options).is_err());
+    }
+
+    #[test]
+    fn encodes_canonically() {
+        let mut dict = Dict::new();
+        dict.insert(Cow::Borrowed(&b"zz"[..]), Value::Int(-1));
+        dict.insert(Cow::Borrowed(&b"a"[..]), list(vec![Value::Int(0), Value::from(vec![0xff, 0x00])]));
+        let value = Value::Dict(dict);
+        let encoded = value.encode();
+        assert_eq!(encoded, b"d1:ali0e2:\xff\x00e2:zzi-1ee");
+        assert_eq!(decode_with(&encoded, Options::strict()).unwrap(), value);
+    }
+
+    #[test]
+    fn decoded_bytes_borrow_from_the_input() {
+        let input = b"l3:abce".to_vec();
+        let value = decode(&input).unwrap();
+        let Value::Bytes(Cow::Borrowed(bytes)) = &value.as_list().unwrap()[0] else {
+            panic!("byte string was copied");
+        };
+        assert_eq!(bytes.as_ptr(), input[3..].as_ptr());
+        assert_eq!(value.clone().into_owned(), value);
+    }
+
+    #[test]
+    fn decode_prefix_stops_after_the_first_value() {
+        let (value, used) = decode_prefix(b"d8:msg_typei1e5:piecei0eeRAW DATA").unwrap();
+        assert_eq!(used, 25);
+        assert_eq!(value.get("msg_type").and_then(Value::as_int), Some(1));
+        assert!(decode_prefix(b"d8:msg_type").is_err());
+    }
+}
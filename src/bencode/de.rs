@@ -0,0 +1,193 @@
+use std::str;
+
+use serde::{
+    de::{self, value::BorrowedBytesDeserializer, DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    forward_to_deserialize_any, Deserialize,
+};
+
+use crate::bencode::bencode::{BencodeError, Options, Parser, Result, RAW_VALUE_TOKEN};
+
+// from_bytes deserializes a value in lenient mode, byte strings can be borrowed from input
+pub fn from_bytes<'a, T: Deserialize<'a>>(input: &'a [u8]) -> Result<T> {
+    from_bytes_with(input, Options::default())
+}
+
+pub fn from_bytes_with<'a, T: Deserialize<'a>>(input: &'a [u8], options: Options) -> Result<T> {
+    let mut parser = Parser::new(input, options)?;
+    let value = T::deserialize(&mut parser)?;
+    parser.finish()?;
+    Ok(value)
+}
+
+impl<'de> de::Deserializer<'de> for &mut Parser<'de> {
+    type Error = BencodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.peek()? {
+            b'i' => visitor.visit_i64(self.int()?),
+            b'0'..=b'9' => visitor.visit_borrowed_bytes(self.bytes()?),
+            b'l' => {
+                self.enter()?;
+                let value = visitor.visit_seq(List { parser: self })?;
+                if !self.at_end_of_container()? {
+                    return Err(BencodeError::at(self.pos, "list has more elements than expected"));
+                }
+                self.leave()?;
+                Ok(value)
+            },
+            b'd' => {
+                self.enter()?;
+                let value = visitor.visit_map(Map { parser: self, prev: None })?;
+                // 访问者可能提前停下，剩下的键值对直接跳过
+                while !self.at_end_of_container()? {
+                    self.bytes()?;
+                    self.skip_value()?;
+                }
+                self.leave()?;
+                Ok(value)
+            },
+            c => Err(BencodeError::at(self.pos, format!("unexpected byte 0x{:02x}", c))),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_bool(self.int()? != 0)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if !self.peek()?.is_ascii_digit() {
+            return self.deserialize_any(visitor);
+        }
+        let bytes = self.bytes()?;
+        match str::from_utf8(bytes) {
+            Ok(s) => visitor.visit_borrowed_str(s),
+            Err(_) => visitor.visit_borrowed_bytes(bytes),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    // bencode has no null, a present value is always Some and a missing field is None
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, name: &'static str, visitor: V) -> Result<V::Value> {
+        if name == RAW_VALUE_TOKEN {
+            return visitor.visit_borrowed_bytes(self.skip_value()?);
+        }
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.skip_value()?;
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit unit_struct seq
+        tuple tuple_struct map struct enum identifier
+    }
+}
+
+struct List<'a, 'de> {
+    parser: &'a mut Parser<'de>,
+}
+
+impl<'de> SeqAccess<'de> for List<'_, 'de> {
+    type Error = BencodeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.parser.at_end_of_container()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.parser).map(Some)
+    }
+}
+
+struct Map<'a, 'de> {
+    parser: &'a mut Parser<'de>,
+    prev: Option<&'de [u8]>,
+}
+
+impl<'de> MapAccess<'de> for Map<'_, 'de> {
+    type Error = BencodeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.parser.at_end_of_container()? {
+            return Ok(None);
+        }
+        let key = self.parser.dict_key(self.prev)?;
+        self.prev = Some(key);
+        seed.deserialize(BorrowedBytesDeserializer::new(key)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.parser)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_derive::Deserialize;
+
+    use super::*;
+    use crate::bencode::bencode::RawValue;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct File<'a> {
+        name: &'a str,
+        length: u64,
+        #[serde(default)]
+        md5sum: Option<&'a [u8]>,
+        path: Vec<String>,
+        private: Option<bool>,
+    }
+
+    #[test]
+    fn deserializes_structs_and_skips_unknown_keys() {
+        let input = b"d6:lengthi12e4:name5:a.txt4:pathl1:a1:be7:privatei1e7:unknownd1:xli1eeee";
+        let file: File = from_bytes(input).unwrap();
+        assert_eq!(file, File { name: "a.txt", length: 12, md5sum: None, path: vec!["a".to_string(), "b".to_string()], private: Some(true) });
+        assert!(from_bytes_with::<File>(input, Options::strict()).is_ok());
+    }
+
+    #[test]
+    fn reports_missing_fields_and_wrong_types() {
+        assert!(from_bytes::<File>(b"d4:name1:ae").is_err());
+        assert!(from_bytes::<File>(b"d6:length1:x4:name1:a4:pathleee").is_err());
+        assert!(from_bytes::<u8>(b"i256e").is_err());
+        assert!(from_bytes::<u64>(b"i-1e").is_err());
+        assert!(from_bytes::<(i64, i64)>(b"li1ei2ei3ee").is_err());
+    }
+
+    #[test]
+    fn raw_value_keeps_the_exact_bytes() {
+        #[derive(Deserialize)]
+        struct Meta<'a> {
+            #[serde(borrow)]
+            info: RawValue<'a>,
+        }
+        // 非规范编码也要原样保留，info hash 依赖它
+        let input = b"d4:infod1:bi1e1:ai02ee8:announce3:urle";
+        let meta: Meta = from_bytes(input).unwrap();
+        assert_eq!(meta.info.as_bytes(), b"d1:bi1e1:ai02ee");
+        assert!(meta.info.decode(Options::strict()).is_err());
+    }
+
+    #[test]
+    fn strict_mode_applies_to_serde() {
+        assert!(from_bytes_with::<Vec<i64>>(b"li01ee", Options::strict()).is_err());
+        assert!(from_bytes_with::<i64>(b"i1ejunk", Options::strict()).is_err());
+        assert_eq!(from_bytes::<Vec<i64>>(b"li01ee").unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn strings_fall_back_to_bytes() {
+        assert_eq!(from_bytes::<String>(b"3:abc").unwrap(), "abc");
+        assert!(from_bytes::<String>(b"2:\xff\xfe").is_err());
+        assert_eq!(from_bytes::<&[u8]>(b"2:\xff\xfe").unwrap(), b"\xff\xfe");
+    }
+}
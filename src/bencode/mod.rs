@@ -0,0 +1,3 @@
+pub mod bencode;
+pub mod de;
+pub mod ser;
@@ -0,0 +1,321 @@
+use std::borrow::Cow;
+
+use serde::ser::{self, Impossible, Serialize};
+
+use crate::bencode::bencode::{BencodeError, Dict, Result, Value};
+
+// to_bytes serializes a value to its canonical encoding, None fields are left out
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    Ok(to_value(value)?.encode())
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value<'static>> {
+    value.serialize(ValueSerializer)?.ok_or_else(|| BencodeError::new("bencode has no null value"))
+}
+
+// ValueSerializer builds a Value, None stands for a missing value that containers skip
+struct ValueSerializer;
+
+fn int<T: TryInto<i64>>(v: T) -> Result<Option<Value<'static>>> {
+    v.try_into().map(|i| Some(Value::Int(i))).map_err(|_| BencodeError::new("integer out of range"))
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Option<Value<'static>>;
+    type Error = BencodeError;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = Impossible<Self::Ok, BencodeError>;
+    type SerializeMap = DictSerializer;
+    type SerializeStruct = DictSerializer;
+    type SerializeStructVariant = Impossible<Self::Ok, BencodeError>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok> {
+        int(v as i64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok> {
+        int(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok> {
+        int(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok> {
+        int(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok> {
+        int(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok> {
+        int(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok> {
+        int(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok> {
+        int(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok> {
+        int(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok> {
+        Err(BencodeError::new("bencode has no floats"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok> {
+        Err(BencodeError::new("bencode has no floats"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok> {
+        Ok(Some(Value::from(v.to_string())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok> {
+        Ok(Some(Value::from(v.to_string())))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok> {
+        Ok(Some(Value::from(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<Self::Ok> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Self::Ok> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str, value: &T) -> Result<Self::Ok> {
+        let mut dict = Dict::new();
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            dict.insert(Cow::Owned(variant.as_bytes().to_vec()), value);
+        }
+        Ok(Some(Value::Dict(dict)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Ok(ListSerializer { list: Vec::with_capacity(len.unwrap_or(0)) })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeTupleVariant> {
+        Err(BencodeError::new("tuple variants are not supported"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Ok(DictSerializer { dict: Dict::new(), key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant> {
+        Err(BencodeError::new("struct variants are not supported"))
+    }
+}
+
+struct ListSerializer {
+    list: Vec<Value<'static>>,
+}
+
+impl ListSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let value = value.serialize(ValueSerializer)?.ok_or_else(|| BencodeError::new("lists can't hold missing values"))?;
+        self.list.push(value);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<Value<'static>>;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        Ok(Some(Value::List(self.list)))
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<Value<'static>>;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        Ok(Some(Value::List(self.list)))
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<Value<'static>>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        Ok(Some(Value::List(self.list)))
+    }
+}
+
+// DictSerializer collects entries into a sorted dictionary, entries whose value is None are dropped
+struct DictSerializer {
+    dict: Dict<'static>,
+    key: Option<Vec<u8>>,
+}
+
+impl DictSerializer {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<()> {
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            self.dict.insert(Cow::Owned(key), value);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for DictSerializer {
+    type Ok = Option<Value<'static>>;
+    type Error = BencodeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        match key.serialize(ValueSerializer)? {
+            Some(Value::Bytes(key)) => {
+                self.key = Some(key.into_owned());
+                Ok(())
+            },
+            _ => Err(BencodeError::new("dictionary keys must be strings or bytes")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self.key.take().ok_or_else(|| BencodeError::new("dictionary value without a key"))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        Ok(Some(Value::Dict(self.dict)))
+    }
+}
+
+impl ser::SerializeStruct for DictSerializer {
+    type Ok = Option<Value<'static>>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok> {
+        Ok(Some(Value::Dict(self.dict)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_derive::Serialize;
+
+    use super::*;
+    use crate::bencode::{bencode::{self, Options}, de};
+
+    #[derive(Serialize)]
+    enum Kind {
+        Plain,
+        Tagged(u32),
+    }
+
+    #[derive(Serialize)]
+    struct Torrent {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u64,
+        comment: Option<String>,
+        files: Vec<(String, i64)>,
+        kind: Kind,
+        tagged: Kind,
+        private: bool,
+    }
+
+    #[test]
+    fn serializes_structs_canonically() {
+        let torrent = Torrent {
+            name: "x".to_string(),
+            piece_length: 16384,
+            comment: None,
+            files: vec![("a".to_string(), -1)],
+            kind: Kind::Plain,
+            tagged: Kind::Tagged(7),
+            private: true,
+        };
+        let encoded = to_bytes(&torrent).unwrap();
+        assert_eq!(&encoded[..], &b"d5:filesll1:ai-1eee4:kind5:Plain4:name1:x12:piece lengthi16384e7:privatei1e6:taggedd6:Taggedi7eee"[..]);
+        assert!(bencode::decode_with(&encoded, Options::strict()).is_ok());
+    }
+
+    #[test]
+    fn map_keys_are_sorted() {
+        let map = HashMap::from([("b", 1), ("a", 2), ("c", 3)]);
+        assert_eq!(to_bytes(&map).unwrap(), b"d1:ai2e1:bi1e1:ci3ee");
+        assert!(to_bytes(&HashMap::from([(1, 2)])).is_err());
+    }
+
+    #[test]
+    fn rejects_what_bencode_cannot_hold() {
+        assert!(to_bytes(&1.5f64).is_err());
+        assert!(to_bytes(&u64::MAX).is_err());
+        assert!(to_bytes(&None::<i64>).is_err());
+        assert!(to_bytes(&vec![Some(1), None]).is_err());
+        assert_eq!(to_bytes(&i64::MIN).unwrap(), b"i-9223372036854775808e");
+    }
+
+    #[test]
+    fn round_trips_values() {
+        let input = b"d4:infod6:lengthi3e4:name2:\xff\x00e4:listli-5e0:deee";
+        let value: bencode::Value = de::from_bytes(input).unwrap();
+        assert_eq!(value, bencode::decode(input).unwrap());
+        assert_eq!(to_bytes(&value).unwrap(), input);
+        assert_eq!(to_value(&value).unwrap(), value);
+    }
+}
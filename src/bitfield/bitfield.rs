@@ -1,5 +1,3 @@
-use std::cell::RefCell;
-
 pub type Bitfield = Vec<u8>;
 
 pub fn has_piece(bitfield: &Bitfield, index: usize) -> bool {
@@ -11,13 +9,26 @@ pub fn has_piece(bitfield: &Bitfield, index: usize) -> bool {
     bitfield[byte_index]>>(7 - offset)&1 != 0
 }
 
-pub fn set_piece(bitfield: & RefCell<&Bitfield>, index: usize) {
+pub fn set_piece(bitfield: &mut Bitfield, index: usize) {
     let byte_index = index / 8;
-    let offset = index & 8;
+    let offset = index % 8;
 
-    if byte_index >= bitfield.borrow().len() {
+    if byte_index >= bitfield.len() {
         return;
     }
-    let mut _bit = bitfield.borrow_mut()[byte_index];
-    _bit |= 1 << (7 - offset)
+    bitfield[byte_index] |= 1 << (7 - offset)
+}
+
+// new_bitfield returns an empty bitfield able to hold num_pieces pieces
+pub fn new_bitfield(num_pieces: usize) -> Bitfield {
+    vec![0u8; num_pieces.div_ceil(8)]
+}
+
+// full_bitfield returns a bitfield with all num_pieces pieces set, the spare bits stay clear
+pub fn full_bitfield(num_pieces: usize) -> Bitfield {
+    let mut bitfield = new_bitfield(num_pieces);
+    for index in 0..num_pieces {
+        set_piece(&mut bitfield, index);
+    }
+    bitfield
 }
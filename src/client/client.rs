@@ -1,136 +1,324 @@
-use std::{net::{TcpStream}, io::{Write, Error, ErrorKind}, cell::RefCell, time::Duration};
+use std::{net::TcpStream, io::{self, ErrorKind, Read, Write}, cell::{Cell, RefCell}, collections::HashMap, time::{Duration, Instant}, sync::Arc};
 
-use crate::{peers::peers::Peer, message::message, bitfield::bitfield::Bitfield, handshake::handshake};
+use tracing::{debug, trace};
+
+use crate::{peers::peers::Peer, message::message::{self, HashRequest, PeerMessage}, bitfield::bitfield::{Bitfield, has_piece, set_piece}, handshake::handshake, error::error::{Error, Result}, mse::mse::{self, EncryptionPolicy, MseStream}, ratelimit::ratelimit::{RateLimiter, RateLimitedStream}, client::transport::PeerStream, utp::utp::UtpSocket, peer_id::peer_id::{self, Fingerprint}};
+
+type PeerConn = RateLimitedStream<MseStream<PeerStream>>;
+
+// 两分钟没有消息对方会断开连接，提前发送 keep-alive
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+// uTP 连不上时很快退回 TCP
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// 消息读到一半时最多再等这么多次读超时
+const MAX_PARTIAL_TIMEOUTS: usize = 10;
 
 pub struct CustomClient<'a> {
-    conn: RefCell<TcpStream>,
+    conn: RefCell<PeerConn>,
     pub choked: RefCell<bool>,
-    pub bit_field: Bitfield,
-    peer: &'a Peer,
-    info_hash: &'a [u8; 20],
-    peer_id: [u8; 20],
+    pub bit_field: RefCell<Bitfield>,
+    pub peer: &'a Peer,
+    pub info_hash: &'a [u8; 20],
+    pub peer_id: [u8; 20],
+    // the peer's handshake, with its reserved bits and peer id
+    pub remote: handshake::Handshake,
+    last_sent: Cell<Instant>,
+    connected_at: Instant,
+    // when the last block arrived, None until the first one
+    last_block: Cell<Option<Instant>>,
+    // block bytes we sent to the peer
+    uploaded: Cell<usize>,
+    // a message read while waiting for the bitfield, returned by the next read
+    pending: RefCell<Option<PeerMessage>>,
+    // the extension message ids from the peer's BEP 10 handshake
+    extensions: RefCell<HashMap<String, u8>>,
 }
 
-fn complete_handshake<'a>(conn: &'a TcpStream, info_hash: &'a [u8; 20], peer_id: &'a [u8; 20]) -> Option<[u8; 20]> {
-    let stream = RefCell::new(conn);
-    let req = handshake::Handshake::new(info_hash, peer_id);
+fn complete_handshake<S: Read + Write>(conn: &mut S, info_hash: &[u8; 20], peer_id: &[u8; 20], reserved: [u8; 8]) -> Result<handshake::Handshake> {
+    let req = handshake::Handshake::new(info_hash, peer_id).with_reserved(reserved);
     let ser = req.serialize();
-    let res = stream.borrow_mut().write(&ser);
+    conn.write_all(&ser)?;
 
-    if let Err(err) = res {
-        println!("handshake error {}", err);
+    let res = handshake::read(conn)?;
+    if &res.info_hash != info_hash {
+        return Err(Error::handshake("info hash mismatch"));
     }
+    Ok(res)
+}
 
-    let res = handshake::read(conn);
-    if let Ok(res_info_hash) = res {
-        if &res_info_hash != info_hash {
-            return None;
-        } else {
-            return Some(res_info_hash);
-        }
-    } else {
-        return None;
-    }
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
 }
 
-fn recv_bitfield(conn: &TcpStream) -> Option<Vec<u8>> {
-    let msg = message::read(conn);
+// Patient passes read timeouts up only while no byte of a message was read, once a message has
+// started it waits for the rest so the stream never loses its framing
+struct Patient<'r, R> {
+    inner: &'r mut R,
+    started: bool,
+}
 
-    if let None = msg {
-        return None;
-    }
-    let mes = msg.unwrap();
-    if mes.id != message::MessageId::MsgBitfield {
-        return None;
+impl<R: Read> Read for Patient<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut timeouts = 0;
+        loop {
+            match self.inner.read(buf) {
+                Ok(n) => {
+                    self.started |= n > 0;
+                    return Ok(n);
+                },
+                Err(e) if self.started && is_timeout(&e) && timeouts < MAX_PARTIAL_TIMEOUTS => timeouts += 1,
+                Err(e) => return Err(e),
+            }
+        }
     }
-    return Some(mes.payload);
 }
 
 impl <'a>CustomClient<'a> {
-    pub fn new(peer: &'a Peer, peer_id: [u8; 20], info_hash: &'a [u8; 20]) -> Result<Self, std::io::Error> {
-
-        println!("创造tcpstream");
-        let addr = peer.general_address();
-        let stream = TcpStream::connect_timeout(&addr, Duration::new(3, 0))?;
-        stream.set_read_timeout(Some(Duration::new(3, 0))).expect("set_write_timeout call failed");
+    // New connects to the peer, over uTP first when a socket is given and falling back to TCP
+    pub fn new(peer: &'a Peer, peer_id: [u8; 20], info_hash: &'a [u8; 20], reserved: [u8; 8], encryption: EncryptionPolicy, utp: Option<&UtpSocket>, limiters: Vec<Arc<RateLimiter>>) -> Result<Self> {
+        Self::connect(peer, peer_id, info_hash, reserved, encryption, utp, limiters).map_err(|e| e.with_peer(peer.general_address()))
+    }
 
-        // println!("开始握手");
-        let handshake_res = complete_handshake(&stream, info_hash, &peer_id);
-        if let None = handshake_res {
-            return Err(Error::new(ErrorKind::Other, "握手失败"));
+    fn open(peer: &Peer, utp: Option<&UtpSocket>) -> Result<PeerStream> {
+        if let Some(utp) = utp {
+            match utp.connect(peer.general_address(), UTP_CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    trace!("utp 连接成功");
+                    stream.set_read_timeout(Some(Duration::new(3, 0)))?;
+                    return Ok(PeerStream::Utp(stream));
+                },
+                Err(e) => debug!(error = %e, "utp failed, trying tcp"),
+            }
         }
-        // println!("握手结束");
+        trace!("创造tcpstream");
+        let stream = TcpStream::connect_timeout(&peer.general_address(), Duration::new(3, 0))?;
+        stream.set_read_timeout(Some(Duration::new(3, 0)))?;
+        Ok(PeerStream::Tcp(stream))
+    }
 
-        let bf = recv_bitfield(&stream);
+    fn connect(peer: &'a Peer, peer_id: [u8; 20], info_hash: &'a [u8; 20], reserved: [u8; 8], encryption: EncryptionPolicy, utp: Option<&UtpSocket>, limiters: Vec<Arc<RateLimiter>>) -> Result<Self> {
+        let stream = Self::open(peer, utp)?;
+        let utp = if stream.is_utp() { utp } else { None };
+        let stream = match mse::initiate(stream, info_hash, encryption) {
+            Ok(stream) => stream,
+            // 对方不支持加密，重新用明文连接
+            Err(e) if encryption == EncryptionPolicy::Enabled => {
+                debug!(error = %e, "mse failed, retrying in plain");
+                MseStream::plain(Self::open(peer, utp)?)
+            },
+            Err(e) => return Err(e),
+        };
+        let mut stream = RateLimitedStream::new(stream, limiters);
 
-        // println!("bitfield 数据");
+        let remote = complete_handshake(&mut stream, info_hash, &peer_id, reserved)?;
+        trace!("握手结束");
 
-        if let None = bf {
-            return Err(Error::new(ErrorKind::Other, "oh no!"));
-        }
-        println!("client 创建成功");
+        Self::finish(peer, stream, peer_id, info_hash, remote)
+    }
+
+    // Accept takes over an inbound connection whose handshake was already read by the session
+    pub fn accept(peer: &'a Peer, stream: MseStream<PeerStream>, remote: handshake::Handshake, peer_id: [u8; 20], info_hash: &'a [u8; 20], reserved: [u8; 8], limiters: Vec<Arc<RateLimiter>>) -> Result<Self> {
+        Self::answer(peer, stream, remote, peer_id, info_hash, reserved, limiters).map_err(|e| e.with_peer(peer.general_address()))
+    }
 
+    fn answer(peer: &'a Peer, stream: MseStream<PeerStream>, remote: handshake::Handshake, peer_id: [u8; 20], info_hash: &'a [u8; 20], reserved: [u8; 8], limiters: Vec<Arc<RateLimiter>>) -> Result<Self> {
+        stream.get_ref().set_read_timeout(Some(Duration::new(3, 0)))?;
+        let mut stream = RateLimitedStream::new(stream, limiters);
+        let req = handshake::Handshake::new(info_hash, &peer_id).with_reserved(reserved);
+        stream.write_all(&req.serialize())?;
+
+        Self::finish(peer, stream, peer_id, info_hash, remote)
+    }
+
+    fn finish(peer: &'a Peer, stream: PeerConn, peer_id: [u8; 20], info_hash: &'a [u8; 20], remote: handshake::Handshake) -> Result<Self> {
+        if remote.peer_id == peer_id {
+            return Err(Error::handshake("connected to ourselves"));
+        }
         Ok(Self {
             conn: RefCell::new(stream),
-            choked: RefCell::new(false),
+            choked: RefCell::new(true),
             peer,
             info_hash,
             peer_id,
-            bit_field: bf.unwrap(),
+            remote,
+            last_sent: Cell::new(Instant::now()),
+            connected_at: Instant::now(),
+            last_block: Cell::new(None),
+            uploaded: Cell::new(0),
+            pending: RefCell::new(None),
+            extensions: RefCell::new(HashMap::new()),
+            bit_field: RefCell::new(vec![]),
         })
     }
 
-    // Read reads and consumes a message from the connection
-    pub fn read(&self) -> Option<message::Message> {
-        let msg = message::read(&mut self.conn.borrow_mut());
-        return msg;
+    // ExchangeBitfields sends ours when we have any piece and reads the peer's. A peer without
+    // pieces may send no bitfield, its first other message is then kept for the next read
+    pub fn exchange_bitfields(&self, ours: &Bitfield) -> Result<()> {
+        if ours.iter().any(|b| *b != 0) {
+            self.send(&PeerMessage::Bitfield(ours.clone()))?;
+        }
+        let mut bits = vec![0u8; ours.len()];
+        loop {
+            match self.poll()? {
+                Some(PeerMessage::KeepAlive) => continue,
+                Some(PeerMessage::Bitfield(theirs)) => bits = theirs,
+                Some(PeerMessage::HaveAll) => bits.fill(0xff),
+                Some(PeerMessage::HaveNone) | None => {},
+                Some(msg) => *self.pending.borrow_mut() = Some(msg),
+            }
+            break;
+        }
+        debug!(pieces = (0..ours.len() * 8).filter(|i| has_piece(&bits, *i)).count(), "client 创建成功");
+        *self.bit_field.borrow_mut() = bits;
+        Ok(())
     }
 
-    // SendRequest sends a Request message to the peer
-    pub fn send_request(&self, index: usize, begin: usize, length: usize) -> Result<(), std::io::Error> {
-        let req = message::format_request(index, begin, length);
-        let mut conn = self.conn.borrow_mut();
-        conn.write_all(&req.serialize())?;
-        conn.flush()?;
-        Ok(())
+    // Fingerprint decodes the client software from the peer's id
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        peer_id::parse(&self.remote.peer_id)
     }
 
-    // SendInterested sends an Interested message to the peer
-    pub fn send_interested(&self) -> Result<(), std::io::Error> {
-        let msg = message::Message::new(message::MessageId::MsgInterested, vec![]);
+    // Read reads and consumes a message from the connection, keep-alives included
+    pub fn read(&self) -> Result<PeerMessage> {
+        if let Some(msg) = self.pending.borrow_mut().take() {
+            return Ok(msg);
+        }
+        message::read(&mut *self.conn.borrow_mut()).map_err(|e| e.with_peer(self.peer.general_address()))
+    }
+
+    // Poll is read for connections that may stay quiet, None when the read timeout passed
+    // before the peer started another message
+    pub fn poll(&self) -> Result<Option<PeerMessage>> {
+        if let Some(msg) = self.pending.borrow_mut().take() {
+            return Ok(Some(msg));
+        }
         let mut conn = self.conn.borrow_mut();
-        conn.write_all(&msg.serialize())?;
-        conn.flush()?;
-        Ok(())
+        let mut reader = Patient { inner: &mut *conn, started: false };
+        match message::read(&mut reader) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(Error::Io(e)) if !reader.started && is_timeout(&e) => Ok(None),
+            Err(e) => Err(e.with_peer(self.peer.general_address())),
+        }
     }
 
-    // SendNotInterested sends a NotInterested message to the peer
-    // pub fn send_not_interested(&self) {
-    //     let msg = message::Message::new(message::MessageId::MsgNotInterested, vec![]);
-    //     let mut conn = self.conn.borrow_mut();
-    //     conn.write_all(&msg.serialize());
-    //     conn.flush();
-    // }
+    // SendRequest sends a Request message to the peer
+    pub fn send_request(&self, index: usize, begin: usize, length: usize) -> Result<()> {
+        self.send(&PeerMessage::Request { index: index as u32, begin: begin as u32, length: length as u32 })
+    }
+
+    // SendInterested sends an Interested message to the peer
+    pub fn send_interested(&self) -> Result<()> {
+        self.send(&PeerMessage::Interested)
+    }
 
     // SendUnchoke sends an Unchoke message to the peer
-    pub fn send_unchoke(&self) -> Result<(), std::io::Error> {
-        let msg = message::Message::new(message::MessageId::MsgUnchoke, vec![]);
-        let mut conn = self.conn.borrow_mut();
-        conn.write_all(&msg.serialize())?;
-        conn.flush()?;
+    pub fn send_unchoke(&self) -> Result<()> {
+        self.send(&PeerMessage::Unchoke)
+    }
+
+    // SendChoke sends a Choke message to the peer
+    pub fn send_choke(&self) -> Result<()> {
+        self.send(&PeerMessage::Choke)
+    }
+
+    // SendPiece sends a block the peer requested
+    pub fn send_piece(&self, index: usize, begin: usize, data: &[u8]) -> Result<()> {
+        self.send(&PeerMessage::Piece { index: index as u32, begin: begin as u32, data: data.to_vec() })?;
+        self.uploaded.set(self.uploaded.get() + data.len());
         Ok(())
     }
 
     // SendHave sends a Have message to the peer
-    pub fn send_have(&self, index: usize) -> Result<(), std::io::Error>{
-        let msg = message::format_have(index);
+    pub fn send_have(&self, index: usize) -> Result<()>{
+        self.send(&PeerMessage::Have { index: index as u32 })
+    }
+
+    // SendHashRequest asks the peer for merkle hashes of a v2 file
+    pub fn send_hash_request(&self, req: &HashRequest) -> Result<()> {
+        self.send(&PeerMessage::HashRequest(*req))
+    }
+
+    // SendHashes answers a hash request, hashes holds the requested nodes followed by the proof
+    pub fn send_hashes(&self, req: &HashRequest, hashes: &[[u8; 32]]) -> Result<()> {
+        self.send(&PeerMessage::Hashes { request: *req, hashes: hashes.to_vec() })
+    }
+
+    pub fn send_hash_reject(&self, req: &HashRequest) -> Result<()> {
+        self.send(&PeerMessage::HashReject(*req))
+    }
+
+    // SendPort tells the peer the UDP port our DHT node listens on
+    pub fn send_port(&self, port: u16) -> Result<()> {
+        self.send(&PeerMessage::Port(port))
+    }
+
+    // SendExtended sends a BEP 10 extension message, id 0 being the extension handshake
+    pub fn send_extended(&self, id: u8, payload: Vec<u8>) -> Result<()> {
+        self.send(&PeerMessage::Extended { id, payload })
+    }
+
+    // SetExtensions keeps the message ids the peer picked for its extensions
+    pub fn set_extensions(&self, extensions: HashMap<String, u8>) {
+        *self.extensions.borrow_mut() = extensions;
+    }
+
+    // ExtensionId is the id to send messages of an extension with, None when the peer lacks it
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.extensions.borrow().get(name).copied()
+    }
+
+    // MarkBlock records that the peer sent us a block
+    pub fn mark_block(&self) {
+        self.last_block.set(Some(Instant::now()));
+    }
+
+    // IdleFor is how long the peer hasn't sent a block, counted from the connection for a new one
+    pub fn idle_for(&self) -> Duration {
+        self.last_block.get().unwrap_or(self.connected_at).elapsed()
+    }
+
+    // Uploaded is the number of block bytes we sent to the peer
+    pub fn uploaded(&self) -> usize {
+        self.uploaded.get()
+    }
+
+    // WasUseful tells whether a block went either way on this connection
+    pub fn was_useful(&self) -> bool {
+        self.last_block.get().is_some() || self.uploaded() > 0
+    }
+
+    // KeepAlive sends a keep-alive when nothing else went to the peer for a while
+    pub fn keep_alive(&self) -> Result<()> {
+        if self.last_sent.get().elapsed() < KEEP_ALIVE_INTERVAL {
+            return Ok(());
+        }
+        trace!("发送 keep-alive");
+        self.send(&PeerMessage::KeepAlive)
+    }
+
+    fn send(&self, msg: &PeerMessage) -> Result<()> {
         let mut conn = self.conn.borrow_mut();
         conn.write_all(&msg.serialize())?;
         conn.flush()?;
+        self.last_sent.set(Instant::now());
         Ok(())
     }
 
     pub fn set_choked(&self, choked: bool) {
         *self.choked.borrow_mut() = choked;
     }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        has_piece(&self.bit_field.borrow(), index)
+    }
+
+    pub fn set_piece(&self, index: usize) {
+        set_piece(&mut self.bit_field.borrow_mut(), index);
+    }
+
+    // IsSeed tells whether the peer has all num_pieces pieces
+    pub fn is_seed(&self, num_pieces: usize) -> bool {
+        let bits = self.bit_field.borrow();
+        (0..num_pieces).all(|i| has_piece(&bits, i))
+    }
 }
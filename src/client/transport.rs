@@ -0,0 +1,59 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use crate::utp::utp::UtpStream;
+
+// PeerStream is a peer connection over TCP or uTP
+#[derive(Debug)]
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl PeerStream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            PeerStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            PeerStream::Utp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            PeerStream::Tcp(stream) => stream.peer_addr(),
+            PeerStream::Utp(stream) => Ok(stream.peer_addr()),
+        }
+    }
+
+    pub fn is_utp(&self) -> bool {
+        matches!(self, PeerStream::Utp(_))
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Tcp(stream) => stream.read(buf),
+            PeerStream::Utp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Tcp(stream) => stream.write(buf),
+            PeerStream::Utp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PeerStream::Tcp(stream) => stream.flush(),
+            PeerStream::Utp(stream) => stream.flush(),
+        }
+    }
+}
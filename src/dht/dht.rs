@@ -0,0 +1,364 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, trace};
+
+use crate::{
+    bencode::bencode::{self, Value},
+    dht::routing::{Node, NodeId, RoutingTable, K},
+    error::error::Result,
+    ipfilter::ipfilter::IpFilter,
+    peers::peers::PeerSource,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// 查询在这段时间内没有回应就算失败
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+// KRPC 错误码，BEP 5
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+fn dict<'a>(entries: Vec<(&'static str, Value<'a>)>) -> Value<'a> {
+    Value::Dict(entries.into_iter().map(|(k, v)| (Cow::Borrowed(k.as_bytes()), v)).collect())
+}
+
+// compact_nodes packs nodes as 20 byte id, 4 byte IPv4 address and 2 byte port, IPv6 nodes are left out
+fn compact_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nodes.len() * 26);
+    for node in nodes {
+        if let SocketAddr::V4(addr) = node.addr {
+            out.extend_from_slice(&node.id);
+            out.extend_from_slice(&addr.ip().octets());
+            out.extend_from_slice(&addr.port().to_be_bytes());
+        }
+    }
+    out
+}
+
+fn node_id(value: Option<&Value>) -> Option<NodeId> {
+    value?.as_bytes()?.try_into().ok()
+}
+
+struct Query {
+    addr: SocketAddr,
+    sent_at: Instant,
+}
+
+struct Shared {
+    udp: UdpSocket,
+    id: NodeId,
+    table: Mutex<RoutingTable>,
+    // outstanding queries by transaction id
+    pending: Mutex<HashMap<[u8; 2], Query>>,
+    next_transaction: AtomicU16,
+    // nodes in blocked ranges are neither answered nor queried
+    filter: RwLock<Option<Arc<IpFilter>>>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn run(&self) {
+        let mut buf = vec![0u8; 65536];
+        while !self.closed.load(Ordering::SeqCst) {
+            match self.udp.recv_from(&mut buf) {
+                Ok((n, addr)) => self.handle(&buf[..n], addr),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+                Err(e) => trace!(error = %e, "dht: receive failed"),
+            }
+            self.expire();
+        }
+    }
+
+    fn send(&self, msg: &Value, addr: SocketAddr) {
+        if let Err(e) = self.udp.send_to(&msg.encode(), addr) {
+            trace!(%addr, error = %e, "dht: send failed");
+        }
+    }
+
+    fn allows(&self, addr: SocketAddr) -> bool {
+        self.filter.read().unwrap().as_ref().is_none_or(|f| f.allows(addr.ip(), PeerSource::Dht))
+    }
+
+    fn handle(&self, buf: &[u8], addr: SocketAddr) {
+        if !self.allows(addr) {
+            return;
+        }
+        let msg = match bencode::decode(buf) {
+            Ok(msg) => msg,
+            Err(e) => {
+                trace!(%addr, error = %e, "dht: bad message");
+                return;
+            },
+        };
+        let Some(transaction) = msg.get("t").and_then(Value::as_bytes) else {
+            return;
+        };
+        match msg.get("y").and_then(Value::as_bytes) {
+            Some(b"q") => self.handle_query(&msg, transaction, addr),
+            Some(b"r") => self.handle_response(&msg, transaction, addr),
+            Some(b"e") => {
+                if let Ok(key) = <[u8; 2]>::try_from(transaction) {
+                    self.pending.lock().unwrap().remove(&key);
+                }
+                trace!(%addr, error = ?msg.get("e"), "dht: error reply");
+            },
+            _ => trace!(%addr, "dht: unknown message type"),
+        }
+    }
+
+    // handle_query answers ping and find_node. Only a node already in the table is refreshed, an
+    // unknown one is pinged and added once it answers
+    fn handle_query(&self, msg: &Value, transaction: &[u8], addr: SocketAddr) {
+        let args = msg.get("a");
+        let Some(id) = node_id(args.and_then(|a| a.get("id"))) else {
+            self.reply_error(transaction, ERROR_PROTOCOL, "missing id", addr);
+            return;
+        };
+        // 查询可能来自伪造的地址，新节点要先回应我们的 ping 才能进路由表
+        let known = {
+            let mut table = self.table.lock().unwrap();
+            table.get(&id).is_some_and(|n| n.addr == addr) && table.insert(id, addr)
+        };
+        if !known && id != self.id {
+            self.ping(addr);
+        }
+
+        let method = msg.get("q").and_then(Value::as_bytes).unwrap_or_default();
+        let reply = match method {
+            b"ping" => dict(vec![("id", Value::from(&self.id[..]))]),
+            b"find_node" => {
+                let Some(target) = node_id(args.and_then(|a| a.get("target"))) else {
+                    self.reply_error(transaction, ERROR_PROTOCOL, "missing target", addr);
+                    return;
+                };
+                let nodes = self.table.lock().unwrap().closest(&target, K);
+                dict(vec![("id", Value::from(&self.id[..])), ("nodes", Value::from(compact_nodes(&nodes)))])
+            },
+            _ => {
+                trace!(%addr, method = %String::from_utf8_lossy(method), "dht: unsupported query");
+                self.reply_error(transaction, ERROR_METHOD_UNKNOWN, "Method Unknown", addr);
+                return;
+            },
+        };
+        let msg = dict(vec![("t", Value::from(transaction)), ("y", Value::from("r")), ("r", reply)]);
+        self.send(&msg, addr);
+    }
+
+    fn reply_error(&self, transaction: &[u8], code: i64, message: &str, addr: SocketAddr) {
+        let error = Value::List(vec![Value::Int(code), Value::from(message)]);
+        let msg = dict(vec![("t", Value::from(transaction)), ("y", Value::from("e")), ("e", error)]);
+        self.send(&msg, addr);
+    }
+
+    // handle_response adds the node behind an answer to one of our queries to the routing table
+    fn handle_response(&self, msg: &Value, transaction: &[u8], addr: SocketAddr) {
+        let Ok(key) = <[u8; 2]>::try_from(transaction) else {
+            return;
+        };
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(&key).is_none_or(|q| q.addr != addr) {
+            trace!(%addr, "dht: unexpected response");
+            return;
+        }
+        pending.remove(&key);
+        drop(pending);
+        match node_id(msg.get("r").and_then(|r| r.get("id"))) {
+            Some(id) if id != self.id => {
+                let added = self.table.lock().unwrap().insert(id, addr);
+                debug!(%addr, id = %hex::encode(id), added, "dht: node answered");
+            },
+            _ => trace!(%addr, "dht: response without a usable id"),
+        }
+    }
+
+    fn expire(&self) {
+        let mut expired = vec![];
+        self.pending.lock().unwrap().retain(|_, q| {
+            let alive = q.sent_at.elapsed() < QUERY_TIMEOUT;
+            if !alive {
+                expired.push(q.addr);
+            }
+            alive
+        });
+        if !expired.is_empty() {
+            let mut table = self.table.lock().unwrap();
+            for addr in expired {
+                trace!(%addr, "dht: query timed out");
+                table.failed(addr);
+            }
+        }
+    }
+
+    fn ping(&self, addr: SocketAddr) {
+        if !self.allows(addr) {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.values().any(|q| q.addr == addr) {
+            return;
+        }
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+        pending.insert(transaction, Query { addr, sent_at: Instant::now() });
+        drop(pending);
+        let args = dict(vec![("id", Value::from(&self.id[..]))]);
+        let msg = dict(vec![
+            ("t", Value::from(&transaction[..])),
+            ("y", Value::from("q")),
+            ("q", Value::from("ping")),
+            ("a", args),
+        ]);
+        trace!(%addr, "dht: ping");
+        self.send(&msg, addr);
+    }
+}
+
+// DhtNode is our node in the mainline DHT (BEP 5). It answers ping and find_node and keeps a
+// routing table of the nodes that answered us
+pub struct DhtNode {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl fmt::Debug for DhtNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DhtNode").field("id", &hex::encode(self.shared.id)).field("local_addr", &self.local_addr).finish()
+    }
+}
+
+impl DhtNode {
+    // bind starts a node with a random id on addr
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let udp = UdpSocket::bind(addr)?;
+        udp.set_read_timeout(Some(POLL_INTERVAL))?;
+        let local_addr = udp.local_addr()?;
+        let id: NodeId = rand::random();
+        let shared = Arc::new(Shared {
+            udp,
+            id,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            filter: RwLock::new(None),
+            closed: AtomicBool::new(false),
+        });
+        let run = shared.clone();
+        let thread = thread::spawn(move || run.run());
+        Ok(Self { shared, local_addr, thread: Some(thread) })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.shared.id
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // port is what we announce to peers in the port message
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    pub fn set_ip_filter(&self, filter: Arc<IpFilter>) {
+        *self.shared.filter.write().unwrap() = Some(filter);
+    }
+
+    // ping asks addr for its id, the node joins the routing table once it answers
+    pub fn ping(&self, addr: SocketAddr) {
+        self.shared.ping(addr);
+    }
+
+    // closest returns up to count known nodes nearest to target
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        self.shared.table.lock().unwrap().closest(target, count)
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.shared.table.lock().unwrap().len()
+    }
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // query sends a KRPC query from udp to addr as the node with id
+    fn query(udp: &UdpSocket, addr: SocketAddr, id: &NodeId, method: &str) {
+        let msg = dict(vec![
+            ("t", Value::from(&b"aa"[..])),
+            ("y", Value::from("q")),
+            ("q", Value::from(method)),
+            ("a", dict(vec![("id", Value::from(&id[..]))])),
+        ]);
+        udp.send_to(&msg.encode(), addr).unwrap();
+    }
+
+    // recv reads messages until one of type kind arrives
+    fn recv(udp: &UdpSocket, kind: &[u8]) -> Vec<u8> {
+        let mut buf = [0; 1500];
+        loop {
+            let (n, _) = udp.recv_from(&mut buf).unwrap();
+            let msg = bencode::decode(&buf[..n]).unwrap();
+            if msg.get("y").and_then(Value::as_bytes) == Some(kind) {
+                return buf[..n].to_vec();
+            }
+        }
+    }
+
+    #[test]
+    fn querying_node_joins_only_after_answering_a_ping() {
+        let node = DhtNode::bind("127.0.0.1:0").unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let id: NodeId = rand::random();
+
+        query(&udp, node.local_addr(), &id, "ping");
+        // 先收到节点的 ping，再收到回复，节点此时还不在路由表里
+        let ping = recv(&udp, b"q");
+        recv(&udp, b"r");
+        assert_eq!(node.num_nodes(), 0);
+
+        let ping = bencode::decode(&ping).unwrap();
+        assert_eq!(ping.get("q").and_then(Value::as_bytes), Some(&b"ping"[..]));
+        let transaction = ping.get("t").and_then(Value::as_bytes).unwrap();
+        let reply = dict(vec![
+            ("t", Value::from(transaction)),
+            ("y", Value::from("r")),
+            ("r", dict(vec![("id", Value::from(&id[..]))])),
+        ]);
+        udp.send_to(&reply.encode(), node.local_addr()).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while node.num_nodes() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(node.closest(&id, 1).first().map(|n| (n.id, n.addr)), Some((id, udp.local_addr().unwrap())));
+
+        // 已知节点的查询只刷新它，不再 ping
+        query(&udp, node.local_addr(), &id, "ping");
+        recv(&udp, b"r");
+        udp.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        assert!(udp.recv_from(&mut [0; 1500]).is_err());
+    }
+}
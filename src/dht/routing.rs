@@ -0,0 +1,122 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+// 每个 bucket 最多 K 个节点，BEP 5
+pub const K: usize = 8;
+pub const ID_BITS: usize = 160;
+// 连续这么多次没有回应的节点被视为坏节点，可以被替换
+const MAX_FAILURES: u32 = 2;
+// 超过这个时间没有消息的节点是可疑的
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+pub type NodeId = [u8; 20];
+
+// distance is the Kademlia XOR metric, compared as a big-endian number
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; 20];
+    for (i, byte) in d.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    d
+}
+
+// bucket_index is the length of the prefix id shares with own, None for own itself
+fn bucket_index(own: &NodeId, id: &NodeId) -> Option<usize> {
+    let d = distance(own, id);
+    let zeros = d.iter().position(|b| *b != 0)?;
+    Some(zeros * 8 + d[zeros].leading_zeros() as usize)
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+    failures: u32,
+}
+
+impl Node {
+    pub fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    pub fn is_good(&self) -> bool {
+        self.failures == 0 && self.last_seen.elapsed() < QUESTIONABLE_AFTER
+    }
+}
+
+// RoutingTable keeps up to K nodes for every prefix length shared with our own id, so we know
+// many nodes close to us and a few far away
+#[derive(Debug)]
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self { own, buckets: vec![vec![]; ID_BITS] }
+    }
+
+    pub fn own_id(&self) -> &NodeId {
+        &self.own
+    }
+
+    // insert records that id answered from addr. A known node is refreshed, a new one takes a free
+    // place or the place of a bad node, otherwise it's dropped and false is returned
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        let Some(index) = bucket_index(&self.own, &id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        let node = Node { id, addr, last_seen: Instant::now(), failures: 0 };
+        if let Some(pos) = bucket.iter().position(|n| n.id == id) {
+            // 最近见过的节点放在末尾
+            bucket.remove(pos);
+            bucket.push(node);
+            return true;
+        }
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+        match bucket.iter().position(Node::is_bad) {
+            Some(pos) => {
+                bucket.remove(pos);
+                bucket.push(node);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // failed counts a query to addr that went unanswered
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for node in self.buckets.iter_mut().flatten().filter(|n| n.addr == addr) {
+            node.failures += 1;
+        }
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<&Node> {
+        let index = bucket_index(&self.own, id)?;
+        self.buckets[index].iter().find(|n| n.id == *id)
+    }
+
+    // closest returns up to count nodes nearest to target, bad nodes left out
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes = self.buckets.iter().flatten().filter(|n| !n.is_bad()).cloned().collect::<Vec<_>>();
+        nodes.sort_by_key(|n| distance(&n.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
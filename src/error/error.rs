@@ -0,0 +1,139 @@
+use std::{fmt, io, net::SocketAddr, path::PathBuf};
+
+use crate::message::message::MessageError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    // the announce request failed or the tracker answered with something we can't use
+    Tracker { url: String, reason: String },
+    // the peer broke the wire protocol
+    Protocol { peer: Option<SocketAddr>, reason: String },
+    Handshake { peer: Option<SocketAddr>, reason: String },
+    // the peer sent a frame that isn't a valid message
+    Message { peer: Option<SocketAddr>, source: MessageError },
+    // connecting to or talking with a peer failed
+    Connection { peer: SocketAddr, source: io::Error },
+    // an http mirror failed to deliver a range
+    WebSeed { url: String, reason: String },
+    Storage { path: PathBuf, source: io::Error },
+    // the .torrent file can't be read or is malformed
+    Metainfo { reason: String },
+    // the ip filter list can't be read
+    IpFilter { path: PathBuf, reason: String },
+    // the download ended before every piece was verified
+    Incomplete { done: usize, total: usize },
+    // a torrent in the session stopped on an error, the original error is reduced to its kind
+    Torrent { kind: ErrorKind, reason: String },
+    Io(io::Error),
+}
+
+// ErrorKind groups errors by what went wrong, so callers can react without matching every variant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Metainfo,
+    // trackers, peers and web seeds
+    Network,
+    Storage,
+    Incomplete,
+    Other,
+}
+
+impl Error {
+    pub fn tracker(url: impl Into<String>, reason: impl fmt::Display) -> Self {
+        Error::Tracker { url: url.into(), reason: reason.to_string() }
+    }
+
+    pub fn web_seed(url: impl Into<String>, reason: impl fmt::Display) -> Self {
+        Error::WebSeed { url: url.into(), reason: reason.to_string() }
+    }
+
+    pub fn protocol(reason: impl fmt::Display) -> Self {
+        Error::Protocol { peer: None, reason: reason.to_string() }
+    }
+
+    pub fn handshake(reason: impl fmt::Display) -> Self {
+        Error::Handshake { peer: None, reason: reason.to_string() }
+    }
+
+    pub fn message(source: MessageError) -> Self {
+        Error::Message { peer: None, source }
+    }
+
+    pub fn metainfo(reason: impl fmt::Display) -> Self {
+        Error::Metainfo { reason: reason.to_string() }
+    }
+
+    pub fn ip_filter(path: impl Into<PathBuf>, reason: impl fmt::Display) -> Self {
+        Error::IpFilter { path: path.into(), reason: reason.to_string() }
+    }
+
+    pub fn storage(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Error::Storage { path: path.into(), source }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Metainfo { .. } => ErrorKind::Metainfo,
+            Error::Tracker { .. } | Error::Connection { .. } | Error::WebSeed { .. } | Error::Handshake { .. } | Error::Protocol { .. } | Error::Message { .. } => ErrorKind::Network,
+            Error::Storage { .. } => ErrorKind::Storage,
+            Error::Incomplete { .. } => ErrorKind::Incomplete,
+            Error::Torrent { kind, .. } => *kind,
+            Error::IpFilter { .. } | Error::Io(_) => ErrorKind::Other,
+        }
+    }
+
+    // with_peer attaches the remote address to errors raised below the connection layer
+    pub fn with_peer(self, addr: SocketAddr) -> Self {
+        match self {
+            Error::Protocol { peer: None, reason } => Error::Protocol { peer: Some(addr), reason },
+            Error::Handshake { peer: None, reason } => Error::Handshake { peer: Some(addr), reason },
+            Error::Message { peer: None, source } => Error::Message { peer: Some(addr), source },
+            Error::Io(source) => Error::Connection { peer: addr, source },
+            e => e,
+        }
+    }
+}
+
+fn fmt_peer(peer: &Option<SocketAddr>) -> String {
+    match peer {
+        Some(addr) => format!(" with {}", addr),
+        None => String::new(),
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Tracker { url, reason } => write!(f, "tracker {}: {}", url, reason),
+            Error::Protocol { peer, reason } => write!(f, "protocol error{}: {}", fmt_peer(peer), reason),
+            Error::Handshake { peer, reason } => write!(f, "handshake failed{}: {}", fmt_peer(peer), reason),
+            Error::Message { peer, source } => write!(f, "bad message{}: {}", fmt_peer(peer), source),
+            Error::Connection { peer, source } => write!(f, "connection to {}: {}", peer, source),
+            Error::WebSeed { url, reason } => write!(f, "web seed {}: {}", url, reason),
+            Error::Storage { path, source } => write!(f, "storage {}: {}", path.display(), source),
+            Error::Metainfo { reason } => write!(f, "invalid metainfo: {}", reason),
+            Error::IpFilter { path, reason } => write!(f, "ip filter {}: {}", path.display(), reason),
+            Error::Incomplete { done, total } => write!(f, "download incomplete: {}/{} pieces", done, total),
+            Error::Torrent { reason, .. } => write!(f, "{}", reason),
+            Error::Io(source) => write!(f, "{}", source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connection { source, .. } | Error::Storage { source, .. } | Error::Io(source) => Some(source),
+            Error::Message { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
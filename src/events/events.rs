@@ -0,0 +1,84 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::session::session::{TorrentId, TorrentState};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    // client is decoded from the peer id when it follows a known convention
+    PeerConnected { addr: SocketAddr, client: Option<String> },
+    PeerDisconnected { addr: SocketAddr, reason: Option<String> },
+    PieceVerified { index: usize },
+    PieceHashFailed { index: usize, peer: Option<SocketAddr> },
+    // the peer sent a corrupt block of piece index, its address is banned for the session
+    PeerBanned { addr: SocketAddr, index: usize },
+    TrackerReply { url: String, peers: usize },
+    TrackerError { url: String, message: String },
+    StateChanged { from: TorrentState, to: TorrentState },
+    FileCompleted { path: PathBuf },
+    StorageError { message: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub time: SystemTime,
+    // None for session wide events
+    pub torrent: Option<TorrentId>,
+    pub kind: EventKind,
+}
+
+// EventBus fans every event out to all subscribers, subscribers that went away are dropped on the next emit
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Sender<Event>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn emit(&self, torrent: Option<TorrentId>, kind: EventKind) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = Event {
+            time: SystemTime::now(),
+            torrent,
+            kind,
+        };
+        subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+// EventSender emits events on behalf of one torrent
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    bus: Arc<EventBus>,
+    torrent: TorrentId,
+}
+
+impl EventSender {
+    pub fn new(bus: Arc<EventBus>, torrent: TorrentId) -> Self {
+        Self { bus, torrent }
+    }
+
+    pub fn torrent(&self) -> TorrentId {
+        self.torrent
+    }
+
+    pub fn emit(&self, kind: EventKind) {
+        self.bus.emit(Some(self.torrent), kind);
+    }
+}
@@ -1,61 +1,109 @@
-use std::{net::TcpStream, cell::RefCell, io::{Read, Error, ErrorKind}, vec};
+use std::io::Read;
 
-#[derive(Debug, Clone)]
-pub struct Handshake<'a> {
-    pub pstr: &'a str,
-    pub info_hash: &'a [u8; 20],
-    pub peer_id: &'a [u8; 20],
+use crate::error::error::{Error, Result};
+
+// BEP 52: the peer can upgrade to the v2 protocol for this torrent
+pub const RESERVED_V2_BYTE: usize = 7;
+pub const RESERVED_V2_BIT: u8 = 0x10;
+// BEP 5: the peer runs a DHT node and understands the port message
+pub const RESERVED_DHT_BYTE: usize = 7;
+pub const RESERVED_DHT_BIT: u8 = 0x01;
+// BEP 10: the peer speaks the extension protocol
+pub const RESERVED_EXTENSION_BYTE: usize = 5;
+pub const RESERVED_EXTENSION_BIT: u8 = 0x10;
+
+// reserved_bits returns the reserved bytes we send for a torrent, the extension protocol is always on
+pub fn reserved_bits(v2: bool, dht: bool) -> [u8; 8] {
+    let mut reserved = [0u8; 8];
+    reserved[RESERVED_EXTENSION_BYTE] |= RESERVED_EXTENSION_BIT;
+    if v2 {
+        reserved[RESERVED_V2_BYTE] |= RESERVED_V2_BIT;
+    }
+    if dht {
+        reserved[RESERVED_DHT_BYTE] |= RESERVED_DHT_BIT;
+    }
+    reserved
 }
 
-impl <'a>Handshake<'a> {
-    pub fn new(info_hash: &'a [u8; 20], peer_id: &'a [u8; 20]) ->Self {
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub pstr: String,
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: &[u8; 20], peer_id: &[u8; 20]) ->Self {
         Self {
-            pstr: "BitTorrent protocol",
-            info_hash,
-            peer_id,
+            pstr: "BitTorrent protocol".to_string(),
+            reserved: [0u8; 8],
+            info_hash: *info_hash,
+            peer_id: *peer_id,
         }
     }
 
+    pub fn with_reserved(mut self, reserved: [u8; 8]) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
-        let pstr_len = self.pstr.as_bytes().len();
+        let pstr_len = self.pstr.len();
         let mut buf = vec![0u8; pstr_len + 49];
         buf[0] = pstr_len as u8;
         let mut curr = 1;
         buf[curr..curr+pstr_len].copy_from_slice(self.pstr.as_bytes());
         curr += pstr_len;
-        buf[curr..curr+8].copy_from_slice(&[0u8; 8]);
+        buf[curr..curr+8].copy_from_slice(&self.reserved);
         curr += 8;
-        buf[curr..curr+self.info_hash.len()].copy_from_slice(self.info_hash);
+        buf[curr..curr+self.info_hash.len()].copy_from_slice(&self.info_hash);
         curr += self.info_hash.len();
-        buf[curr..].copy_from_slice(self.peer_id);
+        buf[curr..].copy_from_slice(&self.peer_id);
         buf
     }
+
+    // supports_v2 tells whether the peer set the BEP 52 upgrade bit
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[RESERVED_V2_BYTE] & RESERVED_V2_BIT != 0
+    }
+
+    // supports_dht tells whether the peer set the BEP 5 DHT bit
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[RESERVED_DHT_BYTE] & RESERVED_DHT_BIT != 0
+    }
+
+    // supports_extensions tells whether the peer set the BEP 10 extension protocol bit
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[RESERVED_EXTENSION_BYTE] & RESERVED_EXTENSION_BIT != 0
+    }
 }
 
-pub fn read(conn: &TcpStream) -> Result<[u8; 20], Error> {
-    let stream = RefCell::new(conn);
+// read reads the remote handshake, reserved bits and peer id included
+pub fn read<R: Read>(conn: &mut R) -> Result<Handshake> {
     let mut length_buf = [0u8; 1];
-    stream.borrow_mut().read_exact(&mut length_buf)?;
+    conn.read_exact(&mut length_buf)?;
     let pstr_len = length_buf[0] as usize;
     if pstr_len == 0 {
-        return Err(Error::new(ErrorKind::Other, "stream长度为0"));
+        return Err(Error::handshake("stream长度为0"));
     }
     let mut handshake_buf = vec![0; 48 + pstr_len];
-    stream.borrow_mut().read_exact(&mut handshake_buf)?;
+    conn.read_exact(&mut handshake_buf)?;
 
+    let mut reserved = [0u8; 8];
     let mut info_hash = [0u8; 20];
     let mut peer_id = [0u8; 20];
 
     {
+        reserved.copy_from_slice(&handshake_buf[pstr_len..pstr_len + 8]);
         info_hash.copy_from_slice(&handshake_buf[pstr_len + 8..pstr_len + 8 + 20]);
         peer_id.copy_from_slice(&handshake_buf[pstr_len+ 8 + 20..]);
     }
 
-    // Handshake {
-    //     pstr: &String::from_utf8(handshake_buf[0..pstr_len].to_vec()).unwrap(),
-    //     info_hash: &[0u8; 20],
-    //     peer_id: &[0u8; 20],
-    // }
-
-    Ok(info_hash)
+    Ok(Handshake {
+        pstr: String::from_utf8_lossy(&handshake_buf[..pstr_len]).into_owned(),
+        reserved,
+        info_hash,
+        peer_id,
+    })
 }
@@ -0,0 +1,374 @@
+use std::{
+    collections::HashSet,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    error::error::{Error, Result},
+    peers::peers::PeerSource,
+};
+
+// eMule 的访问级别低于这个值的范围被屏蔽
+const EMULE_BLOCK_LEVEL: u32 = 128;
+const SOURCES: [PeerSource; 5] = [PeerSource::Tracker, PeerSource::Incoming, PeerSource::Dht, PeerSource::Pex, PeerSource::Lsd];
+
+// IpRanges is a set of blocked address ranges, kept sorted and merged so a lookup is a binary search
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpRanges {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+fn merge<T: Ord + Copy>(ranges: &mut Vec<(T, T)>, next: impl Fn(T) -> Option<T>) {
+    ranges.sort();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            // 重叠或相邻的范围合并成一个
+            Some(last) if next(last.1).is_none_or(|after| start <= after) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    // 第一个起点大于 ip 的范围之前的那个才可能包含它
+    let pos = ranges.partition_point(|(start, _)| *start <= ip);
+    pos > 0 && ip <= ranges[pos - 1].1
+}
+
+// Key is an address as the number ranges are compared by, IPv4-mapped IPv6 counts as IPv4
+enum Key {
+    V4(u32),
+    V6(u128),
+}
+
+fn key(ip: IpAddr) -> Key {
+    match ip {
+        IpAddr::V4(ip) => Key::V4(u32::from(ip)),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => Key::V4(u32::from(v4)),
+            None => Key::V6(u128::from(ip)),
+        },
+    }
+}
+
+// parse_ip accepts zero padded IPv4 addresses as ipfilter.dat writes them, 001.002.003.004
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if let Ok(ip) = s.parse() {
+        return Some(ip);
+    }
+    let octets = s.split('.').map(|o| o.parse::<u8>().ok()).collect::<Option<Vec<_>>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn parse_range(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let (start, end) = s.split_once('-')?;
+    Some((parse_ip(start)?, parse_ip(end)?))
+}
+
+fn parse_cidr(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let (ip, bits) = s.trim().split_once('/')?;
+    let ip = parse_ip(ip)?;
+    let bits = bits.trim().parse::<u32>().ok()?;
+    match ip {
+        IpAddr::V4(ip) if bits <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Some((IpAddr::V4(start.into()), IpAddr::V4((start | !mask).into())))
+        },
+        IpAddr::V6(ip) if bits <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Some((IpAddr::V6(Ipv6Addr::from(start)), IpAddr::V6(Ipv6Addr::from(start | !mask))))
+        },
+        _ => None,
+    }
+}
+
+// parse_line reads one rule, Ok(None) for lines without one
+//   eMule ipfilter.dat:  001.002.003.000 - 001.002.003.255 , 000 , description
+//   PeerGuardian P2P:    description:1.2.3.0-1.2.3.255
+//   CIDR or plain:       1.2.3.0/24, 2001:db8::/32, 1.2.3.4 - 1.2.3.9, 1.2.3.4
+fn parse_line(line: &str) -> std::result::Result<Option<(IpAddr, IpAddr)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+        return Ok(None);
+    }
+    let bad = || format!("unrecognized rule {:?}", line);
+    if let Some((range, rest)) = line.split_once(',') {
+        if let Some(range) = parse_range(range) {
+            let level = rest.split(',').next().unwrap_or_default().trim();
+            let level = level.parse::<u32>().map_err(|_| bad())?;
+            return Ok((level < EMULE_BLOCK_LEVEL).then_some(range));
+        }
+    }
+    if let Some(range) = parse_cidr(line).or_else(|| parse_range(line)) {
+        return Ok(Some(range));
+    }
+    if let Some(ip) = parse_ip(line) {
+        return Ok(Some((ip, ip)));
+    }
+    // 描述里可能有冒号，地址范围在最后一个冒号之后
+    let (_, range) = line.rsplit_once(':').ok_or_else(bad)?;
+    parse_range(range).map(Some).ok_or_else(bad)
+}
+
+impl IpRanges {
+    // parse reads eMule dat, P2P and CIDR rules, the formats can be mixed. Lines that match none
+    // of them are skipped and counted in the second value
+    pub fn parse(text: &str) -> (Self, usize) {
+        let mut ranges = IpRanges::default();
+        let mut skipped = 0;
+        for (n, line) in text.lines().enumerate() {
+            match parse_line(line) {
+                Ok(Some((start, end))) => {
+                    if !ranges.insert(start, end) {
+                        debug!(line = n + 1, "ip filter range mixes address families");
+                        skipped += 1;
+                    }
+                },
+                Ok(None) => {},
+                Err(reason) => {
+                    debug!(line = n + 1, %reason, "skipping ip filter line");
+                    skipped += 1;
+                },
+            }
+        }
+        ranges.normalize();
+        (ranges, skipped)
+    }
+
+    // insert adds the range from start to end, both included, false when they're of different families
+    pub fn insert(&mut self, start: IpAddr, end: IpAddr) -> bool {
+        match (key(start), key(end)) {
+            (Key::V4(a), Key::V4(b)) => self.v4.push((a.min(b), a.max(b))),
+            (Key::V6(a), Key::V6(b)) => self.v6.push((a.min(b), a.max(b))),
+            _ => return false,
+        }
+        true
+    }
+
+    fn normalize(&mut self) {
+        merge(&mut self.v4, |ip| ip.checked_add(1));
+        merge(&mut self.v6, |ip| ip.checked_add(1));
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match key(ip) {
+            Key::V4(ip) => contains(&self.v4, ip),
+            Key::V6(ip) => contains(&self.v6, ip),
+        }
+    }
+
+    // len is the number of merged ranges
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpFilterStats {
+    pub ranges: usize,
+    pub banned: usize,
+    // blocked peers by where we heard of them, Incoming counts refused inbound connections
+    pub blocked: Vec<(PeerSource, u64)>,
+}
+
+impl IpFilterStats {
+    pub fn total_blocked(&self) -> u64 {
+        self.blocked.iter().map(|(_, n)| n).sum()
+    }
+}
+
+// IpFilter is the blocklist a session checks every peer address against. It's shared between
+// threads and can be swapped for a new list while the session runs
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    ranges: RwLock<IpRanges>,
+    // the file the rules came from, reload reads it again
+    path: Mutex<Option<PathBuf>>,
+    // addresses banned while running, e.g. for sending corrupt data, they survive reloads
+    banned: RwLock<HashSet<IpAddr>>,
+    blocked: [AtomicU64; SOURCES.len()],
+}
+
+impl IpFilter {
+    pub fn new(ranges: IpRanges) -> Self {
+        Self { ranges: RwLock::new(ranges), ..Self::default() }
+    }
+
+    // from_file loads a blocklist, see IpRanges::parse for the formats
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let filter = Self::default();
+        filter.load(path)?;
+        Ok(filter)
+    }
+
+    // load replaces the rules with the ones in path, the old rules stay when it can't be read
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let bytes = fs::read(path).map_err(|e| Error::ip_filter(path, e))?;
+        let text = String::from_utf8_lossy(&bytes);
+        let (ranges, skipped) = IpRanges::parse(&text);
+        if ranges.is_empty() && skipped > 0 {
+            return Err(Error::ip_filter(path, format!("none of {} rules could be read", skipped)));
+        }
+        if skipped > 0 {
+            warn!(path = %path.display(), skipped, "ip filter lines skipped");
+        }
+        info!(path = %path.display(), ranges = ranges.len(), "ip filter loaded");
+        let count = ranges.len();
+        *self.ranges.write().unwrap() = ranges;
+        *self.path.lock().unwrap() = Some(path.to_path_buf());
+        Ok(count)
+    }
+
+    // reload reads the last loaded file again, it does nothing when no file was loaded
+    pub fn reload(&self) -> Result<usize> {
+        let path = self.path.lock().unwrap().clone();
+        match path {
+            Some(path) => self.load(&path),
+            None => Ok(self.ranges.read().unwrap().len()),
+        }
+    }
+
+    // set_ranges replaces the rules with ranges not backed by a file
+    pub fn set_ranges(&self, ranges: IpRanges) {
+        *self.ranges.write().unwrap() = ranges;
+        *self.path.lock().unwrap() = None;
+    }
+
+    // ban blocks a single address until the filter is dropped, false when it was banned already
+    pub fn ban(&self, ip: IpAddr) -> bool {
+        self.banned.write().unwrap().insert(ip)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.read().unwrap().contains(&ip)
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.is_banned(ip) || self.ranges.read().unwrap().contains(ip)
+    }
+
+    // allows checks an address of a peer learned from source and counts it when it's blocked
+    pub fn allows(&self, ip: IpAddr, source: PeerSource) -> bool {
+        if !self.is_blocked(ip) {
+            return true;
+        }
+        let index = SOURCES.iter().position(|s| *s == source).unwrap();
+        self.blocked[index].fetch_add(1, Ordering::Relaxed);
+        debug!(%ip, ?source, "blocked by ip filter");
+        false
+    }
+
+    pub fn stats(&self) -> IpFilterStats {
+        IpFilterStats {
+            ranges: self.ranges.read().unwrap().len(),
+            banned: self.banned.read().unwrap().len(),
+            blocked: SOURCES.iter().zip(&self.blocked).map(|(s, n)| (*s, n.load(Ordering::Relaxed))).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_every_format() {
+        let text = "\
+# comment
+// another comment
+
+001.002.003.000 - 001.002.003.255 , 000 , Some ISP
+005.000.000.000 - 005.255.255.255 , 200 , allowed
+Bad-guys, Inc:10.0.0.0-10.0.0.9
+192.168.0.0/16
+2001:db8::/32
+8.8.8.8
+9.9.9.1 - 9.9.9.3
+not a rule
+1.2.3.4/33
+";
+        let (ranges, skipped) = IpRanges::parse(text);
+        assert_eq!(skipped, 2);
+        assert_eq!(ranges.len(), 6);
+        for blocked in ["1.2.3.0", "1.2.3.255", "10.0.0.9", "192.168.255.255", "2001:db8:ffff::1", "8.8.8.8", "9.9.9.2", "::ffff:1.2.3.4"] {
+            assert!(ranges.contains(ip(blocked)), "{} should be blocked", blocked);
+        }
+        for allowed in ["1.2.4.0", "5.1.1.1", "10.0.0.10", "192.167.255.255", "2001:db9::", "8.8.8.9", "9.9.9.4", "::1"] {
+            assert!(!ranges.contains(ip(allowed)), "{} should be allowed", allowed);
+        }
+    }
+
+    #[test]
+    fn cidr_edges() {
+        let (all, _) = IpRanges::parse("0.0.0.0/0\n::/0");
+        assert!(all.contains(ip("255.255.255.255")) && all.contains(ip("ffff::1")));
+        let (one, _) = IpRanges::parse("1.2.3.4/32");
+        assert!(one.contains(ip("1.2.3.4")) && !one.contains(ip("1.2.3.5")));
+        // 主机位不为零时取所在的网段
+        let (net, _) = IpRanges::parse("1.2.3.200/24");
+        assert!(net.contains(ip("1.2.3.0")) && !net.contains(ip("1.2.4.0")));
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        let (ranges, skipped) = IpRanges::parse("1.0.0.0-1.0.0.10\n1.0.0.5-1.0.0.20\n1.0.0.21-1.0.0.30\n1.0.0.40\n255.255.255.0/24\n255.255.255.255");
+        assert_eq!(skipped, 0);
+        assert_eq!(ranges.v4, vec![(0x01000000, 0x0100001e), (0x01000028, 0x01000028), (0xffffff00, 0xffffffff)]);
+        let mut reversed = IpRanges::default();
+        assert!(reversed.insert(ip("1.0.0.9"), ip("1.0.0.1")));
+        assert!(reversed.contains(ip("1.0.0.5")));
+        assert!(!reversed.insert(ip("1.0.0.1"), ip("::2")));
+    }
+
+    #[test]
+    fn loads_reloads_and_keeps_bans() {
+        let path = std::env::temp_dir().join(format!("ipfilter-test-{}.txt", std::process::id()));
+        fs::write(&path, "1.1.1.1\n").unwrap();
+        let filter = IpFilter::from_file(&path).unwrap();
+        assert!(filter.ban(ip("2.2.2.2")));
+        assert!(!filter.ban(ip("2.2.2.2")));
+        assert!(!filter.allows(ip("1.1.1.1"), PeerSource::Tracker));
+        assert!(!filter.allows(ip("2.2.2.2"), PeerSource::Incoming));
+        assert!(filter.allows(ip("3.3.3.3"), PeerSource::Dht));
+
+        fs::write(&path, "3.3.3.0/24\n").unwrap();
+        assert_eq!(filter.reload().unwrap(), 1);
+        assert!(filter.allows(ip("1.1.1.1"), PeerSource::Tracker));
+        assert!(!filter.allows(ip("3.3.3.3"), PeerSource::Dht));
+        assert!(filter.is_banned(ip("2.2.2.2")));
+
+        // 读不出任何规则时保留旧的规则
+        fs::write(&path, "garbage\n").unwrap();
+        assert!(filter.reload().is_err());
+        assert!(filter.is_blocked(ip("3.3.3.3")));
+        fs::remove_file(&path).unwrap();
+        assert!(filter.reload().is_err());
+
+        let stats = filter.stats();
+        assert_eq!((stats.ranges, stats.banned, stats.total_blocked()), (1, 1, 3));
+        assert!(stats.blocked.contains(&(PeerSource::Dht, 1)));
+    }
+}
@@ -0,0 +1,21 @@
+#![allow(clippy::module_inception)]
+
+pub mod error;
+pub mod bencode;
+pub mod merkle;
+pub mod torrent_file;
+pub mod peers;
+pub mod p2p;
+pub mod client;
+pub mod message;
+pub mod bitfield;
+pub mod handshake;
+pub mod peer_id;
+pub mod mse;
+pub mod utp;
+pub mod dht;
+pub mod ipfilter;
+pub mod ratelimit;
+pub mod storage;
+pub mod session;
+pub mod events;
@@ -1,21 +1,433 @@
-mod torrent_file;
-mod peers;
-mod p2p;
-mod client;
-mod message;
-mod bitfield;
-mod handshake;
+use std::{
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc::RecvTimeoutError,
+    time::Duration,
+};
 
-fn main() {
-    let _in_path = "src/torrent_file/testdata/debian-11.3.0-amd64-netinst.iso.torrent";
-    let _out_path = "src/torrent_file/testdata/debian.iso";
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::json;
+use torrent_client::{
+    error::error::{Error, ErrorKind, Result},
+    events::events::EventKind,
+    mse::mse::EncryptionPolicy,
+    p2p::metadata,
+    session::session::{SeedLimitAction, SeedLimits, Session, SessionSettings, TorrentId, TorrentState, TorrentStatus},
+    storage::recheck::{self, RecheckResult},
+    torrent_file::{creator::{self, CreateOptions}, magnet::Magnet, torrent_file::{self, CustomTorrent}},
+};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+// 退出码，方便脚本判断失败原因
+const EXIT_ERROR: i32 = 1;
+const EXIT_METAINFO: i32 = 3;
+const EXIT_NETWORK: i32 = 4;
+const EXIT_STORAGE: i32 = 5;
+const EXIT_INCOMPLETE: i32 = 6;
+
+#[derive(Debug, Parser)]
+#[command(name = "torrent_client", version, about = "A small BitTorrent client")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[command(flatten)]
+    options: GlobalOptions,
+}
+
+#[derive(Debug, Args)]
+struct GlobalOptions {
+    /// Listen port for incoming peers, 0 picks a free one
+    #[arg(long, short = 'p', global = true, default_value_t = 6881)]
+    port: u16,
+
+    /// Connections per torrent
+    #[arg(long, global = true, default_value_t = 50)]
+    max_peers: usize,
+
+    /// Connections across all torrents
+    #[arg(long, global = true, default_value_t = 200)]
+    max_connections: usize,
+
+    /// Upload limit in KiB/s, 0 means unlimited
+    #[arg(long, global = true, default_value_t = 0)]
+    upload_limit: u64,
+
+    /// Download limit in KiB/s, 0 means unlimited
+    #[arg(long, global = true, default_value_t = 0)]
+    download_limit: u64,
+
+    /// Peer connection encryption (MSE/PE)
+    #[arg(long, global = true, value_enum, default_value_t = Encryption::Enabled)]
+    encryption: Encryption,
+
+    /// Only use TCP for peer connections
+    #[arg(long, global = true)]
+    no_utp: bool,
+
+    /// Don't run a DHT node
+    #[arg(long, global = true)]
+    no_dht: bool,
+
+    /// Block peers listed in an eMule ipfilter.dat, PeerGuardian P2P or CIDR file
+    #[arg(long, global = true)]
+    ip_filter: Option<PathBuf>,
 
-    let custom_torrent = torrent_file::torrent_file::open(&_in_path).unwrap();
+    /// Stop seeding once uploaded data reaches this multiple of the torrent size
+    #[arg(long, global = true)]
+    seed_ratio: Option<f64>,
+
+    /// Stop seeding after this many minutes
+    #[arg(long, global = true)]
+    seed_time: Option<u64>,
+
+    /// Stop seeding after this many minutes without uploading
+    #[arg(long, global = true)]
+    seed_idle: Option<u64>,
+
+    /// Output format
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Encryption {
+    Disabled,
+    Enabled,
+    Forced,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Download a torrent into a directory
+    ///
+    /// A magnet link is resolved first: its trackers are asked for peers and the metadata is
+    /// fetched from them (BEP 9), so the link needs at least one tracker.
+    Download {
+        /// .torrent file or magnet link
+        torrent: PathBuf,
+        #[arg(long, short = 'o', default_value = ".")]
+        output: PathBuf,
+        /// Keep seeding after the download until a seed limit is reached
+        #[arg(long)]
+        seed: bool,
+    },
+    /// Show the metadata of a .torrent file
+    Info {
+        torrent: PathBuf,
+        /// Same as --format json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check downloaded data against a .torrent file
+    Verify {
+        torrent: PathBuf,
+        #[arg(long, short = 'd', default_value = ".")]
+        data: PathBuf,
+    },
+    /// Create a .torrent file from a file or directory
+    Create {
+        path: PathBuf,
+        /// Defaults to <name>.torrent in the current directory
+        #[arg(long, short = 'o')]
+        output: Option<PathBuf>,
+        /// Tracker url, repeat for more tiers, separate urls of one tier with commas
+        #[arg(long, short = 'a')]
+        announce: Vec<String>,
+        /// Web seed url, may be repeated
+        #[arg(long)]
+        web_seed: Vec<String>,
+        #[arg(long)]
+        private: bool,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long)]
+        source: Option<String>,
+        /// Piece length in KiB, picked automatically when missing
+        #[arg(long)]
+        piece_length: Option<usize>,
+    },
+    /// Seed existing data
+    Seed {
+        torrent: PathBuf,
+        #[arg(long, short = 'd', default_value = ".")]
+        data: PathBuf,
+    },
+    /// Print the magnet link of a .torrent file
+    Magnet {
+        torrent: PathBuf,
+    },
+}
+
+fn exit_code(e: &Error) -> i32 {
+    match e.kind() {
+        ErrorKind::Metainfo => EXIT_METAINFO,
+        ErrorKind::Network => EXIT_NETWORK,
+        ErrorKind::Storage => EXIT_STORAGE,
+        ErrorKind::Incomplete => EXIT_INCOMPLETE,
+        ErrorKind::Other => EXIT_ERROR,
+    }
+}
+
+fn open_torrent(path: &Path) -> Result<CustomTorrent> {
+    torrent_file::open(&path.to_string_lossy())
+}
 
-    custom_torrent.down_load_to_file(_out_path);
+fn encryption_policy(encryption: Encryption) -> EncryptionPolicy {
+    match encryption {
+        Encryption::Disabled => EncryptionPolicy::Disabled,
+        Encryption::Enabled => EncryptionPolicy::Enabled,
+        Encryption::Forced => EncryptionPolicy::Forced,
+    }
+}
+
+// resolve_magnet asks the link's trackers for peers and fetches the info dictionary from them,
+// with the session's peer id, port and ip filter
+fn resolve_magnet(link: &str, session: &Session, options: &GlobalOptions) -> Result<CustomTorrent> {
+    let magnet = Magnet::parse(link)?;
+    if magnet.trackers.is_empty() {
+        // DHT 节点还不会 get_peers，只能从 tracker 找 peer
+        return Err(Error::metainfo("magnet link has no tracker to find peers with"));
+    }
+    let peers: Vec<_> = magnet
+        .request_peers(&session.peer_id(), rand::random(), session.listen_port())
+        .into_iter()
+        .filter(|p| !session.ip_filter().is_blocked(p.general_address().ip()))
+        .collect();
+    info!(peers = peers.len(), "fetching metadata");
+    let raw_info = metadata::fetch_metadata(&magnet, &peers, session.peer_id(), encryption_policy(options.encryption))?;
+    magnet.torrent(&raw_info)
+}
+
+fn session_settings(options: &GlobalOptions) -> SessionSettings {
+    SessionSettings {
+        listen_port: options.port,
+        max_connections: options.max_connections,
+        max_connections_per_torrent: options.max_peers,
+        upload_limit: options.upload_limit * 1024,
+        download_limit: options.download_limit * 1024,
+        encryption: encryption_policy(options.encryption),
+        enable_utp: !options.no_utp,
+        enable_dht: !options.no_dht,
+        ip_filter: options.ip_filter.clone(),
+        seed_limits: SeedLimits {
+            ratio: options.seed_ratio,
+            seeding_time: options.seed_time.map(|m| Duration::from_secs(m * 60)),
+            idle_time: options.seed_idle.map(|m| Duration::from_secs(m * 60)),
+            action: SeedLimitAction::Stop,
+        },
+        ..SessionSettings::default()
+    }
+}
+
+fn print_status(status: &TorrentStatus, format: Format) {
+    match format {
+        Format::Text => println!(
+            "{}: {:?} {}/{} pieces, down {:.1} KiB/s, up {:.1} KiB/s, ratio {:.2}",
+            status.name,
+            status.state,
+            status.pieces_done,
+            status.num_pieces,
+            status.rates.download.rate / 1024.0,
+            status.rates.upload.rate / 1024.0,
+            status.ratio,
+        ),
+        Format::Json => println!(
+            "{}",
+            json!({
+                "name": status.name,
+                "info_hash": hex::encode(status.info_hash),
+                "state": format!("{:?}", status.state),
+                "pieces_done": status.pieces_done,
+                "num_pieces": status.num_pieces,
+                "download_rate": status.rates.download.rate,
+                "upload_rate": status.rates.upload.rate,
+                "ratio": status.ratio,
+                "seeding_time": status.seeding_time.as_secs(),
+            })
+        ),
+    }
+}
+
+// watch prints the torrent's status every second until it reaches a state done accepts. A torrent
+// that fails keeps the kind of its error, a session that goes away first leaves it incomplete
+fn watch(session: &Session, id: TorrentId, format: Format, done: impl Fn(&TorrentState) -> bool) -> Result<()> {
+    let events = session.subscribe();
+    // 订阅之前状态可能已经变了
+    if session.torrent_status(id).is_some_and(|status| done(&status.state)) {
+        return Ok(());
+    }
+    loop {
+        match events.recv_timeout(Duration::from_secs(1)) {
+            Ok(event) => {
+                if let (Some(torrent), EventKind::StateChanged { to, .. }) = (event.torrent, event.kind) {
+                    match to {
+                        _ if torrent != id => {},
+                        TorrentState::Error(kind, reason) => return Err(Error::Torrent { kind, reason }),
+                        to if done(&to) => break,
+                        _ => {},
+                    }
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(status) = session.torrent_status(id) {
+                    print_status(&status, format);
+                }
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                let status = session.torrent_status(id);
+                return Err(Error::Incomplete {
+                    done: status.as_ref().map_or(0, |s| s.pieces_done),
+                    total: status.as_ref().map_or(0, |s| s.num_pieces),
+                });
+            },
+        }
+    }
+    if let Some(status) = session.torrent_status(id) {
+        print_status(&status, format);
+    }
+    Ok(())
+}
+
+fn download(path: &Path, output: &Path, seed: bool, options: &GlobalOptions) -> Result<()> {
+    let session = Session::new(session_settings(options))?;
+    let link = path.to_string_lossy();
+    let torrent = if link.starts_with("magnet:") { resolve_magnet(&link, &session, options)? } else { open_torrent(path)? };
+    // 先检查已经下载的数据，只下载缺失或损坏的 piece
+    let resume = recheck::recheck(&torrent, output, 0);
+    if resume.pieces_done() > 0 {
+        info!(done = resume.pieces_done(), total = resume.num_pieces, "resuming from existing data");
+    }
+    let id = session.add_torrent_with_resume(torrent, output, &resume.bitfield);
+    if seed {
+        return watch(&session, id, options.format, |state| matches!(state, TorrentState::Paused | TorrentState::Stopped));
+    }
+    watch(&session, id, options.format, |state| matches!(state, TorrentState::Finished | TorrentState::Seeding))
+}
+
+fn seed(path: &Path, data: &Path, options: &GlobalOptions) -> Result<()> {
+    let torrent = open_torrent(path)?;
+    let result = recheck::recheck(&torrent, data, 0);
+    if !result.is_complete() {
+        return Err(Error::Incomplete { done: result.pieces_done(), total: result.num_pieces });
+    }
+    let session = Session::new(session_settings(options))?;
+    let id = session.add_torrent_with_resume(torrent, data, &result.bitfield);
+    watch(&session, id, options.format, |state| matches!(state, TorrentState::Paused | TorrentState::Stopped))
+}
+
+fn info(path: &Path, format: Format) -> Result<()> {
+    let info = open_torrent(path)?.info();
+    match format {
+        Format::Text => print!("{}", info),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&info).map_err(|e| Error::Io(e.into()))?),
+    }
+    Ok(())
+}
+
+fn magnet(path: &Path, format: Format) -> Result<()> {
+    let torrent = open_torrent(path)?;
+    match format {
+        Format::Text => println!("{}", torrent.magnet_link()),
+        Format::Json => println!("{}", json!({ "magnet": torrent.magnet_link() })),
+    }
+    Ok(())
+}
+
+fn print_recheck(name: &str, result: &RecheckResult, format: Format) {
+    match format {
+        Format::Text => {
+            println!("{}: {}/{} pieces verified, {} corrupt", name, result.pieces_done(), result.num_pieces, result.corrupt.len());
+            for file in &result.files {
+                println!("  {:6.2}% {}", file.percent(), file.path.display());
+            }
+            if !result.corrupt.is_empty() {
+                let corrupt = result.corrupt.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+                println!("corrupt pieces: {}", corrupt.join(", "));
+            }
+        },
+        Format::Json => {
+            let files = result
+                .files
+                .iter()
+                .map(|f| json!({ "path": f.path, "length": f.length, "verified": f.verified, "percent": f.percent() }))
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                json!({
+                    "name": name,
+                    "pieces_done": result.pieces_done(),
+                    "num_pieces": result.num_pieces,
+                    "files": files,
+                    "corrupt": result.corrupt,
+                })
+            );
+        },
+    }
+}
+
+fn verify(path: &Path, data: &Path, format: Format) -> Result<()> {
+    let torrent = open_torrent(path)?;
+    let result = recheck::recheck(&torrent, data, 0);
+    print_recheck(&torrent.name, &result, format);
+    if !result.is_complete() {
+        return Err(Error::Incomplete { done: result.pieces_done(), total: result.num_pieces });
+    }
+    Ok(())
+}
+
+fn create(path: &Path, output: Option<&Path>, options: CreateOptions, format: Format) -> Result<()> {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let output = output.map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from(format!("{}.torrent", name)));
+    creator::create_torrent_file(path, &output, &options)?;
+    let torrent = open_torrent(&output)?;
+    match format {
+        Format::Text => println!("{} {}", hex::encode(torrent.info_hash), output.display()),
+        Format::Json => println!("{}", json!({ "info_hash": hex::encode(torrent.info_hash), "path": output })),
+    }
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let options = &cli.options;
+    match &cli.command {
+        Command::Download { torrent, output, seed: keep_seeding } => download(torrent, output, *keep_seeding, options),
+        Command::Info { torrent, json } => info(torrent, if *json { Format::Json } else { options.format }),
+        Command::Magnet { torrent } => magnet(torrent, options.format),
+        Command::Create { path, output, announce, web_seed, private, comment, source, piece_length } => {
+            let options = CreateOptions {
+                piece_length: piece_length.map(|kib| kib * 1024),
+                trackers: announce.iter().map(|tier| tier.split(',').map(str::to_string).collect()).collect(),
+                web_seeds: web_seed.clone(),
+                private: *private,
+                comment: comment.clone(),
+                source: source.clone(),
+                threads: 0,
+            };
+            create(path, output.as_deref(), options, cli.options.format)
+        },
+        Command::Verify { torrent, data } => verify(torrent, data, options.format),
+        Command::Seed { torrent, data } => seed(torrent, data, options),
+    }
+}
+
+fn main() {
+    // RUST_LOG=debug 或 RUST_LOG=torrent_client::p2p=trace 可以打开更多日志
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_writer(std::io::stderr)
+        .init();
 
-    // if let Err(_) = res {
-    //     return;
-    // }
-    // res.unwrap().down_load_to_file(out_path);
+    let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("{}", e);
+        process::exit(exit_code(&e));
+    }
 }
@@ -0,0 +1,166 @@
+use sha2::{Digest, Sha256};
+
+// BEP 52 的 merkle 树叶子是 16 KiB 的块
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Hash256 = [u8; 32];
+
+pub fn sha256(data: &[u8]) -> Hash256 {
+    Sha256::digest(data).into()
+}
+
+pub fn hash_pair(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// pad_hash returns the root of a subtree of 2^height zero leaves
+pub fn pad_hash(height: u32) -> Hash256 {
+    let mut hash = [0u8; 32];
+    for _ in 0..height {
+        hash = hash_pair(&hash, &hash);
+    }
+    hash
+}
+
+// root_padded hashes a layer up to its root, the layer is filled up to width with pad,
+// width must be a power of two not smaller than the layer
+pub fn root_padded(layer: &[Hash256], width: usize, pad: Hash256) -> Hash256 {
+    let mut layer = layer.to_vec();
+    let mut width = width.max(1);
+    let mut pad = pad;
+    while width > 1 {
+        layer = layer.chunks(2).map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad))).collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+// block_hashes returns the leaf hashes of data, the last block may be short
+pub fn block_hashes(data: &[u8]) -> Vec<Hash256> {
+    data.chunks(BLOCK_SIZE).map(sha256).collect()
+}
+
+// piece_hash is the piece layer entry for data, short pieces are padded with zero leaves
+pub fn piece_hash(data: &[u8], piece_length: usize) -> Hash256 {
+    root_padded(&block_hashes(data), (piece_length / BLOCK_SIZE).max(1), [0u8; 32])
+}
+
+// file_root is the pieces root of a whole file
+pub fn file_root(data: &[u8]) -> Hash256 {
+    let leaves = block_hashes(data);
+    root_padded(&leaves, leaves.len().next_power_of_two(), [0u8; 32])
+}
+
+// root_from_piece_layer rebuilds the pieces root of a file larger than one piece from its piece layer
+pub fn root_from_piece_layer(layer: &[Hash256], piece_length: usize) -> Hash256 {
+    let height = (piece_length / BLOCK_SIZE).max(1).trailing_zeros();
+    root_padded(layer, layer.len().next_power_of_two(), pad_hash(height))
+}
+
+// verify_proof checks hashes received in a hashes message: hashes are the consecutive nodes
+// starting at index in their layer, uncles the proof from their subtree up to root
+pub fn verify_proof(hashes: &[Hash256], index: usize, uncles: &[Hash256], root: &Hash256) -> bool {
+    if hashes.is_empty() || !hashes.len().is_power_of_two() || !index.is_multiple_of(hashes.len()) {
+        return false;
+    }
+    let mut node = root_padded(hashes, hashes.len(), [0u8; 32]);
+    let mut pos = index / hashes.len();
+    for uncle in uncles {
+        node = if pos.is_multiple_of(2) { hash_pair(&node, uncle) } else { hash_pair(uncle, &node) };
+        pos /= 2;
+    }
+    pos == 0 && &node == root
+}
+
+// bad_blocks returns the indices of the 16 KiB blocks of data whose hash differs from leaves
+pub fn bad_blocks(data: &[u8], leaves: &[Hash256]) -> Vec<usize> {
+    data.chunks(BLOCK_SIZE)
+        .enumerate()
+        .filter(|(i, block)| leaves.get(*i) != Some(&sha256(block)))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn hash(hex: &str) -> Hash256 {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn known_hashes() {
+        assert_eq!(sha256(b""), hash("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"));
+        assert_eq!(sha256(b"abc"), hash("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+        assert_eq!(pad_hash(0), [0u8; 32]);
+        assert_eq!(pad_hash(1), hash("f5a5fd42d16a20302798ef6ed309979b43003d2320d9f0e8ea9831a92759fb4b"));
+        assert_eq!(pad_hash(2), hash("db56114e00fdd4c1f85c892bf35ac9a89289aaecb1ebd0a96cde606a748b5d71"));
+    }
+
+    #[test]
+    fn file_roots() {
+        // 不足一个块的文件，根就是数据本身的哈希，不补零
+        assert_eq!(file_root(b"abc"), sha256(b"abc"));
+        let one = data(BLOCK_SIZE);
+        assert_eq!(file_root(&one), sha256(&one));
+        // 5 个块补到 8 个叶子
+        let five = data(4 * BLOCK_SIZE + 100);
+        assert_eq!(file_root(&five), hash("4dc991d3778c61cbdd4974b0589d82c3376934f9540df55d7f00f6d77c990365"));
+    }
+
+    #[test]
+    fn piece_layer_rebuilds_the_file_root() {
+        let piece_length = 4 * BLOCK_SIZE;
+        for len in [2 * piece_length, 3 * piece_length - 1, 5 * piece_length + 1] {
+            let file = data(len);
+            let layer = file.chunks(piece_length).map(|piece| piece_hash(piece, piece_length)).collect::<Vec<_>>();
+            assert_eq!(root_from_piece_layer(&layer, piece_length), file_root(&file), "{} bytes", len);
+        }
+        // 最后一个短 piece 用零叶子补齐
+        let short = data(BLOCK_SIZE + 1);
+        let leaves = block_hashes(&short);
+        let expected = hash_pair(&hash_pair(&leaves[0], &leaves[1]), &pad_hash(1));
+        assert_eq!(piece_hash(&short, piece_length), expected);
+    }
+
+    #[test]
+    fn proofs() {
+        let leaves = (0..8u8).map(|i| sha256(&[i])).collect::<Vec<_>>();
+        let root = root_padded(&leaves, 8, [0u8; 32]);
+        let pair = |a: usize, b: usize| hash_pair(&leaves[a], &leaves[b]);
+        // 叶子 4..6 的证明是兄弟 6..8 和左半边的根
+        let uncles = [pair(6, 7), hash_pair(&pair(0, 1), &pair(2, 3))];
+        assert!(verify_proof(&leaves[4..6], 4, &uncles, &root));
+        assert!(verify_proof(&leaves, 0, &[], &root));
+        assert!(!verify_proof(&leaves[4..6], 6, &uncles, &root));
+        assert!(!verify_proof(&leaves[4..6], 4, &uncles[..1], &root));
+        assert!(!verify_proof(&leaves[4..6], 4, &[uncles[1], uncles[0]], &root));
+        assert!(!verify_proof(&leaves[4..7], 4, &uncles, &root));
+        assert!(!verify_proof(&leaves[4..6], 5, &uncles, &root));
+        assert!(!verify_proof(&[], 0, &[], &root));
+        // 多余的 uncle 会越过根
+        assert!(!verify_proof(&leaves, 0, &[root], &root));
+    }
+
+    #[test]
+    fn finds_bad_blocks() {
+        let piece = data(3 * BLOCK_SIZE + 10);
+        let leaves = block_hashes(&piece);
+        assert_eq!(leaves.len(), 4);
+        assert!(bad_blocks(&piece, &leaves).is_empty());
+        let mut corrupt = piece.clone();
+        corrupt[BLOCK_SIZE + 5] ^= 1;
+        corrupt[3 * BLOCK_SIZE] ^= 1;
+        assert_eq!(bad_blocks(&corrupt, &leaves), vec![1, 3]);
+        assert_eq!(bad_blocks(&piece, &leaves[..2]), vec![2, 3]);
+    }
+}
@@ -1,5 +1,13 @@
-use std::{io::{Read, BufReader}, net::TcpStream};
+use std::{fmt, io::Read};
 
+use tracing::trace;
+
+use crate::error::error::{Error, Result};
+
+// 最长的消息，超过的帧说明对方有问题，不去分配内存
+pub const MAX_MESSAGE_LEN: u32 = 1 << 20;
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum MessageId {
     // MsgChoke chokes the receiver
@@ -20,132 +28,413 @@ pub enum MessageId {
 	MsgPiece = 7,
 	// MsgCancel cancels a request
 	MsgCancel = 8,
+	// MsgPort announces the sender's DHT port (BEP 5)
+	MsgPort = 9,
+	// MsgSuggest and the ones below are the fast extension (BEP 6)
+	MsgSuggest = 13,
+	MsgHaveAll = 14,
+	MsgHaveNone = 15,
+	MsgRejectRequest = 16,
+	MsgAllowedFast = 17,
+	// MsgExtended carries extension protocol messages (BEP 10)
+	MsgExtended = 20,
+	// MsgHashRequest asks for merkle hashes of a v2 file (BEP 52)
+	MsgHashRequest = 21,
+	// MsgHashes answers a hash request
+	MsgHashes = 22,
+	// MsgHashReject refuses a hash request
+	MsgHashReject = 23,
 }
 
-fn uint2message_id(num: u8) -> MessageId {
-    match num {
-        0 => MessageId::MsgChoke,
-        1 => MessageId::MsgUnchoke,
-        2 => MessageId::MsgInterested,
-        3 => MessageId::MsgNotInterested,
-        4 => MessageId::MsgHave,
-        5 => MessageId::MsgBitfield,
-        6 => MessageId::MsgRequest,
-        7 => MessageId::MsgPiece,
-        8 => MessageId::MsgCancel,
-        _ => MessageId::MsgCancel,
+impl TryFrom<u8> for MessageId {
+    type Error = u8;
+
+    fn try_from(num: u8) -> std::result::Result<Self, u8> {
+        match num {
+            0 => Ok(MessageId::MsgChoke),
+            1 => Ok(MessageId::MsgUnchoke),
+            2 => Ok(MessageId::MsgInterested),
+            3 => Ok(MessageId::MsgNotInterested),
+            4 => Ok(MessageId::MsgHave),
+            5 => Ok(MessageId::MsgBitfield),
+            6 => Ok(MessageId::MsgRequest),
+            7 => Ok(MessageId::MsgPiece),
+            8 => Ok(MessageId::MsgCancel),
+            9 => Ok(MessageId::MsgPort),
+            13 => Ok(MessageId::MsgSuggest),
+            14 => Ok(MessageId::MsgHaveAll),
+            15 => Ok(MessageId::MsgHaveNone),
+            16 => Ok(MessageId::MsgRejectRequest),
+            17 => Ok(MessageId::MsgAllowedFast),
+            20 => Ok(MessageId::MsgExtended),
+            21 => Ok(MessageId::MsgHashRequest),
+            22 => Ok(MessageId::MsgHashes),
+            23 => Ok(MessageId::MsgHashReject),
+            _ => Err(num),
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct Message {
-    pub id: MessageId,
-    pub payload: Vec<u8>,
+// MessageError is a frame that can't be a valid peer wire message
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageError {
+    // the length prefix is over MAX_MESSAGE_LEN
+    TooLong { length: u32 },
+    // the payload doesn't have the size the message id requires
+    BadPayload { id: MessageId, length: usize },
 }
 
-pub fn format_request(index: usize, begin: usize, length: usize) -> Message {
-    let mut payload = vec![];
-    payload.extend_from_slice(&(index as u32).to_be_bytes());
-    payload.extend_from_slice(&(begin as u32).to_be_bytes());
-    payload.extend_from_slice(&(length as u32).to_be_bytes());
-    Message {
-        id: MessageId::MsgRequest,
-        payload
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::TooLong { length } => write!(f, "message of {} bytes exceeds the limit of {}", length, MAX_MESSAGE_LEN),
+            MessageError::BadPayload { id, length } => write!(f, "{:?} with a payload of {} bytes", id, length),
+        }
     }
 }
 
-pub fn parse_piece(index: usize, buf: &mut [u8], msg: &Message) -> u32 {
-	if msg.id != MessageId::MsgPiece {
-		return 0;
-	}
-	if msg.payload.len() < 8 {
-		return 0;
-	}
-    let mut buffer = [0u8; 4];
-    buffer.copy_from_slice(&msg.payload[0..4]);
-    let parsed_index = u32::from_be_bytes(buffer) as usize;
-	if parsed_index != index {
-		return 0;
-	}
-    buffer.copy_from_slice(&msg.payload[4..8]);
-    let begin = u32::from_be_bytes(buffer) as usize;
-	if begin >= buffer.len() {
-		return 0;
-	}
-	let data = &msg.payload[8..];
-	if begin + data.len() > buf.len() {
-		return 0;
-	}
-    buf[begin..].copy_from_slice(data);
-	return data.len() as u32;
+impl std::error::Error for MessageError {}
+
+// HashRequest names a run of nodes in one layer of a file's merkle tree, layer 0 being the 16 KiB blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    // a power of two
+    pub length: u32,
+    // how many uncle hashes towards the root to include
+    pub proof_layers: u32,
 }
 
-pub fn format_have(i: usize) -> Message {
-    let mut payload = vec![];
-    let index = i as u32;
-    payload.extend_from_slice(&index.to_be_bytes());
-    Message {
-        id: MessageId::MsgHave,
-        payload,
+impl HashRequest {
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = self.pieces_root.to_vec();
+        for n in [self.base_layer, self.index, self.length, self.proof_layers] {
+            payload.extend_from_slice(&n.to_be_bytes());
+        }
+        payload
     }
+
+    fn parse(payload: &[u8]) -> Self {
+        let mut pieces_root = [0u8; 32];
+        pieces_root.copy_from_slice(&payload[..32]);
+        Self {
+            pieces_root,
+            base_layer: u32_at(payload, 32),
+            index: u32_at(payload, 36),
+            length: u32_at(payload, 40),
+            proof_layers: u32_at(payload, 44),
+        }
+    }
+}
+
+// PeerMessage is one decoded peer wire message
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerMessage {
+    // a zero length frame that only keeps the connection open
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have { index: u32 },
+    Bitfield(Vec<u8>),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, data: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Port(u16),
+    Suggest { index: u32 },
+    HaveAll,
+    HaveNone,
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    AllowedFast { index: u32 },
+    Extended { id: u8, payload: Vec<u8> },
+    HashRequest(HashRequest),
+    // the requested nodes followed by the uncle hashes
+    Hashes { request: HashRequest, hashes: Vec<[u8; 32]> },
+    HashReject(HashRequest),
+    // an id we don't know, the caller should ignore it
+    Unknown { id: u8, payload: Vec<u8> },
+}
+
+fn u32_at(buf: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+}
+
+fn block(index: u32, begin: u32, length: u32) -> Vec<u8> {
+    [index, begin, length].iter().flat_map(|n| n.to_be_bytes()).collect()
 }
 
-pub fn parse_have(msg: &Message) -> u32 {
-    if msg.id == MessageId::MsgHave {
-        return 0;
+impl PeerMessage {
+    pub fn id(&self) -> Option<MessageId> {
+        let id = match self {
+            PeerMessage::KeepAlive => return None,
+            PeerMessage::Choke => MessageId::MsgChoke,
+            PeerMessage::Unchoke => MessageId::MsgUnchoke,
+            PeerMessage::Interested => MessageId::MsgInterested,
+            PeerMessage::NotInterested => MessageId::MsgNotInterested,
+            PeerMessage::Have { .. } => MessageId::MsgHave,
+            PeerMessage::Bitfield(_) => MessageId::MsgBitfield,
+            PeerMessage::Request { .. } => MessageId::MsgRequest,
+            PeerMessage::Piece { .. } => MessageId::MsgPiece,
+            PeerMessage::Cancel { .. } => MessageId::MsgCancel,
+            PeerMessage::Port(_) => MessageId::MsgPort,
+            PeerMessage::Suggest { .. } => MessageId::MsgSuggest,
+            PeerMessage::HaveAll => MessageId::MsgHaveAll,
+            PeerMessage::HaveNone => MessageId::MsgHaveNone,
+            PeerMessage::RejectRequest { .. } => MessageId::MsgRejectRequest,
+            PeerMessage::AllowedFast { .. } => MessageId::MsgAllowedFast,
+            PeerMessage::Extended { .. } => MessageId::MsgExtended,
+            PeerMessage::HashRequest(_) => MessageId::MsgHashRequest,
+            PeerMessage::Hashes { .. } => MessageId::MsgHashes,
+            PeerMessage::HashReject(_) => MessageId::MsgHashReject,
+            PeerMessage::Unknown { .. } => return None,
+        };
+        Some(id)
     }
-    if msg.payload.len() != 4 {
-        return 0;
+
+    fn payload(&self) -> Vec<u8> {
+        match self {
+            PeerMessage::KeepAlive
+            | PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested
+            | PeerMessage::HaveAll
+            | PeerMessage::HaveNone => vec![],
+            PeerMessage::Have { index } | PeerMessage::Suggest { index } | PeerMessage::AllowedFast { index } => index.to_be_bytes().to_vec(),
+            PeerMessage::Bitfield(bits) => bits.clone(),
+            PeerMessage::Request { index, begin, length }
+            | PeerMessage::Cancel { index, begin, length }
+            | PeerMessage::RejectRequest { index, begin, length } => block(*index, *begin, *length),
+            PeerMessage::Piece { index, begin, data } => [&index.to_be_bytes()[..], &begin.to_be_bytes(), data].concat(),
+            PeerMessage::Port(port) => port.to_be_bytes().to_vec(),
+            PeerMessage::Extended { id, payload } => [&[*id][..], payload].concat(),
+            PeerMessage::HashRequest(req) | PeerMessage::HashReject(req) => req.payload(),
+            PeerMessage::Hashes { request, hashes } => [request.payload(), hashes.concat()].concat(),
+            PeerMessage::Unknown { payload, .. } => payload.clone(),
+        }
     }
-    let mut buf = [0u8; 4];
-    buf.copy_from_slice(&msg.payload);
-    let index = u32::from_be_bytes(buf);
-    return index;
-}
 
-pub fn read(conn: &TcpStream) -> Option<Message> {
-    let mut reader = BufReader::new(conn);
+    // Serialize frames the message with its length prefix
+    pub fn serialize(&self) -> Vec<u8> {
+        let id = match self {
+            PeerMessage::KeepAlive => return vec![0u8; 4],
+            PeerMessage::Unknown { id, .. } => *id,
+            msg => msg.id().unwrap() as u8,
+        };
+        let payload = self.payload();
+        let mut buf = Vec::with_capacity(5 + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        buf.push(id);
+        buf.extend_from_slice(&payload);
+        buf
+    }
 
-    let mut length_buf = [0u8; 4];
-    let err = reader.read_exact(&mut length_buf);
-    if let Err(_) = err {
-        return None;
+    // Decode parses the body of a frame, the id byte followed by the payload
+    pub fn decode(id: u8, payload: Vec<u8>) -> Result<Self> {
+        let id = match MessageId::try_from(id) {
+            Ok(id) => id,
+            Err(id) => return Ok(PeerMessage::Unknown { id, payload }),
+        };
+        let len = payload.len();
+        let expect = |ok: bool| if ok { Ok(()) } else { Err(Error::message(MessageError::BadPayload { id, length: len })) };
+        let msg = match id {
+            MessageId::MsgChoke | MessageId::MsgUnchoke | MessageId::MsgInterested | MessageId::MsgNotInterested | MessageId::MsgHaveAll | MessageId::MsgHaveNone => {
+                expect(len == 0)?;
+                match id {
+                    MessageId::MsgChoke => PeerMessage::Choke,
+                    MessageId::MsgUnchoke => PeerMessage::Unchoke,
+                    MessageId::MsgInterested => PeerMessage::Interested,
+                    MessageId::MsgNotInterested => PeerMessage::NotInterested,
+                    MessageId::MsgHaveAll => PeerMessage::HaveAll,
+                    _ => PeerMessage::HaveNone,
+                }
+            },
+            MessageId::MsgHave | MessageId::MsgSuggest | MessageId::MsgAllowedFast => {
+                expect(len == 4)?;
+                let index = u32_at(&payload, 0);
+                match id {
+                    MessageId::MsgHave => PeerMessage::Have { index },
+                    MessageId::MsgSuggest => PeerMessage::Suggest { index },
+                    _ => PeerMessage::AllowedFast { index },
+                }
+            },
+            MessageId::MsgBitfield => PeerMessage::Bitfield(payload),
+            MessageId::MsgRequest | MessageId::MsgCancel | MessageId::MsgRejectRequest => {
+                expect(len == 12)?;
+                let (index, begin, length) = (u32_at(&payload, 0), u32_at(&payload, 4), u32_at(&payload, 8));
+                match id {
+                    MessageId::MsgRequest => PeerMessage::Request { index, begin, length },
+                    MessageId::MsgCancel => PeerMessage::Cancel { index, begin, length },
+                    _ => PeerMessage::RejectRequest { index, begin, length },
+                }
+            },
+            MessageId::MsgPiece => {
+                expect(len >= 8)?;
+                PeerMessage::Piece { index: u32_at(&payload, 0), begin: u32_at(&payload, 4), data: payload[8..].to_vec() }
+            },
+            MessageId::MsgPort => {
+                expect(len == 2)?;
+                PeerMessage::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            },
+            MessageId::MsgExtended => {
+                expect(len >= 1)?;
+                PeerMessage::Extended { id: payload[0], payload: payload[1..].to_vec() }
+            },
+            MessageId::MsgHashRequest | MessageId::MsgHashReject => {
+                expect(len == 48)?;
+                let req = HashRequest::parse(&payload);
+                match id {
+                    MessageId::MsgHashRequest => PeerMessage::HashRequest(req),
+                    _ => PeerMessage::HashReject(req),
+                }
+            },
+            MessageId::MsgHashes => {
+                expect(len >= 48 && (len - 48).is_multiple_of(32))?;
+                let hashes = payload[48..]
+                    .chunks_exact(32)
+                    .map(|c| {
+                        let mut hash = [0u8; 32];
+                        hash.copy_from_slice(c);
+                        hash
+                    })
+                    .collect();
+                PeerMessage::Hashes { request: HashRequest::parse(&payload), hashes }
+            },
+        };
+        Ok(msg)
     }
+}
+
+// Read reads one message, keep-alives included, frames over MAX_MESSAGE_LEN are refused before reading them
+pub fn read<R: Read>(reader: &mut R) -> Result<PeerMessage> {
+    let mut length_buf = [0u8; 4];
+    reader.read_exact(&mut length_buf)?;
 
     let length = u32::from_be_bytes(length_buf);
-    println!("buffer length {:?} {}", length_buf, length);
+    trace!(length, "read message");
     if length == 0 {
-        return None;
+        return Ok(PeerMessage::KeepAlive);
+    }
+    if length > MAX_MESSAGE_LEN {
+        return Err(Error::message(MessageError::TooLong { length }));
     }
 
     let mut message_buf = vec![0u8; length as usize];
-    let buf_res = reader.read_exact(&mut message_buf);
-    if let Err(_) = buf_res {
-    //     // println!("stream read message error {:?}", err);
-        return None;
-    }
-    Some(Message {
-        id: uint2message_id(message_buf[0]),
-        payload: message_buf[1..].to_vec(),
-    })
+    reader.read_exact(&mut message_buf)?;
+    let payload = message_buf.split_off(1);
+    PeerMessage::decode(message_buf[0], payload)
 }
 
-impl Message {
-    pub fn new(id: MessageId, payload: Vec<u8>) -> Self {
-        Self {
-            id,
-            payload: payload,
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn request() -> HashRequest {
+        HashRequest { pieces_root: [7u8; 32], base_layer: 2, index: 512, length: 512, proof_layers: 3 }
+    }
+
+    fn bad_payload(id: u8, payload: Vec<u8>) -> Option<(MessageId, usize)> {
+        match PeerMessage::decode(id, payload) {
+            Err(Error::Message { source: MessageError::BadPayload { id, length }, .. }) => Some((id, length)),
+            _ => None,
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let length = self.payload.len() + 1;
-        let mut buf = vec![0u8; 4 + length];
-        let len_buf: [u8; 4] = (length as u32).to_be_bytes();
-        buf[0..4].copy_from_slice(&len_buf);
-        let id = self.id as u8;
-        buf[4] = id;
-        buf[5..].copy_from_slice(&self.payload[..]);
-        buf
+    #[test]
+    fn round_trips_every_message() {
+        let messages = vec![
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have { index: 0x01020304 },
+            PeerMessage::Bitfield(vec![0xff, 0x80]),
+            PeerMessage::Bitfield(vec![]),
+            PeerMessage::Request { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Piece { index: 2, begin: 0, data: vec![1, 2, 3] },
+            PeerMessage::Piece { index: 2, begin: 0, data: vec![] },
+            PeerMessage::Cancel { index: 1, begin: 16384, length: 16384 },
+            PeerMessage::Port(6881),
+            PeerMessage::Suggest { index: 3 },
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest { index: 1, begin: 0, length: 16384 },
+            PeerMessage::AllowedFast { index: 4 },
+            PeerMessage::Extended { id: 0, payload: b"d1:md6:ut_pexi1eee".to_vec() },
+            PeerMessage::HashRequest(request()),
+            PeerMessage::Hashes { request: request(), hashes: vec![[1u8; 32], [2u8; 32]] },
+            PeerMessage::HashReject(request()),
+            PeerMessage::Unknown { id: 99, payload: vec![5] },
+        ];
+        let mut wire = Cursor::new(messages.iter().flat_map(|m| m.serialize()).collect::<Vec<_>>());
+        for msg in messages {
+            assert_eq!(read(&mut wire).unwrap(), msg);
+        }
+        assert!(matches!(read(&mut wire), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn known_encodings() {
+        assert_eq!(PeerMessage::KeepAlive.serialize(), [0, 0, 0, 0]);
+        assert_eq!(PeerMessage::Interested.serialize(), [0, 0, 0, 1, 2]);
+        assert_eq!(PeerMessage::Have { index: 5 }.serialize(), [0, 0, 0, 5, 4, 0, 0, 0, 5]);
+        assert_eq!(PeerMessage::Port(0x1ae1).serialize(), [0, 0, 0, 3, 9, 0x1a, 0xe1]);
+        assert_eq!(
+            PeerMessage::Request { index: 1, begin: 2, length: 0x4000 }.serialize(),
+            [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0x40, 0]
+        );
+        let hash_request = PeerMessage::HashRequest(request()).serialize();
+        assert_eq!(hash_request.len(), 4 + 1 + 48);
+        assert_eq!(&hash_request[37..], [0, 0, 0, 2, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn rejects_payloads_of_the_wrong_length() {
+        assert_eq!(bad_payload(0, vec![0]), Some((MessageId::MsgChoke, 1)));
+        assert_eq!(bad_payload(14, vec![0]), Some((MessageId::MsgHaveAll, 1)));
+        assert_eq!(bad_payload(4, vec![0; 3]), Some((MessageId::MsgHave, 3)));
+        assert_eq!(bad_payload(4, vec![0; 5]), Some((MessageId::MsgHave, 5)));
+        assert_eq!(bad_payload(6, vec![0; 11]), Some((MessageId::MsgRequest, 11)));
+        assert_eq!(bad_payload(8, vec![0; 13]), Some((MessageId::MsgCancel, 13)));
+        assert_eq!(bad_payload(16, vec![0; 8]), Some((MessageId::MsgRejectRequest, 8)));
+        assert_eq!(bad_payload(7, vec![0; 7]), Some((MessageId::MsgPiece, 7)));
+        assert_eq!(bad_payload(9, vec![0; 3]), Some((MessageId::MsgPort, 3)));
+        assert_eq!(bad_payload(20, vec![]), Some((MessageId::MsgExtended, 0)));
+        assert_eq!(bad_payload(21, vec![0; 47]), Some((MessageId::MsgHashRequest, 47)));
+        assert_eq!(bad_payload(23, vec![0; 49]), Some((MessageId::MsgHashReject, 49)));
+        assert_eq!(bad_payload(22, vec![0; 47]), Some((MessageId::MsgHashes, 47)));
+        assert_eq!(bad_payload(22, vec![0; 48 + 31]), Some((MessageId::MsgHashes, 79)));
+        assert!(PeerMessage::decode(22, vec![0; 48]).is_ok());
+    }
+
+    #[test]
+    fn refuses_frames_over_the_limit_before_reading_them() {
+        let mut frame = (MAX_MESSAGE_LEN + 1).to_be_bytes().to_vec();
+        frame.push(7);
+        let mut reader = Cursor::new(frame);
+        match read(&mut reader) {
+            Err(Error::Message { source: MessageError::TooLong { length }, .. }) => assert_eq!(length, MAX_MESSAGE_LEN + 1),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(reader.position(), 4);
+
+        let mut frame = MAX_MESSAGE_LEN.to_be_bytes().to_vec();
+        frame.push(7);
+        frame.extend_from_slice(&vec![0; MAX_MESSAGE_LEN as usize - 1]);
+        assert!(matches!(read(&mut Cursor::new(frame)), Ok(PeerMessage::Piece { .. })));
+    }
+
+    #[test]
+    fn truncated_frames_are_io_errors() {
+        let mut frame = PeerMessage::Request { index: 1, begin: 2, length: 3 }.serialize();
+        frame.pop();
+        assert!(matches!(read(&mut Cursor::new(frame)), Err(Error::Io(_))));
+        assert!(matches!(read(&mut Cursor::new(vec![0, 0])), Err(Error::Io(_))));
     }
 }
@@ -0,0 +1,220 @@
+use rand::RngCore;
+
+// MSE 使用固定的 768 位素数 P 和生成元 2
+pub const KEY_LEN: usize = 96;
+const LIMBS: usize = KEY_LEN / 4;
+// 私钥 160 位
+const PRIVATE_LEN: usize = 20;
+
+const PRIME: [u8; KEY_LEN] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+
+// Num is a little endian number of 32 bit limbs, as wide as the prime
+type Num = [u32; LIMBS];
+
+fn from_bytes(bytes: &[u8]) -> Num {
+    let mut num = [0u32; LIMBS];
+    for (i, chunk) in bytes.rchunks(4).take(LIMBS).enumerate() {
+        num[i] = chunk.iter().fold(0, |acc, b| (acc << 8) | u32::from(*b));
+    }
+    num
+}
+
+fn to_bytes(num: &Num) -> [u8; KEY_LEN] {
+    let mut bytes = [0u8; KEY_LEN];
+    for (i, limb) in num.iter().enumerate() {
+        bytes[KEY_LEN - 4 * (i + 1)..KEY_LEN - 4 * i].copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+// sub_if_ge subtracts m from a, a number with one extra high limb, when a >= m
+fn sub_if_ge(a: &[u32], m: &Num) -> Num {
+    let ge = a[LIMBS] != 0 || (0..LIMBS).rev().find(|&i| a[i] != m[i]).is_none_or(|i| a[i] > m[i]);
+    let mut out = [0u32; LIMBS];
+    out.copy_from_slice(&a[..LIMBS]);
+    if ge {
+        let mut borrow = 0i64;
+        for i in 0..LIMBS {
+            let d = i64::from(out[i]) - i64::from(m[i]) - borrow;
+            out[i] = d as u32;
+            borrow = i64::from(d < 0);
+        }
+    }
+    out
+}
+
+// Modulus does Montgomery multiplication modulo an odd number
+struct Modulus {
+    m: Num,
+    // -m^-1 mod 2^32
+    m_inv: u32,
+    // R^2 mod m with R = 2^(32 * LIMBS)
+    r2: Num,
+}
+
+impl Modulus {
+    fn new(m: Num) -> Self {
+        let mut inv = 1u32;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(m[0].wrapping_mul(inv)));
+        }
+        // 从 1 开始翻倍 2 * 32 * LIMBS 次得到 R^2 mod m
+        let mut r2 = [0u32; LIMBS];
+        r2[0] = 1;
+        for _ in 0..2 * 32 * LIMBS {
+            let mut wide = [0u32; LIMBS + 1];
+            let mut carry = 0;
+            for i in 0..LIMBS {
+                wide[i] = (r2[i] << 1) | carry;
+                carry = r2[i] >> 31;
+            }
+            wide[LIMBS] = carry;
+            r2 = sub_if_ge(&wide, &m);
+        }
+        Self { m, m_inv: inv.wrapping_neg(), r2 }
+    }
+
+    // mul returns a * b / R mod m
+    fn mul(&self, a: &Num, b: &Num) -> Num {
+        let mut t = [0u32; LIMBS + 2];
+        for &bi in b.iter() {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let v = u64::from(t[j]) + u64::from(a[j]) * u64::from(bi) + carry;
+                t[j] = v as u32;
+                carry = v >> 32;
+            }
+            let v = u64::from(t[LIMBS]) + carry;
+            t[LIMBS] = v as u32;
+            t[LIMBS + 1] = (v >> 32) as u32;
+
+            let q = t[0].wrapping_mul(self.m_inv);
+            let mut carry = (u64::from(t[0]) + u64::from(q) * u64::from(self.m[0])) >> 32;
+            for j in 1..LIMBS {
+                let v = u64::from(t[j]) + u64::from(q) * u64::from(self.m[j]) + carry;
+                t[j - 1] = v as u32;
+                carry = v >> 32;
+            }
+            let v = u64::from(t[LIMBS]) + carry;
+            t[LIMBS - 1] = v as u32;
+            t[LIMBS] = t[LIMBS + 1] + (v >> 32) as u32;
+        }
+        sub_if_ge(&t[..=LIMBS], &self.m)
+    }
+
+    // pow returns base^exp mod m, exp is big endian
+    fn pow(&self, base: &Num, exp: &[u8]) -> Num {
+        let base = self.mul(base, &self.r2);
+        let mut one = [0u32; LIMBS];
+        one[0] = 1;
+        let mut acc = self.mul(&one, &self.r2);
+        for byte in exp {
+            for bit in (0..8).rev() {
+                acc = self.mul(&acc, &acc);
+                if byte >> bit & 1 == 1 {
+                    acc = self.mul(&acc, &base);
+                }
+            }
+        }
+        self.mul(&acc, &one)
+    }
+}
+
+// KeyPair is one side of the MSE Diffie-Hellman exchange
+pub struct KeyPair {
+    private: [u8; PRIVATE_LEN],
+    pub public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let mut private = [0u8; PRIVATE_LEN];
+        rand::thread_rng().fill_bytes(&mut private);
+        let modulus = Modulus::new(from_bytes(&PRIME));
+        let mut generator = [0u32; LIMBS];
+        generator[0] = 2;
+        let public = to_bytes(&modulus.pow(&generator, &private));
+        Self { private, public }
+    }
+
+    // shared_secret computes S from the other side's public key
+    pub fn shared_secret(&self, remote: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        let modulus = Modulus::new(from_bytes(&PRIME));
+        let remote = sub_if_ge(&[&from_bytes(remote)[..], &[0]].concat(), &modulus.m);
+        to_bytes(&modulus.pow(&remote, &self.private))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(n: u64) -> Num {
+        from_bytes(&n.to_be_bytes())
+    }
+
+    fn mod_pow(base: u64, exp: u64, m: u64) -> u64 {
+        let (mut acc, mut base, mut exp) = (1u128, u128::from(base) % u128::from(m), exp);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                acc = acc * base % u128::from(m);
+            }
+            base = base * base % u128::from(m);
+            exp >>= 1;
+        }
+        acc as u64
+    }
+
+    #[test]
+    fn small_modexp_matches_u128() {
+        // 跨两个 limb 的素数，进位错误会在这里暴露
+        for m in [1_000_000_007u64, 0xffff_ffff_ffff_ffc5] {
+            let modulus = Modulus::new(num(m));
+            for (base, exp) in [(2u64, 10u64), (3, 0xdead_beef), (0x1234_5678_9abc, u64::MAX), (m - 1, 2)] {
+                let got = modulus.pow(&num(base), &exp.to_be_bytes());
+                assert_eq!(got, num(mod_pow(base, exp, m)), "{}^{} mod {}", base, exp, m);
+            }
+        }
+    }
+
+    #[test]
+    fn modexp_with_the_mse_prime() {
+        let modulus = Modulus::new(from_bytes(&PRIME));
+        let two = num(2);
+        // P 的最高位是 1，2^768 mod P 就是 2^768 - P
+        let mut expected = [0u8; KEY_LEN];
+        let mut borrow = 0u16;
+        for i in (0..KEY_LEN).rev() {
+            let d = 0u16.wrapping_sub(u16::from(PRIME[i])).wrapping_sub(borrow);
+            expected[i] = d as u8;
+            borrow = u16::from(PRIME[i] != 0 || borrow != 0);
+        }
+        assert_eq!(to_bytes(&modulus.pow(&two, &768u16.to_be_bytes())), expected);
+        assert_eq!(to_bytes(&modulus.pow(&two, &[100])), to_bytes(&from_bytes(&[&[16u8][..], &[0u8; 12]].concat())));
+
+        // 费马小定理：a^(P-1) = 1 mod P
+        let mut p_minus_one = PRIME;
+        p_minus_one[KEY_LEN - 1] -= 1;
+        for base in [2u64, 3, 0xffff_ffff_ffff] {
+            assert_eq!(modulus.pow(&num(base), &p_minus_one), num(1));
+        }
+    }
+
+    #[test]
+    fn both_sides_compute_the_same_secret() {
+        let a = KeyPair::generate();
+        let b = KeyPair::generate();
+        assert_ne!(a.public, b.public);
+        let secret = a.shared_secret(&b.public);
+        assert_eq!(secret, b.shared_secret(&a.public));
+        assert_ne!(secret, [0u8; KEY_LEN]);
+        assert_ne!(secret, a.public);
+    }
+}
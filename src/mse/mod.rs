@@ -0,0 +1,3 @@
+pub mod dh;
+pub mod mse;
+pub mod rc4;
@@ -0,0 +1,389 @@
+use std::io::{self, Read, Write};
+
+use rand::Rng;
+use tracing::{debug, trace};
+
+use crate::{
+    error::error::{Error, Result},
+    mse::{dh::{KeyPair, KEY_LEN}, rc4::Rc4},
+};
+
+const VC: [u8; 8] = [0u8; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+const MAX_PAD: usize = 512;
+const PROTOCOL_PREFIX: &[u8] = b"\x13BitTorrent protocol";
+
+// EncryptionPolicy decides whether peer connections use MSE/PE
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EncryptionPolicy {
+    // plain BitTorrent only, encrypted peers are refused
+    Disabled,
+    // outbound connections try MSE first and fall back to plain, inbound accepts both
+    #[default]
+    Enabled,
+    // RC4 only, plain peers are refused
+    Forced,
+}
+
+// MseStream is a peer connection that is either plain or RC4 encrypted after an MSE handshake.
+// It also replays bytes that were read before the stream was handed over.
+pub struct MseStream<S> {
+    inner: S,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    prefix: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    pub fn plain(inner: S) -> Self {
+        Self { inner, encrypt: None, decrypt: None, prefix: vec![] }
+    }
+
+    // with_prefix makes the next reads return prefix before anything from inner
+    fn with_prefix(mut self, prefix: Vec<u8>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: std::fmt::Debug> std::fmt::Debug for MseStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MseStream").field("inner", &self.inner).field("encrypted", &self.is_encrypted()).finish()
+    }
+}
+
+impl<S: Read> Read for MseStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.prefix.is_empty() {
+            let n = buf.len().min(self.prefix.len());
+            buf[..n].copy_from_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Ok(n);
+        }
+        let n = self.inner.read(buf)?;
+        if let Some(rc4) = &mut self.decrypt {
+            rc4.apply(&mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for MseStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encrypt {
+            Some(rc4) => {
+                // RC4 的状态已经前进，必须整块写出
+                let mut data = buf.to_vec();
+                rc4.apply(&mut data);
+                self.inner.write_all(&data)?;
+                Ok(buf.len())
+            },
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut sha = sha1::Sha1::new();
+    for part in parts {
+        sha.update(part);
+    }
+    sha.digest().bytes()
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn read_array<const N: usize, R: Read>(conn: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    conn.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_decrypted<R: Read>(conn: &mut R, rc4: &mut Rc4, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    conn.read_exact(&mut buf)?;
+    rc4.apply(&mut buf);
+    Ok(buf)
+}
+
+fn read_u16<R: Read>(conn: &mut R, rc4: &mut Rc4) -> Result<usize> {
+    let buf = read_decrypted(conn, rc4, 2)?;
+    Ok(usize::from(u16::from_be_bytes([buf[0], buf[1]])))
+}
+
+fn read_u32<R: Read>(conn: &mut R, rc4: &mut Rc4) -> Result<u32> {
+    let buf = read_decrypted(conn, rc4, 4)?;
+    Ok(u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]))
+}
+
+// synchronize reads until pattern shows up, at most max_skip bytes may come before it
+fn synchronize<R: Read>(conn: &mut R, pattern: &[u8], max_skip: usize) -> Result<()> {
+    let mut window = Vec::with_capacity(max_skip + pattern.len());
+    while window.len() < max_skip + pattern.len() {
+        window.push(read_array::<1, _>(conn)?[0]);
+        if window.ends_with(pattern) {
+            trace!(skipped = window.len() - pattern.len(), "mse synchronized");
+            return Ok(());
+        }
+    }
+    Err(Error::handshake("mse: no synchronization pattern"))
+}
+
+fn keys(secret: &[u8], skey: &[u8; 20], initiator: bool) -> (Rc4, Rc4) {
+    let key_a = Rc4::new(&hash(&[b"keyA", secret, skey]));
+    let key_b = Rc4::new(&hash(&[b"keyB", secret, skey]));
+    if initiator { (key_a, key_b) } else { (key_b, key_a) }
+}
+
+// is_plain_handshake tells whether the first bytes of a connection are a plain BitTorrent handshake
+pub fn is_plain_handshake(first: &[u8]) -> bool {
+    first.starts_with(PROTOCOL_PREFIX)
+}
+
+// initiate runs the MSE handshake as the connecting side, skey is the info hash we want
+pub fn initiate<S: Read + Write>(mut conn: S, skey: &[u8; 20], policy: EncryptionPolicy) -> Result<MseStream<S>> {
+    if policy == EncryptionPolicy::Disabled {
+        return Ok(MseStream::plain(conn));
+    }
+    let keys_ours = KeyPair::generate();
+    conn.write_all(&[&keys_ours.public[..], &random_pad()].concat())?;
+    let remote = read_array::<KEY_LEN, _>(&mut conn)?;
+    let secret = keys_ours.shared_secret(&remote);
+    let (mut encrypt, mut decrypt) = keys(&secret, skey, true);
+
+    let provide = match policy {
+        EncryptionPolicy::Forced => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+    let req2 = hash(&[b"req2", skey]);
+    let req3 = hash(&[b"req3", &secret]);
+    let obfuscated = req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b).collect::<Vec<_>>();
+    // VC, crypto_provide, len(PadC) = 0, len(IA) = 0
+    let mut payload = [&VC[..], &provide.to_be_bytes(), &[0, 0], &[0, 0]].concat();
+    encrypt.apply(&mut payload);
+    conn.write_all(&[&hash(&[b"req1", &secret])[..], &obfuscated, &payload].concat())?;
+
+    // 对方的 VC 在 PadB 之后，先算出它加密后的样子再同步
+    let mut vc = VC;
+    decrypt.clone().apply(&mut vc);
+    synchronize(&mut conn, &vc, MAX_PAD)?;
+    decrypt.apply(&mut [0u8; 8]);
+    let select = read_u32(&mut conn, &mut decrypt)?;
+    let pad_len = read_u16(&mut conn, &mut decrypt)?;
+    if pad_len > MAX_PAD {
+        return Err(Error::handshake(format!("mse: padding of {} bytes", pad_len)));
+    }
+    read_decrypted(&mut conn, &mut decrypt, pad_len)?;
+
+    match select {
+        CRYPTO_RC4 => {
+            debug!("mse: rc4 selected");
+            Ok(MseStream { inner: conn, encrypt: Some(encrypt), decrypt: Some(decrypt), prefix: vec![] })
+        },
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => {
+            debug!("mse: plaintext selected");
+            Ok(MseStream::plain(conn))
+        },
+        _ => Err(Error::handshake(format!("mse: peer selected crypto {:#x}", select))),
+    }
+}
+
+// accept answers an incoming connection. first holds the bytes the caller already read, a plain
+// handshake is passed through. skeys are the info hashes we serve, the matching one is returned
+// for encrypted connections.
+pub fn accept<S: Read + Write>(mut conn: S, first: Vec<u8>, skeys: &[[u8; 20]], policy: EncryptionPolicy) -> Result<(MseStream<S>, Option<[u8; 20]>)> {
+    if is_plain_handshake(&first) {
+        if policy == EncryptionPolicy::Forced {
+            return Err(Error::handshake("plain connections are not allowed"));
+        }
+        return Ok((MseStream::plain(conn).with_prefix(first), None));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(Error::handshake("encrypted connections are not allowed"));
+    }
+    if first.len() > KEY_LEN {
+        return Err(Error::handshake("mse: too much data before the key exchange"));
+    }
+    let mut remote = [0u8; KEY_LEN];
+    remote[..first.len()].copy_from_slice(&first);
+    conn.read_exact(&mut remote[first.len()..])?;
+
+    let keys_ours = KeyPair::generate();
+    conn.write_all(&[&keys_ours.public[..], &random_pad()].concat())?;
+    let secret = keys_ours.shared_secret(&remote);
+
+    synchronize(&mut conn, &hash(&[b"req1", &secret]), MAX_PAD)?;
+    let obfuscated = read_array::<20, _>(&mut conn)?;
+    let req3 = hash(&[b"req3", &secret]);
+    let skey = skeys
+        .iter()
+        .find(|skey| {
+            let req2 = hash(&[b"req2", &skey[..]]);
+            req2.iter().zip(req3.iter()).zip(obfuscated.iter()).all(|((a, b), c)| a ^ b == *c)
+        })
+        .copied()
+        .ok_or_else(|| Error::handshake("mse: unknown info hash"))?;
+    let (mut encrypt, mut decrypt) = keys(&secret, &skey, false);
+
+    if read_decrypted(&mut conn, &mut decrypt, VC.len())? != VC {
+        return Err(Error::handshake("mse: bad verification constant"));
+    }
+    let provide = read_u32(&mut conn, &mut decrypt)?;
+    let pad_len = read_u16(&mut conn, &mut decrypt)?;
+    if pad_len > MAX_PAD {
+        return Err(Error::handshake(format!("mse: padding of {} bytes", pad_len)));
+    }
+    read_decrypted(&mut conn, &mut decrypt, pad_len)?;
+    let ia_len = read_u16(&mut conn, &mut decrypt)?;
+    let initial = read_decrypted(&mut conn, &mut decrypt, ia_len)?;
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Forced {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(Error::handshake(format!("mse: no common crypto in {:#x}", provide)));
+    };
+    let mut payload = [&VC[..], &select.to_be_bytes(), &[0, 0]].concat();
+    encrypt.apply(&mut payload);
+    conn.write_all(&payload)?;
+
+    debug!(rc4 = select == CRYPTO_RC4, "mse: accepted");
+    let stream = match select {
+        CRYPTO_RC4 => MseStream { inner: conn, encrypt: Some(encrypt), decrypt: Some(decrypt), prefix: initial },
+        _ => MseStream::plain(conn).with_prefix(initial),
+    };
+    Ok((stream, Some(skey)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+    use crate::{client::client::CustomClient, handshake::handshake, peers::peers::Peer};
+
+    const SKEY: [u8; 20] = [0x5a; 20];
+
+    // serve accepts connections one after another and answers each like the session does, first
+    // reading 20 bytes and handing them to accept. The result of accept is sent back for each
+    fn serve(policies: Vec<EncryptionPolicy>) -> (SocketAddr, mpsc::Receiver<Result<MseStream<TcpStream>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for policy in policies {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut first = vec![0u8; 20];
+                conn.read_exact(&mut first).unwrap();
+                let res = accept(conn, first, &[[1u8; 20], SKEY], policy).map(|(stream, skey)| {
+                    assert!(skey.is_none() || skey == Some(SKEY));
+                    stream
+                });
+                tx.send(res).unwrap();
+            }
+        });
+        (addr, rx)
+    }
+
+    // echo sends a message each way over a finished handshake
+    fn echo(ours: &mut MseStream<TcpStream>, theirs: &mut MseStream<TcpStream>) {
+        ours.write_all(b"ping from the initiator").unwrap();
+        let mut buf = [0u8; 23];
+        theirs.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping from the initiator");
+        theirs.write_all(b"pong").unwrap();
+        let mut buf = [0u8; 4];
+        ours.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn rc4_round_trip() {
+        let (addr, accepted) = serve(vec![EncryptionPolicy::Enabled, EncryptionPolicy::Forced]);
+        for policy in [EncryptionPolicy::Enabled, EncryptionPolicy::Forced] {
+            let mut ours = initiate(TcpStream::connect(addr).unwrap(), &SKEY, policy).unwrap();
+            let mut theirs = accepted.recv().unwrap().unwrap();
+            assert!(ours.is_encrypted() && theirs.is_encrypted());
+            echo(&mut ours, &mut theirs);
+        }
+    }
+
+    #[test]
+    fn plaintext_round_trip() {
+        let (addr, accepted) = serve(vec![EncryptionPolicy::Enabled, EncryptionPolicy::Disabled]);
+        for _ in 0..2 {
+            let mut ours = initiate(TcpStream::connect(addr).unwrap(), &SKEY, EncryptionPolicy::Disabled).unwrap();
+            let req = handshake::Handshake::new(&SKEY, &[3u8; 20]);
+            ours.write_all(&req.serialize()).unwrap();
+            let mut theirs = accepted.recv().unwrap().unwrap();
+            assert!(!ours.is_encrypted() && !theirs.is_encrypted());
+            // 已经读走的 20 字节会被重放，握手完整
+            assert_eq!(handshake::read(&mut theirs).unwrap(), req);
+            echo(&mut ours, &mut theirs);
+        }
+    }
+
+    #[test]
+    fn policies_refuse_the_other_kind() {
+        let (addr, accepted) = serve(vec![EncryptionPolicy::Forced, EncryptionPolicy::Disabled]);
+        // Forced 拒绝明文的 peer
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain.write_all(&handshake::Handshake::new(&SKEY, &[3u8; 20]).serialize()).unwrap();
+        assert!(accepted.recv().unwrap().is_err());
+        // Disabled 拒绝加密的 peer，发起方的握手也失败
+        assert!(initiate(TcpStream::connect(addr).unwrap(), &SKEY, EncryptionPolicy::Enabled).is_err());
+        assert!(accepted.recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn unknown_info_hash_is_refused() {
+        let (addr, accepted) = serve(vec![EncryptionPolicy::Enabled]);
+        assert!(initiate(TcpStream::connect(addr).unwrap(), &[9u8; 20], EncryptionPolicy::Enabled).is_err());
+        assert!(accepted.recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn enabled_falls_back_to_plaintext() {
+        // 只接受明文的 peer：第一次加密连接被拒绝，客户端重新用明文连接
+        let (addr, accepted) = serve(vec![EncryptionPolicy::Disabled, EncryptionPolicy::Disabled]);
+        let peer = Peer::new(addr);
+        let server = thread::spawn(move || {
+            assert!(accepted.recv().unwrap().is_err());
+            let mut theirs = accepted.recv().unwrap().unwrap();
+            let req = handshake::read(&mut theirs).unwrap();
+            theirs.write_all(&handshake::Handshake::new(&req.info_hash, &[8u8; 20]).serialize()).unwrap();
+            theirs
+        });
+        let client = CustomClient::new(&peer, [3u8; 20], &SKEY, [0u8; 8], EncryptionPolicy::Enabled, None, vec![]).unwrap();
+        assert_eq!(client.remote.peer_id, [8u8; 20]);
+        assert!(!server.join().unwrap().is_encrypted());
+
+        // Forced 不会退回明文
+        let (addr, accepted) = serve(vec![EncryptionPolicy::Disabled]);
+        let peer = Peer::new(addr);
+        assert!(CustomClient::new(&peer, [3u8; 20], &SKEY, [0u8; 8], EncryptionPolicy::Forced, None, vec![]).is_err());
+        assert!(accepted.recv().unwrap().is_err());
+    }
+}
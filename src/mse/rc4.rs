@@ -0,0 +1,73 @@
+// Rc4 is the stream cipher used by MSE, the first 1024 bytes of keystream are thrown away
+#[derive(Clone)]
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+// MSE 规定丢弃的密钥流长度
+pub const DISCARD: usize = 1024;
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::keyed(key);
+        rc4.apply(&mut [0u8; DISCARD]);
+        rc4
+    }
+
+    // keyed runs the key schedule, the keystream starts at its first byte
+    fn keyed(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, v) in s.iter_mut().enumerate() {
+            *v = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    // apply encrypts or decrypts buf in place
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        for b in buf.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *b ^= k;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_keystreams() {
+        for (key, plain, cipher) in [
+            ("Key", "Plaintext", "bbf316e8d940af0ad3"),
+            ("Wiki", "pedia", "1021bf0420"),
+            ("Secret", "Attack at dawn", "45a01f645fc35b383552544b9bf5"),
+        ] {
+            let mut buf = plain.as_bytes().to_vec();
+            Rc4::keyed(key.as_bytes()).apply(&mut buf);
+            assert_eq!(hex::encode(&buf), cipher);
+            Rc4::keyed(key.as_bytes()).apply(&mut buf);
+            assert_eq!(buf, plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn new_drops_the_first_kilobyte() {
+        let mut raw = Rc4::keyed(b"Key");
+        raw.apply(&mut [0u8; DISCARD]);
+        let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+        raw.apply(&mut a);
+        Rc4::new(b"Key").apply(&mut b);
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, trace};
+
+use crate::{
+    ipfilter::ipfilter::IpFilter,
+    p2p::p2p::{ConnectionLimit, ConnectionSlot},
+    peers::peers::{Peer, PeerSource},
+};
+
+// 第一次失败后的重试间隔，之后每次翻倍
+const RETRY_BASE: Duration = Duration::from_secs(2);
+const RETRY_MAX: Duration = Duration::from_secs(5 * 60);
+// 连续失败这么多次后放弃这个 peer
+const MAX_FAILURES: u32 = 4;
+
+// crc32c is CRC-32 with the Castagnoli polynomial, the checksum BEP 40 is defined with
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// masked_pair applies the BEP 40 mask: the first keep bytes stay, one more for every prefix length
+// in close both addresses share, the rest is ANDed with 0x55
+fn masked_pair(a: &[u8], b: &[u8], keep: usize, close: &[usize]) -> (Vec<u8>, Vec<u8>) {
+    let shared = close.iter().filter(|n| a[..**n] == b[..**n]).count();
+    let keep = keep + shared;
+    let mask = |ip: &[u8]| ip.iter().enumerate().map(|(i, byte)| if i < keep { *byte } else { byte & 0x55 }).collect::<Vec<_>>();
+    (mask(a), mask(b))
+}
+
+// canonical_priority ranks a connection between two endpoints the same way on both ends, BEP 40.
+// Higher is preferred, peers far from us in address space win so the swarm doesn't cluster
+pub fn canonical_priority(ours: SocketAddr, theirs: SocketAddr) -> u32 {
+    if ours.ip() == theirs.ip() {
+        let (lo, hi) = (ours.port().min(theirs.port()), ours.port().max(theirs.port()));
+        let mut buf = lo.to_be_bytes().to_vec();
+        buf.extend_from_slice(&hi.to_be_bytes());
+        return crc32c(&buf);
+    }
+    let (a, b) = match (ours.ip(), theirs.ip()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => masked_pair(&a.octets(), &b.octets(), 2, &[2, 3]),
+        (a, b) => {
+            let (a, b) = (to_v6(a).octets(), to_v6(b).octets());
+            masked_pair(&a, &b, 6, &[6, 7])
+        },
+    };
+    let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+    crc32c(&[lo, hi].concat())
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+// Candidate is a peer we may connect to, with where we heard of it and how trying it went so far
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub peer: Peer,
+    // the swarm hash to greet the peer with
+    pub info_hash: [u8; 20],
+    pub source: PeerSource,
+    pub failures: u32,
+    next_attempt: Instant,
+    connected: bool,
+    priority: u32,
+}
+
+impl Candidate {
+    fn ready(&self, now: Instant) -> bool {
+        !self.connected && self.next_attempt <= now
+    }
+}
+
+// Next is what a download worker should do after asking the manager for a peer
+#[derive(Debug)]
+pub enum Next {
+    // connect to the candidate, the slots are held for the lifetime of the connection
+    Connect(Candidate, [ConnectionSlot; 2]),
+    // peers are waiting for their retry time or a connection slot
+    Wait,
+    // every candidate is connected or was given up on
+    Exhausted,
+}
+
+// ConnectionManager keeps the candidate peers of one torrent. It hands out the best peer that is
+// due, within the global and the per-torrent connection limit, and backs off peers that failed
+#[derive(Debug)]
+pub struct ConnectionManager {
+    candidates: Mutex<Vec<Candidate>>,
+    // our external address, BEP 40 priorities are computed against it
+    own_ip: IpAddr,
+    global: Arc<ConnectionLimit>,
+    torrent: Arc<ConnectionLimit>,
+    // blocked and banned peers never become candidates
+    filter: Arc<IpFilter>,
+}
+
+impl ConnectionManager {
+    pub fn new(global: Arc<ConnectionLimit>, max_peers: usize) -> Self {
+        Self {
+            candidates: Mutex::new(vec![]),
+            own_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            global,
+            torrent: ConnectionLimit::new(max_peers.max(1)),
+            filter: Arc::new(IpFilter::default()),
+        }
+    }
+
+    pub fn set_limits(&mut self, global: Arc<ConnectionLimit>, max_peers: usize) {
+        self.global = global;
+        self.torrent.set_max(max_peers.max(1));
+    }
+
+    pub fn set_ip_filter(&mut self, filter: Arc<IpFilter>) {
+        self.filter = filter;
+    }
+
+    fn allows(&self, peer: &Peer, source: PeerSource) -> bool {
+        self.filter.allows(peer.general_address().ip(), source)
+    }
+
+    // ban keeps ip out for the rest of the session, false when it was banned already
+    pub fn ban(&self, ip: IpAddr) -> bool {
+        self.filter.ban(ip)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.filter.is_banned(ip)
+    }
+
+    // set_own_ip sets the address peers see us at, until then priorities use the unspecified address
+    pub fn set_own_ip(&mut self, ip: IpAddr) {
+        self.own_ip = ip;
+        for c in self.candidates.get_mut().unwrap() {
+            c.priority = canonical_priority(SocketAddr::new(ip, 0), c.peer.general_address());
+        }
+    }
+
+    pub fn max_peers(&self) -> usize {
+        self.torrent.max()
+    }
+
+    // connections is the number of open connections of this torrent
+    pub fn connections(&self) -> usize {
+        self.torrent.current()
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // add puts a peer into the pool, false when it's already known or blocked by the ip filter
+    pub fn add(&self, peer: Peer, info_hash: [u8; 20], source: PeerSource) -> bool {
+        let mut candidates = self.candidates.lock().unwrap();
+        if !self.allows(&peer, source) {
+            return false;
+        }
+        if let Some(c) = candidates.iter_mut().find(|c| c.peer == peer) {
+            // 对方先连上了我们，现在知道这个地址是它监听的端口，断开后可以主动重连
+            if c.source != PeerSource::Incoming {
+                return false;
+            }
+            c.source = source;
+            return true;
+        }
+        let priority = canonical_priority(SocketAddr::new(self.own_ip, 0), peer.general_address());
+        candidates.push(Candidate {
+            peer,
+            info_hash,
+            source,
+            failures: 0,
+            next_attempt: Instant::now(),
+            connected: false,
+            priority,
+        });
+        true
+    }
+
+    // next picks the due candidate with the highest priority, fewer failures break ties
+    pub fn next(&self) -> Next {
+        let mut candidates = self.candidates.lock().unwrap();
+        // 过滤规则可能在运行中重新加载过
+        candidates.retain(|c| c.connected || self.allows(&c.peer, c.source));
+        let now = Instant::now();
+        let best = candidates
+            .iter_mut()
+            .filter(|c| c.ready(now))
+            .max_by_key(|c| (c.priority, std::cmp::Reverse(c.failures)));
+        let Some(best) = best else {
+            return if candidates.iter().any(|c| !c.connected) { Next::Wait } else { Next::Exhausted };
+        };
+        let Some(slots) = self.torrent.try_acquire().zip(self.global.try_acquire()) else {
+            return Next::Wait;
+        };
+        best.connected = true;
+        trace!(addr = %best.peer.general_address(), source = ?best.source, priority = best.priority, "picked peer");
+        Next::Connect(best.clone(), [slots.0, slots.1])
+    }
+
+    // accept_incoming admits a peer that connected to us, None when the torrent is full or we
+    // already have a connection to the same address
+    pub fn accept_incoming(&self, addr: SocketAddr, info_hash: [u8; 20]) -> Option<ConnectionSlot> {
+        let mut candidates = self.candidates.lock().unwrap();
+        let peer = Peer::new(addr);
+        let existing = candidates.iter().position(|c| c.peer == peer);
+        if existing.is_some_and(|pos| candidates[pos].connected) {
+            debug!(%addr, "refusing second connection to peer");
+            return None;
+        }
+        let slot = self.torrent.try_acquire()?;
+        match existing {
+            Some(pos) => candidates[pos].connected = true,
+            None => candidates.push(Candidate {
+                priority: canonical_priority(SocketAddr::new(self.own_ip, 0), addr),
+                peer,
+                info_hash,
+                source: PeerSource::Incoming,
+                failures: 0,
+                next_attempt: Instant::now(),
+                connected: true,
+            }),
+        }
+        Some(slot)
+    }
+
+    // disconnected schedules the next attempt for a peer whose connection ended. Peers that sent us
+    // data are retried soon, the others back off exponentially until they are given up on. Peers
+    // that connected to us aren't retried, their port is not the one they listen on
+    pub fn disconnected(&self, addr: SocketAddr, useful: bool) {
+        let mut candidates = self.candidates.lock().unwrap();
+        let Some(pos) = candidates.iter().position(|c| c.peer.general_address() == addr) else {
+            return;
+        };
+        let c = &mut candidates[pos];
+        c.connected = false;
+        if c.source == PeerSource::Incoming {
+            candidates.remove(pos);
+            return;
+        }
+        if useful {
+            c.failures = 0;
+        } else {
+            c.failures += 1;
+        }
+        if c.failures >= MAX_FAILURES {
+            debug!(%addr, failures = c.failures, "giving up on peer");
+            candidates.remove(pos);
+            return;
+        }
+        let backoff = RETRY_BASE.saturating_mul(1 << c.failures.saturating_sub(1)).min(RETRY_MAX);
+        c.next_attempt = Instant::now() + backoff;
+        trace!(%addr, failures = c.failures, ?backoff, "peer will be retried");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn bep40_examples() {
+        assert_eq!(canonical_priority(addr("123.213.32.10:6881"), addr("98.76.54.32:6881")), 0xec2d_7224);
+        assert_eq!(canonical_priority(addr("123.213.32.10:6881"), addr("123.213.32.234:6881")), 0x9956_8189);
+    }
+
+    #[test]
+    fn priority_is_the_same_on_both_ends() {
+        let pairs = [
+            ("123.213.32.10:1", "98.76.54.32:2"),
+            ("10.0.1.2:1", "10.0.200.3:2"),
+            ("1.2.3.4:6881", "1.2.3.4:6882"),
+            ("[2001:db8::1]:1", "[2001:db8:1::2]:2"),
+            ("1.2.3.4:1", "[2001:db8::1]:2"),
+        ];
+        for (a, b) in pairs {
+            assert_eq!(canonical_priority(addr(a), addr(b)), canonical_priority(addr(b), addr(a)), "{} {}", a, b);
+        }
+        // 同一个地址时只看端口
+        let same = canonical_priority(addr("1.2.3.4:6881"), addr("1.2.3.4:6882"));
+        assert_eq!(same, crc32c(&[0x1a, 0xe1, 0x1a, 0xe2]));
+        // 同一个 /24 里最后一个字节不再被掩码
+        assert_ne!(canonical_priority(addr("9.9.9.1:1"), addr("9.9.9.2:1")), canonical_priority(addr("9.9.9.1:1"), addr("9.9.9.3:1")));
+    }
+
+    #[test]
+    fn hands_out_the_highest_priority_first() {
+        let mut manager = ConnectionManager::new(ConnectionLimit::unlimited(), 10);
+        manager.set_own_ip("123.213.32.10".parse().unwrap());
+        let near = Peer::new(addr("123.213.32.234:6881"));
+        let far = Peer::new(addr("98.76.54.32:6881"));
+        assert!(manager.add(near.clone(), [0; 20], PeerSource::Tracker));
+        assert!(manager.add(far.clone(), [0; 20], PeerSource::Tracker));
+        assert!(!manager.add(far.clone(), [0; 20], PeerSource::Dht));
+
+        // 0xec2d7224 比 0x99568189 大
+        let Next::Connect(first, _first_slots) = manager.next() else { panic!("no candidate") };
+        assert_eq!(first.peer, far);
+        let Next::Connect(second, second_slots) = manager.next() else { panic!("no candidate") };
+        assert_eq!(second.peer, near);
+        assert!(matches!(manager.next(), Next::Exhausted));
+
+        drop(second_slots);
+        manager.disconnected(near.general_address(), false);
+        assert!(matches!(manager.next(), Next::Wait));
+        assert_eq!(manager.connections(), 1);
+    }
+
+    #[test]
+    fn one_connection_per_address() {
+        let manager = ConnectionManager::new(ConnectionLimit::unlimited(), 10);
+        let peer = Peer::new(addr("98.76.54.32:6881"));
+
+        // 对方先连进来，tracker 之后才给出同一个地址
+        let slot = manager.accept_incoming(peer.general_address(), [0; 20]).expect("incoming refused");
+        assert!(manager.accept_incoming(peer.general_address(), [0; 20]).is_none());
+        assert!(manager.add(peer.clone(), [0; 20], PeerSource::Tracker));
+        assert!(!manager.add(peer.clone(), [0; 20], PeerSource::Dht));
+        assert!(matches!(manager.next(), Next::Exhausted));
+        assert_eq!(manager.connections(), 1);
+
+        // 连进来的连接断开后这个地址留作主动连接的候选
+        drop(slot);
+        manager.disconnected(peer.general_address(), true);
+        assert!(matches!(manager.next(), Next::Wait));
+        assert_eq!(manager.connections(), 0);
+
+        // 主动连上的地址不再接受连进来的连接
+        let other = Peer::new(addr("123.213.32.234:6881"));
+        assert!(manager.add(other.clone(), [0; 20], PeerSource::Tracker));
+        let Next::Connect(candidate, _slots) = manager.next() else { panic!("no candidate") };
+        assert_eq!(candidate.peer, other);
+        assert!(manager.accept_incoming(other.general_address(), [0; 20]).is_none());
+        assert_eq!(manager.connections(), 1);
+    }
+}
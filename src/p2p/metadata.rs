@@ -0,0 +1,424 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, info_span, trace};
+
+use crate::{
+    bencode::bencode::{self, Value},
+    client::client::CustomClient,
+    error::error::{Error, Result},
+    handshake::handshake,
+    message::message::PeerMessage,
+    mse::mse::EncryptionPolicy,
+    peers::peers::Peer,
+    torrent_file::magnet::Magnet,
+};
+
+// BEP 10: 扩展消息 id 0 是扩展握手
+pub const EXTENDED_HANDSHAKE: u8 = 0;
+// the id we ask peers to use for ut_metadata messages sent to us
+const UT_METADATA_ID: u8 = 1;
+// BEP 9: metadata 按 16 KiB 分块
+const METADATA_PIECE_SIZE: usize = 16384;
+// 比这还大的 info 字典多半是恶意的
+const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+// 一个 peer 要在这段时间内给出全部 metadata
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+// 同时向这么多 peer 请求 metadata
+const PARALLEL_FETCHES: usize = 8;
+
+fn dict<'a>(entries: Vec<(&'static str, Value<'a>)>) -> Value<'a> {
+    Value::Dict(entries.into_iter().map(|(k, v)| (Cow::Borrowed(k.as_bytes()), v)).collect())
+}
+
+// MetadataMessage is a ut_metadata message, data messages carry their piece after the dictionary
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataMessage {
+    Request { piece: usize },
+    Data { piece: usize, total_size: usize, data: Vec<u8> },
+    Reject { piece: usize },
+}
+
+impl MetadataMessage {
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            MetadataMessage::Request { piece } => dict(vec![("msg_type", Value::Int(0)), ("piece", Value::Int(*piece as i64))]).encode(),
+            MetadataMessage::Data { piece, total_size, data } => {
+                let header = dict(vec![("msg_type", Value::Int(1)), ("piece", Value::Int(*piece as i64)), ("total_size", Value::Int(*total_size as i64))]);
+                [header.encode(), data.clone()].concat()
+            },
+            MetadataMessage::Reject { piece } => dict(vec![("msg_type", Value::Int(2)), ("piece", Value::Int(*piece as i64))]).encode(),
+        }
+    }
+
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        let (header, used) = bencode::decode_prefix(payload).map_err(|e| Error::protocol(format!("ut_metadata: {}", e)))?;
+        let int = |key: &str| header.get(key).and_then(Value::as_int).and_then(|n| usize::try_from(n).ok());
+        let piece = int("piece").ok_or_else(|| Error::protocol("ut_metadata message without piece"))?;
+        match header.get("msg_type").and_then(Value::as_int) {
+            Some(0) => Ok(MetadataMessage::Request { piece }),
+            Some(1) => Ok(MetadataMessage::Data {
+                piece,
+                total_size: int("total_size").ok_or_else(|| Error::protocol("ut_metadata data without total_size"))?,
+                data: payload[used..].to_vec(),
+            }),
+            Some(2) => Ok(MetadataMessage::Reject { piece }),
+            t => Err(Error::protocol(format!("unknown ut_metadata msg_type {:?}", t))),
+        }
+    }
+}
+
+// ExtensionHandshake is the part of a BEP 10 handshake we use
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtensionHandshake {
+    // extension name to the message id the sender wants to receive it with
+    pub extensions: HashMap<String, u8>,
+    // size of the info dictionary, only sent by peers that have it
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    // ours offers ut_metadata, with the size of the info dictionary when we have it
+    pub fn ours(metadata_size: Option<usize>) -> Self {
+        Self { extensions: HashMap::from([("ut_metadata".to_string(), UT_METADATA_ID)]), metadata_size }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let m = self.extensions.iter().map(|(name, id)| (Cow::Owned(name.as_bytes().to_vec()), Value::Int(*id as i64))).collect();
+        let mut entries = vec![("m", Value::Dict(m)), ("v", Value::from(concat!("torrent_client ", env!("CARGO_PKG_VERSION"))))];
+        if let Some(size) = self.metadata_size {
+            entries.push(("metadata_size", Value::Int(size as i64)));
+        }
+        dict(entries).encode()
+    }
+
+    // parse reads the handshake, id 0 disables an extension so those are left out
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        let header = bencode::decode(payload).map_err(|e| Error::protocol(format!("extension handshake: {}", e)))?;
+        let extensions = header
+            .get("m")
+            .and_then(Value::as_dict)
+            .map(|m| {
+                m.iter()
+                    .filter_map(|(name, id)| Some((String::from_utf8(name.to_vec()).ok()?, u8::try_from(id.as_int()?).ok().filter(|id| *id != 0)?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let metadata_size = header.get("metadata_size").and_then(Value::as_int).and_then(|n| usize::try_from(n).ok());
+        Ok(Self { extensions, metadata_size })
+    }
+}
+
+// answer_request cuts the requested piece out of our info dictionary
+fn answer_request(raw_info: &[u8], piece: usize) -> MetadataMessage {
+    let begin = piece * METADATA_PIECE_SIZE;
+    if begin >= raw_info.len() {
+        return MetadataMessage::Reject { piece };
+    }
+    let data = raw_info[begin..(begin + METADATA_PIECE_SIZE).min(raw_info.len())].to_vec();
+    MetadataMessage::Data { piece, total_size: raw_info.len(), data }
+}
+
+// handle_extended answers the extension messages of a peer on a download or seed connection,
+// peers that only have a magnet link fetch our info dictionary this way
+pub fn handle_extended(c: &CustomClient, raw_info: &[u8], id: u8, payload: &[u8]) -> Result<()> {
+    match id {
+        EXTENDED_HANDSHAKE => c.set_extensions(ExtensionHandshake::parse(payload)?.extensions),
+        UT_METADATA_ID => {
+            if let (MetadataMessage::Request { piece }, Some(their_id)) = (MetadataMessage::parse(payload)?, c.extension_id("ut_metadata")) {
+                trace!(piece, "上传 metadata");
+                c.send_extended(their_id, answer_request(raw_info, piece).serialize())?;
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+// Metadata collects the pieces of an info dictionary of a known size
+#[derive(Debug)]
+pub struct Metadata {
+    buf: Vec<u8>,
+    received: Vec<bool>,
+}
+
+impl Metadata {
+    pub fn new(size: usize) -> Result<Self> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(Error::protocol(format!("metadata size {} out of range", size)));
+        }
+        Ok(Self { buf: vec![0; size], received: vec![false; size.div_ceil(METADATA_PIECE_SIZE)] })
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.received.len()
+    }
+
+    // Add stores a piece, every piece but the last one is exactly 16 KiB
+    pub fn add(&mut self, piece: usize, total_size: usize, data: &[u8]) -> Result<()> {
+        if total_size != self.buf.len() || piece >= self.num_pieces() {
+            return Err(Error::protocol(format!("metadata piece {} of {} bytes doesn't fit", piece, total_size)));
+        }
+        let begin = piece * METADATA_PIECE_SIZE;
+        let end = (begin + METADATA_PIECE_SIZE).min(self.buf.len());
+        if data.len() != end - begin {
+            return Err(Error::protocol(format!("metadata piece {} has {} bytes, expected {}", piece, data.len(), end - begin)));
+        }
+        self.buf[begin..end].copy_from_slice(data);
+        self.received[piece] = true;
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().all(|r| *r)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+// fetch_from_peer runs the extension handshake with one peer and requests every metadata piece
+fn fetch_from_peer(magnet: &Magnet, peer: &Peer, peer_id: [u8; 20], encryption: EncryptionPolicy, found: &AtomicBool) -> Result<Vec<u8>> {
+    let info_hash = magnet.swarm_hash();
+    let c = CustomClient::new(peer, peer_id, &info_hash, handshake::reserved_bits(false, false), encryption, None, vec![])?;
+    if !c.remote.supports_extensions() {
+        return Err(Error::handshake("peer doesn't support the extension protocol"));
+    }
+    c.send_extended(EXTENDED_HANDSHAKE, ExtensionHandshake::ours(None).serialize())?;
+
+    let deadline = Instant::now() + FETCH_TIMEOUT;
+    let mut metadata: Option<Metadata> = None;
+    while Instant::now() < deadline && !found.load(Ordering::SeqCst) {
+        let Some(PeerMessage::Extended { id, payload }) = c.poll()? else {
+            continue;
+        };
+        match id {
+            EXTENDED_HANDSHAKE => {
+                let theirs = ExtensionHandshake::parse(&payload)?;
+                c.set_extensions(theirs.extensions);
+                let Some(their_id) = c.extension_id("ut_metadata") else {
+                    return Err(Error::protocol("peer doesn't offer ut_metadata"));
+                };
+                let m = Metadata::new(theirs.metadata_size.unwrap_or(0))?;
+                trace!(size = m.buf.len(), pieces = m.num_pieces(), "requesting metadata");
+                for piece in 0..m.num_pieces() {
+                    c.send_extended(their_id, MetadataMessage::Request { piece }.serialize())?;
+                }
+                metadata = Some(m);
+            },
+            UT_METADATA_ID => {
+                let Some(m) = metadata.as_mut() else {
+                    return Err(Error::protocol("ut_metadata message before the extension handshake"));
+                };
+                match MetadataMessage::parse(&payload)? {
+                    MetadataMessage::Data { piece, total_size, data } => m.add(piece, total_size, &data)?,
+                    MetadataMessage::Reject { piece } => return Err(Error::protocol(format!("peer rejected metadata piece {}", piece))),
+                    // 我们自己也没有 metadata，对方的请求不用回答
+                    MetadataMessage::Request { .. } => {},
+                }
+                if m.is_complete() {
+                    let raw_info = metadata.take().unwrap().into_bytes();
+                    if !magnet.matches(&raw_info) {
+                        return Err(Error::protocol("metadata hash mismatch"));
+                    }
+                    return Ok(raw_info);
+                }
+            },
+            _ => {},
+        }
+    }
+    Err(Error::protocol("metadata exchange timed out"))
+}
+
+// fetch_metadata downloads the info dictionary a magnet link names from peers (BEP 9), a few
+// peers are asked at a time and the first verified copy wins
+pub fn fetch_metadata(magnet: &Magnet, peers: &[Peer], peer_id: [u8; 20], encryption: EncryptionPolicy) -> Result<Vec<u8>> {
+    let _span = info_span!("metadata", info_hash = %hex::encode(magnet.swarm_hash())).entered();
+    let found = AtomicBool::new(false);
+    let mut last = Error::protocol("no peers to fetch the metadata from");
+    for batch in peers.chunks(PARALLEL_FETCHES) {
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|s| {
+            for peer in batch {
+                let (tx, found) = (tx.clone(), &found);
+                s.spawn(move || {
+                    let res = fetch_from_peer(magnet, peer, peer_id, encryption, found);
+                    if res.is_ok() {
+                        found.store(true, Ordering::SeqCst);
+                    }
+                    let _ = tx.send((peer.general_address(), res));
+                });
+            }
+        });
+        drop(tx);
+        for (addr, res) in rx {
+            match res {
+                Ok(raw_info) => {
+                    info!(%addr, bytes = raw_info.len(), "metadata received");
+                    return Ok(raw_info);
+                },
+                Err(e) => {
+                    debug!(%addr, error = %e, "metadata fetch failed");
+                    last = e;
+                },
+            }
+        }
+    }
+    Err(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Write,
+        net::{SocketAddr, TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        message::message,
+        session::session::{Session, SessionSettings, TorrentState},
+        storage::recheck,
+        torrent_file::{creator::{self, CreateOptions}, torrent_file::CustomTorrent},
+    };
+
+    fn info_dict() -> Vec<u8> {
+        // 超过两个 16 KiB 的块，最后一块不满
+        let pieces = vec![0xabu8; 40000];
+        let mut info = b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces40000:".to_vec();
+        info.extend_from_slice(&pieces);
+        info.push(b'e');
+        info
+    }
+
+    fn magnet_for(info: &[u8]) -> Magnet {
+        let hash = sha1::Sha1::from(info).digest().bytes();
+        Magnet::parse(&format!("magnet:?xt=urn:btih:{}", hex::encode(hash))).unwrap()
+    }
+
+    fn send(stream: &mut TcpStream, id: u8, payload: Vec<u8>) {
+        stream.write_all(&PeerMessage::Extended { id, payload }.serialize()).unwrap();
+    }
+
+    // fake_peer serves info over ut_metadata, answer decides what to send for each request
+    fn fake_peer(info: Vec<u8>, answer: fn(usize, &[u8]) -> MetadataMessage) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let theirs = handshake::read(&mut stream).unwrap();
+            assert!(theirs.supports_extensions());
+            let ours = handshake::Handshake::new(&theirs.info_hash, &[9u8; 20]).with_reserved(handshake::reserved_bits(false, false));
+            stream.write_all(&ours.serialize()).unwrap();
+            stream.write_all(&PeerMessage::HaveNone.serialize()).unwrap();
+            while let Ok(msg) = message::read(&mut stream) {
+                let PeerMessage::Extended { id, payload } = msg else { continue };
+                if id == EXTENDED_HANDSHAKE {
+                    assert_eq!(ExtensionHandshake::parse(&payload).unwrap(), ExtensionHandshake::ours(None));
+                    let reply = ExtensionHandshake { extensions: HashMap::from([("ut_metadata".to_string(), 3)]), metadata_size: Some(info.len()) };
+                    send(&mut stream, EXTENDED_HANDSHAKE, reply.serialize());
+                } else if id == 3 {
+                    let MetadataMessage::Request { piece } = MetadataMessage::parse(&payload).unwrap() else { panic!("expected a request") };
+                    send(&mut stream, UT_METADATA_ID, answer(piece, &info).serialize());
+                }
+            }
+        });
+        addr
+    }
+
+    fn serve(piece: usize, info: &[u8]) -> MetadataMessage {
+        answer_request(info, piece)
+    }
+
+    fn fetch(info: Vec<u8>, answer: fn(usize, &[u8]) -> MetadataMessage) -> Result<Vec<u8>> {
+        let magnet = magnet_for(&info);
+        let addr = fake_peer(info, answer);
+        fetch_metadata(&magnet, &[Peer::new(addr)], [1u8; 20], EncryptionPolicy::Disabled)
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for msg in [
+            MetadataMessage::Request { piece: 2 },
+            MetadataMessage::Reject { piece: 0 },
+            MetadataMessage::Data { piece: 1, total_size: 20000, data: b"d1:ae".to_vec() },
+        ] {
+            assert_eq!(MetadataMessage::parse(&msg.serialize()).unwrap(), msg);
+        }
+        assert!(MetadataMessage::parse(b"d8:msg_typei7e5:piecei0ee").is_err());
+    }
+
+    #[test]
+    fn metadata_checks_piece_sizes() {
+        assert!(Metadata::new(0).is_err());
+        assert!(Metadata::new(MAX_METADATA_SIZE + 1).is_err());
+        let mut m = Metadata::new(20000).unwrap();
+        assert_eq!(m.num_pieces(), 2);
+        assert!(m.add(1, 20000, &[0; 100]).is_err());
+        assert!(m.add(2, 20000, &[0; 3616]).is_err());
+        assert!(m.add(1, 30000, &[0; 3616]).is_err());
+        m.add(1, 20000, &[0; 3616]).unwrap();
+        assert!(!m.is_complete());
+        m.add(0, 20000, &[0; METADATA_PIECE_SIZE]).unwrap();
+        assert!(m.is_complete());
+    }
+
+    #[test]
+    fn fetches_the_info_dictionary_from_a_peer() {
+        let info = info_dict();
+        assert_eq!(fetch(info.clone(), serve).unwrap(), info);
+    }
+
+    #[test]
+    fn refuses_metadata_that_doesnt_match_the_hash() {
+        let corrupt = |piece, info: &[u8]| match serve(piece, info) {
+            MetadataMessage::Data { piece, total_size, mut data } => {
+                data[0] ^= 1;
+                MetadataMessage::Data { piece, total_size, data }
+            },
+            msg => msg,
+        };
+        assert!(fetch(info_dict(), corrupt).is_err());
+    }
+
+    #[test]
+    fn a_rejected_piece_fails_the_peer() {
+        assert!(fetch(info_dict(), |piece, _| MetadataMessage::Reject { piece }).is_err());
+    }
+
+    #[test]
+    fn a_seeding_session_serves_its_info_dictionary() {
+        let dir = std::env::temp_dir().join(format!("metadata-seed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+        fs::write(&path, (0..40000u32).map(|i| i as u8).collect::<Vec<_>>()).unwrap();
+        let options = CreateOptions { piece_length: Some(16384), threads: 1, ..CreateOptions::default() };
+        let torrent = CustomTorrent::general_custom_torrent(&creator::create_torrent(&path, &options).unwrap()).unwrap();
+
+        let settings = SessionSettings { listen_port: 0, enable_dht: false, enable_utp: false, ..SessionSettings::default() };
+        let mut session = Session::new(settings).unwrap();
+        let resume = recheck::recheck(&torrent, &dir, 0);
+        let id = session.add_torrent_with_resume(torrent.clone(), &dir, &resume.bitfield);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while session.torrent_status(id).is_some_and(|s| s.state != TorrentState::Seeding) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let magnet = Magnet::parse(&torrent.magnet_link()).unwrap();
+        let seed = Peer::new(SocketAddr::from(([127, 0, 0, 1], session.listen_port())));
+        let raw_info = fetch_metadata(&magnet, &[seed], [1u8; 20], EncryptionPolicy::Disabled);
+        session.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(raw_info.unwrap(), torrent.raw_info);
+    }
+}
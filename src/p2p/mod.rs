@@ -1 +1,4 @@
 pub mod p2p;
+pub mod webseed;
+pub mod connections;
+pub mod metadata;
@@ -1,26 +1,152 @@
-use std::{cell::RefCell, io};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::{peers::peers::Peer, torrent_file::torrent_file::CustomTorrent, client::client::CustomClient, bitfield::bitfield::{has_piece, set_piece}, message};
+use tracing::{debug, info_span, trace, warn, Span};
+
+use crate::{peers::peers::{Peer, PeerSource}, torrent_file::torrent_file::CustomTorrent, client::client::CustomClient, error::error::{Error, Result}, events::events::{EventKind, EventSender}, handshake::handshake, message::message::{HashRequest, PeerMessage}, merkle::merkle::{Hash256, BLOCK_SIZE}, ratelimit::ratelimit::{RateLimits, TransferStats}, p2p::webseed::{self, WebSeed, WebSeedKind}, mse::mse::{EncryptionPolicy, MseStream}, client::transport::PeerStream, utp::utp::UtpSocket, dht::dht::DhtNode, p2p::connections::{Candidate, ConnectionManager, Next}, p2p::metadata::{self, ExtensionHandshake}, ipfilter::ipfilter::IpFilter, bitfield::bitfield};
 
 const MAX_BACK_LOG: usize = 5;
 const MAX_BLOCK_SIZE: usize = 16384;
+const DEFAULT_MAX_PEERS: usize = 50;
+// 这么久没有收到数据的连接会被断开，让出位置给其他 peer
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// 没有活跃连接时等待入站连接的时间
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// 做种时一个请求最多 128 KiB，更大的请求直接断开
+const MAX_REQUEST_SIZE: usize = 128 * 1024;
+// 做种连接上这么久没有消息就断开
+const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+const SEED_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// PieceReader reads a verified piece back from storage, uploads are cut from it
+pub type PieceReader<'a> = dyn Fn(usize) -> Result<Vec<u8>> + Sync + 'a;
+
+// ConnectionLimit caps the number of open peer connections, it is shared by every torrent of a session
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    max: AtomicUsize,
+    current: AtomicUsize,
+}
+
+// ConnectionSlot is held for the lifetime of a connection and frees its slot on drop
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<ConnectionLimit>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.current.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max: AtomicUsize::new(max),
+            current: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn unlimited() -> Arc<Self> {
+        Self::new(usize::MAX)
+    }
+
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::SeqCst);
+    }
+
+    pub fn max(&self) -> usize {
+        self.max.load(Ordering::SeqCst)
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let mut current = self.current.load(Ordering::SeqCst);
+        loop {
+            if current >= self.max() {
+                return None;
+            }
+            match self.current.compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(ConnectionSlot(self.clone())),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+// IncomingPeer is an inbound connection whose handshake was already read by the session
+#[derive(Debug)]
+pub struct IncomingPeer {
+    // plain or encrypted, see mse::accept
+    pub stream: MseStream<PeerStream>,
+    pub addr: SocketAddr,
+    // the peer's handshake, its info hash is the swarm it asked for, hybrid torrents answer to two
+    pub handshake: handshake::Handshake,
+    pub slot: ConnectionSlot,
+}
 
 #[derive(Debug)]
 pub struct P2pTorrent {
-    peers: Vec<Peer>,
-    peerId: [u8; 20],
-    infoHash: [u8; 20],
-    piece_hashes: Vec<[u8; 20]>,
+    // candidate peers with the swarm hash to greet them with, and the connection limits
+    peers: ConnectionManager,
+    // BEP 19 mirrors and BEP 17 http seeds
+    web_seeds: Vec<(String, WebSeedKind)>,
+    peer_id: [u8; 20],
+    torrent: CustomTorrent,
     piece_length: usize,
     length: usize,
-    name: String,
+    pub name: String,
+    pub limits: RateLimits,
+    stop: Arc<AtomicBool>,
+    encryption: EncryptionPolicy,
+    // outbound connections try uTP on this socket before TCP
+    utp: Option<Arc<UtpSocket>>,
+    // DHT node we announce with the port message and add peers' nodes to
+    dht: Option<Arc<DhtNode>>,
+    events: Option<EventSender>,
+    // piece layer hashes peers sent for a v2 torrent whose metainfo has none, by pieces root
+    // and piece in the file
+    layers: Mutex<HashMap<(Hash256, usize), Hash256>>,
+    failed: FailedBlocks,
 }
 
 #[derive(Debug)]
 struct PieceWork {
     index: usize,
-    hash: [u8; 20],
     length: usize,
+    // BEP 47 padding inside the piece, known to be zeros and never requested
+    pad: Vec<Range<usize>>,
+}
+
+impl PieceWork {
+    fn is_pad(&self, begin: usize, length: usize) -> bool {
+        self.pad.iter().any(|r| r.start <= begin && begin + length <= r.end)
+    }
+}
+
+// BlockSenders tells who sent each block of a piece
+type BlockSenders = Vec<(Range<usize>, SocketAddr)>;
+
+// BlockRecord is one block of a piece that failed its hash check, kept until a good copy of the
+// piece tells whether this block was the corrupt one
+#[derive(Debug)]
+struct BlockRecord {
+    range: Range<usize>,
+    peer: SocketAddr,
+    hash: [u8; 20],
 }
 
 #[derive(Debug)]
@@ -29,125 +155,702 @@ struct PieceResult {
     buffer: Vec<u8>,
 }
 
+// Role is what a peer connection is used for
+#[derive(Clone, Copy)]
+enum Role<'r> {
+    // download the pieces of SharedWork, verified pieces go to the sender
+    Download(&'r Sender<PieceResult>),
+    // answer requests of a peer that downloads from us
+    Upload(&'r PieceReader<'r>),
+}
+
+// ping_peer_node adds the DHT node a peer announced with the port message, it runs on the peer's address
+fn ping_peer_node(dht: Option<&DhtNode>, c: &CustomClient, port: u16) {
+    if let Some(dht) = dht.filter(|_| port != 0) {
+        let addr = SocketAddr::new(c.peer.general_address().ip(), port);
+        trace!(%addr, "peer announced a DHT node");
+        dht.ping(addr);
+    }
+}
+
+// handle_message deals with the messages a connection gets whatever it is waiting for
+fn handle_message(torrent: &CustomTorrent, dht: Option<&DhtNode>, c: &CustomClient, msg: PeerMessage) -> Result<()> {
+    match msg {
+        PeerMessage::Unchoke => c.set_choked(false),
+        PeerMessage::Choke => c.set_choked(true),
+        PeerMessage::Have { index } => {
+            trace!(index, "设置bit field");
+            c.set_piece(index as usize);
+        },
+        PeerMessage::HashRequest(req) => match torrent.answer_hash_request(&req) {
+            Some(hashes) => c.send_hashes(&req, &hashes)?,
+            None => c.send_hash_reject(&req)?,
+        },
+        PeerMessage::Port(port) => ping_peer_node(dht, c, port),
+        PeerMessage::Extended { id, payload } => metadata::handle_extended(c, &torrent.raw_info, id, &payload)?,
+        _ => {},
+    }
+    Ok(())
+}
+
 struct PieceProgress<'a> {
     index: usize,
+    torrent: &'a CustomTorrent,
+    dht: Option<&'a DhtNode>,
     client: RefCell<&'a CustomClient<'a>>,
     buf: Vec<u8>,
+    // who sent each block, pad blocks are never sent
+    senders: BlockSenders,
     downloaded: usize,
     requested: usize,
     backlog: usize,
 }
 
 impl <'a>PieceProgress<'a> {
-    pub fn read_message(&mut self) {
-        let client = self.client.borrow_mut();
-        let msg = client.read();
-        if let None = msg {
-            return;
-        }
-        let msg = msg.unwrap();
-        match msg.id {
-            message::message::MessageId::MsgUnchoke => client.set_choked(false),
-            message::message::MessageId::MsgChoke => client.set_choked(true),
-            message::message::MessageId::MsgInterested => todo!(),
-            message::message::MessageId::MsgNotInterested => todo!(),
-            message::message::MessageId::MsgHave => {
-                let index = message::message::parse_have(&msg);
-                // println!("设置bit field");
-                set_piece(& RefCell::new(&client.bit_field), index as usize);
+    // read_message handles the next message of the peer, returning without one when the peer
+    // stayed quiet for a read timeout so the caller can check how long it has been idle
+    pub fn read_message(&mut self) -> Result<()> {
+        let client = *self.client.borrow();
+        client.keep_alive()?;
+        let Some(msg) = client.poll()? else {
+            return Ok(());
+        };
+        match msg {
+            PeerMessage::Piece { index, begin, data } => {
+                self.copy_block(index as usize, begin as usize, &data)?;
+                client.mark_block();
+                let begin = begin as usize;
+                self.senders.push((begin..begin + data.len(), client.peer.general_address()));
+                self.downloaded += data.len();
+                self.backlog = self.backlog.saturating_sub(1);
+                trace!(index = self.index, downloaded = self.downloaded, "接收到piece数据");
             },
-            message::message::MessageId::MsgBitfield => todo!(),
-            message::message::MessageId::MsgRequest => todo!(),
-            message::message::MessageId::MsgPiece => {
-                let n = message::message::parse_piece(self.index, &mut self.buf, &msg);
-                self.downloaded += n as usize;
-                self.backlog -= 1;
-                // println!("接收到piece数据 {:?} {:?}", self.buf, msg);
-            },
-            message::message::MessageId::MsgCancel => todo!(),
+            msg => handle_message(self.torrent, self.dht, client, msg)?,
+        }
+        Ok(())
+    }
+
+    // copy_block puts a received block into the piece buffer after checking it belongs there
+    fn copy_block(&mut self, index: usize, begin: usize, data: &[u8]) -> Result<()> {
+        if index != self.index {
+            return Err(Error::protocol(format!("expected piece {}, got {}", self.index, index)));
         }
+        if begin >= self.buf.len() {
+            return Err(Error::protocol(format!("begin offset {} out of piece length {}", begin, self.buf.len())));
+        }
+        if begin + data.len() > self.buf.len() {
+            return Err(Error::protocol(format!("block of {} bytes at {} overflows piece length {}", data.len(), begin, self.buf.len())));
+        }
+        self.buf[begin..begin + data.len()].copy_from_slice(data);
+        Ok(())
     }
 }
 
-fn attempt_download_piece(c: &CustomClient, pw: &PieceWork) -> Result<Vec<u8>, io::Error> {
+fn attempt_download_piece(c: &CustomClient, torrent: &CustomTorrent, dht: Option<&DhtNode>, pw: &PieceWork) -> Result<(Vec<u8>, BlockSenders)> {
     let mut state = PieceProgress {
 		index:  pw.index,
+		torrent,
+		dht,
 		client: RefCell::new(c),
-		buf: vec![],
+		buf: vec![0u8; pw.length],
+        senders: vec![],
         downloaded: 0,
         requested: 0,
         backlog: 0,
 	};
 
-    println!("开始下载piece");
+    trace!(index = pw.index, length = pw.length, "开始下载piece");
+    let started = Instant::now();
 
-    if state.downloaded < pw.length {
-        if !*c.choked.borrow() {
-            if state.backlog < MAX_BACK_LOG && state.requested < pw.length {
-                let mut block_size = MAX_BLOCK_SIZE;
+    while state.downloaded < pw.length {
+        while state.requested < pw.length {
+            let mut block_size = MAX_BLOCK_SIZE;
 
-                if pw.length - state.requested < block_size {
-                    block_size = pw.length - state.requested;
-                }
+            if pw.length - state.requested < block_size {
+                block_size = pw.length - state.requested;
+            }
 
-                // println!("下载piece 发送请求 {} {} {}", pw.index, state.requested, block_size);
+            if pw.is_pad(state.requested, block_size) {
+                // buf 已经是全零
+                state.downloaded += block_size;
+                state.requested += block_size;
+                continue;
+            }
+            if *c.choked.borrow() || state.backlog >= MAX_BACK_LOG {
+                break;
+            }
 
-                c.send_request(pw.index, state.requested, block_size)?;
+            trace!(index = pw.index, begin = state.requested, length = block_size, "下载piece 发送请求");
 
-                state.backlog += 1;
-                state.requested += block_size;
+            c.send_request(pw.index, state.requested, block_size)?;
+
+            state.backlog += 1;
+            state.requested += block_size;
+        }
+        if state.downloaded < pw.length {
+            let idle = c.idle_for().min(started.elapsed());
+            if idle > PEER_IDLE_TIMEOUT {
+                return Err(Error::protocol(format!("no data for {}s", idle.as_secs())).with_peer(c.peer.general_address()));
             }
+            state.read_message()?;
         }
-        println!("接收数据");
-        state.read_message();
     }
 
-    return Ok(state.buf);
+    Ok((state.buf, state.senders))
 }
 
-impl P2pTorrent {
-    pub fn general_p2p_torrent(custom_torrent: &CustomTorrent, peers: Vec<Peer>, peer_id: [u8; 20]) -> Self {
+// SharedWork is the piece queue all peer workers of a torrent take from
+struct SharedWork {
+    pieces: Mutex<VecDeque<PieceWork>>,
+    finished: AtomicBool,
+    active: AtomicUsize,
+    // peer ids of open connections, a second connection from the same client is refused
+    peer_ids: Mutex<HashSet<[u8; 20]>>,
+}
+
+impl SharedWork {
+    fn new(pieces: VecDeque<PieceWork>) -> Self {
         Self {
-            peers: peers,
-            peerId: peer_id,
-            infoHash: custom_torrent.info_hash,
-            piece_hashes: custom_torrent.piece_hashes.clone(),
+            pieces: Mutex::new(pieces),
+            finished: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+            peer_ids: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // take returns the first queued piece the client has. A piece the client sent a corrupt copy
+    // of is left for another peer to redownload, unless no other peer is connected
+    fn take(&self, c: &CustomClient, failed: &FailedBlocks) -> Option<PieceWork> {
+        self.take_for(c.peer.general_address(), |index| c.has_piece(index), failed)
+    }
+
+    fn take_for(&self, addr: SocketAddr, has_piece: impl Fn(usize) -> bool, failed: &FailedBlocks) -> Option<PieceWork> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let alone = self.peer_ids.lock().unwrap().len() <= 1;
+        let pos = pieces.iter().position(|pw| has_piece(pw.index) && (alone || !failed.sent_corrupt(pw.index, addr)))?;
+        pieces.remove(pos)
+    }
+
+    fn give_back(&self, pw: PieceWork) {
+        self.pieces.lock().unwrap().push_back(pw);
+    }
+
+    // take_any returns the first queued piece, for sources that have every piece
+    fn take_any(&self) -> Option<PieceWork> {
+        self.pieces.lock().unwrap().pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pieces.lock().unwrap().is_empty()
+    }
+}
+
+// FailedBlocks keeps the blocks of pieces that failed their hash check, by piece index. It lives
+// as long as the P2pTorrent, a piece that fails in one download pass and verifies in a later one
+// is still compared against the corrupt copy
+#[derive(Debug, Default)]
+struct FailedBlocks(Mutex<HashMap<usize, Vec<BlockRecord>>>);
+
+impl FailedBlocks {
+    // record keeps a hash of every block of a corrupt piece along with its sender
+    fn record(&self, index: usize, buf: &[u8], senders: BlockSenders) {
+        let mut failed = self.0.lock().unwrap();
+        let records = failed.entry(index).or_default();
+        for (range, peer) in senders {
+            let hash = sha1::Sha1::from(&buf[range.clone()]).digest().bytes();
+            // 同一个 peer 重复发送的相同数据只记一次
+            if !records.iter().any(|r| r.range == range && r.peer == peer && r.hash == hash) {
+                records.push(BlockRecord { range, peer, hash });
+            }
+        }
+    }
+
+    // sent_corrupt tells whether addr sent a block of a failed copy of the piece
+    fn sent_corrupt(&self, index: usize, addr: SocketAddr) -> bool {
+        self.0.lock().unwrap().get(&index).is_some_and(|records| records.iter().any(|r| r.peer == addr))
+    }
+
+    // culprits compares the blocks of earlier failed copies of a piece with its verified data,
+    // the peers whose blocks differ are the ones that sent corrupt data
+    fn culprits(&self, index: usize, good: &[u8]) -> Vec<SocketAddr> {
+        let Some(records) = self.0.lock().unwrap().remove(&index) else {
+            return vec![];
+        };
+        let mut culprits = vec![];
+        for record in records {
+            let hash = sha1::Sha1::from(&good[record.range.clone()]).digest().bytes();
+            if hash != record.hash && !culprits.iter().any(|p: &SocketAddr| p.ip() == record.peer.ip()) {
+                culprits.push(record.peer);
+            }
+        }
+        culprits
+    }
+}
+
+impl P2pTorrent {
+    pub fn general_p2p_torrent(custom_torrent: &CustomTorrent, peers: Vec<Peer>, peer_id: [u8; 20], limits: RateLimits) -> Self {
+        let mut p2p_torrent = Self {
+            peers: ConnectionManager::new(ConnectionLimit::unlimited(), DEFAULT_MAX_PEERS),
+            web_seeds: vec![],
+            peer_id,
+            torrent: custom_torrent.clone(),
             piece_length: custom_torrent.piece_length,
             length: custom_torrent.length,
             name: custom_torrent.name.clone(),
-        }
+            limits,
+            stop: Arc::new(AtomicBool::new(false)),
+            encryption: EncryptionPolicy::default(),
+            utp: None,
+            dht: None,
+            events: None,
+            layers: Mutex::new(HashMap::new()),
+            failed: FailedBlocks::default(),
+        };
+        p2p_torrent.add_peers(custom_torrent.info_hash, peers, PeerSource::Tracker);
+        p2p_torrent.add_web_seeds(&custom_torrent.web_seeds, WebSeedKind::UrlList);
+        p2p_torrent.add_web_seeds(&custom_torrent.http_seeds, WebSeedKind::HttpSeed);
+        p2p_torrent
     }
 
-    fn start_download_worker(&self, peer: &Peer, work_queue: &Vec<PieceWork>, results: &RefCell<&mut Vec<PieceResult>>) {
-        let client = CustomClient::new(peer, self.peerId, &self.infoHash);
-        if let Err(_) = client {
-            // println!("init client error: {} {:?}", e, peer);
+    // AddPeers adds peers found in the swarm of info_hash, peers already known are skipped and
+    // so are all of them when the torrent doesn't accept peers from source
+    pub fn add_peers(&self, info_hash: [u8; 20], peers: Vec<Peer>, source: PeerSource) {
+        if !self.torrent.allows_peer_source(source) {
+            debug!(?source, count = peers.len(), "ignoring peers of private torrent");
             return;
         }
-        // println!("init client success");
-        let c = client.unwrap();
+        for peer in peers {
+            self.peers.add(peer, info_hash, source);
+        }
+    }
+
+    // AddWebSeeds adds http sources that serve the whole torrent, unsupported schemes are skipped
+    pub fn add_web_seeds(&mut self, urls: &[String], kind: WebSeedKind) {
+        for url in urls {
+            if !webseed::is_supported(url) {
+                debug!(%url, "skipping unsupported web seed");
+            } else if !self.web_seeds.iter().any(|(known, _)| known == url) {
+                self.web_seeds.push((url.clone(), kind));
+            }
+        }
+    }
+
+    // SetEvents reports peer and piece events of this download to a session's event stream
+    pub fn set_events(&mut self, events: EventSender) {
+        self.events = Some(events);
+    }
+
+    fn emit(&self, kind: EventKind) {
+        if let Some(events) = &self.events {
+            events.emit(kind);
+        }
+    }
+
+    // SetStopFlag lets the owner interrupt a running download, workers stop after their current piece
+    pub fn set_stop_flag(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = stop;
+    }
+
+    // SetConnectionLimits shares the global connection limit and caps the connections of this torrent
+    pub fn set_connection_limits(&mut self, connections: Arc<ConnectionLimit>, max_peers: usize) {
+        self.peers.set_limits(connections, max_peers);
+    }
+
+    // SetIpFilter drops blocked peers, set it before adding peers so they are filtered too
+    pub fn set_ip_filter(&mut self, filter: Arc<IpFilter>) {
+        self.peers.set_ip_filter(filter);
+    }
+
+    // SetExternalIp tells the address peers see us at, candidates are ranked against it (BEP 40)
+    pub fn set_external_ip(&mut self, ip: IpAddr) {
+        self.peers.set_own_ip(ip);
+    }
+
+    // SetEncryption sets the MSE policy for outbound connections
+    pub fn set_encryption(&mut self, encryption: EncryptionPolicy) {
+        self.encryption = encryption;
+    }
+
+    // SetUtp makes outbound connections try uTP on socket first
+    pub fn set_utp(&mut self, socket: Arc<UtpSocket>) {
+        self.utp = Some(socket);
+    }
+
+    // SetDht enables the port message with peers that run a DHT node too
+    pub fn set_dht(&mut self, node: Arc<DhtNode>) {
+        self.dht = Some(node);
+    }
+
+    // RateStats returns the torrent wide upload and download rates
+    pub fn rate_stats(&self) -> TransferStats {
+        self.limits.torrent_stats()
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.torrent.num_pieces()
+    }
+
+    // dht is the DHT node this torrent may use, private torrents never touch the DHT (BEP 27)
+    fn dht(&self) -> Option<&DhtNode> {
+        self.dht.as_deref().filter(|_| self.torrent.allows_peer_source(PeerSource::Dht))
+    }
+
+    fn reserved(&self) -> [u8; 8] {
+        handshake::reserved_bits(self.torrent.has_v2(), self.dht().is_some())
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    fn download_from_peer(&self, c: &CustomClient, work: &SharedWork, results: &Sender<PieceResult>) -> Result<()> {
+        if let Some(dht) = self.dht().filter(|_| c.remote.supports_dht()) {
+            c.send_port(dht.port()).err();
+        }
         c.send_unchoke().err();
         c.send_interested().err();
 
-        for pw in work_queue.iter() {
-            if !has_piece(&c.bit_field, pw.index) {
+        while !self.stopped() && !work.finished.load(Ordering::SeqCst) {
+            if self.peers.is_banned(c.peer.general_address().ip()) {
+                return Err(Error::protocol("peer banned for sending corrupt data"));
+            }
+            let pw = match work.take(c, &self.failed) {
+                Some(pw) => pw,
+                None => {
+                    if !work.is_empty() {
+                        // 该 peer 没有剩余需要的 piece
+                        debug!("peer has no piece we need");
+                        return Ok(());
+                    }
+                    c.keep_alive()?;
+                    thread::sleep(Duration::from_millis(200));
+                    continue;
+                },
+            };
+
+            if let Err(e) = self.fetch_layer(c, pw.index) {
+                work.give_back(pw);
+                return Err(e);
+            }
+            let (buf, senders) = match attempt_download_piece(c, &self.torrent, self.dht(), &pw) {
+                Ok(piece) => piece,
+                Err(e) => {
+                    debug!(index = pw.index, error = %e, "piece 下载失败");
+                    work.give_back(pw);
+                    return Err(e);
+                },
+            };
+
+            if !self.verify_piece(pw.index, &buf) {
+                warn!(index = pw.index, "piece 校验失败");
+                self.emit(EventKind::PieceHashFailed { index: pw.index, peer: Some(c.peer.general_address()) });
+                let blamed = self.blame_blocks(c, pw.index, &buf, &senders);
+                if !matches!(blamed, Ok(true)) {
+                    // 重新下载成功后再比较每个 block，找出发送坏数据的 peer
+                    self.failed.record(pw.index, &buf, senders);
+                }
+                work.give_back(pw);
+                blamed?;
                 continue;
             }
+            self.piece_verified(pw.index, &buf);
+
+            c.send_have(pw.index).err();
+            if results.send(PieceResult { index: pw.index, buffer: buf }).is_err() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    // verify_piece checks a piece with the hashes of the metainfo and the piece layers peers sent
+    fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        let layers = self.layers.lock().unwrap();
+        self.torrent.verify_piece_with(index, data, |root, i| layers.get(&(*root, i)).copied())
+    }
+
+    // request_hashes asks the peer for merkle hashes and waits for the answer, which must prove
+    // the hashes against the file's pieces root. None when the peer rejects the request
+    fn request_hashes(&self, c: &CustomClient, req: &HashRequest) -> Result<Option<Vec<Hash256>>> {
+        if !c.remote.supports_v2() {
+            return Ok(None);
+        }
+        c.send_hash_request(req)?;
+        let asked = Instant::now();
+        loop {
+            c.keep_alive()?;
+            match c.poll()? {
+                Some(PeerMessage::Hashes { request, hashes }) if request == *req => {
+                    if !self.torrent.verify_hashes(req, &hashes) {
+                        return Err(Error::protocol("hashes don't match the pieces root"));
+                    }
+                    return Ok(Some(hashes[..req.length as usize].to_vec()));
+                },
+                Some(PeerMessage::HashReject(request)) if request == *req => return Ok(None),
+                Some(msg) => handle_message(&self.torrent, self.dht(), c, msg)?,
+                None if asked.elapsed() > PEER_IDLE_TIMEOUT => {
+                    return Err(Error::protocol(format!("no answer to hash request for {}s", asked.elapsed().as_secs())));
+                },
+                None => {},
+            }
+        }
+    }
+
+    // fetch_layer gets the piece layer hashes a piece of a v2 only torrent needs to be checked
+    // when the metainfo has none, before the piece is downloaded
+    fn fetch_layer(&self, c: &CustomClient, index: usize) -> Result<()> {
+        let req = match self.torrent.layer_request(index) {
+            Some(req) if !self.layers.lock().unwrap().contains_key(&(req.pieces_root, req.index as usize)) => req,
+            _ => return Ok(()),
+        };
+        trace!(index, layer_index = req.index, length = req.length, "请求 piece layer");
+        let hashes = self.request_hashes(c, &req)?.ok_or_else(|| Error::protocol("peer has no piece layer for the file"))?;
+        let mut layers = self.layers.lock().unwrap();
+        for (i, hash) in hashes.into_iter().enumerate() {
+            layers.insert((req.pieces_root, req.index as usize + i), hash);
+        }
+        Ok(())
+    }
+
+    // blame_blocks asks the peer for the block hashes of a v2 piece that failed its check and
+    // bans whoever sent a block that differs. false when the block hashes aren't available
+    fn blame_blocks(&self, c: &CustomClient, index: usize, buf: &[u8], senders: &BlockSenders) -> Result<bool> {
+        let Some(req) = self.torrent.block_request(index) else {
+            return Ok(false);
+        };
+        let Some(leaves) = self.request_hashes(c, &req)? else {
+            return Ok(false);
+        };
+        let bad = self.torrent.bad_blocks(index, buf, &leaves);
+        debug!(index, ?bad, "piece 中损坏的 block");
+        for (range, addr) in senders {
+            if bad.iter().any(|block| range.contains(&(block * BLOCK_SIZE))) {
+                self.ban(*addr, index);
+            }
+        }
+        Ok(true)
+    }
 
-            let buf = attempt_download_piece(&c, pw);
-            if let Err(_) = buf {
+    fn start_web_seed_worker(&self, url: &str, kind: WebSeedKind, work: &SharedWork, results: Sender<PieceResult>) {
+        let _span = info_span!("web_seed", %url).entered();
+        if let Ok(mut seed) = WebSeed::new(url, kind) {
+            self.download_from_web_seed(&mut seed, work, &results);
+        }
+        work.active.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn download_from_web_seed(&self, seed: &mut WebSeed, work: &SharedWork, results: &Sender<PieceResult>) {
+        let limiters = [self.limits.global.clone(), self.limits.torrent.clone()];
+        while !self.stopped() && !work.finished.load(Ordering::SeqCst) && !seed.gave_up() {
+            let pw = match seed.ready().then(|| work.take_any()).flatten() {
+                Some(pw) => pw,
+                None => {
+                    thread::sleep(Duration::from_millis(200));
+                    continue;
+                },
+            };
+            let buf = match seed.fetch_piece(&self.torrent, pw.index, pw.length, &limiters) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    debug!(index = pw.index, error = %e, "web seed 下载失败");
+                    work.give_back(pw);
+                    seed.failed();
+                    continue;
+                },
+            };
+            if !self.verify_piece(pw.index, &buf) {
+                warn!(index = pw.index, url = seed.url(), "web seed piece 校验失败");
+                self.emit(EventKind::PieceHashFailed { index: pw.index, peer: None });
+                work.give_back(pw);
+                seed.failed();
+                continue;
+            }
+            seed.succeeded();
+            self.piece_verified(pw.index, &buf);
+            if results.send(PieceResult { index: pw.index, buffer: buf }).is_err() {
                 return;
             }
-            let buf = buf.unwrap();
+        }
+        if seed.gave_up() {
+            warn!(url = seed.url(), "giving up on web seed");
+        }
+    }
 
-            c.send_have(pw.index).err();
-            results.borrow_mut().push(PieceResult {
-                index: pw.index,
-                buffer: buf,
+    // piece_verified reports a good piece and bans the peers an earlier corrupt copy of it came from
+    fn piece_verified(&self, index: usize, buf: &[u8]) {
+        self.emit(EventKind::PieceVerified { index });
+        for addr in self.failed.culprits(index, buf) {
+            self.ban(addr, index);
+        }
+    }
+
+    fn ban(&self, addr: SocketAddr, index: usize) {
+        if self.peers.ban(addr.ip()) {
+            warn!(%addr, index, "banning peer that sent corrupt data");
+            self.emit(EventKind::PeerBanned { addr, index });
+        }
+    }
+
+    // serve_peer runs one established connection until it fails or has nothing left to offer
+    fn serve_peer(&self, client: Result<CustomClient>, addr: SocketAddr, work: &SharedWork, role: Role) {
+        let mut useful = false;
+        let ours = match role {
+            // 下载时还不上传，不告诉对方我们有哪些 piece
+            Role::Download(_) => bitfield::new_bitfield(self.num_pieces()),
+            Role::Upload(_) => bitfield::full_bitfield(self.num_pieces()),
+        };
+        match client.and_then(|c| c.exchange_bitfields(&ours).and_then(|_| self.send_extension_handshake(&c)).map(|_| c)) {
+            Ok(client) => {
+                let remote_id = client.remote.peer_id;
+                if !work.peer_ids.lock().unwrap().insert(remote_id) {
+                    debug!(peer_id = %String::from_utf8_lossy(&remote_id), "duplicate peer id");
+                } else {
+                    let fingerprint = client.fingerprint();
+                    debug!(client = fingerprint.as_ref().map(tracing::field::display), "peer connected");
+                    self.emit(EventKind::PeerConnected { addr, client: fingerprint.map(|f| f.to_string()) });
+                    let res = match role {
+                        Role::Download(results) => self.download_from_peer(&client, work, results),
+                        Role::Upload(read_piece) => self.upload_to_peer(&client, work, read_piece),
+                    };
+                    work.peer_ids.lock().unwrap().remove(&remote_id);
+                    useful = client.was_useful();
+                    debug!(error = res.as_ref().err().map(tracing::field::display), uploaded = client.uploaded(), "peer disconnected");
+                    self.emit(EventKind::PeerDisconnected { addr, reason: res.err().map(|e| e.to_string()) });
+                }
+            },
+            Err(e) => debug!(error = %e, "init client error"),
+        }
+        self.peers.disconnected(addr, useful);
+        self.limits.remove_peer(&addr);
+    }
+
+    // send_extension_handshake offers our info dictionary to peers that speak the extension protocol
+    fn send_extension_handshake(&self, c: &CustomClient) -> Result<()> {
+        if !c.remote.supports_extensions() {
+            return Ok(());
+        }
+        c.send_extended(metadata::EXTENDED_HANDSHAKE, ExtensionHandshake::ours(Some(self.torrent.raw_info.len())).serialize())
+    }
+
+    fn connect(&self, candidate: &Candidate, work: &SharedWork, role: Role) {
+        let addr = candidate.peer.general_address();
+        let _span = info_span!("peer", %addr, source = ?candidate.source).entered();
+        let client = CustomClient::new(&candidate.peer, self.peer_id, &candidate.info_hash, self.reserved(), self.encryption, self.utp.as_deref(), self.limits.chain(addr));
+        self.serve_peer(client, addr, work, role);
+    }
+
+    // start_download_workers connects to every candidate that is due while there are free slots,
+    // one thread per peer. It returns false once no candidate is left to wait for
+    fn start_download_workers<'s>(&'s self, s: &'s thread::Scope<'s, '_>, work: &'s SharedWork, results: &Sender<PieceResult>) -> bool {
+        loop {
+            let (candidate, slots) = match self.peers.next() {
+                Next::Connect(candidate, slots) => (candidate, slots),
+                Next::Wait => return true,
+                Next::Exhausted => return false,
+            };
+            let results = results.clone();
+            let span = Span::current();
+            work.active.fetch_add(1, Ordering::SeqCst);
+            s.spawn(move || {
+                let _slots = slots;
+                span.in_scope(|| self.connect(&candidate, work, Role::Download(&results)));
+                work.active.fetch_sub(1, Ordering::SeqCst);
             });
         }
     }
 
+    fn start_incoming_worker(&self, incoming: IncomingPeer, work: &SharedWork, role: Role) {
+        let peer = Peer::new(incoming.addr);
+        let info_hash = incoming.handshake.info_hash;
+        let _span = info_span!("peer", addr = %incoming.addr, inbound = true).entered();
+        if let Some(_slot) = self.peers.accept_incoming(incoming.addr, info_hash) {
+            let client = CustomClient::accept(&peer, incoming.stream, incoming.handshake, self.peer_id, &info_hash, self.reserved(), self.limits.chain(incoming.addr));
+            self.serve_peer(client, incoming.addr, work, role);
+        }
+        work.active.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // read_block cuts a requested block out of its piece, the last piece read is kept in cache
+    // since peers request the blocks of a piece one after another
+    fn read_block(&self, cache: &mut Option<(usize, Vec<u8>)>, read_piece: &PieceReader, index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        if index >= self.num_pieces() {
+            return Err(Error::protocol(format!("request for piece {} of {}", index, self.num_pieces())));
+        }
+        let piece_size = self.calculate_piece_size(index);
+        if length == 0 || length > MAX_REQUEST_SIZE || begin + length > piece_size {
+            return Err(Error::protocol(format!("bad request of {} bytes at {} in piece {}", length, begin, index)));
+        }
+        if cache.as_ref().is_none_or(|(cached, _)| *cached != index) {
+            *cache = Some((index, read_piece(index)?));
+        }
+        let (_, piece) = cache.as_ref().unwrap();
+        Ok(piece[begin..begin + length].to_vec())
+    }
+
+    // upload_to_peer answers the requests of a peer that downloads from us. Every interested peer
+    // is unchoked, the connection limits cap how many there are at once
+    fn upload_to_peer(&self, c: &CustomClient, work: &SharedWork, read_piece: &PieceReader) -> Result<()> {
+        let num_pieces = self.num_pieces();
+        if c.is_seed(num_pieces) {
+            debug!("peer is a seed too");
+            return Ok(());
+        }
+        if let Some(dht) = self.dht().filter(|_| c.remote.supports_dht()) {
+            c.send_port(dht.port()).err();
+        }
+        let mut unchoked = false;
+        let mut cache = None;
+        let mut last_message = Instant::now();
+        while !self.stopped() && !work.finished.load(Ordering::SeqCst) {
+            if self.peers.is_banned(c.peer.general_address().ip()) {
+                return Err(Error::protocol("peer banned for sending corrupt data"));
+            }
+            c.keep_alive()?;
+            let msg = match c.poll()? {
+                Some(PeerMessage::KeepAlive) | None => {
+                    if last_message.elapsed() > UPLOAD_IDLE_TIMEOUT {
+                        return Err(Error::protocol(format!("no message for {}s", last_message.elapsed().as_secs())));
+                    }
+                    continue;
+                },
+                Some(msg) => msg,
+            };
+            last_message = Instant::now();
+            match msg {
+                PeerMessage::Interested if !unchoked => {
+                    c.send_unchoke()?;
+                    unchoked = true;
+                },
+                PeerMessage::NotInterested if unchoked => {
+                    c.send_choke()?;
+                    unchoked = false;
+                },
+                PeerMessage::Have { index } => {
+                    c.set_piece(index as usize);
+                    if c.is_seed(num_pieces) {
+                        debug!("peer finished downloading");
+                        return Ok(());
+                    }
+                },
+                // 被 choke 时的请求直接忽略
+                PeerMessage::Request { index, begin, length } if unchoked => {
+                    let (index, begin) = (index as usize, begin as usize);
+                    let block = self.read_block(&mut cache, read_piece, index, begin, length as usize)?;
+                    trace!(index, begin, length, "上传 block");
+                    c.send_piece(index, begin, &block)?;
+                },
+                PeerMessage::HashRequest(req) => match self.torrent.answer_hash_request(&req) {
+                    Some(hashes) => c.send_hashes(&req, &hashes)?,
+                    None => c.send_hash_reject(&req)?,
+                },
+                PeerMessage::Port(port) => ping_peer_node(self.dht(), c, port),
+                PeerMessage::Extended { id, payload } => metadata::handle_extended(c, &self.torrent.raw_info, id, &payload)?,
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
     fn calculate_bounds_for_piece(&self, index: usize) -> (usize, usize) {
         let begin = index * self.piece_length;
         let mut end = begin + self.piece_length;
@@ -162,38 +865,282 @@ impl P2pTorrent {
         end - begin
     }
 
-    pub fn download(&self) -> Vec<u8> {
-        let mut work_queue = vec![];
-        let mut results = vec![];
-        let results_ref = RefCell::new(&mut results);
-        for index in 0..self.piece_hashes.len() {
+    // DownloadPieces fetches every piece not marked in have and hands verified pieces to on_piece.
+    // It returns once all pieces are done, the stop flag is set or no peer is left to try.
+    pub fn download_pieces(&self, have: &[bool], incoming: Option<&Receiver<IncomingPeer>>, mut on_piece: impl FnMut(usize, Vec<u8>)) -> usize {
+        let mut work_queue = VecDeque::new();
+        for index in 0..self.num_pieces() {
+            if have.get(index).copied().unwrap_or(false) {
+                continue;
+            }
             let length = self.calculate_piece_size(index);
-            work_queue.push(PieceWork {
-                index,
-                hash: self.piece_hashes[index],
-                length,
-            })
+            work_queue.push_back(PieceWork { index, length, pad: self.torrent.pad_ranges(index) })
         }
+        let needed = work_queue.len();
+        let work = SharedWork::new(work_queue);
+        work.finished.store(needed == 0, Ordering::SeqCst);
 
-        println!("Downloading peers {:?}", &self.peers.len());
-
-        for peer in &self.peers {
-            self.start_download_worker(peer, &work_queue, &results_ref)
-        }
+        debug!(peers = self.peers.len(), web_seeds = self.web_seeds.len(), pieces = needed, "Downloading");
 
-        let mut buf = vec![0u8; self.length];
+        let (tx, rx) = mpsc::channel();
         let mut done_pieces = 0;
-        while done_pieces < self.piece_hashes.len() {
-            let res = results_ref.borrow_mut().pop();
-            if let None = res {
-                break;
+        let mut idle_since = Instant::now();
+        thread::scope(|s| {
+            if needed > 0 {
+                for (url, kind) in &self.web_seeds {
+                    let tx = tx.clone();
+                    let work = &work;
+                    let span = Span::current();
+                    work.active.fetch_add(1, Ordering::SeqCst);
+                    s.spawn(move || span.in_scope(|| self.start_web_seed_worker(url, *kind, work, tx)));
+                }
             }
-            let res = res.unwrap();
-            let (begin, end) = self.calculate_bounds_for_piece(res.index);
-            buf[begin..end].copy_from_slice(&res.buffer);
+
+            while done_pieces < needed && !self.stopped() {
+                // peer 是陆续加入的，有空位和可连接的 peer 就开新的 worker
+                let waiting = self.start_download_workers(s, &work, &tx);
+                if let Some(incoming) = incoming.and_then(|rx| rx.try_recv().ok()) {
+                    let tx = tx.clone();
+                    let work = &work;
+                    let span = Span::current();
+                    work.active.fetch_add(1, Ordering::SeqCst);
+                    s.spawn(move || span.in_scope(|| self.start_incoming_worker(incoming, work, Role::Download(&tx))));
+                }
+                match rx.recv_timeout(Duration::from_millis(100)) {
+                    Ok(res) => {
+                        on_piece(res.index, res.buffer);
+                        done_pieces += 1;
+                    },
+                    Err(RecvTimeoutError::Timeout) => {
+                        if waiting || work.active.load(Ordering::SeqCst) != 0 {
+                            idle_since = Instant::now();
+                        } else if incoming.is_none() || idle_since.elapsed() > IDLE_TIMEOUT {
+                            break;
+                        }
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            work.finished.store(true, Ordering::SeqCst);
+        });
+        // 停止前已经下载完成的 piece
+        while let Ok(res) = rx.try_recv() {
+            on_piece(res.index, res.buffer);
             done_pieces += 1;
-            // let percent = ((done_pieces as f64) / (self.piece_hashes.len() as f64)) * 100.0;
         }
-        return buf;
+        done_pieces
+    }
+
+    // Seed uploads to the known peers and to peers that connect to us, for a torrent that has
+    // every piece. It returns once the stop flag is set or keep_going, asked about ten times a
+    // second, returns false
+    pub fn seed(&self, incoming: Option<&Receiver<IncomingPeer>>, read_piece: &PieceReader, mut keep_going: impl FnMut() -> bool) {
+        let work = SharedWork::new(VecDeque::new());
+        debug!(peers = self.peers.len(), "Seeding");
+        thread::scope(|s| {
+            while !self.stopped() && keep_going() {
+                if let Some(incoming) = incoming.and_then(|rx| rx.try_recv().ok()) {
+                    let work = &work;
+                    let span = Span::current();
+                    work.active.fetch_add(1, Ordering::SeqCst);
+                    s.spawn(move || span.in_scope(|| self.start_incoming_worker(incoming, work, Role::Upload(read_piece))));
+                }
+                // 做种时每个 peer 一个线程，没有可连接的 peer 也不退出，重新 announce 会带来新的
+                while let Next::Connect(candidate, slots) = self.peers.next() {
+                    let work = &work;
+                    let span = Span::current();
+                    s.spawn(move || {
+                        let _slots = slots;
+                        span.in_scope(|| self.connect(&candidate, work, Role::Upload(read_piece)))
+                    });
+                }
+                thread::sleep(SEED_POLL_INTERVAL);
+            }
+            work.finished.store(true, Ordering::SeqCst);
+        });
+    }
+
+    pub fn download(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.length];
+        let have = vec![false; self.num_pieces()];
+        let done = self.download_pieces(&have, None, |index, data| {
+            let (begin, end) = self.calculate_bounds_for_piece(index);
+            buf[begin..end].copy_from_slice(&data);
+        });
+        if done < self.num_pieces() {
+            return Err(Error::Incomplete { done, total: self.num_pieces() });
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Write,
+        net::TcpStream,
+        path::{Path, PathBuf},
+    };
+
+    use super::*;
+    use crate::{
+        message::message,
+        session::session::{Session, SessionSettings, TorrentState},
+        storage::recheck,
+        torrent_file::creator::{self, CreateOptions},
+    };
+
+    const PIECE: usize = 4 * MAX_BLOCK_SIZE;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn piece() -> Vec<u8> {
+        (0..PIECE).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn block(i: usize) -> Range<usize> {
+        i * MAX_BLOCK_SIZE..(i + 1) * MAX_BLOCK_SIZE
+    }
+
+    // senders has a send blocks 0, 1 and 3 and b block 2
+    fn senders(a: SocketAddr, b: SocketAddr) -> BlockSenders {
+        vec![(block(0), a), (block(1), a), (block(2), b), (block(3), a)]
+    }
+
+    // one_piece_torrent writes a single piece file into a fresh directory and makes a torrent of it
+    fn one_piece_torrent(name: &str, private: bool) -> (PathBuf, CustomTorrent) {
+        let dir = std::env::temp_dir().join(format!("p2p-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("f.bin");
+        fs::write(&path, piece()).unwrap();
+        let options = CreateOptions { piece_length: Some(PIECE), private, threads: 1, ..CreateOptions::default() };
+        let meta = creator::create_torrent(&path, &options).unwrap();
+        (dir, CustomTorrent::general_custom_torrent(&meta).unwrap())
+    }
+
+    fn p2p_torrent(name: &str) -> P2pTorrent {
+        let (dir, torrent) = one_piece_torrent(name, false);
+        fs::remove_dir_all(&dir).unwrap();
+        P2pTorrent::general_p2p_torrent(&torrent, vec![], [1u8; 20], RateLimits::default())
+    }
+
+    // seeder_messages seeds torrent from dir with a DHT node running, connects to it as a peer
+    // with the DHT bit set and returns the seed's handshake and the messages it sent
+    fn seeder_messages(dir: &Path, torrent: &CustomTorrent) -> (handshake::Handshake, Vec<PeerMessage>) {
+        let settings = SessionSettings { listen_port: 0, dht_port: 0, enable_utp: false, ..SessionSettings::default() };
+        let mut session = Session::new(settings).unwrap();
+        let resume = recheck::recheck(torrent, dir, 0);
+        let id = session.add_torrent_with_resume(torrent.clone(), dir, &resume.bitfield);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while session.torrent_status(id).is_some_and(|s| s.state != TorrentState::Seeding) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let mut stream = TcpStream::connect(("127.0.0.1", session.listen_port())).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let ours = handshake::Handshake::new(&torrent.info_hash, &[7u8; 20]).with_reserved(handshake::reserved_bits(false, true));
+        stream.write_all(&ours.serialize()).unwrap();
+        let theirs = handshake::read(&mut stream).unwrap();
+        stream.write_all(&PeerMessage::HaveNone.serialize()).unwrap();
+        let mut messages = vec![];
+        while let Ok(msg) = message::read(&mut stream) {
+            messages.push(msg);
+        }
+        session.shutdown();
+        (theirs, messages)
+    }
+
+    #[test]
+    fn private_torrents_send_no_port_message() {
+        let (dir, public) = one_piece_torrent("public-port", false);
+        let (theirs, messages) = seeder_messages(&dir, &public);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(theirs.supports_dht());
+        assert!(messages.iter().any(|m| matches!(m, PeerMessage::Port(_))), "{:?}", messages);
+
+        let (dir, private) = one_piece_torrent("private-port", true);
+        assert!(private.private);
+        let (theirs, messages) = seeder_messages(&dir, &private);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!theirs.supports_dht());
+        assert!(messages.iter().any(|m| matches!(m, PeerMessage::Bitfield(_))), "{:?}", messages);
+        assert!(!messages.iter().any(|m| matches!(m, PeerMessage::Port(_))), "{:?}", messages);
+    }
+
+    #[test]
+    fn only_the_sender_of_the_bad_block_is_a_culprit() {
+        let (a, b) = (addr("10.0.0.1:6881"), addr("10.0.0.2:6881"));
+        let good = piece();
+        let mut bad = good.clone();
+        bad[2 * MAX_BLOCK_SIZE + 7] ^= 1;
+        let failed = FailedBlocks::default();
+        failed.record(0, &bad, senders(a, b));
+        assert!(failed.sent_corrupt(0, a) && failed.sent_corrupt(0, b));
+        assert!(!failed.sent_corrupt(1, a));
+        assert_eq!(failed.culprits(0, &good), vec![b]);
+        // 比较之后记录被清除
+        assert!(failed.culprits(0, &good).is_empty());
+        assert!(!failed.sent_corrupt(0, b));
+    }
+
+    #[test]
+    fn every_copy_is_compared_and_each_ip_counted_once() {
+        let (a, b, b2) = (addr("10.0.0.1:6881"), addr("10.0.0.2:6881"), addr("10.0.0.2:7000"));
+        let good = piece();
+        let mut first = good.clone();
+        first[0] ^= 1;
+        let mut second = good.clone();
+        second[2 * MAX_BLOCK_SIZE] ^= 1;
+        second[3 * MAX_BLOCK_SIZE] ^= 1;
+        let failed = FailedBlocks::default();
+        failed.record(0, &first, vec![(block(0), a), (block(1), b), (block(2), b), (block(3), b)]);
+        failed.record(0, &second, vec![(block(0), b), (block(1), b), (block(2), b2), (block(3), b)]);
+        let mut culprits = failed.culprits(0, &good);
+        culprits.sort();
+        assert_eq!(culprits.len(), 2);
+        assert_eq!(culprits[0], a);
+        assert_eq!(culprits[1].ip(), b.ip());
+    }
+
+    #[test]
+    fn a_later_pass_still_bans_the_sender_of_an_earlier_bad_copy() {
+        let p2p = p2p_torrent("ban");
+        let (a, b) = (addr("10.0.0.1:6881"), addr("10.0.0.2:6881"));
+        let good = piece();
+        let mut bad = good.clone();
+        bad[2 * MAX_BLOCK_SIZE] ^= 0xff;
+        // 第一轮下载记录坏的副本，之后的一轮才下载到正确的数据
+        p2p.failed.record(0, &bad, senders(a, b));
+        p2p.piece_verified(0, &good);
+        assert!(p2p.peers.is_banned(b.ip()));
+        assert!(!p2p.peers.is_banned(a.ip()));
+    }
+
+    #[test]
+    fn take_skips_pieces_the_peer_sent_corrupt() {
+        let (a, b) = (addr("10.0.0.1:6881"), addr("10.0.0.2:6881"));
+        let queue = (0..2).map(|index| PieceWork { index, length: PIECE, pad: vec![] }).collect();
+        let work = SharedWork::new(queue);
+        let failed = FailedBlocks::default();
+        failed.record(0, &piece(), vec![(block(0), a)]);
+        work.peer_ids.lock().unwrap().extend([[1u8; 20], [2u8; 20]]);
+
+        // a 跳过它发过坏数据的 piece 0
+        let taken = work.take_for(a, |_| true, &failed).unwrap();
+        assert_eq!(taken.index, 1);
+        work.give_back(taken);
+        assert_eq!(work.take_for(a, |index| index == 0, &failed).map(|pw| pw.index), None);
+        let taken = work.take_for(b, |_| true, &failed).unwrap();
+        assert_eq!(taken.index, 0);
+        work.give_back(taken);
+
+        // 只剩这一个 peer 时还是让它重新下载
+        work.peer_ids.lock().unwrap().remove(&[2u8; 20]);
+        assert_eq!(work.take_for(a, |_| true, &failed).map(|pw| pw.index), Some(1));
+        assert_eq!(work.take_for(a, |_| true, &failed).map(|pw| pw.index), Some(0));
+        assert!(work.is_empty());
     }
 }
@@ -0,0 +1,385 @@
+use std::{
+    io::Read,
+    path::{Component, Path},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use reqwest::{blocking::{Client, Response}, header::{RANGE, RETRY_AFTER}, StatusCode};
+use url::form_urlencoded::byte_serialize;
+use tracing::{debug, trace};
+
+use crate::{
+    error::error::{Error, Result},
+    ratelimit::ratelimit::{RateLimitedStream, RateLimiter},
+    torrent_file::torrent_file::{CustomTorrent, TorrentFile},
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const BACKOFF_BASE: Duration = Duration::from_secs(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(600);
+// 连续失败这么多次后放弃该镜像
+const MAX_FAILURES: u32 = 6;
+// 503 没有给出等待时间时使用
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebSeedKind {
+    // BEP 19 url-list: plain files fetched with range requests
+    UrlList,
+    // BEP 17 httpseeds: a script answering ?info_hash=&piece=&ranges=
+    HttpSeed,
+}
+
+// WebSeed is one http source that serves every piece of the torrent
+#[derive(Debug)]
+pub struct WebSeed {
+    url: String,
+    kind: WebSeedKind,
+    client: Client,
+    failures: u32,
+    retry_at: Option<Instant>,
+    // set when the seed asked us to come back later, see failed
+    busy_until: Option<Instant>,
+}
+
+// is_supported tells whether a url-list entry can be used, ftp mirrors are not
+pub fn is_supported(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+// retry_after reads how long a busy http seed wants us to wait, BEP 17 puts the
+// seconds in the body, the Retry-After header is honoured as well
+fn retry_after(resp: Response) -> Duration {
+    let header = resp
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    let body = resp.text().ok().and_then(|body| body.trim().parse::<u64>().ok());
+    header.or(body).map(Duration::from_secs).unwrap_or(DEFAULT_RETRY_AFTER).min(BACKOFF_MAX)
+}
+
+impl WebSeed {
+    pub fn new(url: &str, kind: WebSeedKind) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| Error::web_seed(url, e))?;
+        Ok(Self {
+            url: url.to_string(),
+            kind,
+            client,
+            failures: 0,
+            retry_at: None,
+            busy_until: None,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // ready is false while the mirror backs off after a failure
+    pub fn ready(&self) -> bool {
+        self.retry_at.is_none_or(|at| Instant::now() >= at)
+    }
+
+    pub fn gave_up(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    // failed backs the mirror off exponentially, a seed that only said it is busy
+    // is retried when it asked to and doesn't count towards giving up
+    pub fn failed(&mut self) {
+        if let Some(at) = self.busy_until.take() {
+            debug!(url = %self.url, "web seed busy");
+            self.retry_at = Some(at);
+            return;
+        }
+        self.failures += 1;
+        let backoff = BACKOFF_BASE.saturating_mul(1 << self.failures.min(16)).min(BACKOFF_MAX);
+        debug!(url = %self.url, failures = self.failures, ?backoff, "web seed backing off");
+        self.retry_at = Some(Instant::now() + backoff);
+    }
+
+    // file_url maps a file of the torrent to its url on the mirror: a single file torrent
+    // is the url itself unless it ends with a slash, otherwise the name and path are appended
+    fn file_url(&self, torrent: &CustomTorrent, file: &TorrentFile) -> String {
+        let single = torrent.files.len() == 1 && file.path == Path::new(&torrent.name);
+        if single && !self.url.ends_with('/') {
+            return self.url.clone();
+        }
+        let mut url = self.url.clone();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let parts = file
+            .path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(urlencoding::encode(&part.to_string_lossy()).into_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        url + &parts.join("/")
+    }
+
+    // fetch_range reads length bytes at offset of one file into buf
+    fn fetch_range(&self, url: &str, offset: usize, buf: &mut [u8], limiters: &[Arc<RateLimiter>]) -> Result<()> {
+        let range = format!("bytes={}-{}", offset, offset + buf.len() - 1);
+        trace!(%url, %range, "web seed request");
+        let resp = self
+            .client
+            .get(url)
+            .header(RANGE, &range)
+            .send()
+            .map_err(|e| Error::web_seed(url, e))?;
+        let mut body = RateLimitedStream::new(resp, limiters.to_vec());
+        match body.get_ref().status() {
+            StatusCode::PARTIAL_CONTENT => {},
+            // 不支持 Range 的服务器返回整个文件，跳过前面的部分
+            StatusCode::OK => {
+                std::io::copy(&mut (&mut body).take(offset as u64), &mut std::io::sink())
+                    .map_err(|e| Error::web_seed(url, e))?;
+            },
+            status => return Err(Error::web_seed(url, format!("status {}", status))),
+        }
+        body.read_exact(buf).map_err(|e| Error::web_seed(url, e))
+    }
+
+    // fetch_piece downloads one piece from the seed, the caller checks its hash
+    pub fn fetch_piece(&mut self, torrent: &CustomTorrent, index: usize, length: usize, limiters: &[Arc<RateLimiter>]) -> Result<Vec<u8>> {
+        match self.kind {
+            WebSeedKind::UrlList => self.fetch_files(torrent, index, length, limiters),
+            WebSeedKind::HttpSeed => self.fetch_http_seed(torrent, index, length, limiters),
+        }
+    }
+
+    // fetch_http_seed asks a BEP 17 seed for one whole piece
+    fn fetch_http_seed(&mut self, torrent: &CustomTorrent, index: usize, length: usize, limiters: &[Arc<RateLimiter>]) -> Result<Vec<u8>> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{}info_hash={}&piece={}&ranges=0-{}",
+            self.url,
+            separator,
+            byte_serialize(&torrent.info_hash).collect::<String>(),
+            index,
+            length - 1,
+        );
+        trace!(%url, "http seed request");
+        let resp = self.client.get(&url).send().map_err(|e| Error::web_seed(&self.url, e))?;
+        match resp.status() {
+            StatusCode::OK => {},
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let wait = retry_after(resp);
+                self.busy_until = Some(Instant::now() + wait);
+                return Err(Error::web_seed(&self.url, format!("busy, retry after {:?}", wait)));
+            },
+            status => return Err(Error::web_seed(&self.url, format!("status {}", status))),
+        }
+        let mut buf = vec![0u8; length];
+        let mut body = RateLimitedStream::new(resp, limiters.to_vec());
+        body.read_exact(&mut buf).map_err(|e| Error::web_seed(&self.url, e))?;
+        Ok(buf)
+    }
+
+    // fetch_files downloads a piece from a url-list mirror, splitting it at file boundaries, pad files are left zero
+    fn fetch_files(&self, torrent: &CustomTorrent, index: usize, length: usize, limiters: &[Arc<RateLimiter>]) -> Result<Vec<u8>> {
+        let begin = index * torrent.piece_length;
+        let end = begin + length;
+        let mut buf = vec![0u8; length];
+        let mut offset = 0;
+        for file in &torrent.files {
+            let (start, stop) = (offset.max(begin), (offset + file.length).min(end));
+            if start < stop && !file.is_pad() {
+                let url = self.file_url(torrent, file);
+                self.fetch_range(&url, start - offset, &mut buf[start - begin..stop - begin], limiters)?;
+            }
+            offset += file.length;
+            if offset >= end {
+                break;
+            }
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::Mutex,
+        thread,
+    };
+
+    use super::*;
+    use crate::bencode::bencode::{Dict, Value};
+
+    fn dict(pairs: Vec<(&str, Value<'static>)>) -> Value<'static> {
+        Value::Dict(pairs.into_iter().map(|(k, v)| (Cow::Owned(k.as_bytes().to_vec()), v)).collect::<Dict>())
+    }
+
+    fn bytes(b: &[u8]) -> Value<'static> {
+        Value::Bytes(Cow::Owned(b.to_vec()))
+    }
+
+    // torrent builds a v1 torrent of the given files with 8 byte pieces, the hashes don't matter here
+    fn torrent(files: &[(&[&str], usize)]) -> CustomTorrent {
+        let total = files.iter().map(|(_, length)| length).sum::<usize>();
+        let mut info = vec![
+            ("name", bytes(b"name")),
+            ("piece length", Value::Int(8)),
+            ("pieces", bytes(&vec![0u8; total.div_ceil(8) * 20])),
+        ];
+        if let [(_, length)] = files {
+            info.push(("length", Value::Int(*length as i64)));
+        } else {
+            let list = files
+                .iter()
+                .map(|(path, length)| {
+                    let path = path.iter().map(|p| bytes(p.as_bytes())).collect();
+                    dict(vec![("length", Value::Int(*length as i64)), ("path", Value::List(path))])
+                })
+                .collect();
+            info.push(("files", Value::List(list)));
+        }
+        CustomTorrent::general_custom_torrent(&dict(vec![("info", dict(info))]).encode()).unwrap()
+    }
+
+    fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut out = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+        for (name, value) in headers {
+            out += &format!("{}: {}\r\n", name, value);
+        }
+        out += "\r\n";
+        [out.into_bytes(), body.to_vec()].concat()
+    }
+
+    // stub answers one connection with each response in turn and records the path and range
+    // header of every request
+    fn stub(responses: Vec<Vec<u8>>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for resp in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    let lower = line.to_ascii_lowercase();
+                    if lower.starts_with("get ") {
+                        request += line.split(' ').nth(1).unwrap();
+                    } else if lower.starts_with("range:") {
+                        request += &format!(" {}", line[6..].trim());
+                    }
+                    line.clear();
+                }
+                seen.lock().unwrap().push(request);
+                stream.write_all(&resp).unwrap();
+            }
+        });
+        (base, requests)
+    }
+
+    #[test]
+    fn file_urls_follow_bep_19() {
+        let single = torrent(&[(&["name"], 5)]);
+        let seed = WebSeed::new("http://host/file.iso", WebSeedKind::UrlList).unwrap();
+        assert_eq!(seed.file_url(&single, &single.files[0]), "http://host/file.iso");
+        let seed = WebSeed::new("http://host/dir/", WebSeedKind::UrlList).unwrap();
+        assert_eq!(seed.file_url(&single, &single.files[0]), "http://host/dir/name");
+
+        let multi = torrent(&[(&["a"], 5), (&["dir", "b c"], 7)]);
+        let seed = WebSeed::new("http://host/seed", WebSeedKind::UrlList).unwrap();
+        assert_eq!(seed.file_url(&multi, &multi.files[1]), "http://host/seed/name/dir/b%20c");
+    }
+
+    #[test]
+    fn pieces_are_split_at_file_boundaries() {
+        let multi = torrent(&[(&["a"], 5), (&["dir", "b c"], 7)]);
+        let (base, requests) = stub(vec![
+            response("206 Partial Content", &[], b"AAAAA"),
+            response("206 Partial Content", &[], b"bcd"),
+            response("206 Partial Content", &[], b"efgh"),
+        ]);
+        let seed = WebSeed::new(&format!("{}/seed", base), WebSeedKind::UrlList).unwrap();
+        assert_eq!(seed.fetch_files(&multi, 0, 8, &[]).unwrap(), b"AAAAAbcd");
+        assert_eq!(seed.fetch_files(&multi, 1, 4, &[]).unwrap(), b"efgh");
+        assert_eq!(
+            *requests.lock().unwrap(),
+            ["/seed/name/a bytes=0-4", "/seed/name/dir/b%20c bytes=0-2", "/seed/name/dir/b%20c bytes=3-6"],
+        );
+    }
+
+    #[test]
+    fn whole_file_answer_is_cut_to_the_range() {
+        let (base, _) = stub(vec![response("200 OK", &[], b"0123456789"), response("404 Not Found", &[], b"")]);
+        let seed = WebSeed::new(&base, WebSeedKind::UrlList).unwrap();
+        let url = format!("{}/file", base);
+        let mut buf = [0u8; 4];
+        seed.fetch_range(&url, 3, &mut buf, &[]).unwrap();
+        assert_eq!(&buf, b"3456");
+        assert!(seed.fetch_range(&url, 0, &mut buf, &[]).is_err());
+    }
+
+    #[test]
+    fn http_seed_sends_bep_17_query() {
+        let single = torrent(&[(&["name"], 12)]);
+        let (base, requests) = stub(vec![response("200 OK", &[], b"efgh")]);
+        let mut seed = WebSeed::new(&format!("{}/seed.php?x=1", base), WebSeedKind::HttpSeed).unwrap();
+        assert_eq!(seed.fetch_piece(&single, 1, 4, &[]).unwrap(), b"efgh");
+        let hash = byte_serialize(&single.info_hash).collect::<String>();
+        assert_eq!(requests.lock().unwrap()[0], format!("/seed.php?x=1&info_hash={}&piece=1&ranges=0-3", hash));
+    }
+
+    #[test]
+    fn busy_http_seed_is_retried_when_it_asks() {
+        let single = torrent(&[(&["name"], 12)]);
+        let (base, _) = stub(vec![
+            response("503 Service Unavailable", &[], b"120"),
+            response("503 Service Unavailable", &[("Retry-After", "30")], b"120"),
+            response("503 Service Unavailable", &[], b"busy"),
+        ]);
+        let mut seed = WebSeed::new(&base, WebSeedKind::HttpSeed).unwrap();
+        for expected in [120, 30, DEFAULT_RETRY_AFTER.as_secs()] {
+            assert!(seed.fetch_piece(&single, 0, 8, &[]).is_err());
+            let wait = seed.busy_until.unwrap().duration_since(Instant::now());
+            assert!(wait <= Duration::from_secs(expected) && wait > Duration::from_secs(expected - 5), "{:?}", wait);
+            seed.failed();
+            assert!(!seed.ready());
+            assert_eq!(seed.failures, 0);
+            assert!(seed.busy_until.is_none());
+        }
+    }
+
+    #[test]
+    fn failing_seed_backs_off_then_gives_up() {
+        let mut seed = WebSeed::new("http://host/", WebSeedKind::UrlList).unwrap();
+        assert!(seed.ready());
+        let mut last = Duration::ZERO;
+        for failures in 1..=MAX_FAILURES {
+            assert!(!seed.gave_up());
+            seed.failed();
+            assert!(!seed.ready());
+            let backoff = seed.retry_at.unwrap().duration_since(Instant::now());
+            assert!(backoff > last && backoff <= BACKOFF_MAX, "{:?} after {} failures", backoff, failures);
+            last = backoff;
+        }
+        assert!(seed.gave_up());
+
+        seed.succeeded();
+        assert!(seed.ready());
+        assert!(!seed.gave_up());
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+
+use rand::{distributions::Alphanumeric, Rng};
+
+// 我们自己的客户端代码
+const CLIENT_CODE: &str = "RT";
+const CLIENT_NAME: &str = env!("CARGO_PKG_NAME");
+pub const PEER_ID_LEN: usize = 20;
+
+// Azureus 风格：-XX1234-，两位客户端代码加四位版本
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("7T", "aTorrent"),
+    ("AG", "Ares"),
+    ("AR", "Arctic"),
+    ("AT", "Artemis"),
+    ("AZ", "Vuze"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BE", "BitTorrent SDK"),
+    ("BF", "Bitflu"),
+    ("BI", "BiglyBT"),
+    ("BL", "BitLord"),
+    ("BT", "BitTorrent"),
+    ("BW", "BitWombat"),
+    ("CD", "Enhanced CTorrent"),
+    ("DE", "Deluge"),
+    ("EB", "EBit"),
+    ("FD", "Free Download Manager"),
+    ("FG", "FlashGet"),
+    ("FL", "Folx"),
+    ("FW", "FrostWire"),
+    ("HL", "Halite"),
+    ("KG", "KGet"),
+    ("KT", "KTorrent"),
+    ("LT", "libTorrent"),
+    ("LW", "LimeWire"),
+    ("MG", "MediaGet"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("RT", CLIENT_NAME),
+    ("SD", "Thunder"),
+    ("ST", "SymTorrent"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("TX", "Tixati"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("VG", "Vagaa"),
+    ("WD", "WebTorrent Desktop"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+    ("ZT", "ZipTorrent"),
+    ("lt", "libtorrent"),
+];
+
+// Shadow 风格：一个字母的客户端代码，最多五位版本，后面用 --- 填充
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+// Fingerprint is the client software a peer id announces
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub client: String,
+    pub version: String,
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.client)
+        } else {
+            write!(f, "{} {}", self.client, self.version)
+        }
+    }
+}
+
+// version_digit decodes one version character, 0-9 then A-Z, a-z, '.' and '-' as in Shadow's scheme
+fn version_digit(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some(u32::from(c - b'0')),
+        b'A'..=b'Z' => Some(u32::from(c - b'A') + 10),
+        b'a'..=b'z' => Some(u32::from(c - b'a') + 36),
+        b'.' => Some(62),
+        b'-' => Some(63),
+        _ => None,
+    }
+}
+
+// join_version prints version numbers with dots, trailing zeros are dropped past major.minor
+fn join_version(mut parts: Vec<u32>) -> String {
+    while parts.len() > 2 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+    parts.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(".")
+}
+
+fn parse_azureus(peer_id: &[u8; 20]) -> Option<Fingerprint> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' || !peer_id[1..3].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = peer_id[3..7].iter().map(|c| version_digit(*c).filter(|_| c.is_ascii_alphanumeric())).collect::<Option<Vec<_>>>()?;
+    let client = AZUREUS_CLIENTS.iter().find(|(c, _)| *c == code).map_or(code, |(_, name)| name);
+    Some(Fingerprint { client: client.to_string(), version: join_version(version) })
+}
+
+fn parse_shadow(peer_id: &[u8; 20]) -> Option<Fingerprint> {
+    let (_, client) = SHADOW_CLIENTS.iter().find(|(c, _)| *c == peer_id[0])?;
+    if &peer_id[6..9] != b"---" {
+        return None;
+    }
+    // 版本在第一个 '-' 处结束
+    let version = peer_id[1..6]
+        .iter()
+        .take_while(|c| **c != b'-')
+        .map(|c| version_digit(*c))
+        .collect::<Option<Vec<_>>>()?;
+    if version.is_empty() {
+        return None;
+    }
+    Some(Fingerprint { client: client.to_string(), version: version.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(".") })
+}
+
+// parse decodes Azureus-style (-XX1234-) and Shadow-style (S58B-----) peer ids, None for anything else
+pub fn parse(peer_id: &[u8; 20]) -> Option<Fingerprint> {
+    parse_azureus(peer_id).or_else(|| parse_shadow(peer_id))
+}
+
+fn version_char(n: u32) -> char {
+    char::from_digit(n.min(35), 36).unwrap().to_ascii_uppercase()
+}
+
+// default_prefix is our Azureus-style prefix built from the crate version, 0.1.0 gives -RT0100-
+pub fn default_prefix() -> String {
+    let version = env!("CARGO_PKG_VERSION")
+        .split(['.', '-', '+'])
+        .take(3)
+        .map(|part| part.parse::<u32>().unwrap_or(0))
+        .chain(std::iter::repeat(0))
+        .take(4)
+        .map(version_char)
+        .collect::<String>();
+    format!("-{}{}-", CLIENT_CODE, version)
+}
+
+// generate builds a peer id from prefix followed by random printable characters,
+// a prefix longer than a peer id is cut off
+pub fn generate(prefix: &str) -> [u8; PEER_ID_LEN] {
+    let mut peer_id = [0u8; PEER_ID_LEN];
+    let prefix = &prefix.as_bytes()[..prefix.len().min(PEER_ID_LEN)];
+    peer_id[..prefix.len()].copy_from_slice(prefix);
+    let mut rng = rand::thread_rng();
+    for b in &mut peer_id[prefix.len()..] {
+        *b = rng.sample(Alphanumeric);
+    }
+    peer_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(prefix: &[u8]) -> [u8; PEER_ID_LEN] {
+        let mut peer_id = [b'x'; PEER_ID_LEN];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        peer_id
+    }
+
+    fn fingerprint(prefix: &[u8]) -> Option<String> {
+        parse(&id(prefix)).map(|f| f.to_string())
+    }
+
+    #[test]
+    fn parses_azureus_style() {
+        assert_eq!(fingerprint(b"-qB4250-").as_deref(), Some("qBittorrent 4.2.5"));
+        assert_eq!(fingerprint(b"-TR3000-").as_deref(), Some("Transmission 3.0"));
+        assert_eq!(fingerprint(b"-lt0D60-").as_deref(), Some("libtorrent 0.13.6"));
+        assert_eq!(fingerprint(b"-DE13F0-").as_deref(), Some("Deluge 1.3.15"));
+        // 不认识的代码原样显示
+        assert_eq!(fingerprint(b"-XX1000-").as_deref(), Some("XX 1.0"));
+    }
+
+    #[test]
+    fn parses_shadow_style() {
+        assert_eq!(fingerprint(b"S58B-----").as_deref(), Some("Shadow 5.8.11"));
+        assert_eq!(fingerprint(b"T03I-----").as_deref(), Some("BitTornado 0.3.18"));
+        assert_eq!(fingerprint(b"A2---").as_deref(), None);
+        assert_eq!(fingerprint(b"A-----").as_deref(), None);
+    }
+
+    #[test]
+    fn rejects_other_ids() {
+        assert_eq!(fingerprint(b"M4-3-6--"), None);
+        assert_eq!(fingerprint(b"-qB42 0-"), None);
+        assert_eq!(fingerprint(b"-\x00\x001000-"), None);
+        assert_eq!(fingerprint(b"-qB4250x"), None);
+        assert_eq!(parse(&[0u8; PEER_ID_LEN]), None);
+    }
+
+    #[test]
+    fn our_ids_decode_to_us() {
+        assert_eq!(default_prefix(), "-RT0100-");
+        let peer_id = generate(&default_prefix());
+        assert_eq!(&peer_id[..8], b"-RT0100-");
+        assert!(peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_eq!(parse(&peer_id), Some(Fingerprint { client: CLIENT_NAME.to_string(), version: "0.1".to_string() }));
+        assert_ne!(generate("-RT0100-"), peer_id);
+        assert_eq!(&generate("-XY1000-0123456789abcdef")[..], b"-XY1000-0123456789ab");
+    }
+}
@@ -1,14 +1,32 @@
-use std::net::{self, SocketAddr, Ipv4Addr, IpAddr};
+use std::net::{self, SocketAddr, IpAddr};
 
-#[derive(Debug)]
+// PeerSource is where we learned about a peer, private torrents only accept some of them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerSource {
+    Tracker,
+    // the peer connected to us
+    Incoming,
+    Dht,
+    Pex,
+    Lsd,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Peer {
-    ip: Ipv4Addr,
+    ip: IpAddr,
     port: u16,
 }
 
 impl Peer {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            ip: addr.ip(),
+            port: addr.port(),
+        }
+    }
+
     pub fn general_address(&self) -> SocketAddr {
-        SocketAddr::new(IpAddr::V4(self.ip), self.port)
+        SocketAddr::new(self.ip, self.port)
     }
 }
 
@@ -16,7 +34,7 @@ pub fn un_marshal(peers_bin: &[u8]) -> Vec<Peer> {
     let peer_size = 6;
     let num_peers = peers_bin.len() / peer_size;
     let mut peers = vec![];
-    if peers_bin.len() % peer_size != 0 {
+    if !peers_bin.len().is_multiple_of(peer_size) {
         return peers;
     }
     for i in 0..num_peers {
@@ -26,9 +44,9 @@ pub fn un_marshal(peers_bin: &[u8]) -> Vec<Peer> {
         let port  = (left << 8) + right;
 
         peers.push(Peer {
-            ip: net::Ipv4Addr::new(peers_bin[offset], peers_bin[offset+1], peers_bin[offset+2], peers_bin[offset+3]),
+            ip: IpAddr::V4(net::Ipv4Addr::new(peers_bin[offset], peers_bin[offset+1], peers_bin[offset+2], peers_bin[offset+3])),
             port,
         })
     }
-    return peers;
+    peers
 }
@@ -0,0 +1,444 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// 单次读写最多处理的字节数，保证限速足够平滑
+const MAX_CHUNK: usize = 16384 + 13;
+// 令牌桶最小容量，至少能放下一个完整的 piece block 消息
+const MIN_BURST: f64 = MAX_CHUNK as f64;
+// 统计速率使用的时间窗口
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateStats {
+    // limit in bytes per second, 0 means unlimited
+    pub limit: u64,
+    // rate measured over the last window, in bytes per second
+    pub rate: f64,
+    // total bytes transferred through the bucket
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TransferStats {
+    pub upload: RateStats,
+    pub download: RateStats,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+    total: u64,
+    window_start: Instant,
+    window_bytes: u64,
+    last_rate: f64,
+}
+
+impl BucketState {
+    fn capacity(&self) -> f64 {
+        (self.rate as f64).max(MIN_BURST)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity());
+    }
+
+    fn record(&mut self, n: usize, now: Instant) {
+        self.total += n as u64;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            self.last_rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+        self.window_bytes += n as u64;
+    }
+
+    fn current_rate(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW * 2 {
+            // 长时间没有流量
+            return 0.0;
+        }
+        if elapsed >= RATE_WINDOW {
+            return self.window_bytes as f64 / elapsed.as_secs_f64();
+        }
+        self.last_rate
+    }
+}
+
+// TokenBucket throttles a byte stream to `rate` bytes per second, 0 means unlimited
+#[derive(Debug)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: (rate as f64).max(MIN_BURST),
+                last_refill: now,
+                total: 0,
+                window_start: now,
+                window_bytes: 0,
+                last_rate: 0.0,
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.state.lock().unwrap().rate
+    }
+
+    // SetRate changes the limit, it takes effect on the next consume
+    pub fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.refill(Instant::now());
+        state.rate = rate;
+        state.tokens = state.tokens.min(state.capacity());
+    }
+
+    // Reserve takes n tokens and returns how long the caller has to wait for the bucket to get out of debt
+    pub fn reserve(&self, n: usize) -> Duration {
+        self.reserve_at(n, Instant::now())
+    }
+
+    fn reserve_at(&self, n: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        state.record(n, now);
+        if state.rate == 0 {
+            return Duration::ZERO;
+        }
+        state.refill(now);
+        state.tokens -= n as f64;
+        if state.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-state.tokens / state.rate as f64)
+    }
+
+    // Consume takes n tokens and sleeps while the bucket is in debt
+    pub fn consume(&self, n: usize) {
+        let wait = self.reserve(n);
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    pub fn stats(&self) -> RateStats {
+        let state = self.state.lock().unwrap();
+        RateStats {
+            limit: state.rate,
+            rate: state.current_rate(Instant::now()),
+            total: state.total,
+        }
+    }
+}
+
+// RateLimiter is a pair of upload and download buckets
+#[derive(Debug)]
+pub struct RateLimiter {
+    pub upload: TokenBucket,
+    pub download: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(upload_limit: u64, download_limit: u64) -> Arc<Self> {
+        Arc::new(Self {
+            upload: TokenBucket::new(upload_limit),
+            download: TokenBucket::new(download_limit),
+        })
+    }
+
+    pub fn unlimited() -> Arc<Self> {
+        Self::new(0, 0)
+    }
+
+    pub fn set_upload_limit(&self, limit: u64) {
+        self.upload.set_rate(limit);
+    }
+
+    pub fn set_download_limit(&self, limit: u64) {
+        self.download.set_rate(limit);
+    }
+
+    pub fn stats(&self) -> TransferStats {
+        TransferStats {
+            upload: self.upload.stats(),
+            download: self.download.stats(),
+        }
+    }
+}
+
+// RateLimits holds the global, per-torrent and per-peer limiters a torrent's connections are charged against
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub global: Arc<RateLimiter>,
+    pub torrent: Arc<RateLimiter>,
+    peer_limits: Arc<Mutex<(u64, u64)>>,
+    peers: Arc<Mutex<HashMap<SocketAddr, Arc<RateLimiter>>>>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self::new(RateLimiter::unlimited())
+    }
+}
+
+impl RateLimits {
+    pub fn new(global: Arc<RateLimiter>) -> Self {
+        Self {
+            global,
+            torrent: RateLimiter::unlimited(),
+            peer_limits: Arc::new(Mutex::new((0, 0))),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn set_torrent_limits(&self, upload_limit: u64, download_limit: u64) {
+        self.torrent.set_upload_limit(upload_limit);
+        self.torrent.set_download_limit(download_limit);
+    }
+
+    // SetPeerLimits changes the default per-peer limits for current and future connections
+    pub fn set_peer_limits(&self, upload_limit: u64, download_limit: u64) {
+        *self.peer_limits.lock().unwrap() = (upload_limit, download_limit);
+        for limiter in self.peers.lock().unwrap().values() {
+            limiter.set_upload_limit(upload_limit);
+            limiter.set_download_limit(download_limit);
+        }
+    }
+
+    // Peer returns the limiter of a single connection, creating it with the default peer limits
+    pub fn peer(&self, addr: SocketAddr) -> Arc<RateLimiter> {
+        let (upload_limit, download_limit) = *self.peer_limits.lock().unwrap();
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_insert_with(|| RateLimiter::new(upload_limit, download_limit))
+            .clone()
+    }
+
+    pub fn remove_peer(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    // Chain returns every limiter a connection to addr has to pass, from the widest to the narrowest
+    pub fn chain(&self, addr: SocketAddr) -> Vec<Arc<RateLimiter>> {
+        vec![self.global.clone(), self.torrent.clone(), self.peer(addr)]
+    }
+
+    pub fn torrent_stats(&self) -> TransferStats {
+        self.torrent.stats()
+    }
+
+    pub fn global_stats(&self) -> TransferStats {
+        self.global.stats()
+    }
+
+    pub fn peer_stats(&self) -> Vec<(SocketAddr, TransferStats)> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, limiter)| (*addr, limiter.stats()))
+            .collect()
+    }
+}
+
+// RateLimitedStream charges every byte read or written against a chain of limiters
+#[derive(Debug)]
+pub struct RateLimitedStream<S> {
+    inner: S,
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl<S> RateLimitedStream<S> {
+    pub fn new(inner: S, limiters: Vec<Arc<RateLimiter>>) -> Self {
+        Self { inner, limiters }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+// charge takes n bytes from one bucket of every limiter, the caller waits for the slowest one
+fn charge(limiters: &[Arc<RateLimiter>], n: usize, bucket: fn(&RateLimiter) -> &TokenBucket, now: Instant) -> Duration {
+    limiters.iter().map(|l| bucket(l).reserve_at(n, now)).max().unwrap_or_default()
+}
+
+impl<S: Read> Read for RateLimitedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_CHUNK);
+        let n = self.inner.read(&mut buf[..len])?;
+        let wait = charge(&self.limiters, n, |l| &l.download, Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for RateLimitedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_CHUNK);
+        let n = self.inner.write(&buf[..len])?;
+        let wait = charge(&self.limiters, n, |l| &l.upload, Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn secs(s: f64) -> Duration {
+        Duration::from_secs_f64(s)
+    }
+
+    // start is the instant the bucket was last refilled, later instants are built from it
+    fn start(bucket: &TokenBucket) -> Instant {
+        bucket.state.lock().unwrap().last_refill
+    }
+
+    fn assert_close(got: Duration, want: Duration) {
+        let diff = got.abs_diff(want);
+        assert!(diff < Duration::from_micros(10), "got {:?}, want {:?}", got, want);
+    }
+
+    #[test]
+    fn debt_is_paid_back_at_the_rate() {
+        let bucket = TokenBucket::new(100_000);
+        let t0 = start(&bucket);
+        // 桶一开始是满的
+        assert_eq!(bucket.reserve_at(100_000, t0), Duration::ZERO);
+        assert_close(bucket.reserve_at(50_000, t0), secs(0.5));
+        // 一秒后补充 100000 个令牌，还清 50000 的欠款
+        assert_eq!(bucket.reserve_at(50_000, t0 + secs(1.0)), Duration::ZERO);
+        assert_close(bucket.reserve_at(10_000, t0 + secs(1.0)), secs(0.1));
+        assert_eq!(bucket.stats().total, 210_000);
+    }
+
+    #[test]
+    fn refill_stops_at_the_burst_size() {
+        let bucket = TokenBucket::new(100_000);
+        let t0 = start(&bucket);
+        assert_eq!(bucket.reserve_at(100_000, t0), Duration::ZERO);
+        // 空闲很久也只攒下一秒的令牌
+        let later = t0 + secs(10.0);
+        assert_eq!(bucket.reserve_at(100_000, later), Duration::ZERO);
+        assert_close(bucket.reserve_at(1_000, later), secs(0.01));
+
+        // 低速率的桶至少能放下一个完整的 block 消息
+        let slow = TokenBucket::new(1_000);
+        let t0 = start(&slow);
+        assert_eq!(slow.reserve_at(MAX_CHUNK, t0), Duration::ZERO);
+        assert_close(slow.reserve_at(500, t0), secs(0.5));
+        assert_eq!(slow.reserve_at(MAX_CHUNK, t0 + secs(100.0)), Duration::ZERO);
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let bucket = TokenBucket::new(0);
+        let t0 = start(&bucket);
+        for _ in 0..10 {
+            assert_eq!(bucket.reserve_at(10 << 20, t0), Duration::ZERO);
+        }
+        assert_eq!(bucket.stats(), RateStats { limit: 0, rate: 0.0, total: 100 << 20 });
+
+        // 限速可以随时打开和关掉，打开时桶里只有最小容量的令牌
+        bucket.set_rate(100_000);
+        let t0 = start(&bucket);
+        assert_eq!(bucket.reserve_at(MAX_CHUNK, t0), Duration::ZERO);
+        assert_close(bucket.reserve_at(100_000, t0), secs(1.0));
+        bucket.set_rate(0);
+        assert_eq!(bucket.reserve_at(1 << 30, t0), Duration::ZERO);
+    }
+
+    #[test]
+    fn the_strictest_limit_in_the_chain_applies() {
+        let chain = vec![RateLimiter::new(0, 1_000_000), RateLimiter::new(200_000, 0), RateLimiter::new(50_000, 0)];
+        let now = Instant::now();
+        // 上传：50000 最严格，它的桶也最小
+        assert_eq!(charge(&chain, 50_000, |l| &l.upload, now), Duration::ZERO);
+        assert_close(charge(&chain, 25_000, |l| &l.upload, now), secs(0.5));
+        // 下载：只有第一级限速
+        assert_eq!(charge(&chain, 1_000_000, |l| &l.download, now), Duration::ZERO);
+        assert_close(charge(&chain, 100_000, |l| &l.download, now), secs(0.1));
+        // 每一级都记下了流量
+        for limiter in &chain {
+            assert_eq!(limiter.stats().upload.total, 75_000);
+            assert_eq!(limiter.stats().download.total, 1_100_000);
+        }
+    }
+
+    #[test]
+    fn chain_goes_from_global_to_peer() {
+        let global = RateLimiter::new(0, 1_000_000);
+        let limits = RateLimits::new(global.clone());
+        limits.set_torrent_limits(200_000, 0);
+        limits.set_peer_limits(50_000, 0);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let chain = limits.chain(addr);
+        assert_eq!(chain.len(), 3);
+        assert!(Arc::ptr_eq(&chain[0], &global));
+        assert!(Arc::ptr_eq(&chain[1], &limits.torrent));
+        assert!(Arc::ptr_eq(&chain[2], &limits.peer(addr)));
+        assert_eq!(chain.iter().map(|l| l.upload.rate()).collect::<Vec<_>>(), vec![0, 200_000, 50_000]);
+
+        // 改默认值也会改已有 peer 的限速，移除的 peer 重新开始计数
+        chain[2].upload.reserve(1_000);
+        limits.set_peer_limits(10_000, 0);
+        assert_eq!(chain[2].upload.rate(), 10_000);
+        assert_eq!(limits.peer_stats(), vec![(addr, chain[2].stats())]);
+        limits.remove_peer(&addr);
+        assert_eq!(limits.peer(addr).stats().upload.total, 0);
+        assert_eq!(limits.peer(addr).upload.rate(), 10_000);
+    }
+
+    #[test]
+    fn stream_waits_for_its_limiters() {
+        // 20000 字节的桶用完后，再多 4000 字节在 20000 B/s 下要等 0.2 秒
+        let limiter = RateLimiter::new(20_000, 20_000);
+        let mut stream = RateLimitedStream::new(Cursor::new(vec![0u8; 2 * MAX_CHUNK]), vec![RateLimiter::unlimited(), limiter.clone()]);
+        let started = Instant::now();
+        stream.write_all(&[1u8; 20_000]).unwrap();
+        assert!(started.elapsed() < secs(0.1));
+        stream.write_all(&[1u8; 4_000]).unwrap();
+        assert!(started.elapsed() >= secs(0.18), "{:?}", started.elapsed());
+        assert_eq!(limiter.stats().upload.total, 24_000);
+
+        // 单次读写不超过 MAX_CHUNK
+        stream.get_mut().set_position(0);
+        let mut buf = vec![0u8; 3 * MAX_CHUNK];
+        assert_eq!(stream.read(&mut buf).unwrap(), MAX_CHUNK);
+        assert_eq!(limiter.stats().download.total, MAX_CHUNK as u64);
+    }
+}
@@ -0,0 +1,1191 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    net::{IpAddr, SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, info_span, warn};
+
+use crate::{
+    error::error::{Error, ErrorKind, Result},
+    events::events::{Event, EventBus, EventKind, EventSender},
+    handshake::handshake,
+    p2p::p2p::{ConnectionLimit, ConnectionSlot, IncomingPeer, P2pTorrent},
+    peers::peers::Peer,
+    mse::mse::{self, EncryptionPolicy, MseStream},
+    client::transport::PeerStream,
+    utp::utp::UtpSocket,
+    dht::dht::DhtNode,
+    ipfilter::ipfilter::IpFilter,
+    peer_id::peer_id,
+    peers::peers::PeerSource,
+    ratelimit::ratelimit::{RateLimiter, RateLimits, TransferStats},
+    bitfield::bitfield::{self, Bitfield},
+    storage::{
+        disk_io::DiskIo,
+        recheck::{self, RecheckResult},
+        storage::Storage,
+    },
+    torrent_file::{
+        torrent_file::CustomTorrent,
+        tracker::{AnnounceEvent, AnnounceStats},
+    },
+};
+
+// 两次向 tracker 请求 peers 之间的等待时间
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub type TorrentId = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentState {
+    // waiting for a download slot
+    Queued,
+    // hashing the data already on disk
+    Checking,
+    Downloading,
+    // complete, waiting for a seed slot
+    Finished,
+    Seeding,
+    Paused,
+    // complete and done seeding, a seed limit with SeedLimitAction::Stop was reached
+    Stopped,
+    Error(ErrorKind, String),
+}
+
+// SeedLimitAction is what happens to a torrent that reached one of its seed limits
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SeedLimitAction {
+    // pause it like pause_torrent, resuming seeds again and the limits count from there
+    #[default]
+    Pause,
+    // move it to Stopped, it keeps no seed slot until resumed
+    Stop,
+}
+
+// SeedLimits end seeding once a torrent uploaded ratio times its size, seeded for seeding_time
+// or uploaded nothing for idle_time, None leaves a limit out
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SeedLimits {
+    pub ratio: Option<f64>,
+    pub seeding_time: Option<Duration>,
+    pub idle_time: Option<Duration>,
+    pub action: SeedLimitAction,
+}
+
+impl SeedLimits {
+    // reached tells whether a torrent with these numbers went over any of the limits
+    pub fn reached(&self, ratio: f64, seeding_time: Duration, idle_time: Duration) -> bool {
+        self.ratio.is_some_and(|max| ratio >= max)
+            || self.seeding_time.is_some_and(|max| seeding_time >= max)
+            || self.idle_time.is_some_and(|max| idle_time >= max)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionSettings {
+    // 0 lets the system pick a free port
+    pub listen_port: u16,
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    pub max_active_downloads: usize,
+    pub max_active_seeds: usize,
+    // bytes per second, 0 means unlimited
+    pub upload_limit: u64,
+    pub download_limit: u64,
+    pub disk_threads: usize,
+    pub encryption: EncryptionPolicy,
+    // accept uTP on the listen port and try it before TCP when connecting
+    pub enable_utp: bool,
+    // run a DHT node and swap node ports with peers that run one too
+    pub enable_dht: bool,
+    // UDP port of the DHT node, 0 lets the system pick a free port
+    pub dht_port: u16,
+    // blocklist in eMule dat, P2P or CIDR format, see Session::ip_filter to reload it
+    pub ip_filter: Option<PathBuf>,
+    // the address peers see us at, used to rank candidate peers (BEP 40)
+    pub external_ip: Option<IpAddr>,
+    // when seeding ends for torrents without their own limits, see Session::set_seed_limits
+    pub seed_limits: SeedLimits,
+    // start of our peer id, see with_peer_id_prefix
+    pub peer_id_prefix: String,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            listen_port: 6881,
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            upload_limit: 0,
+            download_limit: 0,
+            disk_threads: 2,
+            encryption: EncryptionPolicy::Enabled,
+            enable_utp: true,
+            enable_dht: true,
+            dht_port: 6882,
+            ip_filter: None,
+            external_ip: None,
+            seed_limits: SeedLimits::default(),
+            peer_id_prefix: peer_id::default_prefix(),
+        }
+    }
+}
+
+impl SessionSettings {
+    // WithPeerIdPrefix lets an embedder announce itself, e.g. "-XY1000-", the rest of the id stays random
+    pub fn with_peer_id_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.peer_id_prefix = prefix.into();
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub id: TorrentId,
+    pub name: String,
+    pub info_hash: [u8; 20],
+    pub state: TorrentState,
+    pub pieces_done: usize,
+    pub num_pieces: usize,
+    pub rates: TransferStats,
+    // uploaded bytes over the torrent size, or over the downloaded bytes when that's more
+    pub ratio: f64,
+    // summed over every time the torrent seeded
+    pub seeding_time: Duration,
+}
+
+struct TorrentEntry {
+    torrent: Arc<CustomTorrent>,
+    storage: Arc<Storage>,
+    state: TorrentState,
+    have: Arc<Mutex<Vec<bool>>>,
+    limits: RateLimits,
+    stop: Arc<AtomicBool>,
+    incoming: Option<Sender<IncomingPeer>>,
+    thread: Option<JoinHandle<()>>,
+    events: EventSender,
+    // overrides SessionSettings::seed_limits, shared with the seed thread
+    seed_limits: Arc<Mutex<Option<SeedLimits>>>,
+    // seeding time of the runs that ended, the current run started at seeding_since
+    seeded: Duration,
+    seeding_since: Option<Instant>,
+    // the download finished in this session, the next seed run announces completed
+    completed: bool,
+    seed_base: SeedBase,
+    // the last seed run ended at a seed limit
+    limit_reached: bool,
+}
+
+// ratio divides the uploaded bytes by the downloaded ones, at least the torrent size so data
+// that was on disk before counts as downloaded
+fn ratio(uploaded: u64, downloaded: u64, length: usize) -> f64 {
+    uploaded as f64 / downloaded.max(length as u64).max(1) as f64
+}
+
+// SeedBase is where the seed limits count from. Resuming a torrent that reached a limit moves
+// it to the current totals, otherwise the torrent would stop again right away
+#[derive(Debug, Clone, Copy, Default)]
+struct SeedBase {
+    uploaded: u64,
+    seeded: Duration,
+}
+
+impl SeedBase {
+    // counted is the part of the uploaded bytes and seeding time the limits look at
+    fn counted(&self, uploaded: u64, seeded: Duration) -> (u64, Duration) {
+        (uploaded.saturating_sub(self.uploaded), seeded.saturating_sub(self.seeded))
+    }
+}
+
+impl TorrentEntry {
+    fn is_complete(&self) -> bool {
+        self.have.lock().unwrap().iter().all(|h| *h)
+    }
+
+    fn status(&self, id: TorrentId) -> TorrentStatus {
+        let have = self.have.lock().unwrap();
+        let rates = self.limits.torrent_stats();
+        TorrentStatus {
+            id,
+            name: self.torrent.name.clone(),
+            info_hash: self.torrent.info_hash,
+            state: self.state.clone(),
+            pieces_done: have.iter().filter(|h| **h).count(),
+            num_pieces: have.len(),
+            ratio: ratio(rates.upload.total, rates.download.total, self.torrent.length),
+            rates,
+            seeding_time: self.seeded + self.seeding_since.map(|since| since.elapsed()).unwrap_or_default(),
+        }
+    }
+
+    // end_seeding adds the time of the seed run that just ended
+    fn end_seeding(&mut self) {
+        if let Some(since) = self.seeding_since.take() {
+            self.seeded += since.elapsed();
+        }
+    }
+
+    // job hands a new runner thread what it needs, a running one should be stopped before
+    fn job(&mut self, settings: &SessionSettings) -> DownloadJob {
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        self.stop = stop.clone();
+        self.incoming = Some(tx);
+        DownloadJob {
+            torrent: self.torrent.clone(),
+            storage: self.storage.clone(),
+            have: self.have.clone(),
+            limits: self.limits.clone(),
+            events: self.events.clone(),
+            stop,
+            incoming: rx,
+            max_peers: settings.max_connections_per_torrent,
+            encryption: settings.encryption,
+            external_ip: settings.external_ip,
+            seed_limits: self.seed_limits.clone(),
+            seeded: self.seeded,
+            completed: self.completed,
+            seed_base: self.seed_base,
+        }
+    }
+
+    fn set_state(&mut self, state: TorrentState) {
+        if self.state == state {
+            return;
+        }
+        let from = std::mem::replace(&mut self.state, state.clone());
+        debug!(torrent = self.events.torrent(), ?from, to = ?state, "state changed");
+        self.events.emit(EventKind::StateChanged { from, to: state });
+    }
+
+    // stop_runner asks the running thread to stop, the handle stays until the thread is reaped or joined
+    fn stop_runner(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.incoming = None;
+    }
+}
+
+// DownloadJob is everything a torrent's download or seed thread needs
+struct DownloadJob {
+    torrent: Arc<CustomTorrent>,
+    storage: Arc<Storage>,
+    have: Arc<Mutex<Vec<bool>>>,
+    limits: RateLimits,
+    events: EventSender,
+    stop: Arc<AtomicBool>,
+    incoming: Receiver<IncomingPeer>,
+    max_peers: usize,
+    encryption: EncryptionPolicy,
+    external_ip: Option<IpAddr>,
+    seed_limits: Arc<Mutex<Option<SeedLimits>>>,
+    // seeding time before this run
+    seeded: Duration,
+    completed: bool,
+    seed_base: SeedBase,
+}
+
+impl DownloadJob {
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    // left is the number of bytes of the pieces we don't have
+    fn left(&self) -> u64 {
+        let have = self.have.lock().unwrap();
+        let torrent = &self.torrent;
+        let missing = have.iter().enumerate().filter(|(_, h)| !**h).map(|(index, _)| {
+            let begin = index * torrent.piece_length;
+            (begin + torrent.piece_length).min(torrent.length) - begin
+        });
+        missing.sum::<usize>() as u64
+    }
+
+    fn announce_stats(&self, event: Option<AnnounceEvent>) -> AnnounceStats {
+        let stats = self.limits.torrent_stats();
+        AnnounceStats { uploaded: stats.upload.total, downloaded: stats.download.total, left: self.left(), event }
+    }
+}
+
+// mark_piece records a piece that is on disk and reports the files it completed
+fn mark_piece(have: &Mutex<Vec<bool>>, storage: &Storage, events: &EventSender, index: usize) {
+    let mut have = have.lock().unwrap();
+    have[index] = true;
+    for file in 0..storage.num_files() {
+        let pieces = storage.file_pieces(file);
+        if !storage.is_pad(file) && pieces.contains(&index) && have[pieces].iter().all(|h| *h) {
+            events.emit(EventKind::FileCompleted { path: storage.file_path(file) });
+        }
+    }
+}
+
+struct SessionInner {
+    settings: Mutex<SessionSettings>,
+    torrents: Mutex<BTreeMap<TorrentId, TorrentEntry>>,
+    next_id: AtomicUsize,
+    peer_id: [u8; 20],
+    // tracker key, sent with every announce of this session
+    key: u32,
+    listen_port: u16,
+    // uTP socket on the listen port, None when disabled or the port is taken
+    utp: Option<Arc<UtpSocket>>,
+    // None when disabled or the DHT port is taken
+    dht: Option<Arc<DhtNode>>,
+    ip_filter: Arc<IpFilter>,
+    limiter: Arc<RateLimiter>,
+    connections: Arc<ConnectionLimit>,
+    disk: Arc<DiskIo>,
+    events: Arc<EventBus>,
+    shutdown: AtomicBool,
+}
+
+// Session runs many torrents at once, sharing one listen socket, the connection and rate limits and the disk threads
+pub struct Session {
+    inner: Arc<SessionInner>,
+    accept_threads: Vec<JoinHandle<()>>,
+}
+
+impl Session {
+    pub fn new(settings: SessionSettings) -> Result<Self> {
+        let ip_filter = Arc::new(match &settings.ip_filter {
+            Some(path) => IpFilter::from_file(path)?,
+            None => IpFilter::default(),
+        });
+
+        let listener = TcpListener::bind(("0.0.0.0", settings.listen_port))?;
+        listener.set_nonblocking(true)?;
+        let listen_port = listener.local_addr()?.port();
+
+        // uTP 和 TCP 共用同一个端口号
+        let utp = if settings.enable_utp {
+            UtpSocket::bind(("0.0.0.0", listen_port))
+                .inspect_err(|e| warn!(error = %e, "uTP disabled"))
+                .ok()
+                .map(Arc::new)
+        } else {
+            None
+        };
+
+        let dht = if settings.enable_dht {
+            DhtNode::bind(("0.0.0.0", settings.dht_port))
+                .inspect_err(|e| warn!(error = %e, "DHT disabled"))
+                .ok()
+                .map(Arc::new)
+        } else {
+            None
+        };
+        if let Some(dht) = &dht {
+            dht.set_ip_filter(ip_filter.clone());
+        }
+
+        // 整个会话使用同一个 peer id
+        let peer_id = peer_id::generate(&settings.peer_id_prefix);
+
+        let inner = Arc::new(SessionInner {
+            limiter: RateLimiter::new(settings.upload_limit, settings.download_limit),
+            connections: ConnectionLimit::new(settings.max_connections),
+            disk: DiskIo::new(settings.disk_threads),
+            events: EventBus::new(),
+            settings: Mutex::new(settings),
+            torrents: Mutex::new(BTreeMap::new()),
+            next_id: AtomicUsize::new(1),
+            peer_id,
+            key: rand::random(),
+            listen_port,
+            utp,
+            dht,
+            ip_filter,
+            shutdown: AtomicBool::new(false),
+        });
+
+        let accept_inner = inner.clone();
+        let mut accept_threads = vec![thread::spawn(move || accept_inner.accept_loop(listener))];
+        if inner.utp.is_some() {
+            let utp_inner = inner.clone();
+            accept_threads.push(thread::spawn(move || utp_inner.utp_accept_loop()));
+        }
+
+        Ok(Self {
+            inner,
+            accept_threads,
+        })
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.inner.listen_port
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.inner.peer_id
+    }
+
+    // IpFilter is the session's blocklist, reload it or read its counters while the session runs
+    pub fn ip_filter(&self) -> &Arc<IpFilter> {
+        &self.inner.ip_filter
+    }
+
+    // DhtPort is the UDP port of our DHT node, None when it isn't running
+    pub fn dht_port(&self) -> Option<u16> {
+        self.inner.dht.as_ref().map(|dht| dht.port())
+    }
+
+    // Subscribe returns a receiver for every event of every torrent emitted from now on
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.inner.events.subscribe()
+    }
+
+    // AddTorrent queues a torrent, its files are stored below save_path
+    pub fn add_torrent(&self, torrent: CustomTorrent, save_path: &Path) -> TorrentId {
+        let resume = bitfield::new_bitfield(torrent.num_pieces());
+        self.add_torrent_with_resume(torrent, save_path, &resume)
+    }
+
+    // AddTorrentWithResume adds a torrent whose pieces in resume are already on disk, usually the
+    // bitfield of a recheck, only the missing pieces get downloaded
+    pub fn add_torrent_with_resume(&self, torrent: CustomTorrent, save_path: &Path, resume: &Bitfield) -> TorrentId {
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        let storage = Arc::new(Storage::for_torrent(&torrent, save_path));
+        let have = (0..torrent.num_pieces()).map(|i| bitfield::has_piece(resume, i)).collect::<Vec<_>>();
+        let state = if have.iter().all(|h| *h) { TorrentState::Finished } else { TorrentState::Queued };
+        let entry = TorrentEntry {
+            have: Arc::new(Mutex::new(have)),
+            torrent: Arc::new(torrent),
+            storage,
+            state,
+            limits: RateLimits::new(self.inner.limiter.clone()),
+            stop: Arc::new(AtomicBool::new(false)),
+            incoming: None,
+            thread: None,
+            events: EventSender::new(self.inner.events.clone(), id),
+            seed_limits: Arc::new(Mutex::new(None)),
+            seeded: Duration::ZERO,
+            seeding_since: None,
+            completed: false,
+            seed_base: SeedBase::default(),
+            limit_reached: false,
+        };
+        self.inner.torrents.lock().unwrap().insert(id, entry);
+        self.inner.apply_queue();
+        id
+    }
+
+    // RemoveTorrent stops the torrent and forgets it, downloaded data stays on disk
+    pub fn remove_torrent(&self, id: TorrentId) -> bool {
+        let entry = self.inner.torrents.lock().unwrap().remove(&id);
+        let mut entry = match entry {
+            Some(entry) => entry,
+            None => return false,
+        };
+        entry.stop_runner();
+        if let Some(thread) = entry.thread.take() {
+            thread.join().ok();
+        }
+        entry.storage.close();
+        self.inner.apply_queue();
+        true
+    }
+
+    pub fn pause_torrent(&self, id: TorrentId) -> bool {
+        let found = {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            match torrents.get_mut(&id) {
+                Some(entry) => {
+                    entry.stop_runner();
+                    entry.set_state(TorrentState::Paused);
+                    true
+                },
+                None => false,
+            }
+        };
+        self.inner.apply_queue();
+        found
+    }
+
+    pub fn resume_torrent(&self, id: TorrentId) -> bool {
+        let found = {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            match torrents.get_mut(&id) {
+                Some(entry) => {
+                    if matches!(entry.state, TorrentState::Paused | TorrentState::Stopped | TorrentState::Error(..)) {
+                        if std::mem::take(&mut entry.limit_reached) {
+                            entry.seed_base = SeedBase { uploaded: entry.limits.torrent_stats().upload.total, seeded: entry.seeded };
+                        }
+                        let state = if entry.is_complete() { TorrentState::Finished } else { TorrentState::Queued };
+                        entry.set_state(state);
+                    }
+                    true
+                },
+                None => false,
+            }
+        };
+        self.inner.apply_queue();
+        found
+    }
+
+    // RecheckTorrent stops the torrent, hashes its data on disk and continues from the verified
+    // pieces, it blocks until the check is done
+    pub fn recheck_torrent(&self, id: TorrentId) -> Option<RecheckResult> {
+        let (torrent, save_path, thread, paused) = {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            let entry = torrents.get_mut(&id)?;
+            entry.stop_runner();
+            let paused = entry.state == TorrentState::Paused;
+            entry.set_state(TorrentState::Checking);
+            (entry.torrent.clone(), entry.storage.root().to_path_buf(), entry.thread.take(), paused)
+        };
+        if let Some(thread) = thread {
+            thread.join().ok();
+        }
+
+        let result = recheck::recheck(&torrent, &save_path, 0);
+        {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            let entry = torrents.get_mut(&id)?;
+            // 之前打开的句柄可能指向已经被替换的文件
+            entry.storage.close();
+            *entry.have.lock().unwrap() = result.have();
+            let state = match (paused, result.is_complete()) {
+                (true, _) => TorrentState::Paused,
+                (false, true) => TorrentState::Finished,
+                (false, false) => TorrentState::Queued,
+            };
+            entry.set_state(state);
+        }
+        self.inner.apply_queue();
+        Some(result)
+    }
+
+    pub fn torrent_status(&self, id: TorrentId) -> Option<TorrentStatus> {
+        self.inner.reap_threads();
+        self.inner.torrents.lock().unwrap().get(&id).map(|entry| entry.status(id))
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        self.inner.reap_threads();
+        self.inner
+            .torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, entry)| entry.status(*id))
+            .collect()
+    }
+
+    // TorrentLimits returns the limiters of one torrent so its torrent and per-peer limits can be changed
+    pub fn torrent_limits(&self, id: TorrentId) -> Option<RateLimits> {
+        self.inner.torrents.lock().unwrap().get(&id).map(|entry| entry.limits.clone())
+    }
+
+    // SetSeedLimits gives a torrent its own seed limits, None falls back to the session's. A
+    // seeding torrent checks them right away
+    pub fn set_seed_limits(&self, id: TorrentId, limits: Option<SeedLimits>) -> bool {
+        match self.inner.torrents.lock().unwrap().get(&id) {
+            Some(entry) => {
+                *entry.seed_limits.lock().unwrap() = limits;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn set_upload_limit(&self, limit: u64) {
+        self.inner.settings.lock().unwrap().upload_limit = limit;
+        self.inner.limiter.set_upload_limit(limit);
+    }
+
+    pub fn set_download_limit(&self, limit: u64) {
+        self.inner.settings.lock().unwrap().download_limit = limit;
+        self.inner.limiter.set_download_limit(limit);
+    }
+
+    pub fn rate_stats(&self) -> TransferStats {
+        self.inner.limiter.stats()
+    }
+
+    pub fn num_connections(&self) -> usize {
+        self.inner.connections.current()
+    }
+
+    pub fn settings(&self) -> SessionSettings {
+        self.inner.settings.lock().unwrap().clone()
+    }
+
+    // ApplySettings changes limits and queue sizes, the listen port and disk threads are fixed at creation
+    pub fn apply_settings(&self, settings: SessionSettings) {
+        self.inner.limiter.set_upload_limit(settings.upload_limit);
+        self.inner.limiter.set_download_limit(settings.download_limit);
+        self.inner.connections.set_max(settings.max_connections);
+        *self.inner.settings.lock().unwrap() = settings;
+        self.inner.apply_queue();
+    }
+
+    pub fn shutdown(&mut self) {
+        if self.inner.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let threads = self
+            .inner
+            .torrents
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter_map(|entry| {
+                entry.stop_runner();
+                entry.thread.take()
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().ok();
+        }
+        for thread in self.accept_threads.drain(..) {
+            thread.join().ok();
+        }
+        self.inner.disk.shutdown();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl SessionInner {
+    // reap_threads marks torrents whose runner died without reporting back
+    fn reap_threads(&self) {
+        let mut torrents = self.torrents.lock().unwrap();
+        for entry in torrents.values_mut() {
+            if !entry.thread.as_ref().is_some_and(|t| t.is_finished()) {
+                continue;
+            }
+            let thread = entry.thread.take().unwrap();
+            entry.end_seeding();
+            if thread.join().is_err() {
+                match entry.state {
+                    TorrentState::Downloading => entry.set_state(TorrentState::Error(ErrorKind::Other, "download thread panicked".to_string())),
+                    TorrentState::Seeding => entry.set_state(TorrentState::Error(ErrorKind::Other, "seed thread panicked".to_string())),
+                    _ => {},
+                }
+            }
+        }
+    }
+
+    // apply_queue starts and stops torrents so the number of active downloads and seeds stays within the settings
+    fn apply_queue(self: &Arc<Self>) {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        self.reap_threads();
+        let settings = self.settings.lock().unwrap().clone();
+        let mut torrents = self.torrents.lock().unwrap();
+
+        let mut downloading = 0;
+        let mut seeding = 0;
+        for entry in torrents.values_mut() {
+            match entry.state {
+                TorrentState::Downloading => {
+                    if downloading < settings.max_active_downloads {
+                        downloading += 1;
+                    } else {
+                        entry.stop_runner();
+                        entry.set_state(TorrentState::Queued);
+                    }
+                },
+                TorrentState::Seeding => {
+                    if seeding < settings.max_active_seeds {
+                        seeding += 1;
+                    } else {
+                        entry.stop_runner();
+                        entry.set_state(TorrentState::Finished);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        for (id, entry) in torrents.iter_mut() {
+            match entry.state {
+                TorrentState::Queued if downloading < settings.max_active_downloads => {
+                    if entry.thread.is_some() {
+                        // 上一个线程还没有退出
+                        continue;
+                    }
+                    self.start_download(*id, entry, &settings);
+                    downloading += 1;
+                },
+                TorrentState::Finished if seeding < settings.max_active_seeds => {
+                    if entry.thread.is_some() {
+                        continue;
+                    }
+                    self.start_seed(*id, entry, &settings);
+                    seeding += 1;
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn start_download(self: &Arc<Self>, id: TorrentId, entry: &mut TorrentEntry, settings: &SessionSettings) {
+        entry.set_state(TorrentState::Downloading);
+        let job = entry.job(settings);
+        let inner = self.clone();
+        entry.thread = Some(thread::spawn(move || {
+            let res = inner.run_download(&job);
+            inner.finish_download(id, res);
+        }));
+    }
+
+    // p2p_torrent sets up a download or seed run of job with the session's sockets and limits
+    fn p2p_torrent(&self, job: &DownloadJob) -> P2pTorrent {
+        let mut p2p_torrent = P2pTorrent::general_p2p_torrent(&job.torrent, vec![], self.peer_id, job.limits.clone());
+        p2p_torrent.set_ip_filter(self.ip_filter.clone());
+        p2p_torrent.set_stop_flag(job.stop.clone());
+        p2p_torrent.set_connection_limits(self.connections.clone(), job.max_peers);
+        p2p_torrent.set_events(job.events.clone());
+        p2p_torrent.set_encryption(job.encryption);
+        if let Some(ip) = job.external_ip {
+            p2p_torrent.set_external_ip(ip);
+        }
+        if let Some(utp) = &self.utp {
+            p2p_torrent.set_utp(utp.clone());
+        }
+        if let Some(dht) = &self.dht {
+            p2p_torrent.set_dht(dht.clone());
+        }
+        p2p_torrent
+    }
+
+    // announce reports the job's transfer to the tracker and returns the peers of every swarm it answered for
+    fn announce(&self, job: &DownloadJob, event: Option<AnnounceEvent>) -> Vec<([u8; 20], Vec<Peer>)> {
+        let torrent = &job.torrent;
+        let stats = job.announce_stats(event);
+        let mut swarms = vec![];
+        // hybrid torrent 同时加入 v1 和 v2 两个 swarm
+        for info_hash in torrent.swarm_hashes() {
+            match torrent.announce(&info_hash, &self.peer_id, self.key, self.listen_port, &stats) {
+                Ok(peers) => {
+                    job.events.emit(EventKind::TrackerReply { url: torrent.announce.clone(), peers: peers.len() });
+                    swarms.push((info_hash, peers));
+                },
+                Err(e) => {
+                    // tracker 出错时只接受入站连接，稍后重试
+                    warn!(error = %e, "announce failed");
+                    job.events.emit(EventKind::TrackerError { url: torrent.announce.clone(), message: e.to_string() });
+                },
+            }
+        }
+        swarms
+    }
+
+    fn run_download(&self, job: &DownloadJob) -> Result<bool> {
+        let torrent = &job.torrent;
+        let _span = info_span!("torrent", id = job.events.torrent(), info_hash = %hex::encode(torrent.info_hash), name = %torrent.name).entered();
+        let mut event = Some(AnnounceEvent::Started);
+        // 整个任务共用一个 P2pTorrent，候选 peer 的失败次数和退避时间在重新 announce 后保留
+        let p2p_torrent = self.p2p_torrent(job);
+        while !job.stopped() {
+            for (info_hash, peers) in self.announce(job, event.take()) {
+                p2p_torrent.add_peers(info_hash, peers, PeerSource::Tracker);
+            }
+
+            let snapshot = job.have.lock().unwrap().clone();
+            let (done_tx, done_rx) = mpsc::channel::<(usize, Result<()>)>();
+            let (have, storage, events) = (&job.have, &job.storage, &job.events);
+            let written = thread::scope(|s| {
+                // piece 一写入磁盘就记录，进度、文件完成事件和 announce 的 left 在下载时就更新
+                let marker = s.spawn(move || {
+                    let mut written = Ok(());
+                    for (index, res) in done_rx {
+                        match res {
+                            Ok(()) => mark_piece(have, storage, events, index),
+                            Err(e) => written = written.and(Err(e)),
+                        }
+                    }
+                    written
+                });
+                p2p_torrent.download_pieces(&snapshot, Some(&job.incoming), move |index, data| {
+                    self.disk.write_piece(storage, index, data, done_tx.clone());
+                });
+                marker.join().unwrap()
+            });
+            written?;
+            job.storage.flush()?;
+
+            if job.have.lock().unwrap().iter().all(|h| *h) {
+                job.storage.apply_attributes()?;
+                info!("下载完成");
+                return Ok(true);
+            }
+            let mut waited = Duration::ZERO;
+            while waited < REANNOUNCE_INTERVAL && !job.stopped() {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                waited += ACCEPT_POLL_INTERVAL;
+            }
+        }
+        Ok(false)
+    }
+
+    fn finish_download(self: &Arc<Self>, id: TorrentId, res: Result<bool>) {
+        {
+            let mut torrents = self.torrents.lock().unwrap();
+            if let Some(entry) = torrents.get_mut(&id) {
+                // 当前线程马上退出，不需要再等待
+                entry.thread.take();
+                if entry.state == TorrentState::Downloading {
+                    entry.incoming = None;
+                    let state = match res {
+                        Ok(true) => {
+                            entry.completed = true;
+                            TorrentState::Finished
+                        },
+                        Ok(false) => TorrentState::Queued,
+                        Err(e) => {
+                            if let Error::Storage { .. } = e {
+                                entry.events.emit(EventKind::StorageError { message: e.to_string() });
+                            }
+                            TorrentState::Error(e.kind(), e.to_string())
+                        },
+                    };
+                    entry.set_state(state);
+                }
+            }
+        }
+        self.apply_queue();
+    }
+
+    fn start_seed(self: &Arc<Self>, id: TorrentId, entry: &mut TorrentEntry, settings: &SessionSettings) {
+        entry.set_state(TorrentState::Seeding);
+        let job = entry.job(settings);
+        entry.completed = false;
+        entry.seeding_since = Some(Instant::now());
+        let inner = self.clone();
+        entry.thread = Some(thread::spawn(move || {
+            let res = inner.run_seed(&job);
+            inner.finish_seed(id, res);
+        }));
+    }
+
+    // seed_limits are the torrent's own limits or the session's
+    fn seed_limits(&self, job: &DownloadJob) -> SeedLimits {
+        let own = *job.seed_limits.lock().unwrap();
+        own.unwrap_or_else(|| self.settings.lock().unwrap().seed_limits)
+    }
+
+    // run_seed uploads until the stop flag is set or a seed limit is reached, it returns the
+    // action of the reached limit. The tracker hears stopped however the run ends
+    fn run_seed(&self, job: &DownloadJob) -> Result<Option<SeedLimitAction>> {
+        let torrent = &job.torrent;
+        let _span = info_span!("torrent", id = job.events.torrent(), info_hash = %hex::encode(torrent.info_hash), name = %torrent.name).entered();
+        if !job.completed {
+            // 从恢复数据或重新校验直接完成的 torrent 没有经过 run_download
+            job.storage.apply_attributes()?;
+        }
+        let p2p_torrent = self.p2p_torrent(job);
+        let add_peers = |swarms: Vec<([u8; 20], Vec<Peer>)>| {
+            for (info_hash, peers) in swarms {
+                p2p_torrent.add_peers(info_hash, peers, PeerSource::Tracker);
+            }
+        };
+        let event = if job.completed { AnnounceEvent::Completed } else { AnnounceEvent::Started };
+        add_peers(self.announce(job, Some(event)));
+
+        let started = Instant::now();
+        let mut announced = Instant::now();
+        let mut uploaded = job.limits.torrent_stats().upload.total;
+        let mut last_upload = Instant::now();
+        let mut reached = None;
+        let storage = &job.storage;
+        let read_piece = |index: usize| storage.read_piece(index);
+        p2p_torrent.seed(Some(&job.incoming), &read_piece, || {
+            if announced.elapsed() >= REANNOUNCE_INTERVAL {
+                add_peers(self.announce(job, None));
+                announced = Instant::now();
+            }
+            let stats = job.limits.torrent_stats();
+            if stats.upload.total > uploaded {
+                uploaded = stats.upload.total;
+                last_upload = Instant::now();
+            }
+            let limits = self.seed_limits(job);
+            let (counted_upload, seeding_time) = job.seed_base.counted(stats.upload.total, job.seeded + started.elapsed());
+            let ratio = ratio(counted_upload, stats.download.total, torrent.length);
+            if limits.reached(ratio, seeding_time, last_upload.elapsed()) {
+                reached = Some(limits.action);
+            }
+            reached.is_none()
+        });
+        if let Some(action) = reached {
+            info!(?action, "seed limit reached");
+        }
+        self.announce(job, Some(AnnounceEvent::Stopped));
+        Ok(reached)
+    }
+
+    fn finish_seed(self: &Arc<Self>, id: TorrentId, res: Result<Option<SeedLimitAction>>) {
+        {
+            let mut torrents = self.torrents.lock().unwrap();
+            if let Some(entry) = torrents.get_mut(&id) {
+                entry.thread.take();
+                entry.end_seeding();
+                if entry.state == TorrentState::Seeding {
+                    entry.incoming = None;
+                    entry.limit_reached = matches!(res, Ok(Some(_)));
+                    let state = match res {
+                        Ok(Some(SeedLimitAction::Pause)) => TorrentState::Paused,
+                        Ok(Some(SeedLimitAction::Stop)) => TorrentState::Stopped,
+                        Ok(None) => TorrentState::Finished,
+                        Err(e) => {
+                            if let Error::Storage { .. } = e {
+                                entry.events.emit(EventKind::StorageError { message: e.to_string() });
+                            }
+                            TorrentState::Error(e.kind(), e.to_string())
+                        },
+                    };
+                    entry.set_state(state);
+                }
+            }
+        }
+        self.apply_queue();
+    }
+
+    fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    // 在任何握手之前丢弃被屏蔽的地址
+                    if !self.ip_filter.allows(addr.ip(), PeerSource::Incoming) {
+                        continue;
+                    }
+                    let slot = match self.connections.try_acquire() {
+                        Some(slot) => slot,
+                        None => continue,
+                    };
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        debug!(%addr, error = %e, "incoming connection dropped");
+                        continue;
+                    }
+                    let inner = self.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = inner.route_incoming(PeerStream::Tcp(stream), addr, slot) {
+                            debug!(%addr, error = %e, "incoming connection rejected");
+                        }
+                    });
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                Err(e) => {
+                    warn!(error = %e, "accept error");
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                },
+            }
+        }
+    }
+
+    fn utp_accept_loop(self: Arc<Self>) {
+        let Some(utp) = self.utp.clone() else {
+            return;
+        };
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let Some(stream) = utp.accept_timeout(ACCEPT_POLL_INTERVAL) else {
+                continue;
+            };
+            let addr = stream.peer_addr();
+            if !self.ip_filter.allows(addr.ip(), PeerSource::Incoming) {
+                continue;
+            }
+            let Some(slot) = self.connections.try_acquire() else {
+                continue;
+            };
+            let inner = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = inner.route_incoming(PeerStream::Utp(stream), addr, slot) {
+                    debug!(%addr, error = %e, "incoming utp connection rejected");
+                }
+            });
+        }
+    }
+
+    fn answer_incoming(&self, mut stream: PeerStream) -> Result<(MseStream<PeerStream>, handshake::Handshake)> {
+        // 明文握手有 68 字节，MSE 公钥有 96 字节，先读 20 字节足以区分
+        let mut first = vec![0u8; 20];
+        stream.read_exact(&mut first)?;
+        let skeys = self.torrents.lock().unwrap().values().flat_map(|entry| entry.torrent.swarm_hashes()).collect::<Vec<_>>();
+        let encryption = self.settings.lock().unwrap().encryption;
+        let (mut stream, skey) = mse::accept(stream, first, &skeys, encryption)?;
+        let remote = handshake::read(&mut stream)?;
+        if skey.is_some_and(|skey| skey != remote.info_hash) {
+            return Err(Error::handshake("info hash differs from the mse key"));
+        }
+        if remote.peer_id == self.peer_id {
+            return Err(Error::handshake("connected to ourselves"));
+        }
+        Ok((stream, remote))
+    }
+
+    // route_incoming reads the peer's handshake, going through MSE first for encrypted peers,
+    // and hands the connection to the torrent it asks for
+    fn route_incoming(&self, stream: PeerStream, addr: SocketAddr, slot: ConnectionSlot) -> Result<()> {
+        stream.set_read_timeout(Some(Duration::new(3, 0)))?;
+        let (stream, handshake) = self.answer_incoming(stream).map_err(|e| e.with_peer(addr))?;
+
+        let torrents = self.torrents.lock().unwrap();
+        let incoming = torrents
+            .values()
+            .find(|entry| entry.torrent.swarm_hashes().contains(&handshake.info_hash))
+            .and_then(|entry| entry.incoming.as_ref());
+        match incoming {
+            Some(incoming) => incoming
+                .send(IncomingPeer { stream, addr, handshake, slot })
+                .map_err(|_| Error::handshake("torrent is not accepting peers").with_peer(addr)),
+            None => Err(Error::handshake("unknown info hash").with_peer(addr)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::torrent_file::creator::{self, CreateOptions};
+
+    const MIN: Duration = Duration::from_secs(60);
+
+    // small_torrent creates name in dir and returns its torrent, it has no tracker and so no peers
+    fn small_torrent(dir: &Path, name: &str) -> CustomTorrent {
+        let path = dir.join(name);
+        fs::write(&path, name.repeat(1000)).unwrap();
+        let options = CreateOptions { threads: 1, ..CreateOptions::default() };
+        CustomTorrent::general_custom_torrent(&creator::create_torrent(&path, &options).unwrap()).unwrap()
+    }
+
+    fn count(session: &Session, state: TorrentState) -> usize {
+        session.torrents().iter().filter(|s| s.state == state).count()
+    }
+
+    fn find(session: &Session, state: TorrentState) -> TorrentId {
+        session.torrents().into_iter().find(|s| s.state == state).unwrap().id
+    }
+
+    #[test]
+    fn each_seed_limit_is_checked_on_its_own() {
+        let none = SeedLimits::default();
+        assert!(!none.reached(100.0, MIN * 1000, MIN * 1000));
+
+        let ratio = SeedLimits { ratio: Some(2.0), ..SeedLimits::default() };
+        assert!(!ratio.reached(1.99, MIN * 1000, MIN * 1000));
+        assert!(ratio.reached(2.0, Duration::ZERO, Duration::ZERO));
+
+        let time = SeedLimits { seeding_time: Some(MIN * 30), ..SeedLimits::default() };
+        assert!(!time.reached(100.0, MIN * 29, MIN * 1000));
+        assert!(time.reached(0.0, MIN * 30, Duration::ZERO));
+
+        let idle = SeedLimits { idle_time: Some(MIN * 10), action: SeedLimitAction::Stop, ..SeedLimits::default() };
+        assert!(!idle.reached(100.0, MIN * 1000, MIN * 9));
+        assert!(idle.reached(0.0, Duration::ZERO, MIN * 10));
+
+        // 多个限制时任何一个达到就算
+        let all = SeedLimits { ratio: Some(1.0), seeding_time: Some(MIN), idle_time: Some(MIN), ..SeedLimits::default() };
+        assert!(!all.reached(0.5, MIN / 2, MIN / 2));
+        assert!(all.reached(0.5, MIN / 2, MIN));
+    }
+
+    #[test]
+    fn ratio_counts_data_on_disk_as_downloaded() {
+        // 做种已有的数据时下载量是 0，按 torrent 大小算
+        assert_eq!(ratio(2000, 0, 1000), 2.0);
+        assert_eq!(ratio(0, 0, 1000), 0.0);
+        // 重复下载的数据让分母更大
+        assert_eq!(ratio(2000, 4000, 1000), 0.5);
+        // 空 torrent 不会除以 0
+        assert_eq!(ratio(10, 0, 0), 10.0);
+        assert_eq!(ratio(0, 0, 0), 0.0);
+    }
+
+    #[test]
+    fn limits_count_from_the_base_after_resume() {
+        let limits = SeedLimits { ratio: Some(2.0), seeding_time: Some(MIN * 60), ..SeedLimits::default() };
+        let length = 1000;
+
+        // 第一次做种：上传 2000 字节达到分享率
+        let base = SeedBase::default();
+        let (uploaded, seeded) = base.counted(2000, MIN * 10);
+        assert!(limits.reached(ratio(uploaded, 0, length), seeded, Duration::ZERO));
+
+        // resume_torrent 把基准移到当前的总量，限制重新开始计算
+        let base = SeedBase { uploaded: 2000, seeded: MIN * 10 };
+        let (uploaded, seeded) = base.counted(2000, MIN * 10);
+        assert_eq!((uploaded, seeded), (0, Duration::ZERO));
+        assert!(!limits.reached(ratio(uploaded, 0, length), seeded, Duration::ZERO));
+        let (uploaded, seeded) = base.counted(3999, MIN * 69);
+        assert!(!limits.reached(ratio(uploaded, 0, length), seeded, Duration::ZERO));
+        let (uploaded, seeded) = base.counted(4000, MIN * 20);
+        assert!(limits.reached(ratio(uploaded, 0, length), seeded, Duration::ZERO));
+        let (uploaded, seeded) = base.counted(2000, MIN * 70);
+        assert!(limits.reached(ratio(uploaded, 0, length), seeded, Duration::ZERO));
+
+        // 统计被清零时不会下溢
+        assert_eq!(base.counted(0, Duration::ZERO), (0, Duration::ZERO));
+    }
+
+    #[test]
+    fn queue_starts_torrents_when_active_ones_pause_or_finish() {
+        let dir = std::env::temp_dir().join(format!("session-queue-{}", std::process::id()));
+        let save = dir.join("save");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&save).unwrap();
+        let settings = SessionSettings {
+            listen_port: 0,
+            enable_utp: false,
+            enable_dht: false,
+            max_active_downloads: 1,
+            max_active_seeds: 1,
+            ..SessionSettings::default()
+        };
+        let mut session = Session::new(settings).unwrap();
+
+        for name in ["a", "b", "c"] {
+            session.add_torrent(small_torrent(&dir, name), &save);
+        }
+        assert_eq!(count(&session, TorrentState::Downloading), 1);
+        assert_eq!(count(&session, TorrentState::Queued), 2);
+
+        // 暂停的下载让出位置
+        let paused = find(&session, TorrentState::Downloading);
+        session.pause_torrent(paused);
+        assert_eq!(session.torrent_status(paused).unwrap().state, TorrentState::Paused);
+        assert_eq!(count(&session, TorrentState::Downloading), 1);
+        assert_eq!(count(&session, TorrentState::Queued), 1);
+
+        // 完成的下载开始做种，最后一个排队的开始下载
+        let finished = find(&session, TorrentState::Downloading);
+        let name = session.torrent_status(finished).unwrap().name;
+        fs::copy(dir.join(&name), save.join(&name)).unwrap();
+        assert!(session.recheck_torrent(finished).unwrap().is_complete());
+        assert_eq!(session.torrent_status(finished).unwrap().state, TorrentState::Seeding);
+        assert_eq!(count(&session, TorrentState::Downloading), 1);
+        assert_eq!(count(&session, TorrentState::Queued), 0);
+
+        // 做种的位置也有上限，暂停之后排队的做种开始
+        let complete = small_torrent(&dir, "d");
+        let resume = recheck::recheck(&complete, &dir, 0);
+        let waiting = session.add_torrent_with_resume(complete, &dir, &resume.bitfield);
+        assert_eq!(session.torrent_status(waiting).unwrap().state, TorrentState::Finished);
+        session.pause_torrent(finished);
+        assert_eq!(session.torrent_status(waiting).unwrap().state, TorrentState::Seeding);
+        assert_eq!(count(&session, TorrentState::Seeding), 1);
+
+        session.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
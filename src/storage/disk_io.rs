@@ -0,0 +1,91 @@
+use std::{
+    sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use crate::{error::error::Result, storage::storage::Storage};
+
+pub enum DiskJob {
+    Write {
+        storage: Arc<Storage>,
+        index: usize,
+        data: Vec<u8>,
+        done: Sender<(usize, Result<()>)>,
+    },
+    Read {
+        storage: Arc<Storage>,
+        index: usize,
+        done: Sender<(usize, Result<Vec<u8>>)>,
+    },
+}
+
+// DiskIo is a small thread pool shared by every torrent of a session so disk access doesn't block peer connections
+pub struct DiskIo {
+    jobs: Mutex<Option<Sender<DiskJob>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+fn run_worker(jobs: Arc<Mutex<Receiver<DiskJob>>>) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        match job {
+            Ok(DiskJob::Write { storage, index, data, done }) => {
+                let res = storage.write_piece(index, &data);
+                done.send((index, res)).ok();
+            },
+            Ok(DiskJob::Read { storage, index, done }) => {
+                let res = storage.read_piece(index);
+                done.send((index, res)).ok();
+            },
+            Err(_) => return,
+        }
+    }
+}
+
+impl DiskIo {
+    pub fn new(threads: usize) -> Arc<Self> {
+        let (tx, rx) = mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let rx = rx.clone();
+                thread::spawn(move || run_worker(rx))
+            })
+            .collect();
+        Arc::new(Self {
+            jobs: Mutex::new(Some(tx)),
+            workers: Mutex::new(workers),
+        })
+    }
+
+    pub fn submit(&self, job: DiskJob) {
+        if let Some(jobs) = self.jobs.lock().unwrap().as_ref() {
+            jobs.send(job).ok();
+        }
+    }
+
+    pub fn write_piece(&self, storage: &Arc<Storage>, index: usize, data: Vec<u8>, done: Sender<(usize, Result<()>)>) {
+        self.submit(DiskJob::Write {
+            storage: storage.clone(),
+            index,
+            data,
+            done,
+        });
+    }
+
+    pub fn read_piece(&self, storage: &Arc<Storage>, index: usize, done: Sender<(usize, Result<Vec<u8>>)>) {
+        self.submit(DiskJob::Read {
+            storage: storage.clone(),
+            index,
+            done,
+        });
+    }
+
+    // shutdown finishes the queued jobs and stops the worker threads
+    pub fn shutdown(&self) {
+        self.jobs.lock().unwrap().take();
+        for worker in self.workers.lock().unwrap().drain(..) {
+            worker.join().ok();
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod storage;
+pub mod disk_io;
+pub mod recheck;
@@ -0,0 +1,217 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use tracing::{debug, info_span};
+
+use crate::{
+    bitfield::bitfield::{self, Bitfield},
+    storage::storage::Storage,
+    torrent_file::torrent_file::CustomTorrent,
+};
+
+// FileProgress is how much of one file is covered by verified pieces
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileProgress {
+    pub path: PathBuf,
+    pub length: usize,
+    pub verified: usize,
+}
+
+impl FileProgress {
+    pub fn percent(&self) -> f64 {
+        if self.length == 0 {
+            return 100.0;
+        }
+        self.verified as f64 * 100.0 / self.length as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecheckResult {
+    pub bitfield: Bitfield,
+    pub num_pieces: usize,
+    pub files: Vec<FileProgress>,
+    // pieces that could be read but whose hash does not match, missing data is not listed
+    pub corrupt: Vec<usize>,
+}
+
+impl RecheckResult {
+    pub fn has_piece(&self, index: usize) -> bool {
+        bitfield::has_piece(&self.bitfield, index)
+    }
+
+    pub fn have(&self) -> Vec<bool> {
+        (0..self.num_pieces).map(|i| self.has_piece(i)).collect()
+    }
+
+    pub fn pieces_done(&self) -> usize {
+        (0..self.num_pieces).filter(|i| self.has_piece(*i)).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces_done() == self.num_pieces
+    }
+}
+
+enum PieceCheck {
+    Valid,
+    Corrupt,
+    Missing,
+}
+
+// recheck hashes every piece of the torrent's files below save_path, 0 threads uses every core
+pub fn recheck(torrent: &CustomTorrent, save_path: &Path, threads: usize) -> RecheckResult {
+    let _span = info_span!("recheck", name = %torrent.name).entered();
+    let num_pieces = torrent.num_pieces();
+    let threads = match threads {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => n,
+    };
+    let next = AtomicUsize::new(0);
+
+    let checks = thread::scope(|s| {
+        let workers = (0..threads.min(num_pieces).max(1))
+            .map(|_| {
+                let next = &next;
+                s.spawn(move || {
+                    // 每个线程使用自己的文件句柄，只读打开，不会创建缺失的文件
+                    let storage = Storage::read_only(save_path, &torrent.files, torrent.piece_length);
+                    let mut checks = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        if index >= num_pieces {
+                            return checks;
+                        }
+                        let check = match storage.read_piece(index) {
+                            Ok(data) if torrent.verify_piece(index, &data) => PieceCheck::Valid,
+                            Ok(_) => PieceCheck::Corrupt,
+                            Err(_) => PieceCheck::Missing,
+                        };
+                        checks.push((index, check));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers.into_iter().flat_map(|w| w.join().expect("recheck thread panicked")).collect::<Vec<_>>()
+    });
+
+    let mut bitfield = bitfield::new_bitfield(num_pieces);
+    let mut corrupt = vec![];
+    let mut missing = 0;
+    for (index, check) in checks {
+        match check {
+            PieceCheck::Valid => bitfield::set_piece(&mut bitfield, index),
+            PieceCheck::Corrupt => corrupt.push(index),
+            PieceCheck::Missing => missing += 1,
+        }
+    }
+    corrupt.sort_unstable();
+
+    let mut offset = 0;
+    let files = torrent
+        .files
+        .iter()
+        .filter_map(|f| {
+            let (begin, end) = (offset, offset + f.length);
+            offset = end;
+            if f.is_pad() {
+                return None;
+            }
+            let mut verified = 0;
+            if f.length > 0 {
+                for index in begin / torrent.piece_length..=(end - 1) / torrent.piece_length {
+                    if bitfield::has_piece(&bitfield, index) {
+                        let piece_begin = index * torrent.piece_length;
+                        let piece_end = piece_begin + torrent.piece_length;
+                        verified += end.min(piece_end) - begin.max(piece_begin);
+                    }
+                }
+            }
+            Some(FileProgress {
+                path: f.path.clone(),
+                length: f.length,
+                verified,
+            })
+        })
+        .collect();
+
+    let result = RecheckResult { bitfield, num_pieces, files, corrupt };
+    debug!(done = result.pieces_done(), corrupt = result.corrupt.len(), missing, total = num_pieces, "recheck finished");
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::torrent_file::creator::{self, CreateOptions};
+
+    const PIECE: usize = 16 * 1024;
+
+    // fixture writes two files that share a piece and returns the save path and their torrent
+    fn fixture(name: &str) -> (PathBuf, CustomTorrent) {
+        let save_path = std::env::temp_dir().join(format!("recheck-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&save_path);
+        let dir = save_path.join("data");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.bin"), (0..PIECE * 2 + 100).map(|i| (i % 251) as u8).collect::<Vec<_>>()).unwrap();
+        fs::write(dir.join("b.bin"), (0..PIECE + 50).map(|i| (i % 13) as u8).collect::<Vec<_>>()).unwrap();
+        let options = CreateOptions { piece_length: Some(PIECE), threads: 1, ..CreateOptions::default() };
+        let meta = creator::create_torrent(&dir, &options).unwrap();
+        (save_path, CustomTorrent::general_custom_torrent(&meta).unwrap())
+    }
+
+    fn verified(result: &RecheckResult) -> Vec<usize> {
+        result.files.iter().map(|f| f.verified).collect()
+    }
+
+    #[test]
+    fn complete_data_checks_out_with_any_thread_count() {
+        let (save_path, torrent) = fixture("complete");
+        assert_eq!(torrent.num_pieces(), 4);
+        for threads in [0, 1, 3, 8] {
+            let result = recheck(&torrent, &save_path, threads);
+            assert!(result.is_complete(), "{} threads", threads);
+            assert!(result.corrupt.is_empty());
+            assert_eq!(verified(&result), vec![PIECE * 2 + 100, PIECE + 50]);
+            assert!(result.files.iter().all(|f| f.percent() == 100.0));
+        }
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn reports_corrupt_pieces_and_partial_files() {
+        let (save_path, torrent) = fixture("corrupt");
+        let path = save_path.join("data/a.bin");
+        let mut data = fs::read(&path).unwrap();
+        data[PIECE + 1] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let result = recheck(&torrent, &save_path, 2);
+        assert_eq!(result.have(), vec![true, false, true, true]);
+        assert_eq!(result.corrupt, vec![1]);
+        assert_eq!(result.pieces_done(), 3);
+        assert_eq!(verified(&result), vec![PIECE + 100, PIECE + 50]);
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+
+    #[test]
+    fn missing_data_is_not_corrupt() {
+        let (save_path, torrent) = fixture("missing");
+        fs::remove_file(save_path.join("data/b.bin")).unwrap();
+
+        let result = recheck(&torrent, &save_path, 1);
+        // 第 2 个 piece 跨过两个文件，缺少 b.bin 时读不出来
+        assert_eq!(result.have(), vec![true, true, false, false]);
+        assert!(result.corrupt.is_empty());
+        assert_eq!(verified(&result), vec![PIECE * 2, 0]);
+        assert_eq!(result.files[1].percent(), 0.0);
+        // 只读检查不会创建缺失的文件
+        assert!(!save_path.join("data/b.bin").exists());
+        fs::remove_dir_all(&save_path).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use tracing::debug;
+
+use crate::{error::error::{Error, Result}, torrent_file::torrent_file::{CustomTorrent, FileAttr, TorrentFile}};
+
+fn open_file(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // 下载时可能已经创建了同名的空文件
+    match fs::symlink_metadata(link) {
+        Ok(meta) if meta.file_type().is_symlink() || meta.len() == 0 => fs::remove_file(link)?,
+        _ => {},
+    }
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perms = fs::metadata(path)?.permissions();
+    // 在有读权限的地方加上执行权限
+    perms.set_mode(perms.mode() | ((perms.mode() & 0o444) >> 2));
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct FileSlot {
+    path: PathBuf,
+    offset: usize,
+    length: usize,
+    attr: FileAttr,
+    symlink: Option<PathBuf>,
+}
+
+// Storage maps the torrent's byte space onto the files below a save directory
+#[derive(Debug)]
+pub struct Storage {
+    root: PathBuf,
+    files: Vec<FileSlot>,
+    piece_length: usize,
+    length: usize,
+    read_only: bool,
+    handles: Mutex<HashMap<usize, File>>,
+}
+
+impl Storage {
+    pub fn new(root: &Path, files: &[TorrentFile], piece_length: usize) -> Self {
+        let mut offset = 0;
+        let files = files
+            .iter()
+            .map(|f| {
+                let slot = FileSlot {
+                    path: f.path.clone(),
+                    offset,
+                    length: f.length,
+                    attr: f.attr,
+                    symlink: f.symlink.clone(),
+                };
+                offset += f.length;
+                slot
+            })
+            .collect::<Vec<_>>();
+        Self {
+            root: root.to_path_buf(),
+            files,
+            piece_length,
+            length: offset,
+            read_only: false,
+            handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // ReadOnly opens existing files without creating or modifying anything
+    pub fn read_only(root: &Path, files: &[TorrentFile], piece_length: usize) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(root, files, piece_length)
+        }
+    }
+
+    pub fn for_torrent(torrent: &CustomTorrent, save_path: &Path) -> Self {
+        Self::new(save_path, &torrent.files, torrent.piece_length)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_pad(&self, index: usize) -> bool {
+        self.files[index].attr.pad
+    }
+
+    pub fn file_path(&self, index: usize) -> PathBuf {
+        self.root.join(&self.files[index].path)
+    }
+
+    // file_pieces returns the pieces holding bytes of the file, empty files have no pieces
+    pub fn file_pieces(&self, index: usize) -> Range<usize> {
+        let file = &self.files[index];
+        if file.length == 0 || self.piece_length == 0 {
+            return 0..0;
+        }
+        let first = file.offset / self.piece_length;
+        let last = (file.offset + file.length - 1) / self.piece_length;
+        first..last + 1
+    }
+
+    pub fn piece_bounds(&self, index: usize) -> (usize, usize) {
+        let begin = index * self.piece_length;
+        let end = (begin + self.piece_length).min(self.length);
+        (begin, end)
+    }
+
+    // spans splits the byte range [begin, end) into (file index, offset in file, length) parts
+    fn spans(&self, begin: usize, end: usize) -> Vec<(usize, usize, usize)> {
+        let mut spans = vec![];
+        for (i, file) in self.files.iter().enumerate() {
+            let file_end = file.offset + file.length;
+            if file_end <= begin || file.offset >= end {
+                continue;
+            }
+            let start = begin.max(file.offset);
+            let stop = end.min(file_end);
+            spans.push((i, start - file.offset, stop - start));
+        }
+        spans
+    }
+
+    fn with_file<T>(&self, index: usize, f: impl FnOnce(&mut File) -> io::Result<T>) -> Result<T> {
+        let path = self.root.join(&self.files[index].path);
+        let mut handles = self.handles.lock().unwrap();
+        if let std::collections::hash_map::Entry::Vacant(e) = handles.entry(index) {
+            let file = if self.read_only { File::open(&path) } else { open_file(&path) };
+            let file = file.map_err(|err| Error::storage(&path, err))?;
+            e.insert(file);
+        }
+        f(handles.get_mut(&index).unwrap()).map_err(|err| Error::storage(&path, err))
+    }
+
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+        let (begin, end) = self.piece_bounds(index);
+        if end - begin != data.len() {
+            let source = io::Error::new(io::ErrorKind::InvalidInput, format!("piece {} length mismatch", index));
+            return Err(Error::storage(&self.root, source));
+        }
+        let mut written = 0;
+        for (file, offset, length) in self.spans(begin, end) {
+            // padding 只存在于 piece 的字节空间里，不写入磁盘
+            if self.files[file].attr.pad {
+                written += length;
+                continue;
+            }
+            self.with_file(file, |f| {
+                f.seek(SeekFrom::Start(offset as u64))?;
+                f.write_all(&data[written..written + length])
+            })?;
+            written += length;
+        }
+        Ok(())
+    }
+
+    pub fn read_piece(&self, index: usize) -> Result<Vec<u8>> {
+        let (begin, end) = self.piece_bounds(index);
+        let mut buf = vec![0u8; end - begin];
+        let mut read = 0;
+        for (file, offset, length) in self.spans(begin, end) {
+            if self.files[file].attr.pad {
+                read += length;
+                continue;
+            }
+            self.with_file(file, |f| {
+                f.seek(SeekFrom::Start(offset as u64))?;
+                f.read_exact(&mut buf[read..read + length])
+            })?;
+            read += length;
+        }
+        Ok(buf)
+    }
+
+    // apply_attributes marks executable files and creates symlinks once the data is complete
+    pub fn apply_attributes(&self) -> Result<()> {
+        for file in &self.files {
+            let path = self.root.join(&file.path);
+            if let Some(target) = &file.symlink {
+                // 链接目标是相对于保存目录的路径，转换成相对于链接所在目录的路径
+                let depth = file.path.components().count().saturating_sub(1);
+                let relative = (0..depth).map(|_| Path::new("..")).collect::<PathBuf>().join(target);
+                self.handles.lock().unwrap().retain(|i, _| self.files[*i].path != file.path);
+                make_symlink(&relative, &path).map_err(|e| Error::storage(&path, e))?;
+                debug!(path = %path.display(), target = %relative.display(), "created symlink");
+            } else if file.attr.executable && !file.attr.pad {
+                set_executable(&path).map_err(|e| Error::storage(&path, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        for (index, file) in self.handles.lock().unwrap().iter_mut() {
+            file.flush().map_err(|err| Error::storage(self.root.join(&self.files[*index].path), err))?;
+        }
+        Ok(())
+    }
+
+    // close drops the cached file handles, files are reopened on the next access
+    pub fn close(&self) {
+        self.handles.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // attr_torrent has two 16 byte pieces: d/a.bin (10), a 6 byte pad file, the executable d/b.sh (8)
+    // and d/link, a symlink to d/a.bin
+    fn attr_torrent() -> CustomTorrent {
+        let files = concat!(
+            "d6:lengthi10e4:pathl5:a.binee",
+            "d4:attr1:p6:lengthi6e4:pathl4:.pad1:6ee",
+            "d4:attr1:x6:lengthi8e4:pathl4:b.shee",
+            "d4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl5:a.binee",
+        );
+        let info = format!("d5:filesl{}e4:name1:d12:piece lengthi16e6:pieces40:{}e", files, "x".repeat(40));
+        CustomTorrent::general_custom_torrent(format!("d4:info{}e", info).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn pad_bytes_stay_off_disk_and_read_as_zeros() {
+        let torrent = attr_torrent();
+        assert_eq!(torrent.pad_ranges(0), vec![10..16]);
+        assert!(torrent.pad_ranges(1).is_empty());
+
+        let dir = temp_dir("pad");
+        let storage = Storage::for_torrent(&torrent, &dir);
+        // pad 区域故意写非零字节，它们不能落到磁盘上
+        let mut first = vec![1u8; 10];
+        first.extend_from_slice(&[0xff; 6]);
+        storage.write_piece(0, &first).unwrap();
+        storage.write_piece(1, &[2u8; 8]).unwrap();
+        storage.flush().unwrap();
+        assert!(storage.write_piece(1, &[2u8; 16]).is_err());
+
+        assert!(!dir.join("d/.pad").exists());
+        assert_eq!(fs::read(dir.join("d/a.bin")).unwrap(), vec![1u8; 10]);
+        assert_eq!(fs::read(dir.join("d/b.sh")).unwrap(), vec![2u8; 8]);
+
+        let mut expected = vec![1u8; 10];
+        expected.extend_from_slice(&[0u8; 6]);
+        assert_eq!(storage.read_piece(0).unwrap(), expected);
+        // 只读模式不会去打开不存在的 pad 文件
+        let read_only = Storage::read_only(&dir, &torrent.files, torrent.piece_length);
+        assert_eq!(read_only.read_piece(0).unwrap(), expected);
+        assert_eq!(read_only.read_piece(1).unwrap(), vec![2u8; 8]);
+        assert!(!dir.join("d/.pad").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn applies_executable_and_symlink_attributes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let torrent = attr_torrent();
+        let dir = temp_dir("attr");
+        let storage = Storage::for_torrent(&torrent, &dir);
+        let mut first = vec![1u8; 10];
+        first.extend_from_slice(&[0u8; 6]);
+        storage.write_piece(0, &first).unwrap();
+        storage.write_piece(1, &[2u8; 8]).unwrap();
+        storage.apply_attributes().unwrap();
+
+        let mode = |path: &str| fs::metadata(dir.join(path)).unwrap().permissions().mode();
+        assert_ne!(mode("d/b.sh") & 0o111, 0);
+        assert_eq!(mode("d/a.bin") & 0o111, 0);
+
+        let link = dir.join("d/link");
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../d/a.bin"));
+        assert_eq!(fs::read(&link).unwrap(), vec![1u8; 10]);
+
+        // 再次应用（比如恢复做种时）不会失败
+        storage.apply_attributes().unwrap();
+        assert_eq!(fs::read(&link).unwrap(), vec![1u8; 10]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
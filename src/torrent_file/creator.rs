@@ -0,0 +1,358 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_derive::Serialize;
+use tracing::debug;
+
+use crate::{
+    bencode::{bencode::Value, ser},
+    error::error::{Error, Result},
+    storage::storage::Storage,
+    torrent_file::torrent_file::{total_length, FileAttr, TorrentFile},
+};
+
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+// 自动选择 piece 大小时的目标 piece 数量
+const TARGET_PIECES: usize = 1500;
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    // None picks a power of two aiming for about 1500 pieces
+    pub piece_length: Option<usize>,
+    // tiers of trackers, the first url becomes announce
+    pub trackers: Vec<Vec<String>>,
+    pub web_seeds: Vec<String>,
+    pub private: bool,
+    pub comment: Option<String>,
+    // some private trackers require a source tag so cross-seeded torrents get a different info hash
+    pub source: Option<String>,
+    // 0 uses every core
+    pub threads: usize,
+}
+
+// 字段为 None 时不写入
+#[derive(Debug, Serialize)]
+struct MetaInfoOut<'a> {
+    announce: Option<&'a str>,
+    #[serde(rename = "announce-list")]
+    announce_list: Option<&'a [Vec<String>]>,
+    comment: Option<&'a str>,
+    #[serde(rename = "created by")]
+    created_by: String,
+    #[serde(rename = "creation date")]
+    creation_date: u64,
+    info: InfoOut<'a>,
+    #[serde(rename = "url-list")]
+    url_list: Option<&'a [String]>,
+}
+
+#[derive(Debug, Serialize)]
+struct InfoOut<'a> {
+    attr: Option<String>,
+    files: Option<Vec<FileOut>>,
+    length: Option<usize>,
+    name: &'a str,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    pieces: Value<'a>,
+    private: Option<bool>,
+    source: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize)]
+struct FileOut {
+    attr: Option<String>,
+    length: usize,
+    path: Vec<String>,
+}
+
+pub fn auto_piece_length(total: usize) -> usize {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while total / piece_length > TARGET_PIECES && piece_length < MAX_PIECE_LENGTH {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+fn attr_string(attr: FileAttr) -> Option<String> {
+    if attr.is_empty() { None } else { Some(attr.to_string()) }
+}
+
+#[cfg(unix)]
+fn file_attr(meta: &fs::Metadata) -> FileAttr {
+    use std::os::unix::fs::PermissionsExt;
+    FileAttr { executable: meta.permissions().mode() & 0o111 != 0, ..FileAttr::default() }
+}
+
+#[cfg(not(unix))]
+fn file_attr(_meta: &fs::Metadata) -> FileAttr {
+    FileAttr::default()
+}
+
+// collect_files walks path and returns its regular files in a stable order, paths relative to the parent of path
+fn collect_files(root: &Path, rel: &Path, files: &mut Vec<TorrentFile>) -> Result<()> {
+    let full = root.join(rel);
+    let meta = fs::symlink_metadata(&full).map_err(|e| Error::storage(&full, e))?;
+    if meta.is_file() {
+        files.push(TorrentFile {
+            path: rel.to_path_buf(),
+            length: meta.len() as usize,
+            pieces_root: None,
+            attr: file_attr(&meta),
+            symlink: None,
+        });
+        return Ok(());
+    }
+    if !meta.is_dir() {
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(&full)
+        .map_err(|e| Error::storage(&full, e))?
+        .map(|entry| entry.map(|e| e.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| Error::storage(&full, e))?;
+    entries.sort();
+    for name in entries {
+        collect_files(root, &rel.join(name), files)?;
+    }
+    Ok(())
+}
+
+// hash_pieces reads the files and hashes every piece, spreading the pieces over threads
+fn hash_pieces(root: &Path, files: &[TorrentFile], piece_length: usize, threads: usize) -> Result<Vec<u8>> {
+    let total = total_length(files)?;
+    let num_pieces = total.div_ceil(piece_length);
+    let next = AtomicUsize::new(0);
+
+    let chunks = thread::scope(|s| {
+        let workers = (0..threads.min(num_pieces).max(1))
+            .map(|_| {
+                let next = &next;
+                s.spawn(move || -> Result<Vec<(usize, [u8; 20])>> {
+                    // 每个线程使用自己的文件句柄
+                    let storage = Storage::read_only(root, files, piece_length);
+                    let mut hashes = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        if index >= num_pieces {
+                            return Ok(hashes);
+                        }
+                        let data = storage.read_piece(index)?;
+                        hashes.push((index, sha1::Sha1::from(&data).digest().bytes()));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers.into_iter().map(|w| w.join().expect("hash thread panicked")).collect::<Vec<_>>()
+    });
+
+    let mut pieces = vec![0u8; num_pieces * 20];
+    for chunk in chunks {
+        for (index, hash) in chunk? {
+            pieces[index * 20..index * 20 + 20].copy_from_slice(&hash);
+        }
+    }
+    Ok(pieces)
+}
+
+// create_torrent hashes a file or directory and returns the bencoded metainfo
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Vec<u8>> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| Error::metainfo(format!("{} has no file name", path.display())))?;
+    let root = path.parent().map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from("."));
+
+    let mut files = vec![];
+    collect_files(&root, Path::new(&name), &mut files)?;
+    if files.is_empty() {
+        return Err(Error::metainfo(format!("{} contains no files", path.display())));
+    }
+    let single_file = fs::metadata(path).map_err(|e| Error::storage(path, e))?.is_file();
+
+    let total = total_length(&files)?;
+    let piece_length = options.piece_length.unwrap_or_else(|| auto_piece_length(total));
+    if piece_length == 0 {
+        return Err(Error::metainfo("piece length must not be zero"));
+    }
+    let threads = match options.threads {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => n,
+    };
+    debug!(%name, files = files.len(), total, piece_length, threads, "hashing");
+    let pieces = hash_pieces(&root, &files, piece_length, threads)?;
+
+    let single_attr = if single_file { attr_string(files[0].attr) } else { None };
+    let files = if single_file {
+        None
+    } else {
+        Some(
+            files
+                .iter()
+                .map(|f| FileOut {
+                    attr: attr_string(f.attr),
+                    length: f.length,
+                    // 去掉开头的 torrent 名字
+                    path: f
+                        .path
+                        .components()
+                        .skip(1)
+                        .filter_map(|c| match c {
+                            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+                            _ => None,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        )
+    };
+
+    let trackers = options.trackers.iter().filter(|tier| !tier.is_empty()).cloned().collect::<Vec<_>>();
+    let multi_tracker = trackers.len() > 1 || trackers.first().is_some_and(|t| t.len() > 1);
+    let meta = MetaInfoOut {
+        announce: trackers.first().map(|tier| tier[0].as_str()),
+        announce_list: if multi_tracker { Some(&trackers) } else { None },
+        comment: options.comment.as_deref(),
+        created_by: format!("torrent_client/{}", env!("CARGO_PKG_VERSION")),
+        creation_date: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        info: InfoOut {
+            attr: single_attr,
+            length: if single_file { Some(total) } else { None },
+            files,
+            name: &name,
+            piece_length,
+            pieces: Value::from(pieces),
+            private: if options.private { Some(true) } else { None },
+            source: options.source.as_deref(),
+        },
+        url_list: if options.web_seeds.is_empty() { None } else { Some(&options.web_seeds) },
+    };
+    ser::to_bytes(&meta).map_err(Error::metainfo)
+}
+
+pub fn create_torrent_file(path: &Path, out: &Path, options: &CreateOptions) -> Result<()> {
+    let data = create_torrent(path, options)?;
+    fs::write(out, data).map_err(|e| Error::storage(out, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bencode::bencode::{self, Options},
+        torrent_file::torrent_file::CustomTorrent,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("creator-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn data(len: usize, seed: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i * 7 + seed) % 251) as u8).collect()
+    }
+
+    fn sha1(data: &[u8]) -> [u8; 20] {
+        sha1::Sha1::from(data).digest().bytes()
+    }
+
+    #[test]
+    fn single_file_round_trip() {
+        let dir = temp_dir("single");
+        let path = dir.join("movie.bin");
+        let content = data(3 * MIN_PIECE_LENGTH + 17, 0);
+        fs::write(&path, &content).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(MIN_PIECE_LENGTH),
+            trackers: vec![vec!["http://a/announce".to_string(), "http://b/announce".to_string()], vec![], vec!["udp://c:80".to_string()]],
+            web_seeds: vec!["http://seed/".to_string()],
+            private: true,
+            comment: Some("hello".to_string()),
+            source: None,
+            threads: 2,
+        };
+        let meta = create_torrent(&path, &options).unwrap();
+        // 输出必须是规范编码，否则别的客户端算出的 info hash 不同
+        assert!(bencode::decode_with(&meta, Options::strict()).is_ok());
+
+        let torrent = CustomTorrent::general_custom_torrent(&meta).unwrap();
+        assert_eq!(torrent.name, "movie.bin");
+        assert_eq!(torrent.length, content.len());
+        assert_eq!(torrent.piece_length, MIN_PIECE_LENGTH);
+        assert_eq!(torrent.files.len(), 1);
+        assert_eq!(torrent.files[0].path, PathBuf::from("movie.bin"));
+        assert_eq!(torrent.piece_hashes, content.chunks(MIN_PIECE_LENGTH).map(sha1).collect::<Vec<_>>());
+        assert_eq!(torrent.announce, "http://a/announce");
+        assert_eq!(torrent.announce_list, vec![vec!["http://a/announce".to_string(), "http://b/announce".to_string()], vec!["udp://c:80".to_string()]]);
+        assert_eq!(torrent.web_seeds, vec!["http://seed/".to_string()]);
+        assert!(torrent.private);
+        assert_eq!(torrent.comment.as_deref(), Some("hello"));
+        assert!(torrent.created_by.as_deref().is_some_and(|c| c.starts_with("torrent_client/")));
+        assert_eq!(torrent.info_hash, sha1(&torrent.raw_info));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directory_round_trip() {
+        let dir = temp_dir("dir");
+        let root = dir.join("album");
+        fs::create_dir_all(root.join("b/c")).unwrap();
+        fs::write(root.join("z.txt"), data(10, 1)).unwrap();
+        fs::write(root.join("b/c/y.txt"), data(MIN_PIECE_LENGTH + 5, 2)).unwrap();
+        fs::write(root.join("a.txt"), data(0, 3)).unwrap();
+        let options = CreateOptions { piece_length: Some(MIN_PIECE_LENGTH), threads: 1, ..CreateOptions::default() };
+        let meta = create_torrent(&root, &options).unwrap();
+        let torrent = CustomTorrent::general_custom_torrent(&meta).unwrap();
+
+        let files = torrent.files.iter().map(|f| (f.path.clone(), f.length)).collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                (PathBuf::from("album/a.txt"), 0),
+                (PathBuf::from("album/b/c/y.txt"), MIN_PIECE_LENGTH + 5),
+                (PathBuf::from("album/z.txt"), 10),
+            ]
+        );
+        assert_eq!(torrent.announce, "");
+        assert!(torrent.announce_list.is_empty());
+        assert!(!torrent.private);
+        // piece 跨过文件边界
+        let joined = [data(MIN_PIECE_LENGTH + 5, 2), data(10, 1)].concat();
+        assert_eq!(torrent.piece_hashes, joined.chunks(MIN_PIECE_LENGTH).map(sha1).collect::<Vec<_>>());
+
+        // 线程数不影响 info hash，source 会改变它
+        let parallel = create_torrent(&root, &CreateOptions { threads: 4, ..options.clone() }).unwrap();
+        assert_eq!(CustomTorrent::general_custom_torrent(&parallel).unwrap().info_hash, torrent.info_hash);
+        let sourced = create_torrent(&root, &CreateOptions { source: Some("TRACKER".to_string()), ..options }).unwrap();
+        assert_ne!(CustomTorrent::general_custom_torrent(&sourced).unwrap().info_hash, torrent.info_hash);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_describe() {
+        let dir = temp_dir("errors");
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        assert!(create_torrent(&dir.join("empty"), &CreateOptions::default()).is_err());
+        assert!(create_torrent(&dir.join("absent"), &CreateOptions::default()).is_err());
+        fs::write(dir.join("f"), b"x").unwrap();
+        assert!(create_torrent(&dir.join("f"), &CreateOptions { piece_length: Some(0), ..CreateOptions::default() }).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn picks_piece_lengths() {
+        assert_eq!(auto_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(TARGET_PIECES * MIN_PIECE_LENGTH), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(TARGET_PIECES * MIN_PIECE_LENGTH + MIN_PIECE_LENGTH), 2 * MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(4 << 30), 4 << 20);
+        assert_eq!(auto_piece_length(usize::MAX), MAX_PIECE_LENGTH);
+    }
+}
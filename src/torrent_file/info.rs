@@ -0,0 +1,197 @@
+use std::{fmt, path::Component};
+
+use serde_derive::Serialize;
+
+use crate::torrent_file::torrent_file::{CustomTorrent, MetaVersion};
+
+// FileNode is one entry of the file tree, directories carry the total size of their children
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileNode {
+    pub name: String,
+    pub length: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<FileNode>,
+}
+
+impl FileNode {
+    fn dir(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            length: 0,
+            children: vec![],
+        }
+    }
+
+    fn insert(&mut self, parts: &[String], length: usize) {
+        self.length += length;
+        let (first, rest) = match parts.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        let pos = match self.children.iter().position(|c| &c.name == first) {
+            Some(pos) => pos,
+            None => {
+                self.children.push(FileNode::dir(first));
+                self.children.len() - 1
+            },
+        };
+        self.children[pos].insert(rest, length);
+    }
+
+    fn fmt_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let suffix = if self.children.is_empty() { "" } else { "/" };
+        writeln!(f, "  {}{}{} ({})", "  ".repeat(depth), self.name, suffix, format_size(self.length))?;
+        for child in &self.children {
+            child.fmt_tree(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+// TorrentInfo is everything the metainfo tells about a torrent, it serializes to json for tooling
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentInfo {
+    pub name: String,
+    pub info_hash: String,
+    pub info_hash_v2: Option<String>,
+    // v1, v2 or hybrid
+    pub version: &'static str,
+    pub total_size: usize,
+    pub piece_length: usize,
+    pub piece_count: usize,
+    pub file_tree: FileNode,
+    // tiers of trackers, the main announce url comes first
+    pub trackers: Vec<Vec<String>>,
+    pub web_seeds: Vec<String>,
+    pub http_seeds: Vec<String>,
+    pub private: bool,
+    pub creation_date: Option<i64>,
+    pub created_by: Option<String>,
+    pub comment: Option<String>,
+}
+
+impl TorrentInfo {
+    pub fn new(torrent: &CustomTorrent) -> Self {
+        let mut file_tree = FileNode::dir(&torrent.name);
+        for file in torrent.files.iter().filter(|f| !f.is_pad()) {
+            // 第一段是 torrent 名字
+            let parts = file
+                .path
+                .components()
+                .skip(1)
+                .filter_map(|c| match c {
+                    Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            file_tree.insert(&parts, file.length);
+        }
+
+        let mut trackers = torrent.announce_list.clone();
+        if !torrent.announce.is_empty() && !trackers.iter().flatten().any(|t| t == &torrent.announce) {
+            trackers.insert(0, vec![torrent.announce.clone()]);
+        }
+
+        Self {
+            name: torrent.name.clone(),
+            info_hash: hex::encode(torrent.info_hash),
+            info_hash_v2: torrent.info_hash_v2.map(hex::encode),
+            version: match torrent.version {
+                MetaVersion::V1 => "v1",
+                MetaVersion::V2 => "v2",
+                MetaVersion::Hybrid => "hybrid",
+            },
+            total_size: torrent.length,
+            piece_length: torrent.piece_length,
+            piece_count: torrent.num_pieces(),
+            file_tree,
+            trackers,
+            web_seeds: torrent.web_seeds.clone(),
+            http_seeds: torrent.http_seeds.clone(),
+            private: torrent.private,
+            creation_date: torrent.creation_date,
+            created_by: torrent.created_by.clone(),
+            comment: torrent.comment.clone(),
+        }
+    }
+}
+
+impl CustomTorrent {
+    pub fn info(&self) -> TorrentInfo {
+        TorrentInfo::new(self)
+    }
+}
+
+pub fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        return format!("{} B", bytes);
+    }
+    format!("{:.2} {}", size, UNITS[unit])
+}
+
+// format_timestamp renders a unix timestamp as a UTC date without pulling in a time crate
+pub fn format_timestamp(secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+impl fmt::Display for TorrentInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name:         {}", self.name)?;
+        writeln!(f, "info hash:    {}", self.info_hash)?;
+        if let Some(v2) = &self.info_hash_v2 {
+            writeln!(f, "info hash v2: {}", v2)?;
+        }
+        writeln!(f, "version:      {}", self.version)?;
+        writeln!(f, "size:         {} ({} bytes)", format_size(self.total_size), self.total_size)?;
+        writeln!(f, "pieces:       {} x {}", self.piece_count, format_size(self.piece_length))?;
+        writeln!(f, "private:      {}", if self.private { "yes" } else { "no" })?;
+        if let Some(date) = self.creation_date {
+            writeln!(f, "created:      {}", format_timestamp(date))?;
+        }
+        if let Some(created_by) = &self.created_by {
+            writeln!(f, "created by:   {}", created_by)?;
+        }
+        if let Some(comment) = &self.comment {
+            writeln!(f, "comment:      {}", comment)?;
+        }
+        writeln!(f, "trackers:")?;
+        for (tier, urls) in self.trackers.iter().enumerate() {
+            for url in urls {
+                writeln!(f, "  [{}] {}", tier, url)?;
+            }
+        }
+        if !self.web_seeds.is_empty() {
+            writeln!(f, "web seeds:")?;
+            for url in &self.web_seeds {
+                writeln!(f, "  {}", url)?;
+            }
+        }
+        if !self.http_seeds.is_empty() {
+            writeln!(f, "http seeds:")?;
+            for url in &self.http_seeds {
+                writeln!(f, "  {}", url)?;
+            }
+        }
+        writeln!(f, "files:")?;
+        self.file_tree.fmt_tree(f, 0)
+    }
+}
@@ -0,0 +1,197 @@
+use std::borrow::Cow;
+
+use tracing::warn;
+use url::Url;
+
+use crate::{
+    bencode::bencode::{Dict, Value},
+    error::error::{Error, Result},
+    merkle::merkle::{self, Hash256},
+    peers::peers::Peer,
+    torrent_file::{tracker::{self, AnnounceStats}, torrent_file::CustomTorrent},
+};
+
+// Magnet is a parsed magnet link. It only names the torrent, the info dictionary has to be
+// fetched from the swarm with the metadata extension (BEP 9) before anything is downloaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct Magnet {
+    // xt=urn:btih, the v1 info hash
+    pub info_hash: Option<[u8; 20]>,
+    // xt=urn:btmh, the sha-256 of a v2 info dictionary
+    pub info_hash_v2: Option<Hash256>,
+    // dn, only a hint until the metadata arrives
+    pub name: Option<String>,
+    // tr
+    pub trackers: Vec<String>,
+}
+
+// parse_btih reads a v1 info hash, 40 hex digits or 32 base32 characters
+fn parse_btih(value: &str) -> Option<[u8; 20]> {
+    match value.len() {
+        40 => hex::decode(value).ok()?.try_into().ok(),
+        32 => base32(value)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+// base32 decodes RFC 4648 base32 without padding, the old form of btih
+fn base32(value: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let (mut bits, mut acc) = (0u32, 0u64);
+    for c in value.bytes() {
+        let v = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        acc = (acc << 5) | v as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+impl Magnet {
+    // Parse reads a magnet uri, it needs at least one btih or sha-256 btmh hash
+    pub fn parse(link: &str) -> Result<Self> {
+        let url = Url::parse(link).map_err(|e| Error::metainfo(format!("magnet link: {}", e)))?;
+        if url.scheme() != "magnet" {
+            return Err(Error::metainfo(format!("{} is not a magnet link", link)));
+        }
+        let mut magnet = Magnet { info_hash: None, info_hash_v2: None, name: None, trackers: vec![] };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        magnet.info_hash = Some(parse_btih(hash).ok_or_else(|| Error::metainfo(format!("bad btih {}", hash)))?);
+                    } else if let Some(hash) = value.strip_prefix("urn:btmh:") {
+                        // multihash: 0x12 是 sha2-256，0x20 是长度
+                        let hash = hash.strip_prefix("1220").and_then(|h| hex::decode(h).ok()).and_then(|h| h.try_into().ok());
+                        magnet.info_hash_v2 = Some(hash.ok_or_else(|| Error::metainfo(format!("bad btmh {}", value)))?);
+                    }
+                },
+                "dn" => magnet.name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                _ => {},
+            }
+        }
+        if magnet.info_hash.is_none() && magnet.info_hash_v2.is_none() {
+            return Err(Error::metainfo("magnet link has no btih or btmh hash"));
+        }
+        Ok(magnet)
+    }
+
+    // SwarmHash is the 20 byte hash sent in handshakes and announces, the v1 hash when there is one
+    pub fn swarm_hash(&self) -> [u8; 20] {
+        match (self.info_hash, self.info_hash_v2) {
+            (Some(hash), _) => hash,
+            (None, Some(v2)) => v2[..20].try_into().unwrap(),
+            (None, None) => unreachable!("parse requires a hash"),
+        }
+    }
+
+    // Matches tells whether raw_info is the info dictionary the link names, every given hash must match
+    pub fn matches(&self, raw_info: &[u8]) -> bool {
+        self.info_hash.is_none_or(|hash| sha1::Sha1::from(raw_info).digest().bytes() == hash)
+            && self.info_hash_v2.is_none_or(|hash| merkle::sha256(raw_info) == hash)
+    }
+
+    // RequestPeers asks every tracker of the link for peers, trackers that fail are skipped
+    pub fn request_peers(&self, peer_id: &[u8], key: u32, port: u16) -> Vec<Peer> {
+        // 拿到 metadata 之前不知道大小，left 不为零表示我们还在下载
+        let stats = AnnounceStats { left: 1, ..AnnounceStats::default() };
+        let mut peers: Vec<Peer> = vec![];
+        for url in &self.trackers {
+            match tracker::announce(url, &self.swarm_hash(), peer_id, key, port, &stats) {
+                Ok(found) => {
+                    for peer in found {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                },
+                Err(e) => warn!(error = %e, "announce failed"),
+            }
+        }
+        peers
+    }
+
+    // Torrent builds the torrent from the fetched info dictionary and the link's trackers
+    pub fn torrent(&self, raw_info: &[u8]) -> Result<CustomTorrent> {
+        if !self.matches(raw_info) {
+            return Err(Error::metainfo("metadata doesn't match the magnet link's hash"));
+        }
+        let mut meta = Dict::new();
+        if let Some(first) = self.trackers.first() {
+            meta.insert(Cow::Borrowed(&b"announce"[..]), Value::from(first.as_str()));
+            let tiers = self.trackers.iter().map(|t| Value::List(vec![Value::from(t.as_str())])).collect();
+            meta.insert(Cow::Borrowed(&b"announce-list"[..]), Value::List(tiers));
+        }
+        // info 要保持原样，它的 hash 就是 info hash；"info" 排在其他 key 后面
+        let mut data = Value::Dict(meta).encode();
+        data.pop();
+        data.extend_from_slice(b"4:info");
+        data.extend_from_slice(raw_info);
+        data.push(b'e');
+        CustomTorrent::general_custom_torrent(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::torrent_file::creator::{self, CreateOptions};
+
+    const HASH: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    #[test]
+    fn parses_hex_and_base32_hashes() {
+        let link = format!("magnet:?xt=urn:btih:{}&dn=some%20name&tr=http%3A%2F%2Fa%2Fannounce&tr=http://b/announce", HASH);
+        let magnet = Magnet::parse(&link).unwrap();
+        assert_eq!(magnet.info_hash.map(hex::encode).as_deref(), Some(HASH));
+        assert_eq!(magnet.name.as_deref(), Some("some name"));
+        assert_eq!(magnet.trackers, vec!["http://a/announce", "http://b/announce"]);
+
+        // 同一个 hash 的 base32 形式
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(magnet.info_hash.map(hex::encode).as_deref(), Some(HASH));
+        assert_eq!(magnet.swarm_hash().to_vec(), hex::decode(HASH).unwrap());
+    }
+
+    #[test]
+    fn rejects_links_without_a_usable_hash() {
+        assert!(Magnet::parse("magnet:?dn=name").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btmh:1114abcd").is_err());
+        assert!(Magnet::parse(&format!("http://host/?xt=urn:btih:{}", HASH)).is_err());
+    }
+
+    #[test]
+    fn builds_the_torrent_from_matching_metadata() {
+        let dir = std::env::temp_dir().join(format!("magnet-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+        fs::write(&path, vec![7u8; 40000]).unwrap();
+        let options = CreateOptions { threads: 1, trackers: vec![vec!["http://t/announce".to_string()]], ..CreateOptions::default() };
+        let original = CustomTorrent::general_custom_torrent(&creator::create_torrent(&path, &options).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let magnet = Magnet::parse(&original.magnet_link()).unwrap();
+        let torrent = magnet.torrent(&original.raw_info).unwrap();
+        assert_eq!(torrent.info_hash, original.info_hash);
+        assert_eq!(torrent.name, original.name);
+        assert_eq!(torrent.length, 40000);
+        assert_eq!(torrent.announce, "http://t/announce");
+
+        let mut corrupt = original.raw_info.clone();
+        let last = corrupt.len() - 2;
+        corrupt[last] ^= 1;
+        assert!(!magnet.matches(&corrupt));
+        assert!(magnet.torrent(&corrupt).is_err());
+    }
+}
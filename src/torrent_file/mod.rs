@@ -1,2 +1,6 @@
 pub mod torrent_file;
 pub mod tracker;
+pub mod info;
+pub mod creator;
+pub mod v2;
+pub mod magnet;
@@ -1,117 +1,429 @@
-use std::fs::File;
+use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::Path;
-use std::str;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
 
-use bendy::{
-    decoding::{FromBencode},
+use serde_derive::Deserialize;
+use tracing::{info, info_span, instrument};
+use url::form_urlencoded::{byte_serialize};
+
+use crate::{
+    bencode::{
+        bencode::{Options, RawValue, Value},
+        de,
+    },
+    error::error::{Error, Result},
+    merkle::merkle::{self, Hash256},
+    torrent_file::{tracker::{self, AnnounceStats}, v2},
+    peers::peers::{Peer, PeerSource},
+    p2p::p2p::P2pTorrent,
+    peer_id::peer_id,
+    ratelimit::ratelimit::RateLimits,
 };
 
-use lava_torrent::torrent::v1::Torrent;
-use rand::RngCore;
-use url::Url;
-extern crate url;
-use url::form_urlencoded::{byte_serialize};
+// FileAttr holds the BEP 47 attr flags of a file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FileAttr {
+    // p: padding, all zeros, never stored on disk
+    pub pad: bool,
+    // x: executable
+    pub executable: bool,
+    // h: hidden
+    pub hidden: bool,
+    // l: symlink, the target is in symlink path
+    pub symlink: bool,
+}
+
+impl FileAttr {
+    // parse reads an attr string, unknown flags are ignored as BEP 47 asks
+    pub fn parse(attr: &str) -> Self {
+        Self {
+            pad: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+
+    pub fn padding() -> Self {
+        Self { pad: true, ..Self::default() }
+    }
 
-use crate::{torrent_file::tracker::BencodeTrackerResp, peers::peers::Peer, p2p::p2p::P2pTorrent};
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl std::fmt::Display for FileAttr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (set, flag) in [(self.executable, 'x'), (self.hidden, 'h'), (self.pad, 'p'), (self.symlink, 'l')] {
+            if set {
+                write!(f, "{}", flag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentFile {
+    // path relative to the save directory, starting with the torrent name
+    pub path: PathBuf,
+    pub length: usize,
+    // BEP 52 merkle root of the file, None for v1 files, pad files and empty files
+    pub pieces_root: Option<Hash256>,
+    pub attr: FileAttr,
+    // symlink target relative to the save directory like path, only set with attr l
+    pub symlink: Option<PathBuf>,
+}
 
-#[derive(Debug)]
+impl TorrentFile {
+    pub fn is_pad(&self) -> bool {
+        self.attr.pad
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetaVersion {
+    V1,
+    V2,
+    // carries both v1 pieces and a v2 file tree, joins both swarms
+    Hybrid,
+}
+
+#[derive(Debug, Clone)]
 pub struct CustomTorrent {
-    pub torrent: Torrent,
+    // the info dictionary exactly as it appeared in the file, the info hash is taken over these bytes
+    pub raw_info: Vec<u8>,
     pub announce: String,
+    pub version: MetaVersion,
+    // v1 SHA-1 info hash, for v2 only torrents the truncated v2 info hash
     pub info_hash: [u8; 20],
+    pub info_hash_v2: Option<[u8; 32]>,
+    // v1 SHA-1 piece hashes, empty for v2 only torrents
     pub piece_hashes: Vec<[u8; 20]>,
+    // BEP 52 piece layers by pieces root
+    pub piece_layers: HashMap<Hash256, Vec<Hash256>>,
     pub piece_length: usize,
     pub length: usize,
     pub name: String,
+    pub files: Vec<TorrentFile>,
+    // tiers of backup trackers from announce-list
+    pub announce_list: Vec<Vec<String>>,
+    // BEP 19 url-list
+    pub web_seeds: Vec<String>,
+    // BEP 17 httpseeds
+    pub http_seeds: Vec<String>,
+    pub private: bool,
+    // unix timestamp
+    pub creation_date: Option<i64>,
+    pub created_by: Option<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetaInfo<'a> {
+    announce: Option<String>,
+    #[serde(rename = "announce-list", default)]
+    announce_list: Vec<Vec<String>>,
+    #[serde(borrow)]
+    info: RawValue<'a>,
+    #[serde(rename = "piece layers", borrow)]
+    piece_layers: Option<Value<'a>>,
+    #[serde(rename = "url-list", borrow)]
+    url_list: Option<Value<'a>>,
+    #[serde(borrow)]
+    httpseeds: Option<Value<'a>>,
+    #[serde(rename = "creation date")]
+    creation_date: Option<i64>,
+    #[serde(rename = "created by")]
+    created_by: Option<String>,
+    comment: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InfoDict<'a> {
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    pieces: Option<&'a [u8]>,
+    length: Option<usize>,
+    // attr of a single file torrent
+    #[serde(default)]
+    attr: String,
+    files: Option<Vec<FileEntry>>,
+    #[serde(default)]
+    private: bool,
+    #[serde(rename = "meta version")]
+    meta_version: Option<i64>,
+    #[serde(rename = "file tree", borrow)]
+    file_tree: Option<Value<'a>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileEntry {
+    length: usize,
+    path: Vec<String>,
+    #[serde(default)]
+    attr: String,
+    #[serde(rename = "symlink path")]
+    symlink_path: Option<Vec<String>>,
+}
+
+// symlink_target resolves a BEP 47 symlink path, which is relative to the torrent root
+pub(crate) fn symlink_target(name: &str, attr: FileAttr, parts: Option<&[String]>) -> Result<Option<PathBuf>> {
+    if !attr.symlink {
+        return Ok(None);
+    }
+    let parts = parts.ok_or_else(|| Error::metainfo("symlink without symlink path"))?;
+    Ok(Some(Path::new(name).join(safe_path(parts)?)))
+}
+
+// total_length sums the file lengths, the lengths of a hostile torrent may overflow
+pub(crate) fn total_length(files: &[TorrentFile]) -> Result<usize> {
+    files
+        .iter()
+        .try_fold(0usize, |total, f| total.checked_add(f.length))
+        .ok_or_else(|| Error::metainfo("file lengths overflow"))
+}
+
+// url-list and httpseeds are either a single url or a list of them
+fn string_list(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::List(list)) => list.iter().filter_map(|v| v.as_str()).map(str::to_string).collect(),
+        Some(v) => v.as_str().filter(|s| !s.is_empty()).map(|s| vec![s.to_string()]).unwrap_or_default(),
+        None => vec![],
+    }
+}
+
+// safe_path joins path elements from the metainfo, refusing anything that could leave the save directory
+pub(crate) fn safe_path(parts: &[String]) -> Result<PathBuf> {
+    let path = parts.iter().collect::<PathBuf>();
+    let normal = !parts.is_empty() && parts.iter().all(|p| !p.is_empty() && !p.contains(['/', '\\']));
+    if !normal || path.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(Error::metainfo(format!("unsafe file path {:?}", parts)));
+    }
+    Ok(path)
 }
 
 impl CustomTorrent {
-    pub fn general_custom_torrent(torrent: Torrent) -> Result<Self, hex::FromHexError> {
-        let mut info_hash = [0u8; 20];
-        hex::decode_to_slice(torrent.info_hash(), &mut info_hash)?;
+    // GeneralCustomTorrent parses the content of a .torrent file
+    pub fn general_custom_torrent(data: &[u8]) -> Result<Self> {
+        let meta: MetaInfo = de::from_bytes(data).map_err(Error::metainfo)?;
+        let raw_info = meta.info.as_bytes();
+        let info: InfoDict = de::from_bytes_with(raw_info, Options::lenient()).map_err(|e| Error::metainfo(format!("info: {}", e)))?;
+
+        if info.piece_length == 0 {
+            return Err(Error::metainfo("piece length is zero"));
+        }
+        safe_path(std::slice::from_ref(&info.name))?;
+        let tree = match info.meta_version.unwrap_or(1) {
+            1 => None,
+            2 => {
+                v2::check_piece_length(info.piece_length)?;
+                let tree = info.file_tree.as_ref().ok_or_else(|| Error::metainfo("v2 info has no file tree"))?;
+                Some(v2::parse_file_tree(tree)?)
+            },
+            v => return Err(Error::metainfo(format!("unsupported meta version {}", v))),
+        };
+        let version = match (info.pieces.is_some(), tree.is_some()) {
+            (true, false) => MetaVersion::V1,
+            (false, true) => MetaVersion::V2,
+            (true, true) => MetaVersion::Hybrid,
+            (false, false) => return Err(Error::metainfo("info has no pieces")),
+        };
 
-        // let hash_len = 20;
-        let piece_hashes = torrent.pieces.iter().map(|p| {
+        let pieces = info.pieces.unwrap_or_default();
+        if !pieces.len().is_multiple_of(20) {
+            return Err(Error::metainfo(format!("pieces has {} bytes, not a multiple of 20", pieces.len())));
+        }
+        let piece_hashes = pieces.chunks_exact(20).map(|p| {
             let mut piece = [0u8; 20];
-            piece.copy_from_slice(&p[..]);
+            piece.copy_from_slice(p);
             piece
         }).collect::<Vec<_>>();
 
+        let files = match (&tree, version) {
+            (Some(tree), MetaVersion::V2) => v2::layout(&info.name, tree, info.piece_length)?,
+            _ => {
+                let mut files = match (&info.files, info.length) {
+                    (Some(files), _) => files.iter().map(|f| {
+                        let attr = FileAttr::parse(&f.attr);
+                        Ok(TorrentFile {
+                            path: Path::new(&info.name).join(safe_path(&f.path)?),
+                            length: f.length,
+                            pieces_root: None,
+                            attr,
+                            symlink: symlink_target(&info.name, attr, f.symlink_path.as_deref())?,
+                        })
+                    }).collect::<Result<Vec<_>>>()?,
+                    (None, Some(length)) => vec![TorrentFile {
+                        path: PathBuf::from(&info.name),
+                        length,
+                        pieces_root: None,
+                        // 单文件 torrent 不能是符号链接
+                        attr: FileAttr { symlink: false, ..FileAttr::parse(&info.attr) },
+                        symlink: None,
+                    }],
+                    (None, None) => return Err(Error::metainfo("info has neither length nor files")),
+                };
+                if let Some(tree) = &tree {
+                    v2::attach_roots(&info.name, tree, &mut files)?;
+                }
+                files
+            },
+        };
+        let length = total_length(&files)?;
+        if version != MetaVersion::V2 && length.div_ceil(info.piece_length) != piece_hashes.len() {
+            return Err(Error::metainfo(format!("{} pieces don't cover {} bytes", piece_hashes.len(), length)));
+        }
+
+        let info_hash_v2 = tree.as_ref().map(|_| merkle::sha256(raw_info));
+        let info_hash = match info_hash_v2 {
+            Some(v2) if version == MetaVersion::V2 => {
+                let mut hash = [0u8; 20];
+                hash.copy_from_slice(&v2[..20]);
+                hash
+            },
+            _ => sha1::Sha1::from(raw_info).digest().bytes(),
+        };
+        let piece_layers = match version {
+            MetaVersion::V1 => HashMap::new(),
+            _ => v2::parse_piece_layers(meta.piece_layers.as_ref(), &files, info.piece_length)?,
+        };
+
         Ok(CustomTorrent {
-            torrent: torrent.clone(),
-            announce: torrent.announce.unwrap_or_else(|| "".to_string()),
+            raw_info: raw_info.to_vec(),
+            announce: meta.announce.unwrap_or_default(),
+            version,
             info_hash,
+            info_hash_v2,
             piece_hashes,
-            piece_length: torrent.piece_length as usize,
-            length: torrent.length as usize,
-            name: torrent.name,
+            piece_layers,
+            piece_length: info.piece_length,
+            length,
+            name: info.name,
+            files,
+            announce_list: meta.announce_list,
+            web_seeds: string_list(meta.url_list.as_ref()),
+            http_seeds: string_list(meta.httpseeds.as_ref()),
+            private: info.private,
+            creation_date: meta.creation_date,
+            created_by: meta.created_by,
+            comment: meta.comment,
         })
     }
 
+    pub fn down_load_to_file(&self, out_path: &str) -> Result<()> {
+        self.down_load_to_file_with_limits(out_path, RateLimits::default())
+    }
 
-    pub fn down_load_to_file(&self, out_path: &str) {
-        let mut peer_id = [0u8; 20];
-        rand::thread_rng().fill_bytes(&mut peer_id);
-        let peers = self.request_peers(&peer_id, 6881);
-        let p2p_torrent = P2pTorrent::general_p2p_torrent(self, peers, peer_id);
-        let buf = p2p_torrent.download();
-        println!("下载完成 buf_len {}", buf.len());
+    // DownLoadToFileWithLimits downloads the torrent while charging all peer traffic to limits,
+    // limits can be cloned and changed from another thread while the download runs
+    pub fn down_load_to_file_with_limits(&self, out_path: &str, limits: RateLimits) -> Result<()> {
+        let _span = info_span!("torrent", info_hash = %hex::encode(self.info_hash), name = %self.name).entered();
+        let peer_id = peer_id::generate(&peer_id::default_prefix());
+        let peers = self.request_peers(&peer_id, rand::random(), 6881)?;
+        let p2p_torrent = P2pTorrent::general_p2p_torrent(self, peers, peer_id, limits);
+        let buf = p2p_torrent.download()?;
+        info!(bytes = buf.len(), "下载完成");
 
         let path = Path::new(out_path);
-        let display = path.display();
-        let mut file = match File::create(&path) {
-            Err(why) => panic!("couldn't create {}: {}", display, why.to_string()),
-            Ok(file) => file,
-        };
-        match file.write_all(&buf) {
-            Err(why) => {
-                panic!("couldn't write to {}: {}", display, why.to_string());
-            },
-            Ok(_) => println!("successfully wrote to {}", display),
+        let mut file = File::create(path).map_err(|e| Error::storage(path, e))?;
+        file.write_all(&buf).map_err(|e| Error::storage(path, e))?;
+        info!(path = %path.display(), "successfully wrote");
+        Ok(())
+    }
+
+    // pad_ranges returns the byte ranges of a piece that belong to pad files, relative to the piece start
+    pub fn pad_ranges(&self, index: usize) -> Vec<Range<usize>> {
+        let begin = index * self.piece_length;
+        let end = (begin + self.piece_length).min(self.length);
+        let mut ranges = vec![];
+        let mut offset = 0;
+        for file in &self.files {
+            let (start, stop) = (offset.max(begin), (offset + file.length).min(end));
+            if file.is_pad() && start < stop {
+                ranges.push(start - begin..stop - begin);
+            }
+            offset += file.length;
         }
+        ranges
     }
 
-    fn request_peers(&self, peer_id: &[u8], port: u16) -> Vec<Peer> {
-        let url = self.build_tracker_url(peer_id, port);
-        let resp = reqwest::blocking::get(url.as_str()).unwrap();
-        let tracker = BencodeTrackerResp::from_bencode(&resp.bytes().unwrap()).unwrap();
-        tracker.peers
+    // MagnetLink returns a magnet uri with the display name and tracker, v2 hashes use the btmh multihash form
+    pub fn magnet_link(&self) -> String {
+        let mut link = match (self.has_v1(), self.info_hash_v2) {
+            (true, Some(v2)) => format!("magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}", hex::encode(self.info_hash), hex::encode(v2)),
+            (false, Some(v2)) => format!("magnet:?xt=urn:btmh:1220{}", hex::encode(v2)),
+            _ => format!("magnet:?xt=urn:btih:{}", hex::encode(self.info_hash)),
+        };
+        link += "&dn=";
+        link += &byte_serialize(self.name.as_bytes()).collect::<String>();
+        if !self.announce.is_empty() {
+            link += "&tr=";
+            link += &byte_serialize(self.announce.as_bytes()).collect::<String>();
+        }
+        link
     }
 
-    fn build_tracker_url(&self, peer_id: &[u8], port: u16) -> url::Url {
-        let info_hash: String = byte_serialize(&self.info_hash).collect();
-        let peer = byte_serialize(&peer_id).collect::<String>();// String::from_utf8_lossy(peer_id).to_string();
-        let port = port.to_string();
-        let uploaded = "0";
-        let downloaded = "0";
-        let compact = "1";
-        let left = &self.length.to_string();
+    // AllowsPeerSource tells whether peers from source may be used, BEP 27 private torrents
+    // only talk to peers handed out by their own trackers and peers that connect to us
+    pub fn allows_peer_source(&self, source: PeerSource) -> bool {
+        !self.private || matches!(source, PeerSource::Tracker | PeerSource::Incoming)
+    }
 
-        let mut parsed = Url::parse(&self.announce).unwrap();
-        let mut hash = "info_hash=".to_string() + &info_hash;
-        hash += "&peer_id=";
-        hash += &peer;
-        parsed.set_query(Some(&hash));
+    pub fn request_peers(&self, peer_id: &[u8], key: u32, port: u16) -> Result<Vec<Peer>> {
+        self.request_peers_for(&self.info_hash, peer_id, key, port)
+    }
 
-        parsed.query_pairs_mut().append_pair("port", &port);
-        parsed.query_pairs_mut().append_pair("uploaded", uploaded);
-        parsed.query_pairs_mut().append_pair("downloaded", downloaded);
-        parsed.query_pairs_mut().append_pair("compact", compact);
-        parsed.query_pairs_mut().append_pair("left", left);
+    // RequestPeersFor announces one of the torrent's swarm hashes, see swarm_hashes, as a client
+    // that has nothing yet
+    pub fn request_peers_for(&self, info_hash: &[u8; 20], peer_id: &[u8], key: u32, port: u16) -> Result<Vec<Peer>> {
+        let stats = AnnounceStats { left: self.length as u64, ..AnnounceStats::default() };
+        self.announce(info_hash, peer_id, key, port, &stats)
+    }
 
-        return parsed;
+    // Announce reports our transfer in the swarm of info_hash and returns the peers the tracker
+    // hands out. Private trackers identify us by peer_id and key, both must stay the same for the
+    // whole session
+    #[instrument(name = "announce", skip_all, fields(url = %self.announce, info_hash = %hex::encode(info_hash), event = stats.event.map(|e| e.as_str())))]
+    pub fn announce(&self, info_hash: &[u8; 20], peer_id: &[u8], key: u32, port: u16, stats: &AnnounceStats) -> Result<Vec<Peer>> {
+        tracker::announce(&self.announce, info_hash, peer_id, key, port, stats)
     }
 
 }
 
-pub fn open(path: &str) -> Result<CustomTorrent, lava_torrent::LavaTorrentError> {
-    let torrent = Torrent::read_from_file(path)?;
+pub fn open(path: &str) -> Result<CustomTorrent> {
+    let data = fs::read(path).map_err(|e| Error::metainfo(format!("{}: {}", path, e)))?;
+    CustomTorrent::general_custom_torrent(&data).map_err(|e| match e {
+        Error::Metainfo { reason } => Error::metainfo(format!("{}: {}", path, reason)),
+        e => e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let torrent = CustomTorrent::general_custom_torrent(torrent);
-    if let Err(_) = torrent {
-        return Err(lava_torrent::LavaTorrentError::from(std::io::Error::new(std::io::ErrorKind::Other, "")));
+    // single_file builds the metainfo of a one piece file, private adds the BEP 27 flag
+    fn single_file(private: bool) -> CustomTorrent {
+        let flag = if private { "7:privatei1e" } else { "" };
+        let info = format!("d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:{}{}e", "x".repeat(20), flag);
+        CustomTorrent::general_custom_torrent(format!("d8:announce13:http://t/anno4:info{}e", info).as_bytes()).unwrap()
     }
-    Ok(torrent.unwrap())
-}
 
+    #[test]
+    fn private_torrents_only_use_tracker_and_incoming_peers() {
+        let sources = [PeerSource::Tracker, PeerSource::Incoming, PeerSource::Dht, PeerSource::Pex, PeerSource::Lsd];
+        let public = single_file(false);
+        assert!(!public.private);
+        assert!(sources.iter().all(|s| public.allows_peer_source(*s)));
+
+        let private = single_file(true);
+        assert!(private.private);
+        let allowed: Vec<_> = sources.into_iter().filter(|s| private.allows_peer_source(*s)).collect();
+        assert_eq!(allowed, vec![PeerSource::Tracker, PeerSource::Incoming]);
+    }
+}
@@ -1,46 +1,131 @@
-use bendy::{
-    decoding::{Error as BendyError, FromBencode, Object},
-};
+use std::net::{IpAddr, SocketAddr};
+
+use serde_derive::Deserialize;
+use tracing::debug;
+use url::{form_urlencoded::byte_serialize, Url};
 
-use crate::peers::peers::{un_marshal, Peer};
+use crate::{
+    bencode::{
+        bencode::{BencodeError, Value},
+        de,
+    },
+    error::error::{Error, Result},
+    peers::peers::{un_marshal, Peer},
+};
 
 #[derive(Debug)]
 pub struct BencodeTrackerResp {
     pub interval: u64,
     pub peers: Vec<Peer>,
+    pub failure_reason: Option<String>,
 }
 
-impl FromBencode for BencodeTrackerResp {
-    fn decode_bencode_object(object: Object) -> Result<Self, BendyError>
-    where
-        Self: Sized,
-    {
-        let mut interval = 0u64;
-        let mut peers = vec![];
-
-        let mut dict_dec = object.try_into_dictionary()?;
-
-        while let Some(pair) = dict_dec.next_pair()? {
-            match pair {
-                (b"interval", value) => {
-                    fn default_v(_: bendy::decoding::Error) -> u64 { 0 }
-                    interval = u64::decode_bencode_object(value).unwrap_or_else(default_v);
-                },
-                (b"peers", value) => {
-                    // fn default_v(s: bendy::decoding::Error) -> String { "".to_string() }
-                    // peers = String::decode_bencode_object(value).unwrap_or_else(default_v);
-                    let byte = value.bytes_or(Err(""));
-                    if let Ok(bytes) = byte {
-                        peers = un_marshal(bytes);
-                    }
-                },
-                (_, _) => {},
-            }
-        }
+#[derive(Debug, Deserialize)]
+struct RawTrackerResp<'a> {
+    #[serde(default)]
+    interval: u64,
+    #[serde(borrow)]
+    peers: Option<Value<'a>>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+// peers is either the compact string or a list of dictionaries with ip and port
+fn parse_peers(peers: &Value) -> Vec<Peer> {
+    if let Some(bytes) = peers.as_bytes() {
+        return un_marshal(bytes);
+    }
+    peers
+        .as_list()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|p| {
+            let ip = p.get("ip")?.as_str()?.parse::<IpAddr>().ok()?;
+            let port = u16::try_from(p.get("port")?.as_int()?).ok()?;
+            Some(Peer::new(SocketAddr::new(ip, port)))
+        })
+        .collect()
+}
 
+impl BencodeTrackerResp {
+    pub fn decode(body: &[u8]) -> std::result::Result<Self, BencodeError> {
+        let resp: RawTrackerResp = de::from_bytes(body)?;
         Ok(BencodeTrackerResp {
-            interval,
-            peers,
+            interval: resp.interval,
+            peers: resp.peers.as_ref().map(parse_peers).unwrap_or_default(),
+            failure_reason: resp.failure_reason,
         })
     }
 }
+
+// AnnounceEvent marks the announces that start, complete or stop our part in a swarm, the
+// regular ones in between carry none
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+// AnnounceStats is what we report about our transfer with an announce, counted in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AnnounceStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+}
+
+// announce sends stats for the swarm of info_hash to the http tracker at url and returns the peers it hands out
+pub fn announce(url: &str, info_hash: &[u8; 20], peer_id: &[u8], key: u32, port: u16, stats: &AnnounceStats) -> Result<Vec<Peer>> {
+    let request = build_tracker_url(url, info_hash, peer_id, key, port, stats)?;
+    let resp = reqwest::blocking::get(request.as_str()).map_err(|e| Error::tracker(url, e))?;
+    if !resp.status().is_success() {
+        return Err(Error::tracker(url, format!("http status {}", resp.status())));
+    }
+    let body = resp.bytes().map_err(|e| Error::tracker(url, e))?;
+    let tracker = BencodeTrackerResp::decode(&body).map_err(|e| Error::tracker(url, e))?;
+    if let Some(reason) = tracker.failure_reason {
+        return Err(Error::tracker(url, reason));
+    }
+    debug!(peers = tracker.peers.len(), interval = tracker.interval, "tracker reply");
+    Ok(tracker.peers)
+}
+
+fn build_tracker_url(url: &str, info_hash: &[u8; 20], peer_id: &[u8], key: u32, port: u16, stats: &AnnounceStats) -> Result<Url> {
+    let info_hash: String = byte_serialize(info_hash).collect();
+    let peer = byte_serialize(peer_id).collect::<String>();// String::from_utf8_lossy(peer_id).to_string();
+    let port = port.to_string();
+    let uploaded = &stats.uploaded.to_string();
+    let downloaded = &stats.downloaded.to_string();
+    let compact = "1";
+    let left = &stats.left.to_string();
+
+    let mut parsed = Url::parse(url).map_err(|e| Error::tracker(url, e))?;
+    let mut hash = "info_hash=".to_string() + &info_hash;
+    hash += "&peer_id=";
+    hash += &peer;
+    parsed.set_query(Some(&hash));
+
+    parsed.query_pairs_mut().append_pair("port", &port);
+    parsed.query_pairs_mut().append_pair("uploaded", uploaded);
+    parsed.query_pairs_mut().append_pair("downloaded", downloaded);
+    parsed.query_pairs_mut().append_pair("compact", compact);
+    parsed.query_pairs_mut().append_pair("left", left);
+    parsed.query_pairs_mut().append_pair("key", &format!("{:08x}", key));
+    if let Some(event) = stats.event {
+        parsed.query_pairs_mut().append_pair("event", event.as_str());
+    }
+
+    Ok(parsed)
+}
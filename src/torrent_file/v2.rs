@@ -0,0 +1,476 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, str};
+
+use crate::{
+    bencode::bencode::Value,
+    error::error::{Error, Result},
+    merkle::merkle::{self, Hash256, BLOCK_SIZE},
+    message::message::HashRequest,
+    torrent_file::torrent_file::{safe_path, symlink_target, CustomTorrent, FileAttr, MetaVersion, TorrentFile},
+};
+
+// 一次 hash request 最多请求的 hash 个数，太大的请求会被对方拒绝
+const MAX_HASH_REQUEST: usize = 512;
+
+// TreeFile is one file of a BEP 52 file tree, path elements are below the torrent name
+#[derive(Debug, Clone)]
+pub(crate) struct TreeFile {
+    pub path: Vec<String>,
+    pub length: usize,
+    // None only for empty files
+    pub pieces_root: Option<Hash256>,
+    pub attr: FileAttr,
+    pub symlink_path: Option<Vec<String>>,
+}
+
+fn to_hash256(bytes: &[u8]) -> Option<Hash256> {
+    bytes.try_into().ok()
+}
+
+fn walk(node: &Value, prefix: &mut Vec<String>, files: &mut Vec<TreeFile>) -> Result<()> {
+    let dict = node.as_dict().ok_or_else(|| Error::metainfo("file tree node is not a dictionary"))?;
+    // BTreeMap 的顺序就是 BEP 52 要求的文件顺序
+    for (name, child) in dict {
+        if name.is_empty() {
+            let length = child
+                .get("length")
+                .and_then(Value::as_int)
+                .and_then(|l| usize::try_from(l).ok())
+                .ok_or_else(|| Error::metainfo(format!("file {:?} has no valid length", prefix)))?;
+            let pieces_root = child.get("pieces root").and_then(Value::as_bytes).and_then(to_hash256);
+            if length > 0 && pieces_root.is_none() {
+                return Err(Error::metainfo(format!("file {:?} has no pieces root", prefix)));
+            }
+            let attr = FileAttr::parse(child.get("attr").and_then(Value::as_str).unwrap_or_default());
+            let symlink_path = child
+                .get("symlink path")
+                .and_then(Value::as_list)
+                .map(|parts| parts.iter().filter_map(Value::as_str).map(str::to_string).collect());
+            files.push(TreeFile { path: prefix.clone(), length, pieces_root, attr, symlink_path });
+            continue;
+        }
+        let name = str::from_utf8(name).map_err(|_| Error::metainfo("file tree name is not utf-8"))?;
+        prefix.push(name.to_string());
+        walk(child, prefix, files)?;
+        prefix.pop();
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_file_tree(tree: &Value) -> Result<Vec<TreeFile>> {
+    let mut files = vec![];
+    walk(tree, &mut vec![], &mut files)?;
+    if files.is_empty() {
+        return Err(Error::metainfo("file tree is empty"));
+    }
+    Ok(files)
+}
+
+// tree_path maps a tree file to a save path, a lone file named like the torrent is stored as is
+fn tree_path(name: &str, tree: &[TreeFile], file: &TreeFile) -> Result<PathBuf> {
+    if tree.len() == 1 && file.path.len() == 1 && file.path[0] == name {
+        return Ok(PathBuf::from(name));
+    }
+    Ok(Path::new(name).join(safe_path(&file.path)?))
+}
+
+// layout lays the files of a v2 only torrent out like a hybrid torrent would, every file
+// starts on a piece boundary and the gaps are filled with pad files
+pub(crate) fn layout(name: &str, tree: &[TreeFile], piece_length: usize) -> Result<Vec<TorrentFile>> {
+    let mut files = vec![];
+    for (i, file) in tree.iter().enumerate() {
+        files.push(TorrentFile {
+            path: tree_path(name, tree, file)?,
+            length: file.length,
+            pieces_root: file.pieces_root,
+            attr: file.attr,
+            symlink: symlink_target(name, file.attr, file.symlink_path.as_deref())?,
+        });
+        let tail = file.length % piece_length;
+        if tail != 0 && i + 1 < tree.len() {
+            let pad = piece_length - tail;
+            files.push(TorrentFile {
+                path: Path::new(name).join(".pad").join(pad.to_string()),
+                length: pad,
+                pieces_root: None,
+                attr: FileAttr::padding(),
+                symlink: None,
+            });
+        }
+    }
+    Ok(files)
+}
+
+// attach_roots copies the pieces roots of a hybrid torrent's file tree onto its v1 file list
+pub(crate) fn attach_roots(name: &str, tree: &[TreeFile], files: &mut [TorrentFile]) -> Result<()> {
+    let mut roots = HashMap::new();
+    for file in tree {
+        roots.insert(tree_path(name, tree, file)?, (file.length, file.pieces_root));
+    }
+    let mut matched = 0;
+    for file in files.iter_mut() {
+        if let Some((length, root)) = roots.get(&file.path) {
+            if *length != file.length {
+                return Err(Error::metainfo(format!("{} has different lengths in v1 and v2", file.path.display())));
+            }
+            file.pieces_root = *root;
+            matched += 1;
+        }
+    }
+    if matched != tree.len() {
+        return Err(Error::metainfo("v1 file list and v2 file tree don't match"));
+    }
+    Ok(())
+}
+
+// parse_piece_layers reads the piece layers of every file larger than a piece and checks
+// each layer against its pieces root
+pub(crate) fn parse_piece_layers(layers: Option<&Value>, files: &[TorrentFile], piece_length: usize) -> Result<HashMap<Hash256, Vec<Hash256>>> {
+    let mut result = HashMap::new();
+    let layers = match layers.and_then(Value::as_dict) {
+        Some(layers) => layers,
+        None => return Ok(result),
+    };
+    for file in files.iter().filter(|f| f.length > piece_length) {
+        let root = match file.pieces_root {
+            Some(root) => root,
+            None => continue,
+        };
+        let bytes = match layers.get(&root[..]).and_then(Value::as_bytes) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        if !bytes.len().is_multiple_of(32) || bytes.len() / 32 != file.length.div_ceil(piece_length) {
+            return Err(Error::metainfo(format!("piece layer of {} has the wrong size", file.path.display())));
+        }
+        let layer = bytes.chunks_exact(32).filter_map(to_hash256).collect::<Vec<_>>();
+        if merkle::root_from_piece_layer(&layer, piece_length) != root {
+            return Err(Error::metainfo(format!("piece layer of {} doesn't match its pieces root", file.path.display())));
+        }
+        result.insert(root, layer);
+    }
+    Ok(result)
+}
+
+// V2Piece is a piece's place in the merkle tree of its file
+#[derive(Debug, Clone, Copy)]
+struct V2Piece {
+    pieces_root: Hash256,
+    // the piece's index within its file
+    index: usize,
+    file_pieces: usize,
+    // bytes of the file in the piece, the rest is padding
+    length: usize,
+}
+
+pub(crate) fn check_piece_length(piece_length: usize) -> Result<()> {
+    if piece_length < BLOCK_SIZE || !piece_length.is_power_of_two() {
+        return Err(Error::metainfo(format!("v2 piece length {} is not a power of two of at least 16 KiB", piece_length)));
+    }
+    Ok(())
+}
+
+impl CustomTorrent {
+    pub fn has_v1(&self) -> bool {
+        self.version != MetaVersion::V2
+    }
+
+    pub fn has_v2(&self) -> bool {
+        self.version != MetaVersion::V1
+    }
+
+    // truncated_info_hash_v2 is the first 20 bytes of the v2 info hash, used in handshakes and announces
+    pub fn truncated_info_hash_v2(&self) -> Option<[u8; 20]> {
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&self.info_hash_v2?[..20]);
+        Some(hash)
+    }
+
+    // swarm_hashes returns the info hashes the torrent is known by, a hybrid torrent joins both swarms
+    pub fn swarm_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes = vec![self.info_hash];
+        if let Some(v2) = self.truncated_info_hash_v2() {
+            if v2 != self.info_hash {
+                hashes.push(v2);
+            }
+        }
+        hashes
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.length.div_ceil(self.piece_length)
+    }
+
+    // verify_piece checks a downloaded piece against every hash the torrent has for it
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        self.verify_piece_with(index, data, |_, _| None)
+    }
+
+    // verify_piece_with is verify_piece for v2 torrents whose metainfo lacks piece layers,
+    // layer_hash looks up piece layer hashes obtained from peers by pieces root and piece in the file
+    pub fn verify_piece_with(&self, index: usize, data: &[u8], layer_hash: impl Fn(&Hash256, usize) -> Option<Hash256>) -> bool {
+        if self.has_v1() && self.piece_hashes.get(index) != Some(&sha1::Sha1::from(data).digest().bytes()) {
+            return false;
+        }
+        if self.has_v2() {
+            return self.verify_piece_v2(index, data, layer_hash);
+        }
+        true
+    }
+
+    // v2_piece locates a piece in the merkle tree of its file, None for pieces of pad files or
+    // files without v2 information
+    fn v2_piece(&self, index: usize) -> Option<V2Piece> {
+        let begin = index * self.piece_length;
+        let mut offset = 0;
+        for file in &self.files {
+            let end = offset + file.length;
+            if file.length == 0 || end <= begin {
+                offset = end;
+                continue;
+            }
+            return file.pieces_root.map(|pieces_root| V2Piece {
+                pieces_root,
+                index: (begin - offset) / self.piece_length,
+                file_pieces: file.length.div_ceil(self.piece_length),
+                length: (end - begin).min(self.piece_length),
+            });
+        }
+        None
+    }
+
+    fn verify_piece_v2(&self, index: usize, data: &[u8], layer_hash: impl Fn(&Hash256, usize) -> Option<Hash256>) -> bool {
+        let piece = match self.v2_piece(index) {
+            Some(piece) => piece,
+            // pad 文件或没有 v2 信息的文件，只能依赖 v1 校验
+            None => return self.has_v1(),
+        };
+        let part = &data[..piece.length.min(data.len())];
+        if piece.file_pieces == 1 {
+            return merkle::file_root(part) == piece.pieces_root;
+        }
+        let expected = self
+            .piece_layers
+            .get(&piece.pieces_root)
+            .and_then(|layer| layer.get(piece.index).copied())
+            .or_else(|| layer_hash(&piece.pieces_root, piece.index));
+        match expected {
+            Some(expected) => merkle::piece_hash(part, self.piece_length) == expected,
+            None => self.has_v1(),
+        }
+    }
+
+    // layer_request asks for the piece layer hashes covering a piece of a v2 only torrent whose
+    // metainfo has no piece layer for the file, None when the piece can be checked without them
+    pub fn layer_request(&self, index: usize) -> Option<HashRequest> {
+        let piece = self.v2_piece(index).filter(|_| !self.has_v1())?;
+        if piece.file_pieces == 1 || self.piece_layers.contains_key(&piece.pieces_root) {
+            return None;
+        }
+        let width = piece.file_pieces.next_power_of_two();
+        let length = width.min(MAX_HASH_REQUEST);
+        Some(HashRequest {
+            pieces_root: piece.pieces_root,
+            base_layer: self.piece_height(),
+            index: (piece.index / length * length) as u32,
+            length: length as u32,
+            proof_layers: (width / length).trailing_zeros(),
+        })
+    }
+
+    // block_request asks for the 16 KiB block hashes of a v2 piece, with them a piece that failed
+    // its check tells which blocks were corrupt
+    pub fn block_request(&self, index: usize) -> Option<HashRequest> {
+        let piece = self.v2_piece(index)?;
+        let per_piece = self.piece_length / BLOCK_SIZE;
+        let (index, length, width) = if piece.file_pieces == 1 {
+            // 单个 piece 的文件没有 piece 层，叶子直接算到 pieces root
+            let width = piece.length.div_ceil(BLOCK_SIZE).next_power_of_two();
+            (0, width, width)
+        } else {
+            (piece.index * per_piece, per_piece, piece.file_pieces.next_power_of_two() * per_piece)
+        };
+        if length > MAX_HASH_REQUEST {
+            return None;
+        }
+        Some(HashRequest {
+            pieces_root: piece.pieces_root,
+            base_layer: 0,
+            index: index as u32,
+            length: length as u32,
+            proof_layers: (width / length).trailing_zeros(),
+        })
+    }
+
+    // bad_blocks returns the blocks of a piece that differ from the leaves of its block_request
+    pub fn bad_blocks(&self, index: usize, data: &[u8], leaves: &[Hash256]) -> Vec<usize> {
+        match self.v2_piece(index) {
+            // 文件之后的 pad 部分不在 merkle 树中
+            Some(piece) => merkle::bad_blocks(&data[..piece.length.min(data.len())], leaves),
+            None => vec![],
+        }
+    }
+
+    fn piece_height(&self) -> u32 {
+        (self.piece_length / BLOCK_SIZE).max(1).trailing_zeros()
+    }
+
+    // answer_hash_request returns the requested piece layer hashes followed by their proof,
+    // None when we can't serve it: only the piece layers of the metainfo are kept
+    pub fn answer_hash_request(&self, req: &HashRequest) -> Option<Vec<Hash256>> {
+        let layer = self.piece_layers.get(&req.pieces_root)?;
+        let height = self.piece_height();
+        let (index, length) = (req.index as usize, req.length as usize);
+        let width = layer.len().next_power_of_two();
+        if req.base_layer != height || length == 0 || !length.is_power_of_two() || !index.is_multiple_of(length) || index + length > width {
+            return None;
+        }
+        let pad = merkle::pad_hash(height);
+        let mut padded = layer.clone();
+        padded.resize(width, pad);
+        let mut hashes = padded[index..index + length].to_vec();
+
+        // 先把该层收缩到以 length 个节点为一个子树的层，再逐层取兄弟节点
+        let mut current = padded;
+        let mut step = length;
+        while step > 1 {
+            current = current.chunks(2).map(|pair| merkle::hash_pair(&pair[0], &pair[1])).collect();
+            step /= 2;
+        }
+        let mut pos = index / length;
+        let mut proof_layers = req.proof_layers;
+        while current.len() > 1 && proof_layers > 0 {
+            hashes.push(current[pos ^ 1]);
+            current = current.chunks(2).map(|pair| merkle::hash_pair(&pair[0], &pair[1])).collect();
+            pos /= 2;
+            proof_layers -= 1;
+        }
+        Some(hashes)
+    }
+
+    // verify_hashes checks a hashes message against the pieces root of a file of the torrent
+    pub fn verify_hashes(&self, req: &HashRequest, hashes: &[Hash256]) -> bool {
+        let known = self.files.iter().any(|f| f.pieces_root == Some(req.pieces_root));
+        let length = req.length as usize;
+        if !known || length == 0 || hashes.len() < length {
+            return false;
+        }
+        let (nodes, uncles) = hashes.split_at(length);
+        merkle::verify_proof(nodes, req.index as usize, uncles, &req.pieces_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::bencode::bencode::Dict;
+
+    fn dict(pairs: Vec<(&str, Value<'static>)>) -> Value<'static> {
+        Value::Dict(pairs.into_iter().map(|(k, v)| (Cow::Owned(k.as_bytes().to_vec()), v)).collect::<Dict>())
+    }
+
+    fn bytes(b: &[u8]) -> Value<'static> {
+        Value::Bytes(Cow::Owned(b.to_vec()))
+    }
+
+    // single_file builds a v2 only torrent of one file from its piece layer
+    fn single_file(length: usize, piece_length: usize, layer: &[Hash256], with_layers: bool) -> CustomTorrent {
+        let root = if layer.len() == 1 { layer[0] } else { merkle::root_from_piece_layer(layer, piece_length) };
+        let file = dict(vec![("", dict(vec![("length", Value::Int(length as i64)), ("pieces root", bytes(&root))]))]);
+        let info = dict(vec![
+            ("file tree", dict(vec![("f", file)])),
+            ("meta version", Value::Int(2)),
+            ("name", bytes(b"f")),
+            ("piece length", Value::Int(piece_length as i64)),
+        ]);
+        let mut meta = vec![("info", info)];
+        if with_layers && layer.len() > 1 {
+            meta.push(("piece layers", Value::Dict([(Cow::Owned(root.to_vec()), bytes(&layer.concat()))].into_iter().collect())));
+        }
+        CustomTorrent::general_custom_torrent(&dict(meta).encode()).unwrap()
+    }
+
+    fn data_torrent(data: &[u8], piece_length: usize, with_layers: bool) -> CustomTorrent {
+        let layer = data.chunks(piece_length).map(|p| merkle::piece_hash(p, piece_length)).collect::<Vec<_>>();
+        let layer = if layer.len() == 1 { vec![merkle::file_root(data)] } else { layer };
+        single_file(data.len(), piece_length, &layer, with_layers)
+    }
+
+    fn sample(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn piece_layers_from_a_peer_verify_pieces() {
+        let data = sample(5 * BLOCK_SIZE + 100);
+        let full = data_torrent(&data, BLOCK_SIZE, true);
+        let bare = data_torrent(&data, BLOCK_SIZE, false);
+        assert!(full.verify_piece(2, &data[2 * BLOCK_SIZE..3 * BLOCK_SIZE]));
+        assert!(!bare.verify_piece(2, &data[2 * BLOCK_SIZE..3 * BLOCK_SIZE]));
+
+        let req = bare.layer_request(2).unwrap();
+        assert_eq!((req.index, req.length, req.proof_layers), (0, 8, 0));
+        let hashes = full.answer_hash_request(&req).unwrap();
+        assert!(bare.verify_hashes(&req, &hashes));
+        let mut forged = hashes.clone();
+        forged[3][0] ^= 1;
+        assert!(!bare.verify_hashes(&req, &forged));
+
+        let layer = &hashes[..req.length as usize];
+        for (index, piece) in data.chunks(BLOCK_SIZE).enumerate() {
+            assert!(bare.verify_piece_with(index, piece, |root, i| (*root == req.pieces_root).then(|| layer[i])));
+        }
+        assert!(!bare.verify_piece_with(1, &data[..BLOCK_SIZE], |_, i| Some(layer[i])));
+        assert!(full.layer_request(2).is_none());
+    }
+
+    #[test]
+    fn large_layers_are_requested_in_proven_chunks() {
+        let pieces = 1100;
+        let layer = (0..pieces).map(|i| merkle::sha256(&(i as u32).to_be_bytes())).collect::<Vec<_>>();
+        let full = single_file(pieces * BLOCK_SIZE, BLOCK_SIZE, &layer, true);
+        let bare = single_file(pieces * BLOCK_SIZE, BLOCK_SIZE, &layer, false);
+
+        let req = bare.layer_request(1030).unwrap();
+        assert_eq!((req.index, req.length, req.proof_layers), (1024, 512, 2));
+        let hashes = full.answer_hash_request(&req).unwrap();
+        assert_eq!(hashes.len(), 514);
+        assert!(bare.verify_hashes(&req, &hashes));
+        assert_eq!(hashes[6], layer[1030]);
+    }
+
+    #[test]
+    fn block_hashes_find_the_corrupt_block() {
+        let piece_length = 4 * BLOCK_SIZE;
+        let data = sample(3 * piece_length);
+        let full = data_torrent(&data, piece_length, true);
+        let req = full.block_request(1).unwrap();
+        assert_eq!((req.base_layer, req.index, req.length, req.proof_layers), (0, 4, 4, 2));
+
+        // 叶子加上 piece 1 到 root 的证明
+        let leaves = merkle::block_hashes(&data[piece_length..2 * piece_length]);
+        let layer_req = HashRequest { pieces_root: req.pieces_root, base_layer: 2, index: 1, length: 1, proof_layers: 2 };
+        let proof = full.answer_hash_request(&layer_req).unwrap();
+        let answer = [leaves.clone(), proof[1..].to_vec()].concat();
+        assert!(full.verify_hashes(&req, &answer));
+
+        let mut piece = data[piece_length..2 * piece_length].to_vec();
+        piece[2 * BLOCK_SIZE + 9] ^= 0xff;
+        assert!(!full.verify_piece(1, &piece));
+        assert_eq!(full.bad_blocks(1, &piece, &leaves), vec![2]);
+    }
+
+    #[test]
+    fn small_file_blocks_prove_to_the_root() {
+        let data = sample(3 * BLOCK_SIZE + 5);
+        let torrent = data_torrent(&data, 8 * BLOCK_SIZE, false);
+        let req = torrent.block_request(0).unwrap();
+        assert_eq!((req.index, req.length, req.proof_layers), (0, 4, 0));
+        let mut leaves = merkle::block_hashes(&data);
+        leaves.resize(4, [0u8; 32]);
+        assert!(torrent.verify_hashes(&req, &leaves));
+        assert!(torrent.layer_request(0).is_none());
+        assert!(torrent.verify_piece(0, &data));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::error::error::{Error, Result};
+
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+// 扩展类型：0 表示没有后续扩展，1 是 selective ack
+const EXT_NONE: u8 = 0;
+const EXT_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            _ => Err(Error::protocol(format!("utp: unknown packet type {}", value))),
+        }
+    }
+}
+
+// Packet is one uTP datagram, BEP 29
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub kind: PacketType,
+    pub conn_id: u16,
+    pub timestamp: u32,
+    pub timestamp_diff: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    // selective ack bitmask, bit 0 of the first byte stands for ack_nr + 2
+    pub sack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(kind: PacketType, conn_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            kind,
+            conn_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr,
+            sack: None,
+            payload: vec![],
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let ext_len = self.sack.as_ref().map_or(0, |sack| 2 + sack.len());
+        let mut buf = Vec::with_capacity(HEADER_LEN + ext_len + self.payload.len());
+        buf.push((self.kind as u8) << 4 | VERSION);
+        buf.push(if self.sack.is_some() { EXT_SELECTIVE_ACK } else { EXT_NONE });
+        buf.extend_from_slice(&self.conn_id.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp_diff.to_be_bytes());
+        buf.extend_from_slice(&self.wnd_size.to_be_bytes());
+        buf.extend_from_slice(&self.seq_nr.to_be_bytes());
+        buf.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(sack) = &self.sack {
+            buf.push(EXT_NONE);
+            buf.push(sack.len() as u8);
+            buf.extend_from_slice(sack);
+        }
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::protocol(format!("utp: packet of {} bytes", buf.len())));
+        }
+        if buf[0] & 0x0f != VERSION {
+            return Err(Error::protocol(format!("utp: version {}", buf[0] & 0x0f)));
+        }
+        let kind = PacketType::try_from(buf[0] >> 4)?;
+        let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+
+        // 扩展是一个链表：每个扩展以下一个扩展的类型和自身长度开头
+        let mut sack = None;
+        let mut ext = buf[1];
+        let mut pos = HEADER_LEN;
+        while ext != EXT_NONE {
+            if pos + 2 > buf.len() {
+                return Err(Error::protocol("utp: truncated extension"));
+            }
+            let (next, len) = (buf[pos], usize::from(buf[pos + 1]));
+            let data = buf.get(pos + 2..pos + 2 + len).ok_or_else(|| Error::protocol("utp: truncated extension"))?;
+            if ext == EXT_SELECTIVE_ACK {
+                if len == 0 || len % 4 != 0 {
+                    return Err(Error::protocol(format!("utp: selective ack of {} bytes", len)));
+                }
+                sack = Some(data.to_vec());
+            }
+            ext = next;
+            pos += 2 + len;
+        }
+
+        Ok(Self {
+            kind,
+            conn_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack,
+            payload: buf[pos..].to_vec(),
+        })
+    }
+
+    // sacked lists the sequence numbers the selective ack bitmask acknowledges
+    pub fn sacked(&self) -> Vec<u16> {
+        let Some(sack) = &self.sack else {
+            return vec![];
+        };
+        let mut seqs = vec![];
+        for (i, byte) in sack.iter().enumerate() {
+            for bit in 0..8 {
+                if byte >> bit & 1 == 1 {
+                    seqs.push(self.ack_nr.wrapping_add(2).wrapping_add((i * 8 + bit) as u16));
+                }
+            }
+        }
+        seqs
+    }
+}
+
+// seq_less reports whether a comes before b, sequence numbers wrap around
+pub fn seq_less(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_with_selective_ack() {
+        let mut packet = Packet::new(PacketType::State, 0x1234, 7, 0xfffe);
+        packet.timestamp = 1;
+        packet.wnd_size = 1 << 20;
+        packet.sack = Some(vec![0b0000_0101, 0, 0, 0x80]);
+        packet.payload = b"data".to_vec();
+        let bytes = packet.serialize();
+        assert_eq!(bytes[..2], [0x21, EXT_SELECTIVE_ACK]);
+        let parsed = Packet::parse(&bytes).unwrap();
+        assert_eq!(parsed, packet);
+        // ack_nr 回绕后 bit 0 对应 ack_nr + 2
+        assert_eq!(parsed.sacked(), vec![0, 2, 31]);
+    }
+
+    #[test]
+    fn rejects_bad_packets() {
+        let packet = Packet::new(PacketType::Data, 1, 2, 3).serialize();
+        assert!(Packet::parse(&packet[..HEADER_LEN - 1]).is_err());
+        let mut version = packet.clone();
+        version[0] = 0x02;
+        assert!(Packet::parse(&version).is_err());
+        let mut truncated = packet.clone();
+        truncated[1] = EXT_SELECTIVE_ACK;
+        assert!(Packet::parse(&truncated).is_err());
+        let mut odd = packet;
+        odd[1] = EXT_SELECTIVE_ACK;
+        odd.extend_from_slice(&[EXT_NONE, 3, 0, 0, 0]);
+        assert!(Packet::parse(&odd).is_err());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(seq_less(1, 2));
+        assert!(seq_less(0xffff, 0));
+        assert!(!seq_less(0, 0xffff));
+        assert!(!seq_less(5, 5));
+    }
+}
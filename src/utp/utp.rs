@@ -0,0 +1,808 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Condvar, Mutex, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tracing::{debug, trace};
+
+use crate::{
+    error::error::Result,
+    utp::packet::{seq_less, Packet, PacketType},
+};
+
+// 每个数据包的负载，留出 IP/UDP 头和扩展的余量
+const MSS: usize = 1200;
+const RECV_WINDOW: usize = 1 << 20;
+const SEND_BUFFER: usize = 1 << 20;
+// LEDBAT: 目标排队延迟 100ms，每个 RTT 窗口最多增长 3000 字节
+const TARGET_DELAY: f64 = 100_000.0;
+const MAX_CWND_INCREASE: f64 = 3000.0;
+const MIN_CWND: f64 = (2 * MSS) as f64;
+const INITIAL_CWND: f64 = (4 * MSS) as f64;
+const MAX_CWND: f64 = (4 << 20) as f64;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+// 连续超时这么多次后放弃连接
+const MAX_TIMEOUTS: u32 = 8;
+const DUP_ACK_THRESHOLD: u32 = 3;
+// 最多缓存这么多个乱序包
+const MAX_OUT_OF_ORDER: u16 = 1024;
+const TICK: Duration = Duration::from_millis(20);
+// 关闭后等待对方 FIN 的时间
+const LINGER: Duration = Duration::from_secs(30);
+const BACKLOG: usize = 64;
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(60);
+
+// now_micros is the timestamp put into every packet, only differences of it mean anything
+fn now_micros() -> u32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+// Wire sends datagrams, dropping a share of them when loss is simulated
+struct Wire {
+    udp: UdpSocket,
+    // 模拟丢包率，单位百万分之一
+    loss: AtomicU32,
+}
+
+impl Wire {
+    fn send(&self, packet: &Packet, addr: SocketAddr) {
+        let loss = self.loss.load(Ordering::Relaxed);
+        if loss > 0 && rand::thread_rng().gen_range(0..1_000_000) < loss {
+            trace!(%addr, seq = packet.seq_nr, kind = ?packet.kind, "utp: simulated loss");
+            return;
+        }
+        if let Err(e) = self.udp.send_to(&packet.serialize(), addr) {
+            trace!(%addr, error = %e, "utp: send failed");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+struct Outgoing {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    // resent because later packets arrived, only a timeout resends it again
+    fast_resent: bool,
+}
+
+// DelayHistory keeps the minimum one way delay of the last minutes as LEDBAT's base delay
+struct DelayHistory {
+    current: u32,
+    previous: u32,
+    since: Instant,
+    last: u32,
+}
+
+impl DelayHistory {
+    fn new() -> Self {
+        Self { current: u32::MAX, previous: u32::MAX, since: Instant::now(), last: 0 }
+    }
+
+    fn add(&mut self, sample: u32) {
+        if self.since.elapsed() >= BASE_DELAY_WINDOW {
+            self.previous = self.current;
+            self.current = u32::MAX;
+            self.since = Instant::now();
+        }
+        self.current = self.current.min(sample);
+        self.last = sample;
+    }
+
+    // queuing returns how far the last sample is above the base delay
+    fn queuing(&self) -> u32 {
+        self.last.saturating_sub(self.current.min(self.previous))
+    }
+}
+
+// Conn is the state of one connection, guarded by Connection
+struct Conn {
+    wire: Arc<Wire>,
+    addr: SocketAddr,
+    status: Status,
+    error: Option<ErrorKind>,
+    send_id: u16,
+    // next sequence number we send and the last one received in order
+    seq_nr: u16,
+    ack_nr: u16,
+    // written by the stream but not packetized yet
+    pending: VecDeque<u8>,
+    in_flight: VecDeque<Outgoing>,
+    in_flight_bytes: usize,
+    received: VecDeque<u8>,
+    out_of_order: HashMap<u16, Packet>,
+    out_of_order_bytes: usize,
+    eof: bool,
+    closing: bool,
+    fin_sent: bool,
+    // the stream handle is gone, the connection only finishes its shutdown
+    dropped: bool,
+    peer_wnd: usize,
+    last_wnd: usize,
+    cwnd: f64,
+    srtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    dup_acks: u32,
+    last_ack: u16,
+    last_cut: Instant,
+    timeouts: u32,
+    // our clock minus the peer's at the last packet, echoed back as timestamp_diff
+    reply_micro: u32,
+    delay: DelayHistory,
+    last_recv: Instant,
+    read_timeout: Option<Duration>,
+}
+
+impl Conn {
+    fn new(wire: Arc<Wire>, addr: SocketAddr, status: Status, send_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            wire,
+            addr,
+            status,
+            error: None,
+            send_id,
+            seq_nr,
+            ack_nr,
+            pending: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            received: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            out_of_order_bytes: 0,
+            eof: false,
+            closing: false,
+            fin_sent: false,
+            dropped: false,
+            peer_wnd: RECV_WINDOW,
+            last_wnd: RECV_WINDOW,
+            cwnd: INITIAL_CWND,
+            srtt: None,
+            rto: INITIAL_RTO,
+            dup_acks: 0,
+            last_ack: ack_nr,
+            last_cut: Instant::now(),
+            timeouts: 0,
+            reply_micro: 0,
+            delay: DelayHistory::new(),
+            last_recv: Instant::now(),
+            read_timeout: None,
+        }
+    }
+
+    fn window(&self) -> usize {
+        RECV_WINDOW.saturating_sub(self.received.len() + self.out_of_order_bytes)
+    }
+
+    fn send_packet(&mut self, packet: &mut Packet) {
+        packet.timestamp = now_micros();
+        packet.timestamp_diff = self.reply_micro;
+        packet.wnd_size = self.window() as u32;
+        self.last_wnd = self.window();
+        self.wire.send(packet, self.addr);
+    }
+
+    // sack builds the selective ack bitmask for packets received past a gap
+    fn sack(&self) -> Option<Vec<u8>> {
+        let furthest = self.out_of_order.keys().map(|seq| seq.wrapping_sub(self.ack_nr).wrapping_sub(2)).max()?;
+        let len = (usize::from(furthest) / 32 + 1).min(8) * 4;
+        let mut mask = vec![0u8; len];
+        for seq in self.out_of_order.keys() {
+            let bit = usize::from(seq.wrapping_sub(self.ack_nr).wrapping_sub(2));
+            if bit < len * 8 {
+                mask[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Some(mask)
+    }
+
+    fn send_state(&mut self) {
+        let mut packet = Packet::new(PacketType::State, self.send_id, self.seq_nr, self.ack_nr);
+        packet.sack = self.sack();
+        self.send_packet(&mut packet);
+    }
+
+    fn transmit(&mut self, mut packet: Packet) {
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.in_flight_bytes += packet.payload.len();
+        self.send_packet(&mut packet);
+        self.in_flight.push_back(Outgoing { packet, sent_at: Instant::now(), transmissions: 1, fast_resent: false });
+    }
+
+    fn resend(&mut self, index: usize) {
+        let mut packet = self.in_flight[index].packet.clone();
+        packet.ack_nr = self.ack_nr;
+        self.send_packet(&mut packet);
+        let out = &mut self.in_flight[index];
+        out.sent_at = Instant::now();
+        out.transmissions += 1;
+        trace!(addr = %self.addr, seq = packet.seq_nr, "utp: resent");
+    }
+
+    fn close(&mut self, kind: ErrorKind) {
+        if self.status == Status::Closed {
+            return;
+        }
+        debug!(addr = %self.addr, ?kind, "utp: connection closed");
+        self.status = Status::Closed;
+        self.error = Some(kind);
+        self.pending.clear();
+        self.in_flight.clear();
+        self.in_flight_bytes = 0;
+    }
+
+    // flush packetizes pending bytes as far as the congestion and the peer's window allow
+    fn flush(&mut self) {
+        if self.status != Status::Connected {
+            return;
+        }
+        let window = (self.cwnd as usize).min(self.peer_wnd);
+        while !self.pending.is_empty() {
+            let len = self.pending.len().min(MSS);
+            // 窗口为零时也允许一个包在途，用来探测对方窗口
+            if !self.in_flight.is_empty() && self.in_flight_bytes + len > window {
+                break;
+            }
+            let mut packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr, self.ack_nr);
+            packet.payload = self.pending.drain(..len).collect();
+            self.transmit(packet);
+        }
+        if self.closing && self.pending.is_empty() && !self.fin_sent {
+            self.fin_sent = true;
+            self.transmit(Packet::new(PacketType::Fin, self.send_id, self.seq_nr, self.ack_nr));
+        }
+    }
+
+    fn sample_rtt(&mut self, rtt: Duration) {
+        let (srtt, var) = match self.srtt {
+            None => (rtt, rtt / 2),
+            Some((srtt, var)) => {
+                let delta = srtt.abs_diff(rtt);
+                (srtt * 7 / 8 + rtt / 8, var * 3 / 4 + delta / 4)
+            },
+        };
+        self.srtt = Some((srtt, var));
+        self.rto = (srtt + var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    fn remove_in_flight(&mut self, index: usize, now: Instant) -> usize {
+        let out = self.in_flight.remove(index).unwrap();
+        // 重传过的包无法判断应答对应哪一次发送
+        if out.transmissions == 1 {
+            self.sample_rtt(now - out.sent_at);
+        }
+        self.in_flight_bytes -= out.packet.payload.len();
+        out.packet.payload.len()
+    }
+
+    // on_ack drops acknowledged packets, grows the window by LEDBAT and resends what the peer lost
+    fn on_ack(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        let mut acked = 0;
+        let mut progressed = false;
+        while self.in_flight.front().is_some_and(|out| !seq_less(packet.ack_nr, out.packet.seq_nr)) {
+            acked += self.remove_in_flight(0, now);
+            progressed = true;
+        }
+        let sacked = packet.sacked();
+        for seq in &sacked {
+            if let Some(index) = self.in_flight.iter().position(|out| out.packet.seq_nr == *seq) {
+                acked += self.remove_in_flight(index, now);
+            }
+        }
+
+        if progressed {
+            self.dup_acks = 0;
+            self.timeouts = 0;
+            self.last_ack = packet.ack_nr;
+            // 有进展后撤销超时退避
+            self.rto = self.srtt.map_or(INITIAL_RTO, |(srtt, var)| (srtt + var * 4).clamp(MIN_RTO, MAX_RTO));
+        } else if packet.kind == PacketType::State && packet.ack_nr == self.last_ack && !self.in_flight.is_empty() {
+            self.dup_acks += 1;
+        }
+
+        if acked > 0 {
+            if packet.timestamp_diff != 0 {
+                self.delay.add(packet.timestamp_diff);
+            }
+            let off_target = ((TARGET_DELAY - f64::from(self.delay.queuing())) / TARGET_DELAY).clamp(-1.0, 1.0);
+            self.cwnd += MAX_CWND_INCREASE * off_target * acked as f64 / self.cwnd;
+            self.cwnd = self.cwnd.clamp(MIN_CWND, MAX_CWND);
+        }
+
+        // 之后已有三个包被选择确认的包认为丢了，重复应答说明第一个在途包丢了
+        let distances = sacked.iter().map(|seq| seq.wrapping_sub(packet.ack_nr)).collect::<Vec<_>>();
+        let lost = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .filter(|(index, out)| {
+                let distance = out.packet.seq_nr.wrapping_sub(packet.ack_nr);
+                let later = distances.len() - distances.partition_point(|d| *d <= distance);
+                !out.fast_resent && (later >= DUP_ACK_THRESHOLD as usize || (*index == 0 && self.dup_acks >= DUP_ACK_THRESHOLD))
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        if lost.is_empty() {
+            return;
+        }
+        for index in lost {
+            self.in_flight[index].fast_resent = true;
+            self.resend(index);
+        }
+        let rtt = self.srtt.map_or(INITIAL_RTO, |(srtt, _)| srtt);
+        if self.last_cut.elapsed() >= rtt {
+            self.last_cut = Instant::now();
+            self.cwnd = (self.cwnd / 2.0).max(MIN_CWND);
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+        if packet.kind == PacketType::Fin {
+            self.eof = true;
+        } else if !self.eof {
+            self.received.extend(packet.payload);
+        }
+    }
+
+    fn on_data(&mut self, packet: Packet) {
+        let distance = packet.seq_nr.wrapping_sub(self.ack_nr);
+        if self.eof || distance == 0 || distance >= 0x8000 {
+            return;
+        }
+        if distance > MAX_OUT_OF_ORDER {
+            return;
+        }
+        if distance > 1 {
+            if !self.out_of_order.contains_key(&packet.seq_nr) {
+                self.out_of_order_bytes += packet.payload.len();
+                self.out_of_order.insert(packet.seq_nr, packet);
+            }
+            return;
+        }
+        self.deliver(packet);
+        while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.out_of_order_bytes -= next.payload.len();
+            self.deliver(next);
+        }
+    }
+
+    fn on_packet(&mut self, packet: Packet) {
+        if self.status == Status::Closed {
+            return;
+        }
+        self.last_recv = Instant::now();
+        self.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+        self.peer_wnd = packet.wnd_size as usize;
+        match packet.kind {
+            PacketType::Reset => {
+                self.close(ErrorKind::ConnectionReset);
+                return;
+            },
+            // 对方没收到我们对 SYN 的应答
+            PacketType::Syn => {
+                self.send_state();
+                return;
+            },
+            _ => {},
+        }
+        if self.status == Status::SynSent {
+            if packet.kind != PacketType::State {
+                return;
+            }
+            self.status = Status::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            debug!(addr = %self.addr, "utp: connected");
+        }
+        self.on_ack(&packet);
+        if matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+            self.on_data(packet);
+            self.send_state();
+        }
+        self.flush();
+    }
+
+    // tick resends on timeout, a connection that keeps timing out is given up
+    fn tick(&mut self) {
+        if self.status == Status::Closed {
+            return;
+        }
+        if self.in_flight.front().is_some_and(|out| out.sent_at.elapsed() >= self.rto) {
+            self.timeouts += 1;
+            if self.timeouts > MAX_TIMEOUTS {
+                self.close(ErrorKind::TimedOut);
+                return;
+            }
+            self.rto = (self.rto * 2).min(MAX_RTO);
+            self.cwnd = MIN_CWND;
+            self.resend(0);
+        }
+        self.flush();
+    }
+
+    fn finished(&self) -> bool {
+        match self.status {
+            Status::Closed => true,
+            Status::SynSent => self.dropped,
+            Status::Connected => {
+                self.dropped && self.fin_sent && self.in_flight.is_empty() && (self.eof || self.last_recv.elapsed() >= LINGER)
+            },
+        }
+    }
+}
+
+struct Connection {
+    state: Mutex<Conn>,
+    changed: Condvar,
+}
+
+impl Connection {
+    fn new(conn: Conn) -> Arc<Self> {
+        Arc::new(Self { state: Mutex::new(conn), changed: Condvar::new() })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Conn)) {
+        f(&mut self.state.lock().unwrap());
+        self.changed.notify_all();
+    }
+}
+
+struct Shared {
+    wire: Arc<Wire>,
+    // keyed by the peer and the connection id it sends to us
+    conns: Mutex<HashMap<(SocketAddr, u16), Arc<Connection>>>,
+    backlog: Mutex<VecDeque<UtpStream>>,
+    accepted: Condvar,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn run(&self) {
+        let mut buf = vec![0u8; 65536];
+        let mut last_tick = Instant::now();
+        while !self.closed.load(Ordering::SeqCst) {
+            match self.wire.udp.recv_from(&mut buf) {
+                Ok((n, addr)) => match Packet::parse(&buf[..n]) {
+                    Ok(packet) => self.handle(packet, addr),
+                    Err(e) => trace!(%addr, error = %e, "utp: bad packet"),
+                },
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+                // ICMP 不可达在某些平台上会变成这里的错误
+                Err(e) => trace!(error = %e, "utp: receive failed"),
+            }
+            if last_tick.elapsed() >= TICK {
+                last_tick = Instant::now();
+                self.tick();
+            }
+        }
+        for conn in self.conns.lock().unwrap().values() {
+            conn.update(|c| c.close(ErrorKind::ConnectionAborted));
+        }
+    }
+
+    fn handle(&self, packet: Packet, addr: SocketAddr) {
+        if packet.kind == PacketType::Syn {
+            self.handle_syn(packet, addr);
+            return;
+        }
+        let conn = self.conns.lock().unwrap().get(&(addr, packet.conn_id)).cloned();
+        match conn {
+            Some(conn) => conn.update(|c| c.on_packet(packet)),
+            None if packet.kind != PacketType::Reset => {
+                trace!(%addr, conn_id = packet.conn_id, "utp: packet for unknown connection");
+                let reset = Packet::new(PacketType::Reset, packet.conn_id, 0, packet.seq_nr);
+                self.wire.send(&reset, addr);
+            },
+            None => {},
+        }
+    }
+
+    // handle_syn accepts a new connection, the initiator sends on conn_id + 1 from now on
+    fn handle_syn(&self, syn: Packet, addr: SocketAddr) {
+        let key = (addr, syn.conn_id.wrapping_add(1));
+        let mut conns = self.conns.lock().unwrap();
+        if let Some(conn) = conns.get(&key).cloned() {
+            drop(conns);
+            conn.update(|c| c.on_packet(syn));
+            return;
+        }
+        let mut backlog = self.backlog.lock().unwrap();
+        if backlog.len() >= BACKLOG {
+            trace!(%addr, "utp: backlog full");
+            return;
+        }
+        let mut state = Conn::new(self.wire.clone(), addr, Status::Connected, syn.conn_id, rand::random(), syn.seq_nr);
+        state.peer_wnd = syn.wnd_size as usize;
+        state.reply_micro = now_micros().wrapping_sub(syn.timestamp);
+        state.send_state();
+        let conn = Connection::new(state);
+        conns.insert(key, conn.clone());
+        debug!(%addr, "utp: accepted");
+        backlog.push_back(UtpStream { conn, addr });
+        self.accepted.notify_all();
+    }
+
+    fn tick(&self) {
+        let mut conns = self.conns.lock().unwrap();
+        conns.retain(|_, conn| {
+            conn.update(Conn::tick);
+            !conn.state.lock().unwrap().finished()
+        });
+    }
+}
+
+// UtpSocket is a UDP socket carrying uTP connections (BEP 29), both the ones we open and the ones we accept
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpSocket").field("local_addr", &self.local_addr).finish()
+    }
+}
+
+impl UtpSocket {
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let udp = UdpSocket::bind(addr)?;
+        udp.set_read_timeout(Some(TICK))?;
+        let local_addr = udp.local_addr()?;
+        let shared = Arc::new(Shared {
+            wire: Arc::new(Wire { udp, loss: AtomicU32::new(0) }),
+            conns: Mutex::new(HashMap::new()),
+            backlog: Mutex::new(VecDeque::new()),
+            accepted: Condvar::new(),
+            closed: AtomicBool::new(false),
+        });
+        let run = shared.clone();
+        let thread = thread::spawn(move || run.run());
+        Ok(Self { shared, local_addr, thread: Some(thread) })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // set_loss drops the given share of outgoing packets, it exists to test recovery on loopback
+    pub fn set_loss(&self, rate: f64) {
+        let loss = (rate.clamp(0.0, 1.0) * 1_000_000.0) as u32;
+        self.shared.wire.loss.store(loss, Ordering::Relaxed);
+    }
+
+    // connect opens a connection, the SYN is resent until the peer answers or timeout passes
+    pub fn connect(&self, addr: SocketAddr, timeout: Duration) -> Result<UtpStream> {
+        let (conn, recv_id) = {
+            let mut conns = self.shared.conns.lock().unwrap();
+            let recv_id = loop {
+                let id: u16 = rand::random();
+                if !conns.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let mut state = Conn::new(self.shared.wire.clone(), addr, Status::SynSent, recv_id.wrapping_add(1), 1, 0);
+            state.transmit(Packet::new(PacketType::Syn, recv_id, 1, 0));
+            let conn = Connection::new(state);
+            conns.insert((addr, recv_id), conn.clone());
+            (conn, recv_id)
+        };
+        trace!(%addr, recv_id, "utp: syn sent");
+
+        let deadline = Instant::now() + timeout;
+        let mut state = conn.state.lock().unwrap();
+        while state.status == Status::SynSent {
+            let now = Instant::now();
+            if now >= deadline {
+                state.close(ErrorKind::TimedOut);
+                break;
+            }
+            state = conn.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        if let Some(kind) = state.error {
+            return Err(io::Error::from(kind).into());
+        }
+        drop(state);
+        Ok(UtpStream { conn, addr })
+    }
+
+    // accept_timeout waits up to timeout for an inbound connection
+    pub fn accept_timeout(&self, timeout: Duration) -> Option<UtpStream> {
+        let backlog = self.shared.backlog.lock().unwrap();
+        let (mut backlog, _) = self.shared.accepted.wait_timeout_while(backlog, timeout, |b| b.is_empty()).unwrap();
+        backlog.pop_front()
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+// UtpStream is one uTP connection, it reads and writes like a TcpStream
+pub struct UtpStream {
+    conn: Arc<Connection>,
+    addr: SocketAddr,
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpStream").field("peer_addr", &self.addr).finish()
+    }
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // set_read_timeout makes reads fail with WouldBlock once the timeout passes, None blocks forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "zero timeout"));
+        }
+        self.conn.state.lock().unwrap().read_timeout = timeout;
+        Ok(())
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.conn.state.lock().unwrap();
+        let deadline = state.read_timeout.map(|t| Instant::now() + t);
+        loop {
+            if !state.received.is_empty() {
+                let n = buf.len().min(state.received.len());
+                for (dst, src) in buf.iter_mut().zip(state.received.drain(..n)) {
+                    *dst = src;
+                }
+                // 窗口曾经接近关闭，告诉对方可以继续发送
+                if state.last_wnd < MSS && state.window() >= MSS {
+                    state.send_state();
+                }
+                return Ok(n);
+            }
+            if state.eof {
+                return Ok(0);
+            }
+            if let Some(kind) = state.error {
+                return Err(kind.into());
+            }
+            state = match deadline {
+                None => self.conn.changed.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(ErrorKind::WouldBlock.into());
+                    }
+                    self.conn.changed.wait_timeout(state, deadline - now).unwrap().0
+                },
+            };
+        }
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.conn.state.lock().unwrap();
+        loop {
+            if let Some(kind) = state.error {
+                return Err(kind.into());
+            }
+            let room = SEND_BUFFER.saturating_sub(state.pending.len());
+            if room > 0 {
+                let n = buf.len().min(room);
+                state.pending.extend(&buf[..n]);
+                state.flush();
+                return Ok(n);
+            }
+            state = self.conn.changed.wait(state).unwrap();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for UtpStream {
+    // drop sends FIN once everything written went out, the socket keeps the connection until it is acknowledged
+    fn drop(&mut self) {
+        self.conn.update(|c| {
+            c.closing = true;
+            c.dropped = true;
+            c.flush();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    // exchange sends a payload each way at once and checks what both ends read
+    fn exchange(loss: f64, len: usize) {
+        let a = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UtpSocket::bind("127.0.0.1:0").unwrap();
+        a.set_loss(loss);
+        b.set_loss(loss);
+        let (to_b, to_a) = (payload(len, 1), payload(len, 2));
+
+        let b_addr = b.local_addr();
+        let (mut outbound, mut inbound) = thread::scope(|s| {
+            let accepted = s.spawn(|| b.accept_timeout(Duration::from_secs(20)).unwrap());
+            let outbound = a.connect(b_addr, Duration::from_secs(20)).unwrap();
+            (outbound, accepted.join().unwrap())
+        });
+        outbound.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+        inbound.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+
+        let (at_b, at_a) = thread::scope(|s| {
+            let at_b = s.spawn(|| {
+                let mut buf = vec![0u8; len];
+                inbound.write_all(&to_a).unwrap();
+                inbound.read_exact(&mut buf).unwrap();
+                buf
+            });
+            let mut buf = vec![0u8; len];
+            outbound.write_all(&to_b).unwrap();
+            outbound.read_exact(&mut buf).unwrap();
+            (at_b.join().unwrap(), buf)
+        });
+        assert!(at_b == to_b, "a to b differs");
+        assert!(at_a == to_a, "b to a differs");
+    }
+
+    #[test]
+    fn transfers_both_ways() {
+        exchange(0.0, 300_000);
+    }
+
+    #[test]
+    fn recovers_from_loss_both_ways() {
+        // SYN、数据包和 ack 都可能丢失，靠重传和 SACK 恢复
+        exchange(0.1, 300_000);
+    }
+
+    #[test]
+    fn reads_end_after_the_peer_closes() {
+        let a = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UtpSocket::bind("127.0.0.1:0").unwrap();
+        a.set_loss(0.1);
+        let b_addr = b.local_addr();
+        let mut outbound = a.connect(b_addr, Duration::from_secs(20)).unwrap();
+        let mut inbound = b.accept_timeout(Duration::from_secs(20)).unwrap();
+        inbound.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+        outbound.write_all(b"last words").unwrap();
+        drop(outbound);
+        let mut buf = vec![];
+        inbound.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"last words");
+    }
+}